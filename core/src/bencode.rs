@@ -1,22 +1,24 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+};
 
 use hex::FromHex;
 
 use bendy::{
     decoding::{Decoder, Error as DecodingError, FromBencode, Object},
-    encoding::AsString,
+    encoding::{
+        AsString, Error as EncodingError, SingleItemEncoder, ToBencode, UnsortedDictEncoder,
+    },
 };
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
 
-use url::Url;
-
 use crate::{
     data::{
-        File, GeneralError, HttpResponse, Info, Mode, Peer, ScrapeResponse, Status, TorrentInfo,
-        SHA1_LEN,
+        File, HttpResponse, Info, Mode, Peer, ScrapeResponse, Status, TorrentInfo, Value, SHA1_LEN,
     },
-    helpers::range_to_array,
+    helpers::{compact_peers, range_to_array},
 };
 
 impl FromBencode for TorrentInfo {
@@ -30,6 +32,7 @@ impl FromBencode for TorrentInfo {
 
         let mut md = TorrentInfo::default();
         let mut info = Info::default();
+        let mut announce = None;
 
         while let Some(pair) = dict.next_pair()? {
             match pair {
@@ -49,33 +52,14 @@ impl FromBencode for TorrentInfo {
                     }
                 }
                 (b"announce", _) => {
-                    let s = String::decode_bencode_object(pair.1)?;
-                    md.announce.push(s);
+                    announce = Some(String::decode_bencode_object(pair.1)?);
                 }
                 (b"announce-list", list) => {
-                    // Not bothering separating announce and announce-list since they both contain
-                    // tracker URLs.
                     let mut list = list.try_into_list()?;
 
-                    while let Some(list) = list.next_object()? {
-                        let mut list = list.try_into_list()?;
-
-                        while let Some(s) = list.next_object()? {
-                            let s = String::decode_bencode_object(s)?;
-
-                            if s.starts_with("http") {
-                                md.announce.http.push(s.clone());
-                            }
-                            if s.starts_with("udp") {
-                                let url = Url::parse(&s)?;
-                                let host = url.host().ok_or(GeneralError::InvalidUdpTracker(s))?;
-                                let port = url.port().unwrap_or(80);
-
-                                if let Ok(mut addr) = (host.to_string(), port).to_socket_addrs() {
-                                    md.announce.udp.push(addr.next().unwrap());
-                                }
-                            }
-                        }
+                    while let Some(tier) = list.next_object()? {
+                        let tier = Vec::<String>::decode_bencode_object(tier)?;
+                        md.announce.push_tier(tier);
                     }
                 }
                 (b"creation date", _) => {
@@ -106,22 +90,41 @@ impl FromBencode for TorrentInfo {
                 (b"nodes", _) => {
                     let mut list = pair.1.try_into_list()?;
 
+                    // [["127.0.0.1", 6881], ["router.example.com", 6881]]
                     while let Some(x) = list.next_object()? {
                         let mut x = x.try_into_list()?;
 
-                        let fst = x.next_object()?;
-                        let _fst = String::decode_bencode_object(fst.unwrap()).unwrap();
+                        let Some(host) = x.next_object()? else {
+                            continue;
+                        };
+                        let host = String::decode_bencode_object(host)?;
+                        let Some(port) = x.next_object()? else {
+                            continue;
+                        };
+                        let port = u16::decode_bencode_object(port)?;
 
-                        let snd = x.next_object()?;
-                        let _snd = u64::decode_bencode_object(snd.unwrap()).unwrap();
+                        md.nodes.push((host, port));
+                    }
+                }
+                // a single URL or a list of them
+                (b"url-list", Object::List(mut list)) => {
+                    while let Some(url) = list.next_object()? {
+                        md.webseeds.push(String::decode_bencode_object(url)?);
                     }
                 }
+                (b"url-list", url) => {
+                    md.webseeds.push(String::decode_bencode_object(url)?);
+                }
                 _ => {
                     let _s = String::decode_bencode_object(pair.1)?;
                 }
             }
         }
 
+        // BEP 12: clients that know the announce-list ignore the announce key
+        if let Some(announce) = announce.filter(|_| md.announce.tiers.is_empty()) {
+            md.announce.push_tier(vec![announce]);
+        }
         md.info = Some(info);
 
         Ok(md)
@@ -158,7 +161,7 @@ impl FromBencode for Info {
                 mut dir_name,
                 mut files,
                 // TODO: check whether md5sums are still relevant in $CURRENT_YEAR.
-                md5sum,
+                mut md5sum,
             } => {
                 while let Some(pair) = dict.next_pair()? {
                     match pair {
@@ -171,14 +174,15 @@ impl FromBencode for Info {
                             assert_eq!(pieces.len() % 20, 0);
 
                             let pieces: Vec<[u8; SHA1_LEN]> = pieces
-                                .chunks(pieces.len() / SHA1_LEN)
+                                .chunks(SHA1_LEN)
                                 .map(|x| x[0..20].try_into().unwrap())
                                 .collect();
 
                             info.pieces = pieces.into_boxed_slice();
                         }
-                        (b"private", _) => {
-                            info.private = Some(());
+                        (b"private", v) => {
+                            let v = Value::decode_bencode_object(v)?;
+                            private(v, &mut info.private, &mut info.extra);
                         }
 
                         (b"name", _) => {
@@ -207,14 +211,24 @@ impl FromBencode for Info {
                                                 file.path.push(s);
                                             }
                                         }
-                                        _ => {}
+                                        (key, v) => {
+                                            let v = Value::decode_bencode_object(v)?;
+                                            file.extra.insert(key.to_vec(), v);
+                                        }
                                     }
                                 }
 
                                 files.push(file);
                             }
                         }
-                        _ => {}
+                        (b"md5sum", _) => {
+                            let v = pair.1.try_into_bytes()?;
+                            md5sum = Some(v.to_vec().into());
+                        }
+                        (key, v) => {
+                            info.extra
+                                .insert(key.to_vec(), Value::decode_bencode_object(v)?);
+                        }
                     }
                 }
 
@@ -244,8 +258,9 @@ impl FromBencode for Info {
 
                             info.pieces = pieces.into_boxed_slice();
                         }
-                        (b"private", _) => {
-                            info.private = Some(());
+                        (b"private", v) => {
+                            let v = Value::decode_bencode_object(v)?;
+                            private(v, &mut info.private, &mut info.extra);
                         }
                         (b"name", _) => {
                             name = String::decode_bencode_object(pair.1)?;
@@ -257,7 +272,10 @@ impl FromBencode for Info {
                             let v = pair.1.try_into_bytes()?;
                             md5sum = Some(v.to_vec().into());
                         }
-                        _ => {}
+                        (key, v) => {
+                            info.extra
+                                .insert(key.to_vec(), Value::decode_bencode_object(v)?);
+                        }
                     }
                 }
 
@@ -275,6 +293,182 @@ impl FromBencode for Info {
     }
 }
 
+// BEP 27 only knows 1, whatever else is in there still counts towards the info hash
+fn private(value: Value, private: &mut Option<()>, extra: &mut BTreeMap<Vec<u8>, Value>) {
+    match value {
+        Value::Integer(1) => *private = Some(()),
+        value => {
+            extra.insert(b"private".to_vec(), value);
+        }
+    }
+}
+
+impl FromBencode for Value {
+    fn decode_bencode_object(object: Object) -> Result<Self, DecodingError>
+    where
+        Self: Sized,
+    {
+        let value = match object {
+            Object::Integer(i) => Value::Integer(i.parse()?),
+            Object::Bytes(bytes) => Value::Bytes(bytes.to_vec()),
+            Object::List(mut list) => {
+                let mut values = Vec::new();
+                while let Some(v) = list.next_object()? {
+                    values.push(Value::decode_bencode_object(v)?);
+                }
+                Value::List(values)
+            }
+            Object::Dict(mut dict) => {
+                let mut values = BTreeMap::new();
+                while let Some((key, v)) = dict.next_pair()? {
+                    values.insert(key.to_vec(), Value::decode_bencode_object(v)?);
+                }
+                Value::Dict(values)
+            }
+        };
+
+        Ok(value)
+    }
+}
+
+impl ToBencode for Value {
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), EncodingError> {
+        match self {
+            Value::Integer(i) => encoder.emit_int(*i),
+            Value::Bytes(bytes) => encoder.emit_bytes(bytes),
+            Value::List(list) => encoder.emit(list),
+            Value::Dict(dict) => encoder.emit(dict),
+        }
+    }
+}
+
+// the keys of the info dictionary that depend on the mode, the dictionary gets sorted once it's
+// complete
+fn emit_mode(mode: &Mode, e: &mut UnsortedDictEncoder) -> Result<(), EncodingError> {
+    let md5sum = match mode {
+        Mode::Single {
+            name,
+            length,
+            md5sum,
+        } => {
+            e.emit_pair(b"name", name)?;
+            e.emit_pair(b"length", length)?;
+            md5sum
+        }
+        Mode::Multi {
+            dir_name,
+            files,
+            md5sum,
+        } => {
+            e.emit_pair(b"name", dir_name)?;
+            e.emit_pair(b"files", files)?;
+            md5sum
+        }
+    };
+
+    match md5sum {
+        Some(md5sum) => e.emit_pair(b"md5sum", AsString(md5sum)),
+        None => Ok(()),
+    }
+}
+
+fn emit_extra(
+    extra: &BTreeMap<Vec<u8>, Value>,
+    e: &mut UnsortedDictEncoder,
+) -> Result<(), EncodingError> {
+    extra
+        .iter()
+        .try_for_each(|(key, value)| e.emit_pair(key, value))
+}
+
+impl ToBencode for Mode {
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), EncodingError> {
+        encoder.emit_unsorted_dict(|e| emit_mode(self, e))
+    }
+}
+
+impl ToBencode for File {
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), EncodingError> {
+        encoder.emit_unsorted_dict(|e| {
+            e.emit_pair(b"length", self.length)?;
+            if let Some(md5sum) = &self.md5sum {
+                e.emit_pair(b"md5sum", AsString(md5sum))?;
+            }
+            e.emit_pair(b"path", &self.path)?;
+            emit_extra(&self.extra, e)
+        })
+    }
+}
+
+// the same bytes that were decoded, so the info hash comes out the same
+impl ToBencode for Info {
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), EncodingError> {
+        encoder.emit_unsorted_dict(|e| {
+            emit_mode(&self.mode, e)?;
+            e.emit_pair(b"piece length", self.piece_length)?;
+            e.emit_pair(b"pieces", AsString(self.pieces.concat()))?;
+            if self.private.is_some() {
+                e.emit_pair(b"private", 1)?;
+            }
+            emit_extra(&self.extra, e)
+        })
+    }
+}
+
+// UDP trackers come back as the address they resolved to, and keys we don't know outside of the
+// info dictionary are gone
+impl ToBencode for TorrentInfo {
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), EncodingError> {
+        let tiers: Vec<Vec<String>> = self
+            .announce
+            .tiers
+            .iter()
+            .map(|tier| tier.iter().map(ToString::to_string).collect())
+            .collect();
+        // [["127.0.0.1", 6881], ["router.example.com", 6881]]
+        let nodes: Vec<_> = self
+            .nodes
+            .iter()
+            .map(|(host, port)| {
+                Value::List(vec![
+                    Value::Bytes(host.as_bytes().to_vec()),
+                    Value::Integer(i64::from(*port)),
+                ])
+            })
+            .collect();
+
+        encoder.emit_dict(|mut e| {
+            if let Some(tracker) = tiers.iter().flatten().next() {
+                e.emit_pair(b"announce", tracker)?;
+            }
+            // BEP 12, only needed once there's more than one
+            if tiers.iter().flatten().nth(1).is_some() {
+                e.emit_pair(b"announce-list", &tiers)?;
+            }
+            if !self.comment.is_empty() {
+                e.emit_pair(b"comment", &self.comment)?;
+            }
+            if let Some(author) = &self.author {
+                e.emit_pair(b"created by", author)?;
+            }
+            if let Some(created) = self.created {
+                e.emit_pair(b"creation date", created)?;
+            }
+            if let Some(info) = &self.info {
+                e.emit_pair(b"info", info)?;
+            }
+            if !nodes.is_empty() {
+                e.emit_pair(b"nodes", &nodes)?;
+            }
+            if !self.webseeds.is_empty() {
+                e.emit_pair(b"url-list", &self.webseeds)?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
 impl FromBencode for HttpResponse {
     const EXPECTED_RECURSION_DEPTH: usize = 5;
 
@@ -304,6 +498,28 @@ impl FromBencode for HttpResponse {
                     let i = u64::decode_bencode_object(pair.1)?;
                     resp.min_interval = Some(i);
                 }
+                (b"tracker id", _) => {
+                    let s = String::decode_bencode_object(pair.1)?;
+                    resp.tracker_id = Some(s);
+                }
+                (b"complete", _) => {
+                    let i = u64::decode_bencode_object(pair.1)?;
+                    resp.complete = Some(i);
+                }
+                (b"incomplete", _) => {
+                    let i = u64::decode_bencode_object(pair.1)?;
+                    resp.incomplete = Some(i);
+                }
+                (b"external ip", _) => {
+                    let AsString(bytes) = AsString::decode_bencode_object(pair.1)?;
+
+                    // raw address bytes, anything else gets ignored
+                    resp.external_ip = match bytes.len() {
+                        4 => Some(IpAddr::from(range_to_array::<4>(&bytes))),
+                        16 => Some(IpAddr::from(range_to_array::<16>(&bytes))),
+                        _ => None,
+                    };
+                }
                 (b"peers", _) => {
                     let mut peers = Vec::new();
 
@@ -343,25 +559,19 @@ impl FromBencode for HttpResponse {
                             }
                         }
                         Object::Bytes(bytes) => {
-                            assert_eq!(bytes.len() % 6, 0);
-
-                            for chunk in bytes.chunks(6) {
-                                let ip = Ipv4Addr::from(range_to_array(&chunk[..4]));
-                                let port = u16::from_be_bytes(range_to_array(&chunk[4..]));
-
-                                let peer = Peer {
-                                    id: None,
-                                    addr: SocketAddr::from((ip, port)),
-                                };
-
-                                peers.push(peer);
-                            }
+                            peers.extend(compact_peers(bytes, false).iter().map(Peer::from));
                         }
                         _ => {
                             panic!();
                         }
                     }
-                    resp.peers = peers;
+                    resp.peers.extend(peers);
+                }
+                // compact IPv6 peers come on their own, BEP 7
+                (b"peers6", _) => {
+                    let AsString(bytes) = AsString::decode_bencode_object(pair.1)?;
+                    resp.peers
+                        .extend(compact_peers(&bytes, true).iter().map(Peer::from));
                 }
                 _ => {}
             }
@@ -379,10 +589,19 @@ impl FromBencode for ScrapeResponse {
         Self: Sized,
     {
         let mut dict = object.try_into_dictionary()?;
-        let mut result = Vec::new();
+        let mut result = ScrapeResponse::new();
 
-        while let Some(files) = dict.next_pair()? {
-            let mut files = files.1.try_into_dictionary()?;
+        while let Some(pair) = dict.next_pair()? {
+            // "flags" is of no use here
+            let files = match pair {
+                (b"files", files) => files,
+                (b"failure reason", reason) => {
+                    result.failure_reason = Some(String::decode_bencode_object(reason)?);
+                    continue;
+                }
+                _ => continue,
+            };
+            let mut files = files.try_into_dictionary()?;
 
             while let Some(file) = files.next_pair()? {
                 let mut decoder = file.1.try_into_dictionary()?;
@@ -397,14 +616,65 @@ impl FromBencode for ScrapeResponse {
                             status.leechers = u32::decode_bencode_object(pair.1)?;
                         }
                         (b"downloaded", _) => {
-                            let _err = u32::decode_bencode_object(pair.1)?;
+                            status.finished = u32::decode_bencode_object(pair.1)?;
+                        }
+                        (b"name", _) => {
+                            status.name = Some(String::decode_bencode_object(pair.1)?);
                         }
                         _ => {}
                     }
                 }
-                result.push((file.0.to_vec(), status));
+
+                // anything but an info hash can't be matched to a torrent anyway
+                if let Ok(hash) = file.0.try_into() {
+                    result.files.insert(hash, status);
+                }
             }
         }
-        Ok(Self { files: result })
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::piece_manager::sha1;
+
+    #[test]
+    fn test_info_round_trip() {
+        let pieces = [[1u8; 20], [2; 20], [3; 20]].concat();
+        // with keys nobody here knows, in the info dictionary and in one of its files
+        let info = [
+            b"d5:filesld6:lengthi8e4:pathl1:aeed4:attr1:x6:lengthi4e4:pathl1:beee".as_slice(),
+            b"4:name3:dir12:piece lengthi4e6:pieces60:",
+            &pieces,
+            b"7:privatei1e6:source3:xyze",
+        ]
+        .concat();
+        let announce = "http://tracker.example/announce";
+        let torrent = [
+            format!(
+                "d8:announce{}:{announce}7:comment2:hi4:info",
+                announce.len()
+            )
+            .as_bytes(),
+            &info,
+            b"e",
+        ]
+        .concat();
+
+        let md = TorrentInfo::from_bencode(&torrent).unwrap();
+        let decoded = md.info.clone().unwrap_or_default();
+        assert_eq!(decoded.private, Some(()));
+        assert_eq!(
+            decoded.extra.get(b"source".as_slice()),
+            Some(&Value::Bytes(b"xyz".to_vec()))
+        );
+
+        let encoded = decoded.to_bencode().unwrap();
+        assert_eq!(encoded, info);
+        assert_eq!(sha1(&encoded), md.hash);
+        assert_eq!(md.to_bencode().unwrap(), torrent);
     }
 }
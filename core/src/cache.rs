@@ -0,0 +1,105 @@
+use std::collections::VecDeque;
+
+use bytes::Bytes;
+
+// pieces recently read back from disk, popular pieces get requested by many peers at once and
+// every one of them asks for each block separately
+pub struct ReadCache {
+    // in bytes, 0 disables the cache
+    capacity: usize,
+    size: usize,
+    // least recently used at the front
+    entries: VecDeque<(usize, Bytes)>,
+}
+
+impl ReadCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn get(&mut self, index: usize) -> Option<Bytes> {
+        let i = self.entries.iter().position(|(k, _)| *k == index)?;
+        let entry = self.entries.remove(i)?;
+        let piece = entry.1.clone();
+        self.entries.push_back(entry);
+
+        Some(piece)
+    }
+
+    pub fn insert(&mut self, index: usize, piece: Bytes) {
+        self.remove(index);
+        if piece.len() > self.capacity {
+            return;
+        }
+
+        self.size += piece.len();
+        self.entries.push_back((index, piece));
+        self.evict();
+    }
+
+    // pieces that changed on disk or failed a check must not be served from memory
+    pub fn remove(&mut self, index: usize) {
+        if let Some(i) = self.entries.iter().position(|(k, _)| *k == index) {
+            let (_, piece) = self.entries.remove(i).unwrap();
+            self.size -= piece.len();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.size = 0;
+    }
+
+    pub fn resize(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.capacity {
+            match self.entries.pop_front() {
+                Some((_, piece)) => self.size -= piece.len(),
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_cache() {
+        let piece = |n: u8| Bytes::from(vec![n; 4]);
+        let mut cache = ReadCache::new(8);
+
+        cache.insert(0, piece(0));
+        cache.insert(1, piece(1));
+        assert_eq!(cache.get(0), Some(piece(0)));
+
+        // 1 is the least recently used now
+        cache.insert(2, piece(2));
+        assert_eq!(cache.get(1), None);
+        assert_eq!(cache.get(0), Some(piece(0)));
+        assert_eq!(cache.get(2), Some(piece(2)));
+
+        cache.insert(3, Bytes::from(vec![3; 16]));
+        assert_eq!(cache.get(3), None);
+
+        cache.resize(4);
+        assert_eq!(cache.get(0), None);
+        assert_eq!(cache.get(2), Some(piece(2)));
+
+        cache.resize(0);
+        assert_eq!(cache.size, 0);
+    }
+}
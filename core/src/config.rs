@@ -0,0 +1,211 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use crate::{
+    data::{TorrentInfo, DOWNLOAD_DIR, TORRENTS_DIR},
+    dht::AnnouncePort,
+    extensions::ExtensionRegistry,
+    external_ip::ExternalIp,
+    helpers::{self, PortRange},
+    krpc,
+    limit::Limits,
+    mse::Encryption,
+    peer_id::PeerIdConfig,
+    proxy::Proxy,
+    stats::Rates,
+    trace::Inspector,
+    tracker_session::{AnnounceInterval, IntervalBounds},
+};
+
+// what everyone requests and nearly every client expects, larger blocks get rejected
+pub const BLOCK_SIZE: usize = 1 << 14;
+
+// timeouts, retry counts and buffer sizes, the defaults assume a reasonably fast link and are worth
+// raising on high-latency ones
+#[derive(Debug, Clone)]
+pub struct Tunables {
+    pub peer_connect_timeout: Duration,
+    // head start a peer address gets before the next one is dialed as well
+    pub connect_stagger: Duration,
+    pub keep_alive: Duration,
+    // Have messages that arrive this close together are merged into a single bitfield
+    pub have_batch: Duration,
+    pub tracker_connect_timeout: Duration,
+    pub udp_timeout: Duration,
+    pub tracker_retries: u8,
+    // how long a DHT node gets to answer, and how often it's asked again before the query fails
+    pub dht_timeout: Duration,
+    pub dht_retries: u8,
+    pub channel_capacity: usize,
+    pub read_buffer: usize,
+}
+
+impl Default for Tunables {
+    fn default() -> Self {
+        Self {
+            peer_connect_timeout: Duration::from_secs(3),
+            connect_stagger: Duration::from_millis(250),
+            keep_alive: Duration::from_secs(120),
+            have_batch: Duration::from_secs(3),
+            tracker_connect_timeout: Duration::from_secs(5),
+            udp_timeout: Duration::from_secs(3),
+            tracker_retries: 4,
+            dht_timeout: krpc::QUERY_TIMEOUT,
+            dht_retries: krpc::QUERY_RETRIES,
+            channel_capacity: 100,
+            read_buffer: 1024,
+        }
+    }
+}
+
+// settings that used to be process-wide statics, a session gets started from one of these
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub block_size: usize,
+    pub port: PortRange,
+    pub peer_id: PeerIdConfig,
+    // skip Have messages for pieces the peer already has, some swarms count them for accounting
+    pub suppress_have: bool,
+    pub tunables: Tunables,
+    pub announce_intervals: Vec<AnnounceInterval>,
+    // peers we upload to at the same time, every other one stays choked
+    pub upload_slots: usize,
+    // whether peer connections get obfuscated with Message Stream Encryption
+    pub encryption: Encryption,
+    // bytes per second over all torrents, 0 for no limit
+    pub upload_limit: u64,
+    pub download_limit: u64,
+    // where torrents that weren't given a directory of their own get downloaded to
+    pub download_dir: PathBuf,
+    // .torrent files that were fetched from a URL are kept here
+    pub torrents_dir: PathBuf,
+    // connections per torrent, peers beyond that get turned away until one of them leaves
+    pub max_peers: usize,
+    pub dht: bool,
+    // announce torrents to the local network and listen for the peers there, BEP 14
+    pub lsd: bool,
+    // ask the router to forward the listen port, over UPnP and NAT-PMP
+    pub port_mapping: bool,
+    pub utp: bool,
+    // trackers, web seeds and the peers we connect to are reached through this
+    pub proxy: Option<Proxy>,
+}
+
+impl Config {
+    // bounds for whichever tracker the announce URL points at, unbounded if it isn't listed
+    pub fn announce_bounds(&self, url: &str) -> IntervalBounds {
+        let Some(host) = url::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_lowercase))
+        else {
+            return IntervalBounds::default();
+        };
+
+        self.announce_intervals
+            .iter()
+            .find(|interval| interval.host == host)
+            .map(|interval| interval.bounds)
+            .unwrap_or_default()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            block_size: BLOCK_SIZE,
+            port: PortRange {
+                low: 1317,
+                high: 1317,
+            },
+            peer_id: PeerIdConfig::default(),
+            suppress_have: true,
+            tunables: Tunables::default(),
+            announce_intervals: Vec::new(),
+            upload_slots: 4,
+            encryption: Encryption::default(),
+            upload_limit: 0,
+            download_limit: 0,
+            download_dir: PathBuf::from(DOWNLOAD_DIR),
+            torrents_dir: PathBuf::from(TORRENTS_DIR),
+            max_peers: 50,
+            dht: true,
+            lsd: true,
+            port_mapping: true,
+            utp: true,
+            proxy: None,
+        }
+    }
+}
+
+// everything shared by the subsystems of a single session, several of them can live side by side
+// in one process
+#[derive(Clone)]
+pub struct Context {
+    pub config: Arc<Config>,
+    pub peer_id: [u8; 20],
+    // the port we actually got, which isn't necessarily the one that was asked for
+    pub port: u16,
+    pub extensions: Arc<ExtensionRegistry>,
+    pub external_ip: Arc<ExternalIp>,
+    // sees every peer wire message of the session when set
+    pub inspector: Option<Inspector>,
+    pub rates: Arc<Rates>,
+    // shared by every connection of the session
+    pub limits: Limits,
+}
+
+impl Context {
+    pub fn new(config: Config, port: u16, extensions: ExtensionRegistry) -> Self {
+        Self {
+            peer_id: config.peer_id.generate(),
+            limits: Limits::new(config.upload_limit, config.download_limit),
+            config: Arc::new(config),
+            port,
+            extensions: Arc::new(extensions),
+            external_ip: Arc::new(ExternalIp::default()),
+            inspector: None,
+            rates: Arc::default(),
+        }
+    }
+
+    pub fn set_inspector(&mut self, inspector: Inspector) {
+        self.inspector = Some(inspector);
+    }
+
+    // uTP connections only ever come in on our UDP port
+    pub fn announce_port(&self, utp: bool) -> AnnouncePort {
+        AnnouncePort::new(self.port, utp, self.external_ip.get(), helpers::local_ip())
+    }
+
+    // private trackers may insist on a different id for every torrent
+    pub fn peer_id(&self, info: &TorrentInfo) -> [u8; 20] {
+        let private = info.info.as_ref().and_then(|info| info.private).is_some();
+
+        match self.config.peer_id.per_torrent || private {
+            true => self.config.peer_id.generate(),
+            false => self.peer_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Info;
+
+    #[test]
+    fn test_context_peer_id() {
+        let ctx = Context::new(Config::default(), 6881, ExtensionRegistry::default());
+        let mut info = TorrentInfo::default();
+        assert_eq!(ctx.peer_id(&info), ctx.peer_id);
+
+        info.info = Some(Info {
+            private: Some(()),
+            ..Default::default()
+        });
+        assert_ne!(ctx.peer_id(&info), ctx.peer_id);
+
+        // two sessions in one process don't share anything
+        let other = Context::new(Config::default(), 6882, ExtensionRegistry::default());
+        assert_ne!(other.peer_id, ctx.peer_id);
+    }
+}
@@ -0,0 +1,508 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::Report;
+use thiserror::Error;
+use tracing::debug;
+use url::Url;
+
+use crate::{helpers, torrent::State, udp::Response};
+
+pub type SocketResponse = (Response, SocketAddr);
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum Event {
+    #[default]
+    None = 0,
+    Completed,
+    Started,
+    Stopped,
+}
+
+#[derive(Error, Debug)]
+pub enum GeneralError {
+    #[error("usage: everlasting [torrent file | magnet link]")]
+    Usage,
+    #[error("first bucket must contain our own node")]
+    UninitializedNode,
+    #[error("no DHT nodes to ask")]
+    EmptyTable,
+    #[error("piece was already flushed or incomplete")]
+    AlreadyFlushed,
+    #[error("info metadata not fetched yet")]
+    MissingInfo,
+    #[error("file does not exist")]
+    NonExistentFile,
+    #[error("byte range lies outside of the file")]
+    InvalidRange,
+    #[error("oops")]
+    InvalidPieceIdx,
+    #[error("oops")]
+    InvalidPieceHash,
+    #[error("magnet link contains no valid trackers: `{0:?}`")]
+    InvalidUdpTracker(String),
+    #[error("magnet link is invalid: `{0:?}`")]
+    InvalidMagnet(String),
+    #[error("no active trackers for this torrent")]
+    DeadUdpTrackers,
+    #[error("timeout from remote address: {0:?}")]
+    Timeout(Option<SocketAddr>),
+    #[error("reconnect")]
+    Reconnect,
+    #[error("unexpected response: {0}")]
+    UnexpectedResponse(String),
+    #[error("failed to parse URL: {0}")]
+    ParseFailure(String),
+    #[error("tracker does not support scraping: {0}")]
+    NotScrapable(String),
+    #[error("tracker refused the scrape: {0}")]
+    ScrapeFailure(String),
+    #[error("malformed packet: {0}")]
+    MalformedPacket(String),
+    #[error("invalid or unavailable port: {0}")]
+    InvalidPort(String),
+    #[error("invalid announce interval, expected HOST=MIN:MAX: {0}")]
+    InvalidInterval(String),
+    #[error("invalid settings: {0}")]
+    InvalidSettings(String),
+    #[error("invalid proxy, expected socks5:// or http:// with a host: {0}")]
+    InvalidProxy(String),
+    #[error("invalid piece strategy, expected sequential or rarest-first[:N]: {0}")]
+    InvalidStrategy(String),
+    #[error("invalid file priority, expected skip, low, normal or high: {0}")]
+    InvalidPriority(String),
+    #[error("unknown extended message id: {0}")]
+    UnknownExtension(u8),
+    #[error("broken pipe")]
+    BrokenPipe,
+    #[error("connection reset by {0}")]
+    ConnectionReset(SocketAddr),
+    #[error("disk write failed: {0}")]
+    DiskIo(String),
+    #[error("encryption handshake failed: {0}")]
+    Encryption(String),
+    #[error("corrupt database record")]
+    CorruptRecord,
+    #[error("torrent was added already: {0}")]
+    DuplicateTorrent(String),
+    #[error("no such torrent: {0}")]
+    UnknownTorrent(String),
+    #[error("invalid info hash, expected 40 hex digits: {0}")]
+    InvalidInfoHash(String),
+    #[error("daemon refused the request: {0}")]
+    RpcFailure(String),
+    #[error("invalid state transition: {0:?} -> {1:?}")]
+    InvalidTransition(State, State),
+    #[error("router refused the port mapping with UPnP error {0}")]
+    UpnpFault(u16),
+    #[error("failed to fetch the torrent: {0}")]
+    FetchFailed(String),
+}
+
+pub const PROTOCOL_ID: i64 = 0x41727101980;
+pub const SHA1_LEN: usize = 20;
+pub const DOWNLOAD_DIR: &str = "./downloads";
+pub const TORRENTS_DIR: &str = "./torrents";
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Tracker {
+    Http(String),
+    Udp(SocketAddr),
+}
+
+impl Tracker {
+    // UDP trackers get resolved right away, None for schemes we don't speak
+    pub fn parse(s: &str) -> Result<Option<Self>, Report> {
+        if s.starts_with("http") {
+            return Ok(Some(Tracker::Http(s.to_owned())));
+        }
+        if !s.starts_with("udp") {
+            return Ok(None);
+        }
+
+        let url = Url::parse(s)?;
+        if url.host().zip(url.port()).is_none() {
+            return Err(GeneralError::InvalidUdpTracker(s.to_owned()).into());
+        }
+
+        // hosts like [2001:db8::1] keep their brackets in the url, this takes care of them
+        let addr = url
+            .socket_addrs(|| None)?
+            .into_iter()
+            .next()
+            .ok_or(GeneralError::InvalidUdpTracker(s.to_owned()))?;
+
+        Ok(Some(Tracker::Udp(addr)))
+    }
+}
+
+// the URL a tracker goes by in a torrent file, UDP trackers only kept the address they resolved to
+impl fmt::Display for Tracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tracker::Http(url) => write!(f, "{url}"),
+            Tracker::Udp(addr) => write!(f, "udp://{addr}"),
+        }
+    }
+}
+
+#[derive(Default, Debug, PartialEq, Clone)]
+pub struct Announce {
+    // BEP 12: a tier only gets asked once every tracker of the tiers before it failed
+    pub tiers: Vec<Vec<Tracker>>,
+    pub peers: Vec<SocketAddr>,
+}
+
+impl Announce {
+    // a tier of its own, after the ones we know already
+    pub fn push(&mut self, s: String) -> Result<(), Report> {
+        if let Some(tracker) = Tracker::parse(&s)? {
+            self.tiers.push(vec![tracker]);
+        }
+
+        Ok(())
+    }
+
+    // trackers that can't be resolved leave the rest of the tier
+    pub fn push_tier(&mut self, tier: Vec<String>) {
+        let tier: Vec<_> = tier
+            .iter()
+            .filter_map(|s| match Tracker::parse(s) {
+                Ok(tracker) => tracker,
+                Err(e) => {
+                    debug!("skipping tracker {s}: {e}");
+                    None
+                }
+            })
+            .collect();
+
+        if !tier.is_empty() {
+            self.tiers.push(tier);
+        }
+    }
+
+    pub fn trackers(&self) -> impl Iterator<Item = &Tracker> {
+        self.tiers.iter().flatten()
+    }
+
+    pub fn http(&self) -> Vec<String> {
+        self.trackers()
+            .filter_map(|tracker| match tracker {
+                Tracker::Http(url) => Some(url.clone()),
+                Tracker::Udp(_) => None,
+            })
+            .collect()
+    }
+
+    pub fn udp(&self) -> Vec<SocketAddr> {
+        self.trackers()
+            .filter_map(|tracker| match tracker {
+                Tracker::Udp(addr) => Some(*addr),
+                Tracker::Http(_) => None,
+            })
+            .collect()
+    }
+}
+
+#[derive(Default, Debug, PartialEq, Clone)]
+pub struct TorrentInfo {
+    pub info: Option<Info>,
+    // no need for Vec<Vec<T>> as torrents rarely have only one tracker
+    pub announce: Announce,
+    pub created: Option<u64>,
+    pub comment: String,
+    pub author: Option<String>,
+    pub hash: [u8; 20],
+    // DHT nodes close to the torrent, trackerless torrents list them instead of trackers
+    pub nodes: Vec<(String, u16)>,
+    // HTTP servers that have the torrent's files (BEP 19), pieces get fetched from them as well
+    pub webseeds: Vec<String>,
+}
+
+impl TorrentInfo {
+    pub fn length(&self) -> usize {
+        match self.info.as_ref().map(|info| info.mode.clone()) {
+            Some(Mode::Single { length, .. }) => length.to_owned() as usize,
+            Some(Mode::Multi { files, .. }) => files.iter().map(|f| f.length as usize).sum(),
+            None => 0usize,
+        }
+    }
+
+    pub fn bitfield_size(&self) -> usize {
+        let len = self.length();
+        let piece_len = self
+            .info
+            .as_ref()
+            .map(|info| info.piece_length.clone() as usize)
+            .unwrap();
+
+        len / piece_len / 64 + 1
+    }
+}
+
+impl TorrentInfo {
+    // the info dictionary is missing, peers found through the trackers in the link, the DHT and
+    // the peers in the link itself send it to us
+    pub fn from_magnet(s: &str) -> Result<Self, Report> {
+        Url::parse(s)?.try_into()
+    }
+}
+
+impl TryFrom<Url> for TorrentInfo {
+    type Error = Report;
+
+    fn try_from(url: Url) -> Result<Self, Self::Error> {
+        if url.scheme() != "magnet" {
+            return Err(GeneralError::InvalidMagnet(url.to_string()).into());
+        }
+
+        let pairs = url.query_pairs().into_owned();
+        let mut info = TorrentInfo::default();
+        let mut hash = None;
+
+        // v1: magnet:?xt=urn:btih:<info-hash>&dn=<name>&tr=<tracker-url>&x.pe=<peer-address>
+        // v2: magnet:?xt=urn:btmh:<tagged-info-hash>&dn=<name>&tr=<tracker-url>&x.pe=<peer-address>
+
+        for pair in pairs {
+            match (pair.0.as_str(), pair.1) {
+                // hybrid links carry both, only the v1 hash means anything to us
+                ("xt", s) => match s.strip_prefix("urn:btih:") {
+                    Some(v) => {
+                        let v = helpers::decode_hash(v);
+                        hash = Some(v.ok_or(GeneralError::InvalidMagnet(s))?);
+                    }
+                    None if s.starts_with("urn:btmh:") => {}
+                    None => return Err(GeneralError::InvalidMagnet(s).into()),
+                },
+                // trackers that can't be resolved leave the others, the DHT is still there
+                ("tr", s) => {
+                    if let Err(e) = info.announce.push(s.clone()) {
+                        debug!("skipping tracker {s}: {e}");
+                    }
+                }
+                ("dn", s) => {
+                    info.comment = s;
+                }
+                ("ws", s) => {
+                    info.webseeds.push(s);
+                }
+                ("x.pe", s) => {
+                    let s = s
+                        .to_socket_addrs()?
+                        .next()
+                        .ok_or(GeneralError::InvalidUdpTracker(s))?;
+                    info.announce.peers.push(s.to_owned());
+                }
+                _ => {}
+            }
+        }
+
+        info.hash = hash.ok_or_else(|| GeneralError::InvalidMagnet(url.to_string()))?;
+        Ok(info)
+    }
+}
+
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct Info {
+    pub mode: Mode,
+    pub piece_length: u64,
+    pub pieces: Box<[[u8; SHA1_LEN]]>,
+    pub private: Option<()>,
+    pub value: [u8; 20],
+    // keys we don't know, kept so the dictionary encodes to the same bytes and the same info hash
+    pub extra: BTreeMap<Vec<u8>, Value>,
+}
+
+// any bencoded value, for keys nobody here reads
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mode {
+    Single {
+        name: String,
+        length: u64,
+        md5sum: Option<Box<[u8]>>,
+    },
+    Multi {
+        dir_name: String,
+        files: Vec<File>,
+        md5sum: Option<Box<[u8]>>,
+    },
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Mode::Single {
+            length: 0,
+            name: Default::default(),
+            md5sum: None,
+        }
+    }
+}
+
+impl Mode {
+    pub fn name(&self) -> String {
+        match self {
+            Mode::Single { name, .. } => name.to_owned(),
+            Mode::Multi { dir_name, .. } => dir_name.to_owned(),
+        }
+    }
+
+    // on-disk path and expected length of every file, in torrent order
+    pub fn files(&self, root: &Path) -> Vec<(PathBuf, u64)> {
+        match self {
+            Mode::Single { name, length, .. } => vec![(root.join(name), *length)],
+            Mode::Multi {
+                dir_name, files, ..
+            } => files
+                .iter()
+                .map(|file| {
+                    let path = file
+                        .path
+                        .iter()
+                        .fold(root.join(dir_name), |path, acc| path.join(acc));
+                    (path, file.length)
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct File {
+    pub length: u64,
+    pub md5sum: Option<Box<[u8]>>,
+    pub path: Vec<String>,
+    pub extra: BTreeMap<Vec<u8>, Value>,
+}
+
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub struct Peer {
+    pub id: Option<[u8; 20]>,
+    pub addr: SocketAddr,
+}
+
+pub type Peers = Vec<Peer>;
+
+impl From<&SocketAddr> for Peer {
+    fn from(addr: &SocketAddr) -> Self {
+        Peer {
+            id: None,
+            addr: *addr,
+        }
+    }
+}
+
+#[derive(Default, Debug, PartialEq)]
+pub struct HttpResponse {
+    pub failure_reason: Option<String>,
+    pub warning: Option<String>,
+    pub interval: u64,
+    pub min_interval: Option<u64>,
+    pub tracker_id: Option<String>,
+    // seeders and leechers, not every tracker bothers to send them
+    pub complete: Option<u64>,
+    pub incomplete: Option<u64>,
+    pub peers: Vec<Peer>,
+    // how the tracker sees us, see BEP 24
+    pub external_ip: Option<IpAddr>,
+}
+
+#[derive(Default, Debug)]
+pub struct ScrapeResponse {
+    pub files: HashMap<[u8; 20], Status>,
+    pub failure_reason: Option<String>,
+}
+
+impl ScrapeResponse {
+    pub fn new() -> Self {
+        Self {
+            files: HashMap::new(),
+            failure_reason: None,
+        }
+    }
+
+    pub fn get(&self, hash: &[u8; 20]) -> Option<&Status> {
+        self.files.get(hash)
+    }
+
+    // a multi-hash scrape only answers for the torrents the tracker knows about
+    pub fn matching<'a, T>(
+        &'a self,
+        torrents: impl IntoIterator<Item = ([u8; 20], T)> + 'a,
+    ) -> impl Iterator<Item = (T, &'a Status)> + 'a {
+        torrents
+            .into_iter()
+            .filter_map(|(hash, t)| Some((t, self.files.get(&hash)?)))
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Status {
+    pub seeders: u32,
+    pub finished: u32,
+    pub leechers: u32,
+    pub name: Option<String>,
+}
+
+pub struct HttpRequest {
+    hash: [u8; SHA1_LEN],
+    id: [u8; SHA1_LEN],
+    port: u16,
+    uploaded: u64,
+    downloaded: u64,
+    compact: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_magnet() -> Result<(), Report> {
+        let info = TorrentInfo::from_magnet(
+            "magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK&dn=debian.iso\
+             &tr=http%3A%2F%2Ftracker.example%2Fannounce&tr=udp%3A%2F%2F127.0.0.1%3A6969\
+             &x.pe=10.0.0.1%3A6881&ws=http%3A%2F%2Fmirror.example%2Fdebian.iso",
+        )?;
+        assert_eq!(
+            hex::encode(info.hash),
+            "c12fe1c06bba254a9dc9f519b335aa7c1367a88a"
+        );
+        assert!(info.info.is_none());
+        assert_eq!(info.comment, "debian.iso");
+        assert_eq!(
+            info.announce.tiers,
+            vec![
+                vec![Tracker::Http("http://tracker.example/announce".to_owned())],
+                vec![Tracker::Udp("127.0.0.1:6969".parse()?)],
+            ]
+        );
+        assert_eq!(info.announce.peers, vec!["10.0.0.1:6881".parse()?]);
+        assert_eq!(info.webseeds, vec!["http://mirror.example/debian.iso"]);
+
+        // hybrid links name the v2 hash as well
+        let info = TorrentInfo::from_magnet(
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a\
+             &xt=urn:btmh:1220caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e",
+        )?;
+        assert_eq!(info.hash[0], 0xc1);
+
+        assert!(TorrentInfo::from_magnet("magnet:?dn=nothing").is_err());
+        assert!(TorrentInfo::from_magnet("magnet:?xt=urn:btih:c12fe1").is_err());
+        assert!(TorrentInfo::from_magnet("magnet:?xt=urn:sha1:c12fe1").is_err());
+        assert!(TorrentInfo::from_magnet("https://example.com/a.torrent").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+use std::{io::ErrorKind, net::SocketAddr, net::UdpSocket as StdSocket, sync::Arc};
+
+use bytes::Bytes;
+use color_eyre::Report;
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{channel, error::TrySendError, Receiver, Sender},
+};
+use tracing::debug;
+
+use crate::helpers;
+
+pub type Datagram = (Bytes, SocketAddr);
+
+// what a datagram on our UDP port is meant for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    // KRPC messages are bencoded dictionaries
+    Dht,
+    // BEP 15 responses start with a 32-bit action, 0 through 3
+    Tracker,
+    // BEP 29 packets start with their type, 0 through 4, and version 1
+    Utp,
+    Unknown,
+}
+
+impl Kind {
+    pub fn classify(datagram: &[u8]) -> Self {
+        match datagram {
+            [b'd', .., b'e'] => Kind::Dht,
+            [0, 0, 0, 0..=3, _, _, _, _, ..] => Kind::Tracker,
+            [0x01 | 0x11 | 0x21 | 0x31 | 0x41, ..] if datagram.len() >= 20 => Kind::Utp,
+            _ => Kind::Unknown,
+        }
+    }
+}
+
+// trackers and the DHT share a single socket, so only one UDP port needs forwarding
+pub struct Demux {
+    socket: Arc<UdpSocket>,
+    tracker_tx: Sender<Datagram>,
+    dht_tx: Sender<Datagram>,
+    utp_tx: Option<Sender<Datagram>>,
+}
+
+impl Demux {
+    pub fn new(
+        socket: StdSocket,
+    ) -> Result<(Self, Receiver<Datagram>, Receiver<Datagram>), Report> {
+        socket.set_nonblocking(true)?;
+        let socket = Arc::new(UdpSocket::from_std(socket)?);
+
+        let (tracker_tx, tracker_rx) = channel(100);
+        let (dht_tx, dht_rx) = channel(100);
+
+        let demux = Self {
+            socket,
+            tracker_tx,
+            dht_tx,
+            utp_tx: None,
+        };
+
+        Ok((demux, tracker_rx, dht_rx))
+    }
+
+    // uTP packets get dropped unless somebody wants them
+    pub fn set_utp(&mut self, utp_tx: Sender<Datagram>) {
+        self.utp_tx = Some(utp_tx);
+    }
+
+    // replies go out through the same socket, that's what the other side expects
+    pub fn socket(&self) -> Arc<UdpSocket> {
+        self.socket.clone()
+    }
+
+    pub async fn run(self) -> Result<(), Report> {
+        let mut buf = [0u8; 1 << 16];
+
+        loop {
+            let (n, addr) = match self.socket.recv_from(&mut buf).await {
+                Ok(x) => x,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                // ICMP port unreachable shows up as a reset on some platforms, it's not ours
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let datagram = Bytes::copy_from_slice(&buf[..n]);
+            let addr = helpers::canonical(addr);
+
+            let tx = match Kind::classify(&datagram) {
+                Kind::Dht => &self.dht_tx,
+                Kind::Tracker => &self.tracker_tx,
+                Kind::Utp => match &self.utp_tx {
+                    Some(tx) => tx,
+                    None => continue,
+                },
+                Kind::Unknown => {
+                    debug!("dropping unknown datagram from [{addr}]");
+                    continue;
+                }
+            };
+
+            // a subsystem that can't keep up loses datagrams, UDP never promised anything else
+            match tx.try_send((datagram, addr)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => debug!("dropping datagram from [{addr}], queue full"),
+                Err(TrySendError::Closed(_)) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(
+            Kind::classify(b"d1:rd2:id20:aaaaaaaaaaaaaaaaaaaae1:t2:aa1:y1:re"),
+            Kind::Dht
+        );
+        // connect response: action 0, transaction id, connection id
+        assert_eq!(
+            Kind::classify(&[0, 0, 0, 0, 1, 2, 3, 4, 9, 9, 9, 9, 9, 9, 9, 9]),
+            Kind::Tracker
+        );
+        assert_eq!(
+            Kind::classify(&[0, 0, 0, 3, 1, 2, 3, 4, b'n', b'o']),
+            Kind::Tracker
+        );
+        // a SYN, version 1
+        let mut syn = [0u8; 20];
+        syn[0] = 0x41;
+        assert_eq!(Kind::classify(&syn), Kind::Utp);
+        assert_eq!(Kind::classify(&syn[..19]), Kind::Unknown);
+        assert_eq!(Kind::classify(&[0, 0, 0, 4, 1, 2, 3, 4]), Kind::Unknown);
+        assert_eq!(Kind::classify(&[0, 0, 0, 1]), Kind::Unknown);
+        assert_eq!(Kind::classify(b"d1:a"), Kind::Unknown);
+        assert_eq!(Kind::classify(&[]), Kind::Unknown);
+    }
+}
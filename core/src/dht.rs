@@ -0,0 +1,1660 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ahash::HashSet;
+use bendy::{
+    decoding::{self, FromBencode, Object},
+    encoding::{self, AsString, SingleItemEncoder, ToBencode},
+};
+use chrono::{DateTime, Utc};
+use color_eyre::Report;
+use crypto::{hmac::Hmac, mac::Mac, sha1::Sha1};
+use tokio::{
+    net::UdpSocket,
+    sync::{
+        mpsc::{self, Receiver},
+        oneshot, watch, Mutex,
+    },
+    time::{interval, interval_at},
+};
+use tracing::debug;
+
+use crate::{
+    data::{GeneralError, Peer, Peers},
+    demux::Datagram,
+    krpc::{
+        self, Answer, Arguments, CompactNode, ErrorKind, ExtMessage, KrpcClient, Method, Values,
+    },
+    shutdown::Signal,
+    sqlite::{Database, DhtNode},
+};
+
+const CAPACITY: usize = 8;
+// nodes rotate the secret behind their tokens every few minutes and accept the previous one, BEP 5
+// suggests tokens stay good for 10 minutes
+const TOKEN_VALIDITY: Duration = Duration::from_secs(10 * 60);
+// and ours do the same, a token is good for one to two of these
+const SECRET_ROTATION: Duration = Duration::from_secs(5 * 60);
+// how long an announce keeps a peer in our store, nodes forget peers that stop re-announcing
+const PEER_EXPIRY: Duration = Duration::from_secs(30 * 60);
+// what we keep for anyone who asks, a few hundred bytes of memory per peer and torrent at most
+const MAX_STORED_PEERS: usize = 100;
+const MAX_STORED_TORRENTS: usize = 1000;
+// peers per get_peers answer, any more and it won't fit a datagram
+const MAX_VALUES: usize = 50;
+// a lookup that finished this recently is handed out again instead of starting another one
+const LOOKUP_CACHE: Duration = Duration::from_secs(60);
+pub const REANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Default, Clone, Copy, Debug)]
+pub struct Node {
+    pub id: [u8; 20],
+    pub addr: Option<SocketAddr>,
+    // what the node said it's running, handy when some implementation turns out to misbehave
+    pub version: Option<[u8; 4]>,
+}
+
+impl Node {
+    fn new(id: [u8; 20], addr: SocketAddr) -> Self {
+        Node {
+            id,
+            addr: Some(addr),
+            version: None,
+        }
+    }
+
+    pub fn from_message(addr: SocketAddr, message: &ExtMessage) -> Option<Self> {
+        let mut node = Node::new(message.sender()?, addr);
+        node.version = message.version.as_deref().and_then(|v| v.try_into().ok());
+
+        Some(node)
+    }
+
+    // XOR metric, compares like a big-endian number
+    fn distance(&self, other: &[u8; 20]) -> [u8; 20] {
+        std::array::from_fn(|i| self.id[i] ^ other[i])
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug)]
+pub struct Bucket {
+    nodes: [Option<Node>; 8],
+    len: usize,
+    last_changed: chrono::DateTime<chrono::Utc>,
+}
+
+impl Bucket {
+    fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.nodes[..self.len].iter().flatten()
+    }
+
+    // a full bucket keeps the nodes it has, the ones that stuck around so far are likely to stay
+    fn insert(&mut self, node: Node) {
+        let known = self.nodes[..self.len]
+            .iter_mut()
+            .flatten()
+            .find(|known| known.id == node.id);
+
+        match known {
+            Some(known) => *known = node,
+            None if self.len < CAPACITY => {
+                self.nodes[self.len] = Some(node);
+                self.len += 1;
+            }
+            None => return,
+        }
+        self.last_changed = Utc::now();
+    }
+
+    fn remove(&mut self, id: &[u8; 20]) {
+        let mut nodes = [None; CAPACITY];
+        let mut len = 0;
+
+        for node in self.nodes().filter(|node| node.id != *id) {
+            nodes[len] = Some(*node);
+            len += 1;
+        }
+        self.nodes = nodes;
+        self.len = len;
+    }
+}
+
+// boxed, it's large enough to overflow the stack of a task that moves it around
+pub struct Table {
+    inner: Box<[Option<Bucket>; 160]>,
+}
+
+impl Table {
+    fn id(&self) -> Option<&Node> {
+        let id = self.inner[0].as_ref()?;
+        id.nodes[0].as_ref()
+    }
+
+    pub fn new(id: Node) -> Self {
+        let mut inner: [Option<Bucket>; 160] = [None; 160];
+        let mut nodes = [None; 8];
+        nodes[0] = Some(id);
+
+        inner[0] = Some(Bucket {
+            nodes,
+            len: 1,
+            last_changed: Utc::now(),
+        });
+
+        Table {
+            inner: Box::new(inner),
+        }
+    }
+
+    // bucket i holds the nodes whose distance to us lies in [2^i, 2^(i+1)), None for ourselves
+    fn bucket(&self, node: &[u8; 20]) -> Result<Option<usize>, Report> {
+        let id = self.id().ok_or(GeneralError::UninitializedNode)?;
+
+        let distance = id.distance(node);
+        let zeros = distance
+            .iter()
+            .position(|&x| x != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize);
+
+        Ok(zeros.map(|zeros| 159 - zeros))
+    }
+
+    pub fn insert(&mut self, node: Node) -> Result<(), Report> {
+        let Some(n) = self.bucket(&node.id)? else {
+            return Ok(());
+        };
+
+        if let Some(bucket) = &mut self.inner[n] {
+            bucket.insert(node);
+        } else {
+            let mut nodes = [None; 8];
+            nodes[0] = Some(node);
+
+            self.inner[n] = Some(Bucket {
+                nodes,
+                len: 1,
+                last_changed: Utc::now(),
+            });
+        }
+
+        Ok(())
+    }
+
+    // everyone we know about, without ourselves
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.inner.iter().flatten().flat_map(Bucket::nodes).skip(1)
+    }
+
+    pub fn contains(&self, id: &[u8; 20]) -> bool {
+        self.nodes().any(|node| node.id == *id)
+    }
+
+    pub fn remove(&mut self, id: &[u8; 20]) {
+        if self.id().is_some_and(|ours| ours.id == *id) {
+            return;
+        }
+
+        for bucket in self.inner.iter_mut().flatten() {
+            bucket.remove(id);
+        }
+    }
+
+    // what find_node and get_peers answer with
+    pub fn closest(&self, target: &[u8; 20], n: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.nodes().copied().collect();
+        nodes.sort_by_key(|node| node.distance(target));
+        nodes.truncate(n);
+
+        nodes
+    }
+
+    // nodes in buckets that didn't change since `before`, BEP 5 has them pinged to find out whether
+    // they're still around
+    pub fn stale(&self, before: DateTime<Utc>) -> Vec<Node> {
+        let ours = self.id().map(|node| node.id);
+
+        self.inner
+            .iter()
+            .flatten()
+            .filter(|bucket| bucket.last_changed < before)
+            .flat_map(Bucket::nodes)
+            .filter(|node| Some(node.id) != ours)
+            .copied()
+            .collect()
+    }
+
+    // keeps the id the state was saved with, nodes that know us keep routing to us that way
+    pub fn from_state(state: &DhtState) -> Result<Self, Report> {
+        let mut table = Table::new(Node {
+            id: state.id.unwrap_or_else(rand::random),
+            ..Default::default()
+        });
+        for node in &state.nodes {
+            table.insert(*node)?;
+        }
+
+        Ok(table)
+    }
+
+    // what the last session left in the database, or a table with a fresh id
+    pub fn load(db: &Database) -> Result<Self, Report> {
+        let mut table = Table::new(Node {
+            id: db.dht_id()?.unwrap_or_else(rand::random),
+            ..Default::default()
+        });
+        table.restore(&db.dht_nodes()?)?;
+
+        Ok(table)
+    }
+
+    // the buckets keep the time they last changed, not the time they got restored
+    pub fn restore(&mut self, nodes: &[DhtNode]) -> Result<(), Report> {
+        let mut changed = HashMap::new();
+
+        for node in nodes {
+            self.insert(Node::new(node.id, node.addr))?;
+            if let Some(n) = self.bucket(&node.id)? {
+                let last = changed.entry(n).or_insert(node.changed);
+                *last = node.changed.max(*last);
+            }
+        }
+        for (n, last) in changed {
+            if let Some(bucket) = &mut self.inner[n] {
+                bucket.last_changed = last;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn saved(&self) -> Vec<DhtNode> {
+        self.inner
+            .iter()
+            .flatten()
+            .flat_map(|bucket| {
+                bucket.nodes().filter_map(|node| {
+                    Some(DhtNode {
+                        id: node.id,
+                        addr: node.addr?,
+                        changed: bucket.last_changed,
+                    })
+                })
+            })
+            .filter(|node| self.id().map(|ours| ours.id) != Some(node.id))
+            .collect()
+    }
+
+    pub fn state(&self) -> Result<DhtState, Report> {
+        let id = self.id().ok_or(GeneralError::UninitializedNode)?;
+
+        Ok(DhtState {
+            id: Some(id.id),
+            nodes: self.nodes().copied().collect(),
+        })
+    }
+}
+
+// the dht.dat other clients keep between sessions: our id and the nodes as compact node info,
+// "nodes" for IPv4 and "nodes6" for IPv6; libtorrent calls the id "node-id" and uTorrent adds a
+// few keys of its own that we don't need
+#[derive(Debug, Default, Clone)]
+pub struct DhtState {
+    pub id: Option<[u8; 20]>,
+    pub nodes: Vec<Node>,
+}
+
+impl DhtState {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Report> {
+        let bytes = std::fs::read(path)?;
+
+        DhtState::from_bencode(&bytes)
+            .map_err(|e| GeneralError::MalformedPacket(e.to_string()).into())
+    }
+
+    // written next to the target first, a crash halfway through shouldn't cost us the old table
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Report> {
+        let bytes = self
+            .to_bencode()
+            .map_err(|e| GeneralError::MalformedPacket(e.to_string()))?;
+
+        let tmp = path.as_ref().with_extension("tmp");
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(tmp, path)?;
+
+        Ok(())
+    }
+}
+
+// <20:id><4:ipv4><2:port> or <20:id><16:ipv6><2:port>
+fn compact_nodes(v: &[u8], ip_len: usize) -> impl Iterator<Item = Node> + '_ {
+    v.chunks_exact(20 + ip_len + 2).map(move |v| {
+        let ip = match ip_len {
+            4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&v[20..24]).unwrap())),
+            _ => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&v[20..36]).unwrap())),
+        };
+        let port = u16::from_be_bytes(v[20 + ip_len..].try_into().unwrap());
+
+        Node::new(v[..20].try_into().unwrap(), SocketAddr::new(ip, port))
+    })
+}
+
+impl FromBencode for DhtState {
+    fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        let mut dict = object.try_into_dictionary()?;
+        let mut state = DhtState::default();
+
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"id" | b"node-id", v) => {
+                    let AsString(id) = AsString::decode_bencode_object(v)?;
+                    state.id = id.as_slice().try_into().ok();
+                }
+                (b"nodes", v) => {
+                    let AsString(v) = AsString::decode_bencode_object(v)?;
+                    state.nodes.extend(compact_nodes(&v, 4));
+                }
+                (b"nodes6", v) => {
+                    let AsString(v) = AsString::decode_bencode_object(v)?;
+                    state.nodes.extend(compact_nodes(&v, 16));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(state)
+    }
+}
+
+impl ToBencode for DhtState {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), encoding::Error> {
+        let (mut nodes, mut nodes6) = (Vec::new(), Vec::new());
+
+        for node in &self.nodes {
+            match node.addr {
+                Some(SocketAddr::V4(addr)) => nodes.extend(
+                    [
+                        node.id.as_slice(),
+                        &addr.ip().octets(),
+                        &addr.port().to_be_bytes(),
+                    ]
+                    .concat(),
+                ),
+                Some(SocketAddr::V6(addr)) => nodes6.extend(
+                    [
+                        node.id.as_slice(),
+                        &addr.ip().octets(),
+                        &addr.port().to_be_bytes(),
+                    ]
+                    .concat(),
+                ),
+                None => {}
+            }
+        }
+
+        encoder.emit_dict(|mut e| {
+            if let Some(id) = &self.id {
+                e.emit_pair(b"id", AsString(id.as_slice()))?;
+            }
+            e.emit_pair(b"nodes", AsString(nodes))?;
+            e.emit_pair(b"nodes6", AsString(nodes6))
+        })
+    }
+}
+
+// what announce_peer tells nodes about our port, BEP 5 lets them take the source port of the
+// packet instead, which is the only one that's right behind a NAT remapping ports and the only one
+// there is when peers reach us over uTP
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnnouncePort {
+    pub port: u16,
+    pub implied: bool,
+}
+
+impl AnnouncePort {
+    pub fn new(listen: u16, utp: bool, external: Option<IpAddr>, local: Option<IpAddr>) -> Self {
+        let nat = match (external, local) {
+            (Some(external), Some(local)) => external != local,
+            // nobody told us yet, but private addresses don't get far on their own
+            (None, Some(IpAddr::V4(local))) => local.is_private(),
+            _ => false,
+        };
+
+        Self {
+            port: listen,
+            implied: utp || nat,
+        }
+    }
+}
+
+// outcome of a get_peers lookup, the tokens let us announce to the nodes that answered
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    pub peers: Vec<SocketAddr>,
+    pub tokens: Vec<(SocketAddr, String)>,
+    pub finished: Instant,
+}
+
+// the part of a DHT node that keeping torrents announced relies on
+pub trait PeerLookup {
+    fn get_peers(&self, hash: [u8; 20]) -> impl Future<Output = Result<Lookup, Report>> + Send;
+    fn announce_peer(
+        &self,
+        node: SocketAddr,
+        hash: [u8; 20],
+        token: String,
+    ) -> impl Future<Output = Result<(), Report>> + Send;
+}
+
+// keeps every active torrent announced, lookups that are already running or just finished get
+// shared between whoever asks for peers
+pub struct Announcer {
+    interval: Duration,
+    torrents: HashMap<[u8; 20], Option<Lookup>>,
+    running: HashMap<[u8; 20], watch::Receiver<Option<Lookup>>>,
+}
+
+impl Announcer {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            torrents: HashMap::new(),
+            running: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, hash: [u8; 20]) {
+        self.torrents.entry(hash).or_insert(None);
+    }
+
+    pub fn remove(&mut self, hash: &[u8; 20]) {
+        self.torrents.remove(hash);
+    }
+
+    // torrents that haven't been looked up within the interval
+    pub fn due(&self, now: Instant) -> Vec<[u8; 20]> {
+        self.torrents
+            .iter()
+            .filter(|(hash, lookup)| match lookup {
+                _ if self.running.contains_key(*hash) => false,
+                Some(lookup) => now.duration_since(lookup.finished) >= self.interval,
+                None => true,
+            })
+            .map(|(hash, _)| *hash)
+            .collect()
+    }
+
+    pub fn cached(&self, hash: &[u8; 20], now: Instant) -> Option<&Lookup> {
+        self.torrents
+            .get(hash)?
+            .as_ref()
+            .filter(|lookup| now.duration_since(lookup.finished) < LOOKUP_CACHE)
+    }
+
+    pub fn finished(&mut self, hash: [u8; 20], lookup: Lookup) {
+        self.running.remove(&hash);
+
+        // removed while the lookup was running
+        if let Some(last) = self.torrents.get_mut(&hash) {
+            *last = Some(lookup);
+        }
+    }
+
+    // nodes that still accept our tokens, anything older needs another get_peers first
+    pub fn tokens(&self, hash: &[u8; 20], now: Instant) -> Vec<(SocketAddr, String)> {
+        match self.torrents.get(hash) {
+            Some(Some(lookup)) if now.duration_since(lookup.finished) < TOKEN_VALIDITY => {
+                lookup.tokens.clone()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+pub async fn lookup<L: PeerLookup>(
+    announcer: &Mutex<Announcer>,
+    client: &L,
+    hash: [u8; 20],
+) -> Result<Lookup, Report> {
+    let mut guard = announcer.lock().await;
+
+    if let Some(lookup) = guard.cached(&hash, Instant::now()) {
+        return Ok(lookup.clone());
+    }
+
+    // somebody else is already on it
+    if let Some(rx) = guard.running.get(&hash) {
+        let mut rx = rx.clone();
+        drop(guard);
+
+        let lookup = rx.wait_for(Option::is_some).await?;
+        return Ok(lookup.clone().unwrap());
+    }
+
+    let (tx, rx) = watch::channel(None);
+    guard.running.insert(hash, rx);
+    drop(guard);
+
+    let result = client.get_peers(hash).await;
+    let mut guard = announcer.lock().await;
+
+    match result {
+        Ok(lookup) => {
+            guard.finished(hash, lookup.clone());
+            tx.send_replace(Some(lookup.clone()));
+
+            Ok(lookup)
+        }
+        // dropping the sender lets everyone waiting fail as well
+        Err(e) => {
+            guard.running.remove(&hash);
+            Err(e)
+        }
+    }
+}
+
+// looks up and re-announces whatever is due, for as long as the DHT is running
+pub async fn reannounce<L: PeerLookup>(announcer: Arc<Mutex<Announcer>>, client: L) {
+    let mut timer = interval(Duration::from_secs(60));
+
+    loop {
+        timer.tick().await;
+        let due = announcer.lock().await.due(Instant::now());
+
+        for hash in due {
+            if let Err(e) = lookup(&announcer, &client, hash).await {
+                debug!("DHT lookup for [{}] failed: {e}", hex::encode(hash));
+                continue;
+            }
+
+            let tokens = announcer.lock().await.tokens(&hash, Instant::now());
+            debug!(
+                "announcing [{}] to {} nodes",
+                hex::encode(hash),
+                tokens.len()
+            );
+
+            for (node, token) in tokens {
+                if let Err(e) = client.announce_peer(node, hash, token).await {
+                    debug!("announce to [{node}] failed: {e}");
+                }
+            }
+        }
+    }
+}
+
+// the tokens we hand out with get_peers answers: an HMAC of the node's IP, so only the node we
+// gave one to can announce with it, under a secret that changes every few minutes
+pub struct Tokens {
+    secret: [u8; 20],
+    previous: [u8; 20],
+    rotated: Instant,
+}
+
+impl Tokens {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            secret: rand::random(),
+            previous: rand::random(),
+            rotated: now,
+        }
+    }
+
+    pub fn rotate(&mut self, now: Instant) {
+        if now.duration_since(self.rotated) >= SECRET_ROTATION {
+            self.previous = std::mem::replace(&mut self.secret, rand::random());
+            self.rotated = now;
+        }
+    }
+
+    pub fn token(&self, ip: IpAddr) -> String {
+        sign(&self.secret, ip)
+    }
+
+    // anything we gave out under the last secret is still good
+    pub fn valid(&self, ip: IpAddr, token: &str) -> bool {
+        [&self.secret, &self.previous]
+            .into_iter()
+            .any(|secret| sign(secret, ip) == token)
+    }
+}
+
+fn sign(secret: &[u8; 20], ip: IpAddr) -> String {
+    let mut hmac = Hmac::new(Sha1::new(), secret);
+    match ip {
+        IpAddr::V4(ip) => hmac.input(&ip.octets()),
+        IpAddr::V6(ip) => hmac.input(&ip.octets()),
+    }
+
+    hex::encode(&hmac.result().code()[..8])
+}
+
+// peers that announced themselves to us, by torrent, until they stop re-announcing
+#[derive(Default)]
+pub struct PeerStore {
+    torrents: HashMap<[u8; 20], HashMap<SocketAddr, Instant>>,
+}
+
+impl PeerStore {
+    // a full torrent makes room by dropping whoever announced longest ago, once there are too
+    // many torrents new ones don't get in
+    pub fn insert(&mut self, hash: [u8; 20], peer: SocketAddr, now: Instant) {
+        if self.torrents.len() >= MAX_STORED_TORRENTS && !self.torrents.contains_key(&hash) {
+            return;
+        }
+
+        let peers = self.torrents.entry(hash).or_default();
+        if peers.len() >= MAX_STORED_PEERS && !peers.contains_key(&peer) {
+            let oldest = peers
+                .iter()
+                .min_by_key(|(_, at)| **at)
+                .map(|(addr, _)| *addr);
+            if let Some(oldest) = oldest {
+                peers.remove(&oldest);
+            }
+        }
+        peers.insert(peer, now);
+    }
+
+    pub fn peers(&self, hash: &[u8; 20], now: Instant) -> Vec<SocketAddr> {
+        let Some(peers) = self.torrents.get(hash) else {
+            return Vec::new();
+        };
+
+        peers
+            .iter()
+            .filter(|(_, at)| now.duration_since(**at) < PEER_EXPIRY)
+            .map(|(addr, _)| *addr)
+            .take(MAX_VALUES)
+            .collect()
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        for peers in self.torrents.values_mut() {
+            peers.retain(|_, at| now.duration_since(*at) < PEER_EXPIRY);
+        }
+        self.torrents.retain(|_, peers| !peers.is_empty());
+    }
+}
+
+// where every client starts from when it doesn't know a single node yet
+pub const ROUTERS: [(&str, u16); 3] = [
+    ("router.bittorrent.com", 6881),
+    ("router.utorrent.com", 6881),
+    ("dht.transmissionbt.com", 6881),
+];
+// BEP 5 considers a bucket nobody was heard from in this long questionable
+const BUCKET_REFRESH: Duration = Duration::from_secs(15 * 60);
+// how often buckets are checked for going quiet, the table gets saved and stored peers expire on
+// the same occasion
+const REFRESH_CHECK: Duration = Duration::from_secs(60);
+// the most nodes a single lookup asks
+const LOOKUP_QUERIES: usize = 32;
+// queries of a lookup that are out at once, Kademlia's α
+const ALPHA: usize = 3;
+// a round of a lookup doesn't wait any longer for its slowest node, whoever didn't answer by then
+// is out of the lookup
+const ROUND_TIMEOUT: Duration = Duration::from_secs(3);
+
+// what an iterative lookup found out
+#[derive(Debug, Default)]
+pub struct Found {
+    // every node that answered, closest to the target first
+    pub nodes: Vec<Node>,
+    pub peers: Vec<SocketAddr>,
+    // from the k closest of them, the ones an announce goes to
+    pub tokens: Vec<(SocketAddr, String)>,
+}
+
+struct Candidate {
+    node: Node,
+    // None until it's been asked
+    answered: Option<bool>,
+    token: Option<String>,
+}
+
+// Kademlia's iterative lookup: asks the α closest nodes that weren't asked yet and adds the nodes
+// they name, until the k closest it heard of all answered or failed
+pub async fn iterate(
+    client: &KrpcClient,
+    id: [u8; 20],
+    method: Method,
+    target: [u8; 20],
+    start: Vec<Node>,
+) -> Found {
+    let mut candidates: Vec<Candidate> = start
+        .into_iter()
+        .map(|node| Candidate {
+            node,
+            answered: None,
+            token: None,
+        })
+        .collect();
+    let mut peers = HashSet::default();
+    let mut asked = 0;
+
+    loop {
+        candidates.sort_by_key(|candidate| candidate.node.distance(&target));
+        let round: Vec<Node> = candidates
+            .iter()
+            .filter(|candidate| candidate.answered != Some(false))
+            .take(CAPACITY)
+            .filter(|candidate| candidate.answered.is_none())
+            .take(ALPHA.min(LOOKUP_QUERIES - asked))
+            .map(|candidate| candidate.node)
+            .collect();
+        if round.is_empty() {
+            break;
+        }
+        asked += round.len();
+
+        let queries = round.iter().map(|node| async move {
+            let args = Arguments {
+                method,
+                id,
+                target: (method == Method::FindNode).then_some(target),
+                info_hash: (method == Method::GetPeers).then_some(target),
+                ..Default::default()
+            };
+            let answer = tokio::time::timeout(ROUND_TIMEOUT, client.query(node.addr?, args));
+
+            answer.await.ok()?.ok()
+        });
+        let answers = futures::future::join_all(queries).await;
+
+        for (node, answer) in round.into_iter().zip(answers) {
+            let candidate = candidates
+                .iter_mut()
+                .find(|candidate| candidate.node.id == node.id)
+                .unwrap();
+            let Some(answer) = answer else {
+                candidate.answered = Some(false);
+                continue;
+            };
+
+            candidate.answered = Some(true);
+            candidate.node.version = answer.version.as_deref().and_then(|v| v.try_into().ok());
+            candidate.token = answer.values.token;
+            peers.extend(answer.values.values.unwrap_or_default());
+
+            for named in answer.values.nodes.unwrap_or_default() {
+                let known = candidates.iter().any(|known| known.node.id == named.id);
+                if named.id != id && !known {
+                    candidates.push(Candidate {
+                        node: Node::new(named.id, named.ip),
+                        answered: None,
+                        token: None,
+                    });
+                }
+            }
+        }
+    }
+
+    let answered: Vec<&Candidate> = candidates
+        .iter()
+        .filter(|candidate| candidate.answered == Some(true))
+        .collect();
+    let tokens = answered
+        .iter()
+        .take(CAPACITY)
+        .filter_map(|candidate| Some((candidate.node.addr?, candidate.token.clone()?)))
+        .collect();
+
+    Found {
+        nodes: answered.iter().map(|candidate| candidate.node).collect(),
+        peers: peers.into_iter().collect(),
+        tokens,
+    }
+}
+
+// what the rest of the client asks of the running node
+enum Command {
+    GetPeers([u8; 20], oneshot::Sender<Result<Lookup, Report>>),
+    FindNode([u8; 20], oneshot::Sender<Result<Vec<Node>, Report>>),
+    AnnouncePeer(
+        SocketAddr,
+        [u8; 20],
+        String,
+        oneshot::Sender<Result<(), Report>>,
+    ),
+}
+
+// why a query was sent, decides what happens with the answer
+enum Query {
+    // somebody we haven't heard from yet, answering gets it into the table
+    Ping,
+    // a node in a bucket that went quiet, it's dropped unless it answers
+    Refresh([u8; 20]),
+    // asks for the nodes close to us while bootstrapping
+    FindNode,
+    Announce(oneshot::Sender<Result<(), Report>>),
+}
+
+// a query that got answered, failed or timed out
+type Settled = (SocketAddr, Query, Result<Answer, Report>);
+
+// what a lookup that finished was for, lookups for peers are shared by everyone who asks
+enum Search {
+    Peers([u8; 20]),
+    Node(oneshot::Sender<Result<Vec<Node>, Report>>),
+}
+
+// a DHT node: answers the queries of other nodes, keeps the routing table fresh and looks up peers
+// for whoever holds a DhtHandle
+pub struct Dht {
+    id: [u8; 20],
+    table: Table,
+    socket: Arc<UdpSocket>,
+    datagrams: Receiver<Datagram>,
+    commands: mpsc::Receiver<Command>,
+    port: AnnouncePort,
+    routers: Vec<(String, u16)>,
+    tokens: Tokens,
+    peers: PeerStore,
+    client: KrpcClient,
+    // our queries come back here once they're settled
+    settled_tx: mpsc::Sender<Settled>,
+    settled: Receiver<Settled>,
+    // lookups come back here once they're done
+    found_tx: mpsc::Sender<(Search, Found)>,
+    found: Receiver<(Search, Found)>,
+    // whoever waits for a running get_peers lookup
+    lookups: HashMap<[u8; 20], Vec<oneshot::Sender<Result<Lookup, Report>>>>,
+    state: Option<PathBuf>,
+    db: Option<Database>,
+    shutdown: Signal,
+}
+
+// talks to the running node, cheap to clone
+#[derive(Clone)]
+pub struct DhtHandle(mpsc::Sender<Command>);
+
+impl Dht {
+    pub fn new(
+        table: Table,
+        socket: Arc<UdpSocket>,
+        datagrams: Receiver<Datagram>,
+        port: AnnouncePort,
+    ) -> Result<(Self, DhtHandle), Report> {
+        let id = table.id().ok_or(GeneralError::UninitializedNode)?.id;
+        let (tx, commands) = mpsc::channel(16);
+        let (settled_tx, settled) = mpsc::channel(64);
+        let (found_tx, found) = mpsc::channel(16);
+
+        let dht = Self {
+            id,
+            table,
+            socket: socket.clone(),
+            datagrams,
+            commands,
+            port,
+            routers: ROUTERS
+                .iter()
+                .map(|(host, port)| (host.to_string(), *port))
+                .collect(),
+            tokens: Tokens::new(Instant::now()),
+            peers: PeerStore::default(),
+            client: KrpcClient::new(socket.clone()),
+            settled_tx,
+            settled,
+            found_tx,
+            found,
+            lookups: HashMap::new(),
+            state: None,
+            db: None,
+            shutdown: Signal::default(),
+        };
+
+        Ok((dht, DhtHandle(tx)))
+    }
+
+    // the "nodes" of a trackerless torrent are hosts its author expected to be in the DHT
+    pub fn add_routers(&mut self, nodes: &[(String, u16)]) {
+        self.routers.extend_from_slice(nodes);
+    }
+
+    // the table gets written here every now and then
+    pub fn set_state(&mut self, path: PathBuf) {
+        self.state = Some(path);
+    }
+
+    // the table gets saved here as well, for the next session to start from
+    pub fn set_database(&mut self, db: Database) {
+        self.db = Some(db);
+    }
+
+    // how long nodes get to answer, and how often they're asked before they count as gone
+    pub fn set_query_timeout(&mut self, timeout: Duration, retries: u8) {
+        self.client.set_timeout(timeout);
+        self.client.set_retries(retries);
+    }
+
+    // the table gets written one last time once it fires
+    pub fn set_shutdown(&mut self, shutdown: Signal) {
+        self.shutdown = shutdown;
+    }
+
+    pub async fn run(mut self) -> Result<(), Report> {
+        self.bootstrap().await;
+
+        let mut refresh = interval_at(tokio::time::Instant::now() + REFRESH_CHECK, REFRESH_CHECK);
+
+        loop {
+            tokio::select! {
+                datagram = self.datagrams.recv() => {
+                    let Some((datagram, addr)) = datagram else {
+                        return Ok(());
+                    };
+                    if let Err(e) = self.received(&datagram, addr).await {
+                        debug!("[{addr}] dropped a KRPC message: {e}");
+                    }
+                }
+                Some(command) = self.commands.recv() => self.command(command),
+                Some((addr, query, answer)) = self.settled.recv() => {
+                    self.answer(addr, query, answer)?;
+                }
+                Some((search, found)) = self.found.recv() => self.found(search, found)?,
+                _ = refresh.tick() => self.refresh().await?,
+                _ = self.shutdown.recv() => return self.save(),
+            }
+        }
+    }
+
+    // asks the routers and whoever is left from the last session for the nodes close to us, the
+    // ones they name get pinged and are in once they answer
+    async fn bootstrap(&mut self) {
+        let local = match self.socket.local_addr() {
+            Ok(local) => local,
+            Err(e) => return debug!("DHT socket is gone: {e}"),
+        };
+
+        let mut addrs: Vec<SocketAddr> = self
+            .table
+            .closest(&self.id, CAPACITY)
+            .into_iter()
+            .filter_map(|node| node.addr)
+            .collect();
+        for (host, port) in self.routers.clone() {
+            match tokio::net::lookup_host((host.as_str(), port)).await {
+                // a name may resolve to both families, a dual-stack socket speaks both of them
+                Ok(resolved) => {
+                    addrs.extend(resolved.filter(|addr| local.is_ipv6() || addr.is_ipv4()))
+                }
+                Err(e) => debug!("failed to resolve DHT router [{host}:{port}]: {e}"),
+            }
+        }
+
+        debug!("bootstrapping the DHT from {} nodes", addrs.len());
+        for addr in addrs {
+            let args = Arguments {
+                method: Method::FindNode,
+                id: self.id,
+                target: Some(self.id),
+                ..Default::default()
+            };
+            self.query(addr, args, Query::FindNode);
+        }
+    }
+
+    async fn received(&mut self, datagram: &[u8], addr: SocketAddr) -> Result<(), Report> {
+        let message = ExtMessage::from_bencode(datagram)
+            .map_err(|e| GeneralError::MalformedPacket(e.to_string()))?;
+
+        if let krpc::Message::Query(args) = &message.inner {
+            // nodes that query us are as alive as the ones that answer
+            if let Some(node) = Node::from_message(addr, &message) {
+                self.table.insert(node)?;
+            }
+            let reply = message.reply(self.respond(args, addr));
+
+            return self.client.send(&reply, addr).await;
+        }
+
+        self.client.received(message, addr);
+
+        Ok(())
+    }
+
+    fn answer(
+        &mut self,
+        addr: SocketAddr,
+        query: Query,
+        answer: Result<Answer, Report>,
+    ) -> Result<(), Report> {
+        let answer = match answer {
+            Ok(answer) => {
+                let mut node = Node::new(answer.values.id, addr);
+                node.version = answer.version.as_deref().and_then(|v| v.try_into().ok());
+                self.table.insert(node)?;
+
+                Ok(answer.values)
+            }
+            Err(e) => Err(e),
+        };
+
+        for (addr, args, query) in self.answered(addr, query, answer) {
+            self.query(addr, args, query);
+        }
+
+        Ok(())
+    }
+
+    fn respond(&mut self, args: &Arguments, addr: SocketAddr) -> krpc::Message {
+        let mut values = Values {
+            id: self.id,
+            ..Default::default()
+        };
+
+        let target = match args.method {
+            Method::Ping => return krpc::Message::Response(values),
+            Method::AnnouncePeer => {
+                return match self.announced(args, addr) {
+                    Ok(()) => krpc::Message::Response(values),
+                    Err(e) => krpc::Message::Err(e),
+                };
+            }
+            Method::FindNode => args.target,
+            Method::GetPeers => {
+                values.token = Some(self.tokens.token(addr.ip()));
+                if let Some(hash) = &args.info_hash {
+                    let peers = self.peers.peers(hash, Instant::now());
+                    values.values = (!peers.is_empty()).then_some(peers);
+                }
+                args.info_hash
+            }
+        };
+        let Some(target) = target else {
+            return krpc::Message::Err(krpc::Error {
+                description: "missing target".to_owned(),
+                kind: ErrorKind::Protocol,
+            });
+        };
+
+        // IPv6 nodes go out as "nodes6", BEP 32
+        let nodes = self
+            .table
+            .closest(&target, CAPACITY)
+            .into_iter()
+            .filter_map(|node| {
+                Some(CompactNode {
+                    id: node.id,
+                    ip: node.addr?,
+                })
+            })
+            .collect();
+        values.nodes = Some(nodes);
+
+        krpc::Message::Response(values)
+    }
+
+    // keeps the peer for whoever asks about the torrent next, if the node got its token from us
+    fn announced(&mut self, args: &Arguments, addr: SocketAddr) -> Result<(), krpc::Error> {
+        let error = |description: &str| krpc::Error {
+            description: description.to_owned(),
+            kind: ErrorKind::Protocol,
+        };
+
+        let hash = args.info_hash.ok_or_else(|| error("missing info_hash"))?;
+        let token = args.token.as_deref().unwrap_or_default();
+        if !self.tokens.valid(addr.ip(), token) {
+            return Err(error("bad token"));
+        }
+        // the port the query came from, when the node is behind a NAT or speaks uTP
+        let port = match args.implied_port {
+            Some(true) => addr.port(),
+            _ => args.port.ok_or_else(|| error("missing port"))?,
+        };
+
+        self.peers
+            .insert(hash, SocketAddr::new(addr.ip(), port), Instant::now());
+
+        Ok(())
+    }
+
+    // settles a query that got answered, failed or timed out, returns the queries it leads to
+    fn answered(
+        &mut self,
+        addr: SocketAddr,
+        query: Query,
+        answer: Result<Values, Report>,
+    ) -> Vec<(SocketAddr, Arguments, Query)> {
+        let mut next = Vec::new();
+
+        match (query, answer) {
+            (Query::Refresh(id), Err(e)) => {
+                debug!("dropping DHT node [{addr}]: {e}");
+                self.table.remove(&id);
+            }
+            (Query::FindNode, Ok(values)) => {
+                for node in values.nodes.unwrap_or_default() {
+                    if !self.table.contains(&node.id) {
+                        let args = Arguments {
+                            method: Method::Ping,
+                            id: self.id,
+                            ..Default::default()
+                        };
+                        next.push((node.ip, args, Query::Ping));
+                    }
+                }
+            }
+            (Query::Announce(reply), answer) => {
+                let _ = reply.send(answer.map(|_| ()));
+            }
+            (Query::Ping | Query::Refresh(_) | Query::FindNode, _) => {}
+        }
+
+        next
+    }
+
+    // nodes that answered are as good as the ones that query us
+    fn found(&mut self, search: Search, found: Found) -> Result<(), Report> {
+        for node in &found.nodes {
+            self.table.insert(*node)?;
+        }
+
+        match search {
+            // a lookup nobody answered fails, so it doesn't get cached
+            Search::Peers(hash) => {
+                debug!(
+                    "DHT lookup for [{}] found {} peers",
+                    hex::encode(hash),
+                    found.peers.len()
+                );
+                for reply in self.lookups.remove(&hash).unwrap_or_default() {
+                    let lookup = match found.tokens.is_empty() && found.peers.is_empty() {
+                        true => Err(GeneralError::Timeout(None).into()),
+                        false => Ok(Lookup {
+                            peers: found.peers.clone(),
+                            tokens: found.tokens.clone(),
+                            finished: Instant::now(),
+                        }),
+                    };
+                    let _ = reply.send(lookup);
+                }
+            }
+            Search::Node(reply) => {
+                let nodes = match found.nodes.is_empty() {
+                    true => Err(GeneralError::Timeout(None).into()),
+                    false => Ok(found.nodes.into_iter().take(CAPACITY).collect()),
+                };
+                let _ = reply.send(nodes);
+            }
+        }
+
+        Ok(())
+    }
+
+    // starts from the closest nodes we know, the outcome comes back through `found`
+    fn search(&self, method: Method, target: [u8; 20], search: Search) -> Result<(), Search> {
+        let start = self.table.closest(&target, CAPACITY);
+        if start.is_empty() {
+            return Err(search);
+        }
+
+        let (client, found, id) = (self.client.clone(), self.found_tx.clone(), self.id);
+        tokio::spawn(async move {
+            let result = iterate(&client, id, method, target, start).await;
+            let _ = found.send((search, result)).await;
+        });
+
+        Ok(())
+    }
+
+    fn command(&mut self, command: Command) {
+        match command {
+            Command::GetPeers(hash, reply) => {
+                if let Some(waiting) = self.lookups.get_mut(&hash) {
+                    waiting.push(reply);
+                    return;
+                }
+
+                match self.search(Method::GetPeers, hash, Search::Peers(hash)) {
+                    Ok(()) => {
+                        self.lookups.insert(hash, vec![reply]);
+                    }
+                    Err(_) => {
+                        let _ = reply.send(Err(GeneralError::EmptyTable.into()));
+                    }
+                }
+            }
+            Command::FindNode(id, reply) => {
+                if let Err(Search::Node(reply)) =
+                    self.search(Method::FindNode, id, Search::Node(reply))
+                {
+                    let _ = reply.send(Err(GeneralError::EmptyTable.into()));
+                }
+            }
+            Command::AnnouncePeer(addr, hash, token, reply) => {
+                let args = Arguments::announce_peer(self.id, hash, token, self.port);
+                self.query(addr, args, Query::Announce(reply));
+            }
+        }
+    }
+
+    async fn refresh(&mut self) -> Result<(), Report> {
+        let before = Utc::now() - chrono::Duration::from_std(BUCKET_REFRESH)?;
+        let now = Instant::now();
+        self.tokens.rotate(now);
+        self.peers.expire(now);
+
+        for node in self.table.stale(before) {
+            let Some(addr) = node.addr else {
+                continue;
+            };
+            if self.client.outstanding(addr) {
+                continue;
+            }
+
+            let args = Arguments {
+                method: Method::Ping,
+                id: self.id,
+                ..Default::default()
+            };
+            self.query(addr, args, Query::Refresh(node.id));
+        }
+
+        // lost touch with nearly everyone, start over
+        if self.table.nodes().count() < CAPACITY {
+            self.bootstrap().await;
+        }
+
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), Report> {
+        if let Some(path) = &self.state {
+            self.table.state()?.save(path)?;
+        }
+        if let Some(db) = &self.db {
+            db.save_dht(&self.id, &self.table.saved())?;
+            db.flush()?;
+        }
+
+        Ok(())
+    }
+
+    // runs on its own, the outcome comes back through `settled` once the client is done asking
+    fn query(&self, addr: SocketAddr, args: Arguments, query: Query) {
+        let (client, settled) = (self.client.clone(), self.settled_tx.clone());
+
+        tokio::spawn(async move {
+            let answer = client.query(addr, args).await;
+            let _ = settled.send((addr, query, answer)).await;
+        });
+    }
+}
+
+impl DhtHandle {
+    // the peers of a torrent, with the tokens of the nodes closest to it
+    pub async fn lookup_peers(&self, hash: [u8; 20]) -> Result<Lookup, Report> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::GetPeers(hash, tx))
+            .await
+            .map_err(|_| GeneralError::BrokenPipe)?;
+
+        rx.await.map_err(|_| GeneralError::BrokenPipe)?
+    }
+
+    // the k nodes closest to `id` that answered, closest first
+    pub async fn lookup_node(&self, id: [u8; 20]) -> Result<Vec<Node>, Report> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::FindNode(id, tx))
+            .await
+            .map_err(|_| GeneralError::BrokenPipe)?;
+
+        rx.await.map_err(|_| GeneralError::BrokenPipe)?
+    }
+}
+
+impl PeerLookup for DhtHandle {
+    async fn get_peers(&self, hash: [u8; 20]) -> Result<Lookup, Report> {
+        self.lookup_peers(hash).await
+    }
+
+    async fn announce_peer(
+        &self,
+        node: SocketAddr,
+        hash: [u8; 20],
+        token: String,
+    ) -> Result<(), Report> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::AnnouncePeer(node, hash, token, tx))
+            .await
+            .map_err(|_| GeneralError::BrokenPipe)?;
+
+        rx.await.map_err(|_| GeneralError::BrokenPipe)?
+    }
+}
+
+// hands whatever the DHT knows about a torrent to its router, asks again once the last lookup is
+// no longer shared or as soon as it's cached no longer if it came up empty
+pub async fn find_peers<L: PeerLookup>(
+    announcer: Arc<Mutex<Announcer>>,
+    client: L,
+    hash: [u8; 20],
+    peer_tx: mpsc::Sender<Peers>,
+) {
+    loop {
+        let wait = match lookup(&announcer, &client, hash).await {
+            Ok(lookup) if !lookup.peers.is_empty() => {
+                let peers = lookup.peers.iter().map(Peer::from).collect();
+                if peer_tx.send(peers).await.is_err() {
+                    return;
+                }
+                REANNOUNCE_INTERVAL
+            }
+            Ok(_) => LOOKUP_CACHE,
+            Err(e) => {
+                debug!("DHT lookup for [{}] failed: {e}", hex::encode(hash));
+                LOOKUP_CACHE
+            }
+        };
+
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num::BigUint;
+    use rand::Rng;
+
+    use super::*;
+    use crate::demux::Demux;
+
+    #[test]
+    fn test_insert_dht() {
+        use super::*;
+
+        let id: [u8; 20] = rand::thread_rng().gen();
+        let x = BigUint::new(
+            id.chunks(4)
+                .map(|x| u32::from_be_bytes(x.try_into().unwrap()))
+                .collect(),
+        );
+        dbg!(x);
+
+        let node = Node::new(id, "127.0.0.1:8080".parse().unwrap());
+        let mut table = Table::new(node);
+
+        let id: [u8; 20] = rand::thread_rng().gen();
+        let other = Node::new(id, "127.0.0.1:4040".parse().unwrap());
+        table.insert(other).unwrap();
+
+        dbg!(table
+            .inner
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, b)| b.map(|b| (i, b.nodes)))
+            .collect::<Vec<_>>());
+    }
+
+    struct Fake(std::sync::atomic::AtomicUsize);
+
+    impl PeerLookup for Fake {
+        async fn get_peers(&self, _: [u8; 20]) -> Result<Lookup, Report> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+            Ok(Lookup {
+                peers: vec!["127.0.0.1:6881".parse()?],
+                tokens: vec![("127.0.0.1:4040".parse()?, "token".to_owned())],
+                finished: Instant::now(),
+            })
+        }
+
+        async fn announce_peer(&self, _: SocketAddr, _: [u8; 20], _: String) -> Result<(), Report> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_table_maintenance() -> Result<(), Report> {
+        let mut table = Table::new(Node::new([0u8; 20], "127.0.0.1:6881".parse()?));
+        let node = |x: u8| {
+            let mut id = [0u8; 20];
+            id[0] = x;
+            Node::new(id, SocketAddr::from(([10, 0, 0, x], 6881)))
+        };
+
+        // ourselves and nodes we already know don't take up room
+        table.insert(Node::new([0u8; 20], "127.0.0.1:6882".parse()?))?;
+        for x in [0x80, 0x40, 0x41, 0x41, 0x01] {
+            table.insert(node(x))?;
+        }
+        assert_eq!(table.nodes().count(), 4);
+        assert!(table.inner[159].is_some() && table.inner[158].is_some());
+
+        let closest = table.closest(&node(0x42).id, 2);
+        assert_eq!(closest[0].id, node(0x40).id);
+        assert_eq!(closest[1].id, node(0x41).id);
+
+        // the nodes of a bucket that went quiet get checked on
+        let later = Utc::now() + chrono::Duration::minutes(20);
+        assert_eq!(table.stale(later).len(), 4);
+        assert!(table
+            .stale(Utc::now() - chrono::Duration::minutes(20))
+            .is_empty());
+
+        table.remove(&node(0x40).id);
+        table.remove(&[0u8; 20]);
+        assert!(!table.contains(&node(0x40).id));
+        assert!(table.contains(&node(0x41).id));
+        assert_eq!(table.state()?.id, Some([0u8; 20]));
+
+        // a full bucket keeps the nodes it has
+        for x in 0x80..=0x8f {
+            table.insert(node(x))?;
+        }
+        assert_eq!(table.inner[159].unwrap().len, CAPACITY);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dht_node() -> Result<(), Report> {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        rt.block_on(async {
+            let port = AnnouncePort {
+                port: 6881,
+                implied: false,
+            };
+            let mut nodes = Vec::new();
+            for id in [[1u8; 20], [2u8; 20]] {
+                let (demux, _, dht_rx) = Demux::new(std::net::UdpSocket::bind("127.0.0.1:0")?)?;
+                let socket = demux.socket();
+                tokio::spawn(demux.run());
+
+                let table = Table::new(Node::new(id, socket.local_addr()?));
+                let (mut dht, handle) = Dht::new(table, socket.clone(), dht_rx, port)?;
+                dht.routers.clear();
+                nodes.push((dht, handle, socket.local_addr()?));
+            }
+            let (mut a, a_handle, a_addr) = nodes.remove(0);
+            let (b, _, b_addr) = nodes.remove(0);
+            let token = b.tokens.token(a_addr.ip());
+
+            a.table.insert(Node::new([2u8; 20], b_addr))?;
+            tokio::spawn(a.run());
+            tokio::spawn(b.run());
+
+            // b knows no peers, but hands out a token to announce with
+            let lookup = a_handle.get_peers([3u8; 20]).await?;
+            assert!(lookup.peers.is_empty());
+            assert_eq!(lookup.tokens, vec![(b_addr, token.clone())]);
+            a_handle.announce_peer(b_addr, [3u8; 20], token).await?;
+
+            // which b remembers for the next one asking, tokens it didn't hand out get turned down
+            let lookup = a_handle.get_peers([3u8; 20]).await?;
+            assert_eq!(lookup.peers, vec!["127.0.0.1:6881".parse()?]);
+            let forged = a_handle.announce_peer(b_addr, [3u8; 20], "forged".to_owned());
+            assert!(forged.await.is_err());
+
+            // b names nobody but a, which doesn't ask itself
+            let nodes = a_handle.lookup_node([2u8; 20]).await?;
+            assert_eq!(nodes.len(), 1);
+            assert_eq!(nodes[0].addr, Some(b_addr));
+
+            Ok::<_, Report>(())
+        })
+    }
+
+    #[test]
+    fn test_dht_state() -> Result<(), Report> {
+        let ours = Node::new([0u8; 20], "127.0.0.1:6881".parse()?);
+        let mut table = Table::new(ours);
+        table.insert(Node::new([0xff; 20], "192.0.2.1:6881".parse()?))?;
+        table.insert(Node::new([0x0f; 20], "[2001:db8::1]:51413".parse()?))?;
+
+        let bytes = table.state()?.to_bencode().unwrap();
+        let state = DhtState::from_bencode(&bytes).unwrap();
+        assert_eq!(state.id, Some([0u8; 20]));
+        assert_eq!(state.nodes.len(), 2);
+
+        let restored = Table::from_state(&state)?;
+        let mut addrs: Vec<_> = restored.nodes().filter_map(|node| node.addr).collect();
+        addrs.sort();
+        assert_eq!(
+            addrs,
+            vec!["192.0.2.1:6881".parse()?, "[2001:db8::1]:51413".parse()?]
+        );
+
+        // libtorrent's naming, with a key we don't know about
+        let id = [b'a'; 20];
+        let node = [
+            [b'b'; 20].as_slice(),
+            &[10, 0, 0, 1],
+            &6881u16.to_be_bytes(),
+        ]
+        .concat();
+        let dat = [
+            b"d7:node-id20:".as_slice(),
+            &id,
+            b"5:nodes26:",
+            &node,
+            b"7:unknowni1ee",
+        ]
+        .concat();
+        let state = DhtState::from_bencode(&dat).unwrap();
+        assert_eq!(state.id, Some(id));
+        assert_eq!(state.nodes[0].addr, Some("10.0.0.1:6881".parse()?));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_announce_port() -> Result<(), Report> {
+        let public: IpAddr = "203.0.113.7".parse()?;
+        let private: IpAddr = "192.168.1.20".parse()?;
+
+        let port = AnnouncePort::new(6881, false, Some(public), Some(public));
+        assert_eq!(
+            port,
+            AnnouncePort {
+                port: 6881,
+                implied: false
+            }
+        );
+
+        assert!(AnnouncePort::new(6881, false, Some(public), Some(private)).implied);
+        assert!(AnnouncePort::new(6881, false, None, Some(private)).implied);
+        assert!(AnnouncePort::new(6881, true, Some(public), Some(public)).implied);
+        assert!(!AnnouncePort::new(6881, false, None, None).implied);
+
+        let args = Arguments::announce_peer([0u8; 20], [1u8; 20], "token".to_owned(), port);
+        assert_eq!(args.method, Method::AnnouncePeer);
+        assert_eq!((args.port, args.implied_port), (Some(6881), Some(false)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reannounce_schedule() -> Result<(), Report> {
+        let hash = [1u8; 20];
+        let mut announcer = Announcer::new(REANNOUNCE_INTERVAL);
+        announcer.add(hash);
+
+        let now = Instant::now();
+        assert_eq!(announcer.due(now), vec![hash]);
+        assert!(announcer.tokens(&hash, now).is_empty());
+
+        let announcer = Mutex::new(announcer);
+        let client = Fake(Default::default());
+
+        // asking twice in a row only hits the network once
+        futures::executor::block_on(async {
+            let (a, b) = futures::join!(
+                lookup(&announcer, &client, hash),
+                lookup(&announcer, &client, hash)
+            );
+            assert_eq!(a?.peers, b?.peers);
+            lookup(&announcer, &client, hash).await?;
+
+            Ok::<_, Report>(())
+        })?;
+        assert_eq!(client.0.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        let announcer = announcer.into_inner();
+        let now = Instant::now();
+        assert!(announcer.due(now).is_empty());
+        assert_eq!(announcer.tokens(&hash, now).len(), 1);
+
+        // tokens expire long before the next announce is due
+        let later = now + TOKEN_VALIDITY;
+        assert!(announcer.tokens(&hash, later).is_empty());
+        assert!(announcer.cached(&hash, later).is_none());
+        assert_eq!(announcer.due(now + REANNOUNCE_INTERVAL), vec![hash]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_token_store() -> Result<(), Report> {
+        let now = Instant::now();
+        let (ip, other): (IpAddr, IpAddr) = ("10.0.0.1".parse()?, "10.0.0.2".parse()?);
+        let mut tokens = Tokens::new(now);
+
+        // only good for the node it was given to, and for one rotation after that
+        let token = tokens.token(ip);
+        assert!(tokens.valid(ip, &token));
+        assert!(!tokens.valid(other, &token));
+        tokens.rotate(now + SECRET_ROTATION);
+        assert!(tokens.valid(ip, &token));
+        tokens.rotate(now + 2 * SECRET_ROTATION);
+        assert!(!tokens.valid(ip, &token));
+
+        let mut store = PeerStore::default();
+        let hash = [1u8; 20];
+        for port in 0..MAX_STORED_PEERS as u16 + 1 {
+            let at = now + Duration::from_secs(port.into());
+            store.insert(hash, SocketAddr::new(ip, port), at);
+        }
+        // the first one made room for the last
+        let later = now + Duration::from_secs(MAX_STORED_PEERS as u64);
+        let peers = store.peers(&hash, later);
+        assert_eq!(peers.len(), MAX_VALUES);
+        assert_eq!(store.torrents[&hash].len(), MAX_STORED_PEERS);
+        assert!(!store.torrents[&hash].contains_key(&SocketAddr::new(ip, 0)));
+
+        store.expire(later + PEER_EXPIRY);
+        assert!(store.peers(&hash, later).is_empty());
+        assert!(store.torrents.is_empty());
+
+        Ok(())
+    }
+}
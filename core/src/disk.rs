@@ -0,0 +1,327 @@
+use std::{
+    collections::BTreeMap,
+    ops::Range,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Condvar, Mutex, MutexGuard, OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use color_eyre::Report;
+use tracing::debug;
+
+use crate::{data::GeneralError, storage::Storage};
+
+// nothing sits in memory for longer than this, a crash loses no more than what came in since
+const WRITE_BACK_DELAY: Duration = Duration::from_secs(5);
+
+enum Job {
+    // write whatever is queued
+    Write,
+    // and sync the files afterwards
+    Sync,
+}
+
+struct Queue {
+    // blocks by piece and offset, until the worker wrote them
+    blocks: BTreeMap<(usize, usize), Bytes>,
+    bytes: usize,
+    // jobs handed to the worker and the ones it finished, callers wait for theirs by number
+    sent: u64,
+    done: u64,
+    // the last write that failed, whoever comes next gets to hear about it
+    error: Option<String>,
+}
+
+struct Shared<S> {
+    storage: Mutex<S>,
+    queue: Mutex<Queue>,
+    finished: Condvar,
+}
+
+// buffers writes in memory and leaves them to a thread of its own, so the runtime never waits for
+// the disk. Reads are served from the buffer while their data is in there
+pub struct WriteBack<S> {
+    shared: Arc<Shared<S>>,
+    // bytes that pile up before the worker gets woken, twice as much before writers wait for it. 0
+    // writes every block through right away
+    capacity: usize,
+    // the worker starts with the first write, and stops once this is dropped
+    jobs: OnceLock<mpsc::Sender<Job>>,
+}
+
+impl<S: Storage + 'static> WriteBack<S> {
+    pub fn new(storage: S) -> Self {
+        let queue = Queue {
+            blocks: BTreeMap::new(),
+            bytes: 0,
+            sent: 0,
+            done: 0,
+            error: None,
+        };
+
+        Self {
+            shared: Arc::new(Shared {
+                storage: Mutex::new(storage),
+                queue: Mutex::new(queue),
+                finished: Condvar::new(),
+            }),
+            capacity: 0,
+            jobs: OnceLock::new(),
+        }
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+
+    // the storage underneath, the worker waits while this is held
+    pub fn inner(&self) -> MutexGuard<'_, S> {
+        self.shared.storage.lock().unwrap()
+    }
+
+    fn queue(&self) -> MutexGuard<'_, Queue> {
+        self.shared.queue.lock().unwrap()
+    }
+
+    // the number to wait for until the job is done
+    fn send(&self, job: Job) -> Result<u64, Report> {
+        let jobs = self.jobs.get_or_init(|| spawn(self.shared.clone()));
+
+        let mut queue = self.queue();
+        jobs.send(job)
+            .map_err(|_| GeneralError::DiskIo("worker is gone".to_owned()))?;
+        queue.sent += 1;
+
+        Ok(queue.sent)
+    }
+
+    fn wait(&self, job: u64) -> Result<(), Report> {
+        let queue = self.queue();
+        let mut queue = self
+            .shared
+            .finished
+            .wait_while(queue, |queue| queue.done < job)
+            .unwrap();
+
+        match queue.error.take() {
+            Some(e) => Err(GeneralError::DiskIo(e).into()),
+            None => Ok(()),
+        }
+    }
+
+    fn write_out(&self, job: Job) -> Result<(), Report> {
+        self.wait(self.send(job)?)
+    }
+
+    // the queued block at the start of a piece, if it's there and at least `len` long
+    fn queued(&self, index: usize, range: Range<usize>) -> Option<Bytes> {
+        let queue = self.queue();
+        let (&(_, begin), data) = queue
+            .blocks
+            .range((index, 0)..=(index, range.start))
+            .next_back()?;
+
+        let range = range.start - begin..range.end - begin;
+        (range.end <= data.len()).then(|| data.slice(range))
+    }
+}
+
+impl<S: Storage + 'static> Storage for WriteBack<S> {
+    fn read_block(&self, index: usize, range: Range<usize>) -> Result<Vec<u8>, Report> {
+        if let Some(data) = self.queued(index, range.clone()) {
+            return Ok(data.to_vec());
+        }
+
+        // the piece is partly queued and partly in the files, or was just written
+        let partly = self
+            .queue()
+            .blocks
+            .range((index, 0)..(index + 1, 0))
+            .next()
+            .is_some();
+        if partly {
+            self.write_out(Job::Write)?;
+        }
+
+        self.inner().read_block(index, range)
+    }
+
+    fn write_block(&mut self, index: usize, begin: usize, block: &[u8]) -> Result<(), Report> {
+        let bytes = {
+            let mut queue = self.queue();
+            if let Some(e) = queue.error.take() {
+                return Err(GeneralError::DiskIo(e).into());
+            }
+
+            let old = queue
+                .blocks
+                .insert((index, begin), Bytes::copy_from_slice(block));
+            queue.bytes -= old.map_or(0, |old| old.len());
+            queue.bytes += block.len();
+            queue.bytes
+        };
+
+        if bytes > 2 * self.capacity {
+            self.write_out(Job::Write)
+        } else if bytes > self.capacity {
+            self.send(Job::Write).map(|_| ())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Report> {
+        self.write_out(Job::Sync)
+    }
+
+    fn drain(&mut self) -> Result<(), Report> {
+        self.write_out(Job::Write)
+    }
+
+    // pieces that lost their hash check don't need to be written anymore
+    fn verified(&mut self, indices: &[usize], valid: bool) -> Result<(), Report> {
+        if !valid {
+            let mut queue = self.queue();
+            for &index in indices {
+                let keys: Vec<_> = queue
+                    .blocks
+                    .range((index, 0)..(index + 1, 0))
+                    .map(|(&key, _)| key)
+                    .collect();
+                for key in keys {
+                    let data = queue.blocks.remove(&key).unwrap_or_default();
+                    queue.bytes -= data.len();
+                }
+            }
+        }
+
+        self.inner().verified(indices, valid)
+    }
+
+    fn contains(&self, index: usize, len: usize) -> bool {
+        self.queued(index, 0..len).is_some() || self.inner().contains(index, len)
+    }
+
+    fn allocate(&mut self) -> Result<(), Report> {
+        self.inner().allocate()
+    }
+
+    fn claim_files(&mut self) -> Result<(), Report> {
+        self.inner().claim_files()
+    }
+}
+
+fn spawn<S: Storage + 'static>(shared: Arc<Shared<S>>) -> mpsc::Sender<Job> {
+    let (tx, rx) = mpsc::channel();
+    thread::Builder::new()
+        .name("disk-io".to_owned())
+        .spawn(move || worker(shared, rx))
+        .expect("failed to spawn the disk IO thread");
+
+    tx
+}
+
+fn worker<S: Storage>(shared: Arc<Shared<S>>, jobs: mpsc::Receiver<Job>) {
+    loop {
+        let job = match jobs.recv_timeout(WRITE_BACK_DELAY) {
+            Ok(job) => Some(job),
+            // nobody asked, but whatever is queued has waited long enough
+            Err(RecvTimeoutError::Timeout) => None,
+            // the storage is gone, what it left behind still gets written
+            Err(RecvTimeoutError::Disconnected) => {
+                if let Err(e) = write(&shared, false) {
+                    debug!("disk write failed: {e}");
+                }
+                return;
+            }
+        };
+
+        let result = write(&shared, matches!(job, Some(Job::Sync)));
+        let mut queue = shared.queue.lock().unwrap();
+        if let Err(e) = result {
+            debug!("disk write failed: {e}");
+            queue.error = Some(e.to_string());
+        }
+        if job.is_some() {
+            queue.done += 1;
+        }
+        drop(queue);
+
+        shared.finished.notify_all();
+    }
+}
+
+// writes what's queued right now, sorted by piece so the files get written front to back. Written
+// or not, the blocks don't stay queued, a failure gets reported to whoever comes next
+fn write<S: Storage>(shared: &Shared<S>, sync: bool) -> Result<(), Report> {
+    let batch: Vec<_> = shared
+        .queue
+        .lock()
+        .unwrap()
+        .blocks
+        .iter()
+        .map(|(&(index, begin), data)| (index, begin, data.clone()))
+        .collect();
+
+    let result = {
+        let mut storage = shared.storage.lock().unwrap();
+        let written = match batch.is_empty() {
+            true => Ok(()),
+            false => storage.write_batch(&batch),
+        };
+        written.and_then(|()| match sync {
+            true => storage.flush(),
+            false => Ok(()),
+        })
+    };
+
+    // blocks that got replaced in the meantime stay for the next round
+    let mut queue = shared.queue.lock().unwrap();
+    for (index, begin, data) in batch {
+        let key = (index, begin);
+        if queue.blocks.get(&key).map(|queued| queued.as_ptr()) == Some(data.as_ptr()) {
+            queue.blocks.remove(&key);
+            queue.bytes -= data.len();
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_write_back() -> Result<(), Report> {
+        let mut storage = WriteBack::new(MemoryStorage::default());
+        storage.set_capacity(16);
+
+        // stays in memory until there's enough of it, reads don't notice
+        storage.write_block(0, 0, b"abcdefgh")?;
+        assert!(!storage.inner().contains(0, 8));
+        assert!(storage.contains(0, 8));
+        assert_eq!(storage.read_block(0, 2..6)?, b"cdef");
+
+        storage.drain()?;
+        assert_eq!(storage.inner().read_block(0, 0..8)?, b"abcdefgh");
+        assert_eq!(storage.queue().bytes, 0);
+
+        // a piece that failed its check doesn't get written
+        storage.write_block(1, 0, b"ijkl")?;
+        storage.verified(&[1], false)?;
+        storage.flush()?;
+        assert!(!storage.contains(1, 4));
+
+        // too much at once and the writer waits for the worker
+        storage.write_block(2, 0, &[7u8; 40])?;
+        assert!(storage.inner().contains(2, 40));
+
+        Ok(())
+    }
+}
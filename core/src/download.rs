@@ -0,0 +1,670 @@
+use std::{collections::VecDeque, net::SocketAddr, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use color_eyre::Report;
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc::{self, Receiver, Sender},
+        RwLock,
+    },
+    time::Instant,
+};
+use tracing::debug;
+
+use crate::{data::GeneralError, piece_manager::BitField, pwp::Block, torrent::Torrent};
+
+// blocks on their way from the connections to the disk, a single task writes all of them so
+// connections never wait for a piece to be hashed and flushed
+pub struct Pipeline {
+    torrent: Arc<RwLock<Torrent>>,
+    block_rx: Receiver<(SocketAddr, Block, Bytes)>,
+    // pieces that made it to disk, every connection tells its peer about them
+    have_tx: broadcast::Sender<usize>,
+    // blocks more than one peer was asked for in endgame, the others cancel their requests
+    cancel_tx: broadcast::Sender<(SocketAddr, Block)>,
+}
+
+impl Pipeline {
+    pub fn new(
+        torrent: Arc<RwLock<Torrent>>,
+        have_tx: broadcast::Sender<usize>,
+        cancel_tx: broadcast::Sender<(SocketAddr, Block)>,
+        capacity: usize,
+    ) -> (Self, Sender<(SocketAddr, Block, Bytes)>) {
+        let (block_tx, block_rx) = mpsc::channel(capacity);
+        let pipeline = Self {
+            torrent,
+            block_rx,
+            have_tx,
+            cancel_tx,
+        };
+
+        (pipeline, block_tx)
+    }
+
+    pub async fn run(mut self) {
+        while let Some((peer, block, data)) = self.block_rx.recv().await {
+            let mut torrent = self.torrent.write().await;
+            if torrent.requesters(block.index) > 1 {
+                let _ = self.cancel_tx.send((peer, block));
+            }
+
+            match torrent.write_block(block.index, block.begin, &data).await {
+                Ok(true) => {
+                    debug!("piece {} is complete", block.index);
+                    let _ = self.have_tx.send(block.index);
+                }
+                Ok(false) => {}
+                Err(e) => debug!("[{peer}] piece {}: {e}", block.index),
+            }
+        }
+    }
+}
+
+// requests a peer has outstanding before its extension handshake says how many it takes, and the
+// bounds on what its reqq gets it
+const DEFAULT_DEPTH: usize = 16;
+const MIN_DEPTH: usize = 5;
+const MAX_DEPTH: usize = 50;
+// whatever the depth, no more than this is on its way from a single peer
+const MAX_IN_FLIGHT: usize = 1 << 20;
+// a peer that sits on a request of ours for this long is snubbing us
+pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+// what a single connection downloads, requests are pipelined over as many pieces as it takes to
+// keep the peer busy
+pub struct Downloader {
+    torrent: Arc<RwLock<Torrent>>,
+    block_tx: Sender<(SocketAddr, Block, Bytes)>,
+    // claimed, and blocks of them are still on their way or waiting to be requested
+    pieces: Vec<usize>,
+    queued: VecDeque<Block>,
+    // requested and not delivered yet, along with when they were requested
+    pending: Vec<(Block, Instant)>,
+    // requests outstanding at most, grows by one with every block up to `limit`
+    depth: usize,
+    limit: usize,
+    // the peer sat on our requests, it gets asked for nothing until it unchokes us again
+    snubbed: bool,
+    // BEP 6, pieces the peer serves while choking us and pieces it would like us to pick
+    allowed: Vec<usize>,
+    suggested: Vec<usize>,
+}
+
+impl Downloader {
+    pub fn new(
+        torrent: Arc<RwLock<Torrent>>,
+        block_tx: Sender<(SocketAddr, Block, Bytes)>,
+    ) -> Self {
+        Self {
+            torrent,
+            block_tx,
+            pieces: Vec::new(),
+            queued: VecDeque::new(),
+            pending: Vec::new(),
+            depth: DEFAULT_DEPTH,
+            limit: DEFAULT_DEPTH,
+            snubbed: false,
+            allowed: Vec::new(),
+            suggested: Vec::new(),
+        }
+    }
+
+    pub fn is_snubbed(&self) -> bool {
+        self.snubbed
+    }
+
+    // the reqq of the peer's extension handshake, within reason
+    pub fn set_limit(&mut self, reqq: usize) {
+        self.limit = reqq.clamp(MIN_DEPTH, MAX_DEPTH);
+        self.depth = self.depth.min(self.limit);
+    }
+
+    pub fn allow(&mut self, index: usize) {
+        if !self.allowed.contains(&index) {
+            self.allowed.push(index);
+        }
+    }
+
+    // usually pieces the peer has in its cache, they get picked before any other
+    pub fn suggest(&mut self, index: usize) {
+        if !self.suggested.contains(&index) {
+            self.suggested.push(index);
+        }
+    }
+
+    fn in_flight(&self) -> usize {
+        self.pending.iter().map(|(block, _)| block.length).sum()
+    }
+
+    // blocks to request next, as many as it takes to fill the pipe, claiming pieces as it goes
+    pub async fn next_blocks(&mut self, has: &(dyn Fn(usize) -> bool + Sync)) -> Vec<Block> {
+        let mut blocks = Vec::new();
+        if self.snubbed {
+            return blocks;
+        }
+
+        let now = Instant::now();
+        while self.pending.len() < self.depth && self.in_flight() < MAX_IN_FLIGHT {
+            if self.queued.is_empty() && !self.claim(has).await {
+                break;
+            }
+            let Some(block) = self.queued.pop_front() else {
+                break;
+            };
+            self.pending.push((block, now));
+            blocks.push(block);
+        }
+
+        blocks
+    }
+
+    // what a peer that chokes us still serves, the allowed fast pieces
+    pub async fn next_allowed(&mut self, has: &(dyn Fn(usize) -> bool + Sync)) -> Vec<Block> {
+        if self.allowed.is_empty() {
+            return Vec::new();
+        }
+        let allowed = self.allowed.clone();
+
+        self.next_blocks(&|i| has(i) && allowed.contains(&i)).await
+    }
+
+    // in endgame the same piece may come up again, one peer never gets it twice
+    async fn claim(&mut self, has: &(dyn Fn(usize) -> bool + Sync)) -> bool {
+        let (claimed, suggested) = (&self.pieces, &self.suggested);
+        let has = |i| has(i) && !claimed.contains(&i);
+        let suggestion = |i| has(i) && suggested.contains(&i);
+
+        let mut torrent = self.torrent.write().await;
+        let claim = torrent
+            .claim_piece(&suggestion)
+            .or_else(|| torrent.claim_piece(&has));
+        drop(torrent);
+
+        let Some((index, blocks)) = claim else {
+            return false;
+        };
+        self.suggested.retain(|&i| i != index);
+        self.pieces.push(index);
+        self.queued.extend(blocks);
+
+        true
+    }
+
+    // drops the requests for every piece but the ones to keep, the pieces go back to the others
+    async fn forget(&mut self, keep: &(dyn Fn(usize) -> bool + Sync)) {
+        self.pending.retain(|(block, _)| keep(block.index));
+        self.queued.retain(|block| keep(block.index));
+
+        let (kept, gone): (Vec<usize>, Vec<usize>) = self.pieces.iter().partition(|&&i| keep(i));
+        self.pieces = kept;
+        if !gone.is_empty() {
+            let mut torrent = self.torrent.write().await;
+            gone.into_iter()
+                .for_each(|index| torrent.release_piece(index));
+        }
+    }
+
+    // a piece we neither wait for nor have to ask for anymore
+    fn settled(&mut self, index: usize) -> bool {
+        let left = self
+            .pending
+            .iter()
+            .map(|(block, _)| block)
+            .chain(&self.queued)
+            .any(|b| b.index == index);
+        if !left {
+            self.pieces.retain(|&i| i != index);
+        }
+
+        !left
+    }
+
+    pub async fn received(
+        &mut self,
+        peer: SocketAddr,
+        index: usize,
+        begin: usize,
+        data: Bytes,
+    ) -> Result<(), Report> {
+        let position = self
+            .pending
+            .iter()
+            .position(|(block, _)| block.index == index && block.begin == begin);
+        let Some(position) = position else {
+            return Err(GeneralError::UnexpectedResponse(format!("block {index}:{begin}")).into());
+        };
+
+        let (block, _) = self.pending.swap_remove(position);
+        if data.len() != block.length {
+            return Err(GeneralError::InvalidRange.into());
+        }
+        self.block_tx.send((peer, block, data)).await?;
+
+        self.depth = (self.depth + 1).min(self.limit);
+        // the pipeline releases the piece once it's been flushed
+        self.settled(index);
+
+        Ok(())
+    }
+
+    // a choked peer throws away our requests, someone else may finish the pieces. Requests for
+    // allowed fast pieces still get served
+    pub async fn choked(&mut self) {
+        let allowed = self.allowed.clone();
+        self.forget(&|i| allowed.contains(&i)).await;
+    }
+
+    // the peer won't send the block, so the piece is up to the others. Whatever else of it is
+    // still outstanding is returned, to be cancelled
+    pub async fn rejected(&mut self, block: Block) -> Vec<Block> {
+        let Some(position) = self.pending.iter().position(|(b, _)| *b == block) else {
+            return Vec::new();
+        };
+        self.pending.swap_remove(position);
+        // even if it said we may have it
+        self.allowed.retain(|&i| i != block.index);
+
+        let others = self
+            .pending
+            .iter()
+            .map(|(b, _)| *b)
+            .filter(|b| b.index == block.index)
+            .collect();
+        self.forget(&|i| i != block.index).await;
+
+        others
+    }
+
+    // a fresh start for a peer that snubbed us, it gets asked for little at first
+    pub fn unchoked(&mut self) {
+        self.snubbed = false;
+    }
+
+    // a peer that sat on any of our requests for too long is snubbing us, its pieces go back to
+    // the others. What it had outstanding is returned, to be cancelled
+    pub async fn snubbed(&mut self, now: Instant) -> Vec<Block> {
+        let overdue = self
+            .pending
+            .iter()
+            .any(|(_, requested)| now.duration_since(*requested) >= SNUB_TIMEOUT);
+        if !overdue {
+            return Vec::new();
+        }
+
+        let pending = self.pending.iter().map(|(block, _)| *block).collect();
+        self.forget(&|_| false).await;
+        self.snubbed = true;
+        self.depth = MIN_DEPTH;
+
+        pending
+    }
+
+    // another peer delivered the block first, true if we were still waiting for it
+    pub async fn cancel(&mut self, block: Block) -> bool {
+        let requested = match self.pending.iter().position(|(b, _)| *b == block) {
+            Some(position) => {
+                self.pending.swap_remove(position);
+                true
+            }
+            None => match self.queued.iter().position(|b| *b == block) {
+                Some(position) => {
+                    self.queued.remove(position);
+                    false
+                }
+                None => return false,
+            },
+        };
+
+        if self.settled(block.index) {
+            self.torrent.write().await.release_piece(block.index);
+        }
+
+        requested
+    }
+
+    // whether we should be interested in a peer with the pieces `has` says it does
+    pub async fn wants(&self, has: &(dyn Fn(usize) -> bool + Sync)) -> bool {
+        self.torrent.read().await.wants(has)
+    }
+
+    // what the peer has, so the picker knows how common each piece is
+    pub async fn peer_bitfield(&self, peer: SocketAddr, bitfield: BitField) {
+        self.torrent.write().await.peer_bitfield(peer, bitfield);
+    }
+
+    pub async fn peer_have_all(&self, peer: SocketAddr) {
+        self.torrent.write().await.peer_have_all(peer);
+    }
+
+    pub async fn peer_have(&self, peer: SocketAddr, indices: &[usize]) {
+        let mut torrent = self.torrent.write().await;
+        indices.iter().for_each(|&i| torrent.peer_have(peer, i));
+    }
+
+    // the peer's pieces don't count anymore, along with giving up on the pieces
+    pub async fn gone(&mut self, peer: SocketAddr) {
+        self.forget(&|_| false).await;
+        self.torrent.write().await.peer_gone(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::{Info, Mode, TorrentInfo},
+        piece_manager::sha1,
+        testing::TempDir,
+        torrent::{AddOptions, State},
+    };
+    use std::fs;
+
+    #[test]
+    fn test_download() -> Result<(), Report> {
+        let root = TempDir::new();
+        let data: Vec<u8> = (0..12).collect();
+
+        let info = Info {
+            mode: Mode::Single {
+                name: "data".to_owned(),
+                length: 12,
+                md5sum: None,
+            },
+            piece_length: 8,
+            pieces: vec![sha1(&data[..8]), sha1(&data[8..])].into_boxed_slice(),
+            ..Default::default()
+        };
+        let options = AddOptions {
+            root: Some(root.to_path_buf()),
+            block_size: Some(4),
+            ..Default::default()
+        };
+        let mut torrent = Torrent::new(
+            TorrentInfo {
+                info: Some(info),
+                ..Default::default()
+            },
+            options,
+        )?;
+        torrent.files_checked()?;
+        let torrent = Arc::new(RwLock::new(torrent));
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        rt.block_on(async {
+            let (have_tx, mut have_rx) = broadcast::channel(4);
+            let cancel_tx = broadcast::channel(4).0;
+            let (pipeline, block_tx) = Pipeline::new(torrent.clone(), have_tx, cancel_tx, 4);
+            tokio::spawn(pipeline.run());
+
+            let peer: SocketAddr = "10.0.0.1:6881".parse()?;
+            let mut first = Downloader::new(torrent.clone(), block_tx.clone());
+            let mut second = Downloader::new(torrent.clone(), block_tx);
+            let all = |_| true;
+            assert!(first.wants(&all).await);
+
+            // a peer with only the first piece, the pipe has room for more but there's no more
+            let first_only = |i| i == 0;
+            let blocks = first.next_blocks(&first_only).await;
+            assert_eq!(blocks.len(), 2);
+            assert!(first.next_blocks(&first_only).await.is_empty());
+
+            // the first piece is taken, the peer choking us gives it back
+            let other = second.next_blocks(&all).await;
+            assert_eq!(other[0].index, 1);
+            second.choked().await;
+
+            let block =
+                |b: &Block| Bytes::copy_from_slice(&data[b.index * 8 + b.begin..][..b.length]);
+            assert!(first.received(peer, 1, 0, block(&other[0])).await.is_err());
+            first.received(peer, 0, 4, block(&blocks[1])).await?;
+            first.received(peer, 0, 0, block(&blocks[0])).await?;
+            assert_eq!(have_rx.recv().await?, 0);
+            // a peer with nothing but the first piece has nothing left for us
+            assert!(!first.wants(&|i| i == 0).await);
+            assert!(first.wants(&|i| i == 1).await);
+
+            let blocks = first.next_blocks(&all).await;
+            assert_eq!(blocks.len(), 1);
+            first.received(peer, 1, 0, block(&blocks[0])).await?;
+            assert_eq!(have_rx.recv().await?, 1);
+            assert!(!first.wants(&all).await);
+
+            let torrent = torrent.read().await;
+            assert_eq!(torrent.state(), State::Seeding);
+            assert_eq!(torrent.stats().up_down_left(), (0, 12, 0));
+            assert_eq!(fs::read(root.join("data"))?, data);
+
+            Ok::<_, Report>(())
+        })?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_endgame() -> Result<(), Report> {
+        let root = TempDir::new();
+        let data: Vec<u8> = (0..8).collect();
+
+        let info = Info {
+            mode: Mode::Single {
+                name: "data".to_owned(),
+                length: 8,
+                md5sum: None,
+            },
+            piece_length: 8,
+            pieces: vec![sha1(&data)].into_boxed_slice(),
+            ..Default::default()
+        };
+        let options = AddOptions {
+            root: Some(root.to_path_buf()),
+            block_size: Some(4),
+            ..Default::default()
+        };
+        let mut torrent = Torrent::new(
+            TorrentInfo {
+                info: Some(info),
+                ..Default::default()
+            },
+            options,
+        )?;
+        torrent.files_checked()?;
+        let torrent = Arc::new(RwLock::new(torrent));
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        rt.block_on(async {
+            let (have_tx, mut have_rx) = broadcast::channel(4);
+            let (cancel_tx, mut cancel_rx) = broadcast::channel(4);
+            let (pipeline, block_tx) = Pipeline::new(torrent.clone(), have_tx, cancel_tx, 4);
+            tokio::spawn(pipeline.run());
+
+            let (a, b): (SocketAddr, SocketAddr) =
+                ("10.0.0.1:6881".parse()?, "10.0.0.2:6881".parse()?);
+            let mut first = Downloader::new(torrent.clone(), block_tx.clone());
+            let mut second = Downloader::new(torrent.clone(), block_tx);
+            let all = |_| true;
+
+            // the only piece is taken, so the second peer gets asked for it as well
+            let blocks = first.next_blocks(&all).await;
+            assert_eq!(second.next_blocks(&all).await, blocks);
+            assert_eq!(torrent.read().await.requesters(0), 2);
+
+            let block = |b: &Block| Bytes::copy_from_slice(&data[b.begin..][..b.length]);
+            first.received(a, 0, 0, block(&blocks[0])).await?;
+            assert_eq!(cancel_rx.recv().await?, (a, blocks[0]));
+            assert!(second.cancel(blocks[0]).await);
+            assert!(!first.cancel(blocks[0]).await);
+
+            second.received(b, 0, 4, block(&blocks[1])).await?;
+            assert_eq!(cancel_rx.recv().await?, (b, blocks[1]));
+            assert_eq!(have_rx.recv().await?, 0);
+
+            // nothing left to wait for, the piece is gone from the first peer as well
+            assert!(first.cancel(blocks[1]).await);
+            assert_eq!(torrent.read().await.requesters(0), 0);
+            assert_eq!(torrent.read().await.state(), State::Seeding);
+
+            Ok::<_, Report>(())
+        })?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pipelining() -> Result<(), Report> {
+        let root = TempDir::new();
+        let data: Vec<u8> = (0..80).collect();
+
+        let info = Info {
+            mode: Mode::Single {
+                name: "data".to_owned(),
+                length: 80,
+                md5sum: None,
+            },
+            piece_length: 8,
+            pieces: data.chunks(8).map(sha1).collect(),
+            ..Default::default()
+        };
+        let options = AddOptions {
+            root: Some(root.to_path_buf()),
+            block_size: Some(4),
+            ..Default::default()
+        };
+        let mut torrent = Torrent::new(
+            TorrentInfo {
+                info: Some(info),
+                ..Default::default()
+            },
+            options,
+        )?;
+        torrent.files_checked()?;
+        let torrent = Arc::new(RwLock::new(torrent));
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        rt.block_on(async {
+            let (have_tx, _) = broadcast::channel(16);
+            let cancel_tx = broadcast::channel(16).0;
+            let (pipeline, block_tx) = Pipeline::new(torrent.clone(), have_tx, cancel_tx, 16);
+            tokio::spawn(pipeline.run());
+
+            let peer: SocketAddr = "10.0.0.1:6881".parse()?;
+            let mut downloader = Downloader::new(torrent.clone(), block_tx);
+            let all = |_| true;
+
+            // two blocks a piece, the pipe takes eight pieces before it's full
+            let blocks = downloader.next_blocks(&all).await;
+            assert_eq!(blocks.len(), DEFAULT_DEPTH);
+            assert!(downloader.next_blocks(&all).await.is_empty());
+
+            // a peer that takes fewer requests than we sent gets no more until it caught up
+            downloader.set_limit(2);
+            let block =
+                |b: &Block| Bytes::copy_from_slice(&data[b.index * 8 + b.begin..][..b.length]);
+            downloader
+                .received(peer, blocks[0].index, blocks[0].begin, block(&blocks[0]))
+                .await?;
+            assert!(downloader.next_blocks(&all).await.is_empty());
+
+            // requests that go unanswered for too long, the pieces go back to the others and the
+            // rest gets cancelled
+            let now = Instant::now();
+            assert!(downloader.snubbed(now).await.is_empty());
+            let cancelled = downloader.snubbed(now + SNUB_TIMEOUT).await;
+            assert_eq!(cancelled.len(), DEFAULT_DEPTH - 1);
+            assert!(downloader.is_snubbed());
+            assert_eq!(torrent.read().await.requesters(cancelled[0].index), 0);
+            assert!(downloader.snubbed(now + SNUB_TIMEOUT).await.is_empty());
+
+            // nothing more for a peer that snubbed us, until it unchokes us again
+            assert!(downloader.next_blocks(&all).await.is_empty());
+            downloader.unchoked();
+            let blocks = downloader.next_blocks(&all).await;
+            assert_eq!(blocks.len(), MIN_DEPTH);
+
+            Ok::<_, Report>(())
+        })?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fast() -> Result<(), Report> {
+        let root = TempDir::new();
+        let data: Vec<u8> = (0..32).collect();
+
+        let info = Info {
+            mode: Mode::Single {
+                name: "data".to_owned(),
+                length: 32,
+                md5sum: None,
+            },
+            piece_length: 8,
+            pieces: data.chunks(8).map(sha1).collect(),
+            ..Default::default()
+        };
+        let options = AddOptions {
+            root: Some(root.to_path_buf()),
+            block_size: Some(4),
+            ..Default::default()
+        };
+        let mut torrent = Torrent::new(
+            TorrentInfo {
+                info: Some(info),
+                ..Default::default()
+            },
+            options,
+        )?;
+        torrent.files_checked()?;
+        let torrent = Arc::new(RwLock::new(torrent));
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        rt.block_on(async {
+            let block_tx = mpsc::channel(4).0;
+            let mut downloader = Downloader::new(torrent.clone(), block_tx);
+            let all = |_| true;
+
+            // suggestions go first
+            downloader.allow(2);
+            downloader.suggest(3);
+            let blocks = downloader.next_blocks(&all).await;
+            assert_eq!(blocks.len(), 8);
+            assert_eq!(blocks[0].index, 3);
+
+            // choked, but the allowed fast piece is still on its way
+            downloader.choked().await;
+            assert_eq!(torrent.read().await.requesters(0), 0);
+            assert_eq!(torrent.read().await.requesters(2), 1);
+            assert!(downloader.next_allowed(&all).await.is_empty());
+
+            // the peer changed its mind, the rest of the piece gets cancelled
+            let rejected = Block {
+                index: 2,
+                begin: 0,
+                length: 4,
+            };
+            let cancelled = downloader.rejected(rejected).await;
+            assert_eq!(cancelled.len(), 1);
+            assert_eq!(cancelled[0].index, 2);
+            assert_eq!(torrent.read().await.requesters(2), 0);
+            assert!(downloader.next_allowed(&all).await.is_empty());
+            assert!(downloader.rejected(rejected).await.is_empty());
+
+            Ok::<_, Report>(())
+        })?;
+
+        Ok(())
+    }
+}
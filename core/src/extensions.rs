@@ -0,0 +1,837 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Cursor;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bendy::decoding::Decoder;
+use bendy::decoding::Error as DecodingError;
+use bendy::decoding::FromBencode;
+use bendy::decoding::Object;
+use bendy::encoding::AsString;
+use bendy::encoding::Error as EncodingError;
+use bendy::encoding::SingleItemEncoder;
+use bendy::encoding::ToBencode;
+use bytes::Bytes;
+use color_eyre::Report;
+use tracing::debug;
+
+use crate::data::GeneralError;
+use crate::framing::ParseCheck;
+use crate::framing::ParseError;
+use crate::helpers::{compact, compact_peers, range_to_array};
+use crate::pwp::{self, Request};
+
+pub type Handler = Box<dyn Fn(SocketAddr, Bytes) -> Result<(), Report> + Send + Sync>;
+
+// how often extensions get asked whether they have something to send on their own, BEP 11 wants
+// no more than one PEX message a minute
+pub const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+// an extension built on top of the engine, the registry hands out its id and routes its messages
+pub trait ExtensionHandler: Send + Sync {
+    // the key in the "m" dictionary of the handshake, ut_metadata for instance
+    fn name(&self) -> &str;
+
+    // keys that go into the top level of our handshake, like the metadata_size of ut_metadata
+    fn handshake(&self) -> Vec<(String, Value)> {
+        Vec::new()
+    }
+
+    // every handshake a peer sends, whether it knows about this extension or not
+    fn peer_handshake(&self, _peer: SocketAddr, _h: &Handshake) {}
+
+    // whatever gets returned is sent back to the peer under the same extension
+    fn message(&self, peer: SocketAddr, payload: Bytes) -> Result<Option<Bytes>, Report>;
+
+    // every peer we're connected to, whether it supports extensions or not
+    fn connected(&self, _peer: SocketAddr) {}
+
+    fn disconnected(&self, _peer: SocketAddr) {}
+
+    // asked every POLL_INTERVAL for peers that support the extension
+    fn poll(&self, _peer: SocketAddr) -> Option<Bytes> {
+        None
+    }
+}
+
+// lets whoever registered an extension keep a handle on it
+impl<E: ExtensionHandler> ExtensionHandler for Arc<E> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn handshake(&self) -> Vec<(String, Value)> {
+        (**self).handshake()
+    }
+
+    fn peer_handshake(&self, peer: SocketAddr, h: &Handshake) {
+        (**self).peer_handshake(peer, h)
+    }
+
+    fn message(&self, peer: SocketAddr, payload: Bytes) -> Result<Option<Bytes>, Report> {
+        (**self).message(peer, payload)
+    }
+
+    fn connected(&self, peer: SocketAddr) {
+        (**self).connected(peer)
+    }
+
+    fn disconnected(&self, peer: SocketAddr) {
+        (**self).disconnected(peer)
+    }
+
+    fn poll(&self, peer: SocketAddr) -> Option<Bytes> {
+        (**self).poll(peer)
+    }
+}
+
+// extensions that only ever listen
+struct Listener {
+    name: String,
+    handler: Handler,
+}
+
+impl ExtensionHandler for Listener {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn message(&self, peer: SocketAddr, payload: Bytes) -> Result<Option<Bytes>, Report> {
+        (self.handler)(peer, payload).map(|_| None)
+    }
+}
+
+// extended messages are addressed by ids the receiver picked, so we hand out our own ids to
+// whatever got registered and keep track of the ids every peer wants to be sent
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    // the id of an extension is its index + 1, 0 is the extension handshake
+    local: Vec<Box<dyn ExtensionHandler>>,
+    remote: Mutex<HashMap<SocketAddr, HashMap<String, u8>>>,
+    // how many requests every peer said it queues, if it did
+    reqq: Mutex<HashMap<SocketAddr, u8>>,
+    // the `v` every peer sent, the name and version of its client
+    clients: Mutex<HashMap<SocketAddr, String>>,
+}
+
+impl ExtensionRegistry {
+    pub fn register<F>(&mut self, name: &str, handler: F) -> u8
+    where
+        F: Fn(SocketAddr, Bytes) -> Result<(), Report> + Send + Sync + 'static,
+    {
+        self.register_handler(Listener {
+            name: name.to_owned(),
+            handler: Box::new(handler),
+        })
+    }
+
+    pub fn register_handler<E: ExtensionHandler + 'static>(&mut self, extension: E) -> u8 {
+        self.local.push(Box::new(extension));
+        self.local.len() as u8
+    }
+
+    pub fn local_id(&self, name: &str) -> Option<u8> {
+        self.local
+            .iter()
+            .position(|e| e.name() == name)
+            .map(|i| i as u8 + 1)
+    }
+
+    // the dictionary we open every connection with
+    pub fn handshake(&self) -> Handshake {
+        let inner = self
+            .local
+            .iter()
+            .zip(1u32..)
+            .map(|(e, id)| (e.name().to_owned(), id))
+            .collect();
+        let extra = self.local.iter().flat_map(|e| e.handshake()).collect();
+
+        Handshake {
+            inner,
+            extra,
+            ..Default::default()
+        }
+    }
+
+    // later handshakes only update what they mention, an id of 0 switches an extension off
+    pub fn peer_handshake(&self, peer: SocketAddr, h: &Handshake) {
+        {
+            let mut remote = self.remote.lock().unwrap();
+            let ids = remote.entry(peer).or_default();
+
+            for (name, &id) in &h.inner {
+                match u8::try_from(id) {
+                    Ok(0) | Err(_) => ids.remove(name),
+                    Ok(id) => ids.insert(name.clone(), id),
+                };
+            }
+        }
+        if let Some(reqq) = h.reqq {
+            self.reqq.lock().unwrap().insert(peer, reqq);
+        }
+        if let Some(client) = &h.client {
+            self.clients.lock().unwrap().insert(peer, client.clone());
+        }
+
+        self.local.iter().for_each(|e| e.peer_handshake(peer, h));
+    }
+
+    pub fn remote_id(&self, peer: SocketAddr, name: &str) -> Option<u8> {
+        let remote = self.remote.lock().unwrap();
+        remote.get(&peer)?.get(name).copied()
+    }
+
+    pub fn reqq(&self, peer: SocketAddr) -> Option<u8> {
+        self.reqq.lock().unwrap().get(&peer).copied()
+    }
+
+    pub fn client(&self, peer: SocketAddr) -> Option<String> {
+        self.clients.lock().unwrap().get(&peer).cloned()
+    }
+
+    // None if the peer never told us it understands this extension
+    pub fn encode(&self, peer: SocketAddr, name: &str, payload: Bytes) -> Option<pwp::Message> {
+        let id = self.remote_id(peer, name)?;
+        Some(pwp::Message::Extended { id, payload })
+    }
+
+    // returns the reply of the extension, if it has one for the peer
+    pub fn dispatch(
+        &self,
+        peer: SocketAddr,
+        id: u8,
+        payload: Bytes,
+    ) -> Result<Option<pwp::Message>, Report> {
+        if id == 0 {
+            let Some(Message::Handshake(h)) = Message::from_request(&payload) else {
+                return Err(GeneralError::MalformedPacket("extension handshake".to_owned()).into());
+            };
+            debug!("[{peer}] supports {:?}", h.inner.keys());
+
+            self.peer_handshake(peer, &h);
+            return Ok(None);
+        }
+
+        let extension = self
+            .local
+            .get(id as usize - 1)
+            .ok_or(GeneralError::UnknownExtension(id))?;
+
+        let reply = extension.message(peer, payload)?;
+        Ok(reply.and_then(|reply| self.encode(peer, extension.name(), reply)))
+    }
+
+    pub fn connected(&self, peer: SocketAddr) {
+        self.local.iter().for_each(|e| e.connected(peer));
+    }
+
+    pub fn disconnected(&self, peer: SocketAddr) {
+        self.remote.lock().unwrap().remove(&peer);
+        self.reqq.lock().unwrap().remove(&peer);
+        self.clients.lock().unwrap().remove(&peer);
+        self.local.iter().for_each(|e| e.disconnected(peer));
+    }
+
+    // what the extensions the peer supports want to send it on their own
+    pub fn poll(&self, peer: SocketAddr) -> Vec<pwp::Message> {
+        self.local
+            .iter()
+            .filter_map(|e| {
+                let id = self.remote_id(peer, e.name())?;
+                let payload = e.poll(peer)?;
+
+                Some(pwp::Message::Extended { id, payload })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Handshake(Handshake),
+    Extension(Extension),
+}
+
+impl Default for Message {
+    fn default() -> Self {
+        let h = Handshake {
+            inner: HashMap::new(),
+            port: None,
+            client: None,
+            ext_ip: None,
+            ipv4: None,
+            ipv6: None,
+            reqq: None,
+            extra: BTreeMap::new(),
+        };
+
+        Message::Handshake(h)
+    }
+}
+
+impl ParseCheck for Message {
+    fn check(v: &mut Cursor<&[u8]>) -> Result<(), ParseError> {
+        todo!()
+    }
+
+    fn parse(v: Bytes) -> Result<Self, ParseError>
+    where
+        Self: Sized,
+    {
+        todo!()
+    }
+}
+
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct Handshake {
+    pub inner: HashMap<String, u32>,
+    pub port: Option<u16>,
+    pub client: Option<String>,
+    pub ext_ip: Option<IpAddr>,
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+    pub reqq: Option<u8>,
+    // top level keys that belong to some extension rather than to the handshake itself
+    pub extra: BTreeMap<String, Value>,
+}
+
+// the kinds of values extensions put into the handshake
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+}
+
+#[derive(Default, Clone, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Extension {
+    #[default]
+    None,
+    Metadata {
+        msg_type: MsgType,
+        piece: u32,
+        total_size: Option<u32>,
+        payload: Option<Vec<u8>>,
+    } = 20,
+    // BEP 11, peers that connected and went away since the last message, IPv6 ones included
+    Pex {
+        added: Vec<SocketAddr>,
+        dropped: Vec<SocketAddr>,
+    },
+}
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MsgType {
+    Request = 0,
+    Data,
+    Reject,
+}
+
+impl ToBencode for Message {
+    const MAX_DEPTH: usize = 10;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), EncodingError> {
+        match self {
+            Message::Handshake(h) => {
+                encoder.emit_unsorted_dict(|e| {
+                    e.emit_pair(b"m", &h.inner)?;
+                    if let Some(port) = h.port {
+                        e.emit_pair(b"p", port)?;
+                    }
+                    if let Some(client) = &h.client {
+                        e.emit_pair(b"v", &client)?;
+                    }
+                    if let Some(IpAddr::V4(ext_ip)) = h.ext_ip {
+                        e.emit_pair(b"v", format!("{:?}", ext_ip))?;
+                    }
+                    if let Some(ipv4) = h.ipv4 {
+                        e.emit_pair(b"ipv4", format!("{:?}", ipv4))?;
+                    }
+                    if let Some(ipv6) = h.ipv6 {
+                        e.emit_pair(b"ipv6", format!("{:?}", ipv6))?;
+                    }
+                    if let Some(reqq) = h.reqq {
+                        e.emit_pair(b"reqq", reqq)?;
+                    }
+                    for (k, v) in &h.extra {
+                        match v {
+                            Value::Int(i) => e.emit_pair(k.as_bytes(), i)?,
+                            Value::Bytes(b) => e.emit_pair(k.as_bytes(), AsString(b))?,
+                        }
+                    }
+                    // e.emit_pair(b"metadate_size", 31235)
+
+                    Ok(())
+                })
+            }
+            Message::Extension(e) => match e {
+                Extension::Metadata {
+                    msg_type,
+                    total_size,
+                    piece,
+                    ..
+                } => encoder.emit_unsorted_dict(|e| {
+                    e.emit_pair(b"msg_type", *msg_type as u8)?;
+                    e.emit_pair(b"piece", piece)?;
+
+                    if let MsgType::Data = msg_type {
+                        e.emit_pair(b"total_size", total_size.unwrap())?;
+                    }
+
+                    Ok(())
+                }),
+                Extension::Pex { added, dropped } => encoder.emit_unsorted_dict(|e| {
+                    let pack = |peers: &[SocketAddr], v6: bool| -> Vec<u8> {
+                        peers
+                            .iter()
+                            .filter(|addr| addr.is_ipv6() == v6)
+                            .flat_map(compact)
+                            .collect()
+                    };
+
+                    // both lists are always there, the IPv6 ones only when they're not empty
+                    for (key, peers) in [(&b"added"[..], added), (b"dropped", dropped)] {
+                        e.emit_pair(key, AsString(pack(peers, false)))?;
+
+                        let v6 = pack(peers, true);
+                        if !v6.is_empty() {
+                            e.emit_pair(&[key, b"6"].concat(), AsString(v6))?;
+                        }
+                    }
+
+                    Ok(())
+                }),
+                Extension::None => Ok(()),
+            },
+        }
+    }
+}
+
+impl FromBencode for Message {
+    fn decode_bencode_object(object: Object) -> Result<Self, DecodingError>
+    where
+        Self: Sized,
+    {
+        let mut message = Message::default();
+
+        let mut dict = object.try_into_dictionary()?;
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"msg_type", _) => message = Message::Extension(Extension::default()),
+                (b"added" | b"added6" | b"dropped" | b"dropped6", _) => {
+                    message = Message::Extension(Extension::Pex {
+                        added: Vec::new(),
+                        dropped: Vec::new(),
+                    })
+                }
+                _ => {}
+            }
+        }
+
+        // Decoder -> Object -> DictDecoder
+        let mut dict = Decoder::new(dict.into_raw()?);
+        let dict = dict.next_object()?.unwrap();
+        let mut dict = dict.try_into_dictionary()?;
+
+        let message = match message {
+            Message::Handshake(mut h) => {
+                while let Some(pair) = dict.next_pair()? {
+                    match pair {
+                        (b"m", _) => {
+                            let mut dict = pair.1.try_into_dictionary()?;
+
+                            while let Some(pair) = dict.next_pair()? {
+                                let k = std::str::from_utf8(pair.0)?;
+                                let v = pair.1.try_into_integer()?;
+
+                                h.inner.insert(k.to_owned(), v.parse::<u32>()?);
+                            }
+                        }
+                        (b"p", _) => {
+                            let port = pair.1.try_into_integer()?;
+                            let port = port.parse::<u16>()?;
+
+                            h.port = Some(port);
+                        }
+                        (b"v", _) => {
+                            let client = std::str::from_utf8(pair.1.try_into_bytes()?)?;
+
+                            h.client = Some(client.to_owned());
+                        }
+                        (b"yourip", _) => {
+                            let ext_ip = pair.1.try_into_bytes()?;
+                            let ext_ip = match ext_ip.len() {
+                                4 => IpAddr::V4(Ipv4Addr::from(range_to_array(ext_ip))),
+                                16 => IpAddr::V6(Ipv6Addr::from(range_to_array(ext_ip))),
+                                _ => panic!(),
+                            };
+
+                            h.ext_ip = Some(ext_ip);
+                        }
+                        (b"ipv4", _) => {
+                            let ip = pair.1.try_into_bytes()?;
+                            let ip = Ipv4Addr::from(range_to_array(ip));
+
+                            h.ipv4 = Some(ip);
+                        }
+                        (b"ipv6", _) => {
+                            let ip = pair.1.try_into_bytes()?;
+                            let ip = Ipv6Addr::from(range_to_array(ip));
+
+                            h.ipv6 = Some(ip);
+                        }
+                        (b"reqq", _) => {
+                            let reqq = pair.1.try_into_integer()?;
+                            // plenty of clients say thousands, more than we'd ever send anyway
+                            let reqq = reqq.parse::<u64>()?.min(u8::MAX as u64) as u8;
+
+                            h.reqq = Some(reqq);
+                        }
+                        (k, Object::Integer(i)) => {
+                            let k = std::str::from_utf8(k)?;
+                            h.extra.insert(k.to_owned(), Value::Int(i.parse()?));
+                        }
+                        (k, Object::Bytes(b)) => {
+                            let k = std::str::from_utf8(k)?;
+                            h.extra.insert(k.to_owned(), Value::Bytes(b.to_vec()));
+                        }
+                        _ => {}
+                    }
+                }
+
+                Message::Handshake(h.clone())
+            }
+            Message::Extension(e) => {
+                let e = match e {
+                    none @ Extension::None => none,
+                    Extension::Metadata {
+                        mut msg_type,
+                        mut piece,
+                        mut total_size,
+                        ..
+                    } => {
+                        while let Some(pair) = dict.next_pair()? {
+                            match pair {
+                                (b"msg_type", _) => {
+                                    let i = pair.1.try_into_integer()?;
+                                    let i = i.parse::<u8>()?;
+                                    let i = match i {
+                                        0 => MsgType::Request,
+                                        1 => MsgType::Data,
+                                        2 => MsgType::Reject,
+                                        _ => panic!(),
+                                    };
+
+                                    msg_type = i;
+                                }
+                                (b"piece", _) => {
+                                    let i = pair.1.try_into_integer()?;
+
+                                    piece = i.parse::<u32>()?;
+                                }
+                                (b"total_size", _) => {
+                                    let i = pair.1.try_into_integer()?;
+
+                                    total_size = Some(i.parse::<u32>()?);
+                                }
+                                _ => {}
+                            }
+                        }
+
+                        Extension::Metadata {
+                            msg_type,
+                            piece,
+                            total_size,
+                            payload: None,
+                        }
+                    }
+                    Extension::Pex {
+                        mut added,
+                        mut dropped,
+                    } => {
+                        while let Some(pair) = dict.next_pair()? {
+                            let (peers, v6) = match pair.0 {
+                                b"added" => (&mut added, false),
+                                b"added6" => (&mut added, true),
+                                b"dropped" => (&mut dropped, false),
+                                b"dropped6" => (&mut dropped, true),
+                                _ => continue,
+                            };
+
+                            peers.extend(compact_peers(pair.1.try_into_bytes()?, v6));
+                        }
+
+                        Extension::Pex { added, dropped }
+                    }
+                };
+
+                Message::Extension(e.clone())
+            }
+        };
+
+        Ok(message)
+    }
+}
+
+impl Message {
+    // what goes into an extended message, the registry adds the id
+    pub fn payload(&self) -> Vec<u8> {
+        let v = self.to_bencode().unwrap();
+
+        match self {
+            // the piece goes right after the dictionary, not inside of it
+            Message::Extension(Extension::Metadata {
+                payload: Some(payload),
+                ..
+            }) => [v, payload.clone()].concat(),
+            _ => v,
+        }
+    }
+}
+
+impl Request for Message {
+    fn to_request(&self) -> Vec<u8> {
+        let len = |i: usize| (i as u32).to_be_bytes();
+
+        match self {
+            Message::Handshake(_) => {
+                let v = self.payload();
+                [len(2 + v.len()).as_slice(), &[20u8], &[0u8], &v].concat()
+            }
+            Message::Extension(e) => match e {
+                // the peer picks the id of these in its handshake, so they only ever go out as a
+                // payload the registry puts behind that id
+                Extension::None | Extension::Pex { .. } => {
+                    unreachable!("{e:?} is sent through the registry with the peer's extended id")
+                }
+                Extension::Metadata { msg_type, .. } => {
+                    let v = self.payload();
+                    [len(2 + v.len()).as_slice(), &[20u8], &[*msg_type as u8], &v].concat()
+                }
+            },
+        }
+    }
+
+    fn from_request(v: &[u8]) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        // the dictionary knows where it ends, whatever follows is raw metadata
+        let mut decoder = Decoder::new(v);
+        let header = decoder.next_object().ok()??.try_into_dictionary().ok()?;
+        let header = header.into_raw().ok()?;
+        let rest = &v[header.len()..];
+
+        let mut message = Message::from_bencode(header).ok()?;
+        if let Message::Extension(Extension::Metadata { payload, .. }) = &mut message {
+            *payload = (!rest.is_empty()).then(|| rest.to_vec());
+        }
+
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bencode_extension() {
+        let extensions = [
+            ("xv_metadata".to_owned(), 1u32),
+            ("xv_pex".to_owned(), 2u32),
+        ];
+        let h = Handshake {
+            inner: HashMap::from(extensions),
+            port: Some(6881),
+            client: Some("µTorrent 1.2".to_owned()),
+            ext_ip: None,
+            ipv4: None,
+            ipv6: None,
+            reqq: None,
+            extra: BTreeMap::new(),
+        };
+        let m = Message::Handshake(h);
+
+        let s = b"d1:md11:xv_metadatai1e6:xv_pexi2ee1:pi6881e1:v13:\xc2\xb5Torrent 1.2e";
+
+        assert_eq!(m.to_bencode().unwrap(), s);
+        assert_eq!(m, Message::from_bencode(s).unwrap());
+    }
+
+    #[test]
+    fn test_extension_registry() -> Result<(), Report> {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        let received = Arc::new(AtomicUsize::new(0));
+        let mut registry = ExtensionRegistry::default();
+
+        let counter = received.clone();
+        let metadata = registry.register("ut_metadata", move |_, payload| {
+            counter.fetch_add(payload.len(), Ordering::Relaxed);
+            Ok(())
+        });
+        let pex = registry.register("ut_pex", |_, _| Ok(()));
+
+        assert_eq!((metadata, pex), (1, 2));
+        assert_eq!(registry.local_id("ut_pex"), Some(2));
+        assert_eq!(registry.handshake().inner["ut_metadata"], 1);
+
+        // the peer picks its own ids
+        let peer: SocketAddr = "127.0.0.1:6881".parse()?;
+        let h = Handshake {
+            inner: HashMap::from([("ut_metadata".to_owned(), 3), ("ut_pex".to_owned(), 1)]),
+            ..Default::default()
+        };
+        registry.peer_handshake(peer, &h);
+        assert_eq!(registry.remote_id(peer, "ut_metadata"), Some(3));
+        assert_eq!(registry.reqq(peer), None);
+        assert!(matches!(
+            registry.encode(peer, "ut_metadata", Bytes::new()),
+            Some(pwp::Message::Extended { id: 3, .. })
+        ));
+
+        let h = Handshake {
+            inner: HashMap::from([("ut_pex".to_owned(), 0)]),
+            reqq: Some(250),
+            client: Some("everlasting 0.1".to_owned()),
+            ..Default::default()
+        };
+        registry.peer_handshake(peer, &h);
+        assert_eq!(registry.remote_id(peer, "ut_pex"), None);
+        assert_eq!(registry.remote_id(peer, "ut_metadata"), Some(3));
+        assert_eq!(registry.reqq(peer), Some(250));
+        assert_eq!(registry.client(peer).as_deref(), Some("everlasting 0.1"));
+
+        // incoming messages use our ids
+        registry.dispatch(peer, metadata, Bytes::from_static(b"abcd"))?;
+        assert_eq!(received.load(Ordering::Relaxed), 4);
+        assert!(registry.dispatch(peer, 7, Bytes::new()).is_err());
+
+        registry.disconnected(peer);
+        assert!(registry.encode(peer, "ut_metadata", Bytes::new()).is_none());
+        assert_eq!(registry.reqq(peer), None);
+        assert_eq!(registry.client(peer), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extension_handler() -> Result<(), Report> {
+        // answers every message with its reverse
+        struct Echo;
+
+        impl ExtensionHandler for Echo {
+            fn name(&self) -> &str {
+                "xv_echo"
+            }
+
+            fn handshake(&self) -> Vec<(String, Value)> {
+                vec![("echo_version".to_owned(), Value::Int(2))]
+            }
+
+            fn message(&self, _: SocketAddr, payload: Bytes) -> Result<Option<Bytes>, Report> {
+                Ok(Some(payload.iter().rev().copied().collect()))
+            }
+        }
+
+        let mut registry = ExtensionRegistry::default();
+        registry.register("ut_pex", |_, _| Ok(()));
+        let echo = registry.register_handler(Echo);
+        assert_eq!(echo, 2);
+
+        let h = registry.handshake();
+        assert_eq!(h.inner["xv_echo"], 2);
+        assert_eq!(h.extra["echo_version"], Value::Int(2));
+
+        let peer: SocketAddr = "127.0.0.1:6881".parse()?;
+        registry.peer_handshake(
+            peer,
+            &Handshake {
+                inner: HashMap::from([("xv_echo".to_owned(), 9)]),
+                ..Default::default()
+            },
+        );
+
+        let reply = registry.dispatch(peer, echo, Bytes::from_static(b"abc"))?;
+        assert!(matches!(
+            reply,
+            Some(pwp::Message::Extended { id: 9, payload }) if payload == b"cba"[..]
+        ));
+        assert!(registry.dispatch(peer, 1, Bytes::new())?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_message() {
+        let e = Extension::Metadata {
+            msg_type: MsgType::Request,
+            piece: 0,
+            total_size: None,
+            payload: None,
+        };
+        let m = Message::Extension(e);
+
+        let s = b"d8:msg_typei0e5:piecei0ee";
+
+        assert_eq!(m.to_bencode().unwrap(), s);
+        assert_eq!(m, Message::from_bencode(s).unwrap());
+    }
+    #[test]
+    fn test_data_message() {
+        let e = Extension::Metadata {
+            msg_type: MsgType::Data,
+            piece: 0,
+            total_size: Some(8),
+            payload: Some(b"xxxxxxxx".to_vec()),
+        };
+        let m = Message::Extension(e);
+
+        let s = b"d8:msg_typei1e5:piecei0e10:total_sizei8eexxxxxxxx";
+
+        // the payload is no part of the dictionary
+        assert_eq!(m.to_bencode().unwrap(), &s[..s.len() - 8]);
+        assert_eq!(&m.to_request()[6..], s);
+        assert_eq!(m.to_request()[..4], (2 + s.len() as u32).to_be_bytes());
+        assert_eq!(m, Message::from_request(s).unwrap());
+    }
+    #[test]
+    fn test_pex_message() {
+        let peer = |s: &str| s.parse::<SocketAddr>().unwrap();
+        let e = Extension::Pex {
+            added: vec![peer("10.0.0.1:6881"), peer("[::1]:6881")],
+            dropped: vec![peer("10.0.0.2:256")],
+        };
+        let m = Message::Extension(e);
+
+        let s = b"d5:added6:\x0a\x00\x00\x01\x1a\xe16:added618:\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe17:dropped6:\x0a\x00\x00\x02\x01\x00e";
+
+        assert_eq!(m.to_bencode().unwrap(), s);
+        assert_eq!(m, Message::from_bencode(s).unwrap());
+    }
+    #[test]
+    fn test_reject_message() {
+        let e = Extension::Metadata {
+            msg_type: MsgType::Reject,
+            piece: 0,
+            total_size: None,
+            payload: None,
+        };
+        let m = Message::Extension(e);
+
+        let s = b"d8:msg_typei2e5:piecei0ee";
+
+        assert_eq!(m.to_bencode().unwrap(), s);
+        assert_eq!(m, Message::from_bencode(s).unwrap());
+    }
+}
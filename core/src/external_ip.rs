@@ -0,0 +1,62 @@
+use std::{collections::HashMap, net::IpAddr, sync::Mutex};
+
+use tracing::debug;
+
+// trackers, DHT nodes and peers can all tell us how they see us, every source gets a single vote
+// so one misbehaving tracker can't decide on its own
+#[derive(Debug, Default)]
+pub struct ExternalIp {
+    votes: Mutex<HashMap<String, IpAddr>>,
+}
+
+impl ExternalIp {
+    pub fn vote(&self, source: &str, ip: IpAddr) {
+        let mut votes = self.votes.lock().unwrap();
+
+        if votes.insert(source.to_owned(), ip) != Some(ip) {
+            debug!("[{source}] reports our address as {ip}");
+        }
+    }
+
+    // the address most sources agree on, ties go to the lower address to stay deterministic
+    pub fn get(&self) -> Option<IpAddr> {
+        let votes = self.votes.lock().unwrap();
+        let mut count: HashMap<IpAddr, usize> = HashMap::new();
+
+        for ip in votes.values() {
+            *count.entry(*ip).or_default() += 1;
+        }
+
+        count
+            .into_iter()
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+            .map(|(ip, _)| ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_external_ip() -> Result<(), color_eyre::Report> {
+        let ip = ExternalIp::default();
+        assert_eq!(ip.get(), None);
+
+        let (a, b): (IpAddr, IpAddr) = ("203.0.113.7".parse()?, "198.51.100.1".parse()?);
+        ip.vote("http://a.example/announce", a);
+        ip.vote("http://b.example/announce", b);
+        ip.vote("http://c.example/announce", a);
+        assert_eq!(ip.get(), Some(a));
+
+        // repeating yourself doesn't count twice
+        ip.vote("http://b.example/announce", b);
+        ip.vote("http://b.example/announce", b);
+        assert_eq!(ip.get(), Some(a));
+
+        ip.vote("http://c.example/announce", b);
+        assert_eq!(ip.get(), Some(b));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use bendy::decoding::FromBencode;
+use color_eyre::Report;
+use tokio::sync::{Mutex, RwLock};
+use tracing::debug;
+
+use crate::{
+    config::Config,
+    data::{GeneralError, TorrentInfo},
+    manager::TorrentManager,
+    proxy,
+    torrent::{AddOptions, Torrent},
+};
+
+// .torrent files are small, anything larger is something else
+const MAX_TORRENT: usize = 10 << 20;
+// for the whole download, a slow server shouldn't keep the torrent from being added forever
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+// anything else is taken for a path or a magnet link
+pub fn is_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
+}
+
+// gets .torrent files from wherever a URL points, the enclosure of an RSS item or `add https://...`.
+// They're kept in the torrents directory under their info hash, the server may not have them the
+// next time
+pub struct Fetcher {
+    client: reqwest::Client,
+    dir: PathBuf,
+}
+
+impl Fetcher {
+    pub fn new(config: &Config) -> Result<Self, Report> {
+        let client = proxy::http_client(config.proxy.as_ref())?
+            .connect_timeout(config.tunables.tracker_connect_timeout)
+            .timeout(FETCH_TIMEOUT)
+            .build()?;
+
+        Ok(Self {
+            client,
+            dir: config.torrents_dir.clone(),
+        })
+    }
+
+    // the torrent, and where it was stored once it turned out to be one
+    pub async fn fetch(&self, url: &str) -> Result<(TorrentInfo, PathBuf), Report> {
+        let failed = |e: String| GeneralError::FetchFailed(format!("{url}: {e}"));
+
+        let mut response = self
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()
+            .map_err(|e| failed(e.to_string()))?;
+
+        let mut torrent = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            torrent.extend_from_slice(&chunk);
+            if torrent.len() > MAX_TORRENT {
+                return Err(failed(format!("larger than {MAX_TORRENT} bytes")).into());
+            }
+        }
+
+        let info = TorrentInfo::from_bencode(&torrent)
+            .map_err(|_| failed("not a torrent file".to_owned()))?;
+
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let path = self.dir.join(format!("{}.torrent", hex::encode(info.hash)));
+        tokio::fs::write(&path, &torrent).await?;
+        debug!(
+            "fetched [{}] into {}",
+            hex::encode(info.hash),
+            path.display()
+        );
+
+        Ok((info, path))
+    }
+
+    pub async fn add(
+        &self,
+        manager: &Mutex<TorrentManager>,
+        url: &str,
+        options: AddOptions,
+    ) -> Result<Arc<RwLock<Torrent>>, Report> {
+        let (info, _) = self.fetch(url).await?;
+        manager.lock().await.add(info, options).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{piece_manager::sha1, testing::TempDir};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    // answers every request with the body its path asks for
+    async fn serve(listener: TcpListener, torrent: Vec<u8>) -> Result<(), Report> {
+        loop {
+            let (mut stream, _) = listener.accept().await?;
+            let mut buf = vec![0; 1024];
+            let n = stream.read(&mut buf).await?;
+            let request = String::from_utf8_lossy(&buf[..n]);
+
+            let (status, body) = match request.split_whitespace().nth(1) {
+                Some("/good.torrent") => ("200 OK", torrent.clone()),
+                Some("/page.html") => ("200 OK", b"<html></html>".to_vec()),
+                Some("/huge.torrent") => ("200 OK", vec![b'd'; MAX_TORRENT + 1]),
+                _ => ("404 Not Found", Vec::new()),
+            };
+            let head = format!(
+                "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(head.as_bytes()).await?;
+            // the client hangs up on the huge one before it's all there
+            let _ = stream.write_all(&body).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch() -> Result<(), Report> {
+        let info = [
+            b"d6:lengthi4e4:name1:a12:piece lengthi4e6:pieces20:".as_slice(),
+            &[1u8; 20],
+            b"e",
+        ]
+        .concat();
+        let torrent = [b"d4:info".as_slice(), &info, b"e"].concat();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let base = format!("http://{}", listener.local_addr()?);
+        tokio::spawn(serve(listener, torrent.clone()));
+
+        let dir = TempDir::new();
+        let fetcher = Fetcher::new(&Config {
+            torrents_dir: dir.to_path_buf(),
+            ..Default::default()
+        })?;
+
+        let (fetched, path) = fetcher.fetch(&format!("{base}/good.torrent")).await?;
+        assert_eq!(fetched.hash, sha1(&info));
+        assert_eq!(
+            path,
+            dir.join(format!("{}.torrent", hex::encode(fetched.hash)))
+        );
+        assert_eq!(std::fs::read(&path)?, torrent);
+
+        // nothing gets stored that isn't a torrent
+        for path in ["page.html", "huge.torrent", "missing.torrent"] {
+            let Err(e) = fetcher.fetch(&format!("{base}/{path}")).await else {
+                panic!("fetched {path}");
+            };
+            assert!(matches!(
+                e.downcast_ref(),
+                Some(GeneralError::FetchFailed(_))
+            ));
+        }
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+
+        assert!(is_url("https://example.org/a.torrent"));
+        assert!(!is_url(
+            "magnet:?xt=urn:btih:0202020202020202020202020202020202020202"
+        ));
+        assert!(!is_url("./http.torrent"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+use std::{io::Cursor, marker::PhantomData};
+
+use bytes::{Bytes, BytesMut};
+use color_eyre::Report;
+use thiserror::Error;
+use tokio::io::AsyncReadExt;
+
+use crate::{data::GeneralError, transport::ReadHalf};
+
+// nothing legitimate comes close, the largest frames are bitfields of huge torrents
+pub const MAX_FRAME: usize = 1 << 20;
+
+pub struct FrameReader<T> {
+    pub inner: ReadHalf,
+    pub buffer: BytesMut,
+    pub max_frame: usize,
+    item: PhantomData<T>,
+}
+
+impl<T> FrameReader<T>
+where
+    T: ParseCheck,
+{
+    pub fn new(inner: ReadHalf, capacity: usize) -> Self {
+        Self {
+            inner,
+            buffer: BytesMut::with_capacity(capacity),
+            max_frame: MAX_FRAME,
+            item: PhantomData,
+        }
+    }
+
+    pub fn from(inner: ReadHalf, buffer: BytesMut) -> Self {
+        Self {
+            inner,
+            buffer,
+            max_frame: MAX_FRAME,
+            item: PhantomData,
+        }
+    }
+
+    pub fn max_frame(mut self, max_frame: usize) -> Self {
+        self.max_frame = max_frame;
+        self
+    }
+
+    pub async fn read_frame(&mut self) -> Result<Option<T>, Report> {
+        loop {
+            if let Some(res) = self.parse_frame()? {
+                return Ok(Some(res));
+            }
+
+            let n = self.inner.read_buf(&mut self.buffer).await?;
+
+            if 0 == n {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                } else {
+                    return Err(GeneralError::BrokenPipe.into());
+                }
+            }
+        }
+    }
+
+    fn parse_frame(&mut self) -> Result<Option<T>, Report> {
+        // bail before buffering anything a peer claims to be sending
+        if let Some(len) = T::frame_len(&self.buffer) {
+            if len > self.max_frame {
+                return Err(ParseError::TooLarge(len).into());
+            }
+        }
+
+        let mut buf = Cursor::new(&self.buffer[..]);
+
+        let check = T::check(&mut buf);
+
+        match check {
+            Ok(_) => {
+                let len = buf.position() as usize;
+                let frame = self.buffer.split_to(len).freeze();
+
+                Ok(Some(T::parse(frame)?))
+            }
+            Err(ParseError::Incomplete) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+pub trait ParseCheck {
+    // total size of the frame at the start of the buffer, for formats with a length prefix
+    fn frame_len(_v: &[u8]) -> Option<usize> {
+        None
+    }
+
+    fn check(v: &mut Cursor<&[u8]>) -> Result<(), ParseError>;
+    // gets handed exactly the bytes `check` accepted
+    fn parse(v: Bytes) -> Result<Self, ParseError>
+    where
+        Self: Sized;
+}
+
+#[derive(Error, Debug)]
+pub enum ParseError {
+    #[error("incomplete")]
+    Incomplete,
+    #[error("frame of {0} bytes exceeds the limit")]
+    TooLarge(usize),
+    #[error("invalid length {1} for message id {0}")]
+    InvalidLength(u8, usize),
+    #[error("malformed payload for message id {0}")]
+    Malformed(u8),
+}
@@ -0,0 +1,291 @@
+use std::{
+    ffi::OsStr,
+    ops::Range,
+    path::{Component, Path},
+    sync::Arc,
+    thread::{self, sleep},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use color_eyre::Report;
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    Request,
+};
+use libc::{EIO, ENOENT};
+use tokio::sync::RwLock;
+use tracing::debug;
+
+use crate::torrent::Torrent;
+
+const TTL: Duration = Duration::from_secs(1);
+const ROOT: u64 = 1;
+
+enum Kind {
+    Dir(Vec<u64>),
+    File {
+        torrent: usize,
+        index: usize,
+        length: u64,
+    },
+}
+
+struct Node {
+    parent: u64,
+    name: String,
+    kind: Kind,
+}
+
+// read-only view of the session's torrents, reads block until the requested range has been
+// downloaded and push it to the front of the picker in the meantime
+pub struct TorrentFs {
+    torrents: Vec<Arc<RwLock<Torrent>>>,
+    // inode n lives at index n - 1
+    nodes: Vec<Node>,
+}
+
+impl TorrentFs {
+    // has to be called outside of the runtime since it takes blocking locks
+    pub fn new(torrents: Vec<Arc<RwLock<Torrent>>>) -> Self {
+        let root = Node {
+            parent: ROOT,
+            name: String::new(),
+            kind: Kind::Dir(Vec::new()),
+        };
+        let mut fs = Self {
+            torrents: Vec::new(),
+            nodes: vec![root],
+        };
+
+        for (i, torrent) in torrents.iter().enumerate() {
+            let torrent = torrent.blocking_read();
+
+            // magnet links without metadata have no file layout yet
+            let Some(info) = torrent.info().info.as_ref() else {
+                debug!("skipping torrent without metadata");
+                continue;
+            };
+
+            for (index, (path, length)) in info.mode.files(Path::new("")).into_iter().enumerate() {
+                let components: Vec<_> = path
+                    .components()
+                    .filter_map(|c| match c {
+                        Component::Normal(s) => Some(s.to_string_lossy().into_owned()),
+                        _ => None,
+                    })
+                    .collect();
+
+                let Some((name, dirs)) = components.split_last() else {
+                    continue;
+                };
+
+                let parent = dirs.iter().fold(ROOT, |parent, dir| fs.dir(parent, dir));
+                let kind = Kind::File {
+                    torrent: i,
+                    index,
+                    length,
+                };
+                fs.insert(parent, name.to_owned(), kind);
+            }
+        }
+
+        fs.torrents = torrents;
+        fs
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get(ino.checked_sub(1)? as usize)
+    }
+
+    fn child(&self, parent: u64, name: &str) -> Option<u64> {
+        match &self.node(parent)?.kind {
+            Kind::Dir(children) => children
+                .iter()
+                .copied()
+                .find(|&ino| self.node(ino).map(|n| n.name == name).unwrap_or(false)),
+            Kind::File { .. } => None,
+        }
+    }
+
+    fn insert(&mut self, parent: u64, name: String, kind: Kind) -> u64 {
+        self.nodes.push(Node { parent, name, kind });
+        let ino = self.nodes.len() as u64;
+
+        if let Some(Kind::Dir(children)) =
+            self.nodes.get_mut(parent as usize - 1).map(|n| &mut n.kind)
+        {
+            children.push(ino);
+        }
+
+        ino
+    }
+
+    fn dir(&mut self, parent: u64, name: &str) -> u64 {
+        match self.child(parent, name) {
+            Some(ino) => ino,
+            None => self.insert(parent, name.to_owned(), Kind::Dir(Vec::new())),
+        }
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let (kind, size, perm, nlink) = match &self.node(ino)?.kind {
+            Kind::Dir(_) => (FileType::Directory, 0, 0o555, 2),
+            Kind::File { length, .. } => (FileType::RegularFile, *length, 0o444, 1),
+        };
+
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: (size + 511) / 512,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind,
+            perm,
+            nlink,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            rdev: 0,
+            blksize: 512,
+            flags: 0,
+        })
+    }
+}
+
+impl Filesystem for TorrentFs {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let ino = self.child(parent, &name.to_string_lossy());
+
+        match ino.and_then(|ino| self.attr(ino)) {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let Some(Node {
+            kind:
+                Kind::File {
+                    torrent,
+                    index,
+                    length,
+                },
+            ..
+        }) = self.node(ino)
+        else {
+            return reply.error(ENOENT);
+        };
+
+        let start = (offset as u64).min(*length);
+        let range = start..(start + size as u64).min(*length);
+        if range.is_empty() {
+            return reply.data(&[]);
+        }
+
+        // waiting for pieces can take a while, don't hold up the rest of the filesystem
+        let (torrent, index, length) = (self.torrents[*torrent].clone(), *index, *length);
+        thread::spawn(move || match read_range(&torrent, index, length, range) {
+            Ok(buf) => reply.data(&buf),
+            Err(e) => {
+                debug!("failed to read file {index}: {e}");
+                reply.error(EIO);
+            }
+        });
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(Node {
+            parent,
+            kind: Kind::Dir(children),
+            ..
+        }) = self.node(ino)
+        else {
+            return reply.error(ENOENT);
+        };
+
+        let entries = [
+            (ino, FileType::Directory, ".".to_owned()),
+            (*parent, FileType::Directory, "..".to_owned()),
+        ]
+        .into_iter()
+        .chain(children.iter().filter_map(|&child| {
+            let node = self.node(child)?;
+            let kind = match node.kind {
+                Kind::Dir(_) => FileType::Directory,
+                Kind::File { .. } => FileType::RegularFile,
+            };
+
+            Some((child, kind, node.name.clone()))
+        }));
+
+        for (i, (ino, kind, name)) in entries.enumerate().skip(offset as usize) {
+            // the reply buffer is full
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+}
+
+fn read_range(
+    torrent: &RwLock<Torrent>,
+    index: usize,
+    length: u64,
+    range: Range<u64>,
+) -> Result<Vec<u8>, Report> {
+    let mut boosted = false;
+
+    while !torrent.blocking_read().has_range(index, range.clone())? {
+        // readers tend to be sequential, so prioritize everything up to the end of the file
+        if !boosted {
+            torrent
+                .blocking_write()
+                .prioritize_range(index, range.start..length)?;
+            boosted = true;
+        }
+
+        sleep(Duration::from_millis(500));
+    }
+
+    // verified pieces may not have been written yet, so not straight from the file
+    torrent.blocking_read().read_range(index, range)
+}
+
+// blocks until the filesystem gets unmounted
+pub fn mount(torrents: Vec<Arc<RwLock<Torrent>>>, mountpoint: &Path) -> Result<(), Report> {
+    let fs = TorrentFs::new(torrents);
+    let options = [
+        MountOption::RO,
+        MountOption::FSName("everlasting".to_owned()),
+    ];
+
+    debug!("mounting torrents on {}", mountpoint.display());
+    fuser::mount2(fs, mountpoint, &options).map_err(Into::into)
+}
@@ -0,0 +1,357 @@
+use color_eyre::Report;
+use futures_util::Future;
+use rand::Rng;
+use std::{
+    fmt,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    str::FromStr,
+    thread::sleep,
+    time::{self, Duration},
+};
+use url::Url;
+
+use crate::data::GeneralError;
+
+pub struct Timer(time::Instant, time::Duration);
+
+impl Timer {
+    pub fn new(duration: time::Duration) -> Self {
+        Self(time::Instant::now(), duration)
+    }
+
+    pub fn reset(&mut self) {
+        self.0 = time::Instant::now();
+    }
+
+    pub fn elapsed(&self) -> bool {
+        self.0.elapsed() >= self.1
+    }
+}
+
+pub fn prettier(s: String) -> String {
+    s.chars()
+        .map(|c| {
+            if c == '(' || c == '{' || c == ',' {
+                c.to_string() + "\n"
+            } else if c == ')' || c == '}' {
+                "\n".to_string() + &c.to_string()
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
+pub fn range_to_array<const N: usize>(v: &[u8]) -> [u8; N] {
+    core::array::from_fn::<u8, N, _>(|i| v[i])
+}
+
+// peers the way trackers and PEX pack them, the address followed by the port, a trailing partial
+// one is ignored
+pub fn compact_peers(v: &[u8], ipv6: bool) -> Vec<SocketAddr> {
+    let len = if ipv6 { 18 } else { 6 };
+
+    v.chunks_exact(len)
+        .map(|c| {
+            let ip = match ipv6 {
+                true => IpAddr::V6(Ipv6Addr::from(range_to_array::<16>(c))),
+                false => IpAddr::V4(Ipv4Addr::from(range_to_array::<4>(c))),
+            };
+            SocketAddr::new(ip, u16::from_be_bytes(range_to_array(&c[len - 2..])))
+        })
+        .collect()
+}
+
+// dual-stack sockets see IPv4 peers as ::ffff:a.b.c.d, everything else knows them as a.b.c.d
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+// the other way around, an IPv6 socket can't send to an IPv4 address unless it's mapped
+pub fn mapped(local: &SocketAddr, addr: SocketAddr) -> SocketAddr {
+    match (local, addr.ip()) {
+        (SocketAddr::V6(_), IpAddr::V4(ip)) => {
+            SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port())
+        }
+        _ => addr,
+    }
+}
+
+pub fn compact(addr: &SocketAddr) -> Vec<u8> {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+
+    [ip, addr.port().to_be_bytes().to_vec()].concat()
+}
+
+// info hashes in magnet links are hex or, in older ones, base32
+pub fn decode_hash(s: &str) -> Option<[u8; 20]> {
+    match s.len() {
+        40 => hex::decode(s).ok()?.try_into().ok(),
+        32 => {
+            let mut out = Vec::with_capacity(20);
+            let (mut bits, mut n) = (0u64, 0);
+
+            for c in s.bytes() {
+                let v = match c.to_ascii_uppercase() {
+                    c @ b'A'..=b'Z' => c - b'A',
+                    c @ b'2'..=b'7' => c - b'2' + 26,
+                    _ => return None,
+                };
+                bits = bits << 5 | v as u64;
+                n += 5;
+
+                if n >= 8 {
+                    n -= 8;
+                    out.push((bits >> n) as u8);
+                }
+            }
+
+            out.try_into().ok()
+        }
+        _ => None,
+    }
+}
+
+// percent-encodes raw bytes, everything but the unreserved characters of RFC 3986 gets escaped
+pub fn encode(arr: &[u8]) -> String {
+    arr.iter()
+        .map(|&b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                char::from(b).to_string()
+            }
+            x => format!("%{x:02X}"),
+        })
+        .collect()
+}
+
+// query strings with binary values, url's own serializer only takes UTF-8
+#[derive(Debug, Default, Clone)]
+pub struct Query(Vec<String>);
+
+impl Query {
+    // keeps whatever the URL already carries, private trackers put passkeys in there
+    pub fn from_url(url: &Url) -> Self {
+        Self(url.query().into_iter().map(ToOwned::to_owned).collect())
+    }
+
+    pub fn bytes(mut self, key: &str, value: &[u8]) -> Self {
+        self.0
+            .push(format!("{}={}", encode(key.as_bytes()), encode(value)));
+        self
+    }
+
+    pub fn pair<T: ToString>(self, key: &str, value: T) -> Self {
+        self.bytes(key, value.to_string().as_bytes())
+    }
+
+    pub fn build(&self) -> String {
+        self.0.join("&")
+    }
+}
+
+// either a single port or `low-high` to pick a random one at startup, 0 leaves it to the OS
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PortRange {
+    pub low: u16,
+    pub high: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = GeneralError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |s: &str| {
+            s.trim()
+                .parse::<u16>()
+                .map_err(|_| GeneralError::InvalidPort(s.to_owned()))
+        };
+
+        let (low, high) = match s.split_once('-') {
+            Some((low, high)) => (parse(low)?, parse(high)?),
+            None => (parse(s)?, parse(s)?),
+        };
+
+        if low > high {
+            return Err(GeneralError::InvalidPort(s.to_owned()));
+        }
+
+        Ok(Self { low, high })
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.low == self.high {
+            true => write!(f, "{}", self.low),
+            false => write!(f, "{}-{}", self.low, self.high),
+        }
+    }
+}
+
+impl PortRange {
+    // whatever port we end up on is the one that has to be announced, so callers should read it
+    // back from the socket
+    pub fn bind(&self) -> Result<UdpSocket, Report> {
+        let attempts = match self.low == self.high {
+            true => 1,
+            false => 8,
+        };
+
+        for _ in 0..attempts {
+            let port = rand::thread_rng().gen_range(self.low..=self.high);
+
+            match bind_any(port) {
+                Ok(socket) => return Ok(socket),
+                Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Err(GeneralError::InvalidPort(self.to_string()).into())
+    }
+}
+
+// both families on one socket where the OS lets us, IPv4 alone on hosts without IPv6
+fn bind_any(port: u16) -> std::io::Result<UdpSocket> {
+    match UdpSocket::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))) {
+        Err(e) if e.kind() != ErrorKind::AddrInUse => {
+            UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+        }
+        res => res,
+    }
+}
+
+// address of the interface we'd send from, connecting a UDP socket only picks a route
+pub fn local_ip() -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((Ipv4Addr::new(192, 0, 2, 1), 9)).ok()?;
+
+    Some(socket.local_addr().ok()?.ip())
+}
+
+pub async fn attempt<T, F, C>(func: C, count: u8, interval: u8) -> Result<T, Report>
+where
+    C: Fn() -> F,
+    F: Future<Output = Result<T, Report>>,
+{
+    for _ in 0..count {
+        match func().await {
+            Ok(res) => return Ok(res),
+            Err(_) => {
+                sleep(Duration::from_secs(interval.into()));
+                continue;
+            }
+        }
+    }
+
+    Err(GeneralError::Timeout(None).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query() -> Result<(), color_eyre::Report> {
+        assert_eq!(encode(b"azAZ09-._~"), "azAZ09-._~");
+        assert_eq!(
+            encode(&[0x00, b' ', b'%', b'z' + 1, 0xff]),
+            "%00%20%25%7B%FF"
+        );
+
+        let url = Url::parse("http://tracker.example/announce?passkey=abc")?;
+        let query = Query::from_url(&url)
+            .bytes("info_hash", &[0x12, b'Z', 0xfe])
+            .pair("port", 6881u16);
+        assert_eq!(query.build(), "passkey=abc&info_hash=%12Z%FE&port=6881");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_hash() {
+        let hash = [
+            0xc1, 0x2f, 0xe1, 0xc0, 0x6b, 0xba, 0x25, 0x4a, 0x9d, 0xc9, 0xf5, 0x19, 0xb3, 0x35,
+            0xaa, 0x7c, 0x13, 0x67, 0xa8, 0x8a,
+        ];
+        assert_eq!(
+            decode_hash("c12fe1c06bba254a9dc9f519b335aa7c1367a88a"),
+            Some(hash)
+        );
+        assert_eq!(decode_hash("YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK"), Some(hash));
+        assert_eq!(decode_hash("yex6dqdlxisuvhoj6um3gnnkpqjwpkek"), Some(hash));
+
+        assert_eq!(decode_hash("c12fe1c06bba254a9dc9f519b335aa7c1367a88"), None);
+        assert_eq!(decode_hash("YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKE1"), None);
+        assert_eq!(
+            decode_hash("z12fe1c06bba254a9dc9f519b335aa7c1367a88a"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_dual_stack() -> Result<(), color_eyre::Report> {
+        let v4: SocketAddr = "10.0.0.1:6881".parse()?;
+        let mapped_v4: SocketAddr = "[::ffff:10.0.0.1]:6881".parse()?;
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse()?;
+
+        assert_eq!(canonical(mapped_v4), v4);
+        assert_eq!(canonical(v6), v6);
+
+        let (any4, any6): (SocketAddr, SocketAddr) = ("0.0.0.0:0".parse()?, "[::]:0".parse()?);
+        assert_eq!(mapped(&any6, v4), mapped_v4);
+        assert_eq!(mapped(&any6, v6), v6);
+        assert_eq!(mapped(&any4, v4), v4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_port_range() -> Result<(), color_eyre::Report> {
+        assert_eq!(
+            "6881".parse::<PortRange>()?,
+            PortRange {
+                low: 6881,
+                high: 6881
+            }
+        );
+        assert_eq!(
+            "49152-65535".parse::<PortRange>()?,
+            PortRange {
+                low: 49152,
+                high: 65535
+            }
+        );
+        assert!("6889-6881".parse::<PortRange>().is_err());
+        assert!("http".parse::<PortRange>().is_err());
+
+        let range: PortRange = "49152-65535".parse()?;
+        let port = range.bind()?.local_addr()?.port();
+        assert!((49152..=65535).contains(&port));
+
+        // the OS picks, the caller has to find out which
+        let socket = PortRange { low: 0, high: 0 }.bind()?;
+        assert_ne!(socket.local_addr()?.port(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_peers() -> Result<(), color_eyre::Report> {
+        let (v4, v6): (SocketAddr, SocketAddr) =
+            ("10.0.0.1:6881".parse()?, "[2001:db8::1]:51413".parse()?);
+
+        assert_eq!(compact(&v4), [10, 0, 0, 1, 0x1a, 0xe1]);
+        assert_eq!(
+            compact_peers(&[compact(&v4), vec![1, 2]].concat(), false),
+            [v4]
+        );
+        assert_eq!(compact_peers(&compact(&v6), true), [v6]);
+
+        Ok(())
+    }
+}
@@ -1,12 +1,21 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use bendy::{
     decoding::{self, Decoder, FromBencode, Object},
     encoding::{self, AsString, Encoder, SingleItemEncoder, ToBencode},
 };
+use color_eyre::Report;
 use rand::Rng;
+use tokio::{net::UdpSocket, sync::oneshot, time::timeout};
 
-use crate::dht::Node;
+use crate::data::GeneralError;
+use crate::dht::{AnnouncePort, Node};
+use crate::helpers;
 
 pub type NodeContact = (Node, SocketAddr);
 
@@ -17,19 +26,54 @@ pub enum Message {
     Err(Error),
 }
 
+// two characters naming the client and two version bytes, BEP 20 style
+pub const CLIENT_VERSION: [u8; 4] = [b'X', b'V', 0, 1];
+
 #[derive(Debug, PartialEq)]
 pub struct ExtMessage {
     pub inner: Message,
-    pub transaction_id: String,
+    // whatever bytes the querying node picked, they have to come back unchanged
+    pub transaction_id: Vec<u8>,
+    // the "v" key, not every implementation sends one
+    pub version: Option<Vec<u8>>,
 }
 
 impl From<Message> for ExtMessage {
     fn from(inner: Message) -> ExtMessage {
-        let transaction_id = rand::thread_rng().gen::<[char; 2]>().iter().collect();
+        let transaction_id = rand::thread_rng().gen::<[u8; 2]>().to_vec();
         ExtMessage {
             inner,
             transaction_id,
+            version: Some(CLIENT_VERSION.to_vec()),
+        }
+    }
+}
+
+impl ExtMessage {
+    pub fn reply(&self, inner: Message) -> ExtMessage {
+        ExtMessage {
+            transaction_id: self.transaction_id.clone(),
+            ..inner.into()
+        }
+    }
+
+    // id of the node that sent this, errors don't carry one
+    pub fn sender(&self) -> Option<[u8; 20]> {
+        match &self.inner {
+            Message::Query(args) => Some(args.id),
+            Message::Response(values) => Some(values.id),
+            Message::Err(_) => None,
+        }
+    }
+}
+
+// "UT 3.5" for the usual layout, hex for anything we can't make sense of
+pub fn client_name(v: &[u8]) -> String {
+    match v {
+        [a, b, major, minor] if a.is_ascii_alphanumeric() && b.is_ascii_alphanumeric() => {
+            format!("{}{} {major}.{minor}", *a as char, *b as char)
         }
+        v => hex::encode(v),
     }
 }
 
@@ -57,12 +101,12 @@ impl From<i64> for ErrorKind {
             x if x == Server as i64 => Server,
             x if x == Protocol as i64 => Protocol,
             x if x == MethodUnknown as i64 => MethodUnknown,
-            _ => panic!(),
+            _ => Generic,
         }
     }
 }
 
-#[derive(Default, Debug, PartialEq)]
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
 pub enum Method {
     #[default]
     Ping,
@@ -103,39 +147,50 @@ pub struct Arguments {
     pub token: Option<String>,
 }
 
+impl Arguments {
+    // the port still has to be there when it's implied, older nodes ignore the flag
+    pub fn announce_peer(
+        id: [u8; 20],
+        info_hash: [u8; 20],
+        token: String,
+        port: AnnouncePort,
+    ) -> Self {
+        Self {
+            method: Method::AnnouncePeer,
+            id,
+            info_hash: Some(info_hash),
+            port: Some(port.port),
+            implied_port: Some(port.implied),
+            token: Some(token),
+            ..Default::default()
+        }
+    }
+}
+
+const IPV4_NODE: usize = 26;
+const IPV6_NODE: usize = 38;
+
 #[derive(Debug, PartialEq)]
 pub struct CompactNode {
     pub id: [u8; 20],
     pub ip: SocketAddr,
 }
 
+// <20:id><4:ipv4><2:port> in "nodes", <20:id><16:ipv6><2:port> in "nodes6"
 impl From<Vec<u8>> for CompactNode {
     fn from(v: Vec<u8>) -> Self {
-        assert!(v.len() == 26);
+        assert!(v.len() == IPV4_NODE || v.len() == IPV6_NODE);
 
-        let ip = SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::new(v[20], v[21], v[22], v[23])),
-            u16::from_be_bytes(v[24..26].try_into().unwrap()),
-        );
         CompactNode {
             id: v[..20].try_into().unwrap(),
-            ip,
+            ip: helpers::compact_peers(&v[20..], v.len() == IPV6_NODE)[0],
         }
     }
 }
 
 impl From<&CompactNode> for Vec<u8> {
     fn from(v: &CompactNode) -> Self {
-        match v.ip.ip() {
-            IpAddr::V4(ip) => [
-                v.id.as_slice(),
-                ip.octets().as_slice(),
-                &v.ip.port().to_be_bytes(),
-            ]
-            .concat()
-            .to_vec(),
-            IpAddr::V6(_) => todo!(),
-        }
+        [v.id.as_slice(), &helpers::compact(&v.ip)].concat()
     }
 }
 
@@ -143,7 +198,7 @@ impl From<&CompactNode> for Vec<u8> {
 pub struct Values {
     pub id: [u8; 20],
     pub nodes: Option<Vec<CompactNode>>,
-    pub values: Option<Vec<CompactNode>>,
+    pub values: Option<Vec<SocketAddr>>,
     pub token: Option<String>,
 }
 
@@ -156,20 +211,22 @@ impl FromBencode for ExtMessage {
 
         let mut message: ExtMessage = Message::Err(Error::default()).into();
         let mut method: Method = Default::default();
-        let mut transaction_id: String = Default::default();
+        let mut transaction_id: Vec<u8> = Default::default();
+        let mut version: Option<Vec<u8>> = None;
         let mut payload: Option<Vec<u8>> = None;
 
         while let Some(pair) = dict.next_pair()? {
             match pair {
                 (b"t", _) => {
-                    transaction_id = String::decode_bencode_object(pair.1)?;
+                    let AsString(t) = AsString::decode_bencode_object(pair.1)?;
+                    transaction_id = t;
                 }
                 (b"y", _) => {
                     message = match String::decode_bencode_object(pair.1)?.as_str() {
                         "q" => Message::Query(Arguments::default()).into(),
                         "r" => Message::Response(Values::default()).into(),
                         "e" => Message::Err(Error::default()).into(),
-                        _ => panic!(),
+                        y => return Err(decoding::Error::unexpected_token("q, r or e", y)),
                     };
                 }
                 (b"q", _) => {
@@ -178,7 +235,7 @@ impl FromBencode for ExtMessage {
                         "find_node" => Method::FindNode,
                         "get_peers" => Method::GetPeers,
                         "announce_peer" => Method::AnnouncePeer,
-                        _ => panic!(),
+                        q => return Err(decoding::Error::unexpected_token("a KRPC method", q)),
                     };
                 }
                 (b"a", _) | (b"r", _) => {
@@ -187,11 +244,16 @@ impl FromBencode for ExtMessage {
                 (b"e", _) => {
                     payload = Some(pair.1.try_into_list()?.into_raw()?.to_vec());
                 }
-                _ => panic!(),
+                (b"v", _) => {
+                    let AsString(v) = AsString::decode_bencode_object(pair.1)?;
+                    version = Some(v);
+                }
+                // "ip" and "ro" among others, none of which we act on yet
+                _ => {}
             };
         }
 
-        let payload = payload.unwrap();
+        let payload = payload.ok_or_else(|| decoding::Error::missing_field("a, r or e"))?;
         let mut dict = Decoder::new(payload.as_slice());
         let dict = dict.next_object()?.unwrap();
 
@@ -204,16 +266,16 @@ impl FromBencode for ExtMessage {
                 while let Some(pair) = dict.next_pair()? {
                     match pair {
                         (b"id", _) => {
-                            let s = String::decode_bencode_object(pair.1)?;
-                            arguments.id = s.as_bytes().try_into()?;
+                            let AsString(s) = AsString::decode_bencode_object(pair.1)?;
+                            arguments.id = s.as_slice().try_into()?;
                         }
                         (b"target", _) => {
-                            let s = String::decode_bencode_object(pair.1)?;
-                            arguments.target = Some(s.as_bytes().try_into()?);
+                            let AsString(s) = AsString::decode_bencode_object(pair.1)?;
+                            arguments.target = Some(s.as_slice().try_into()?);
                         }
                         (b"info_hash", _) => {
-                            let s = String::decode_bencode_object(pair.1)?;
-                            arguments.info_hash = Some(s.as_bytes().try_into()?);
+                            let AsString(s) = AsString::decode_bencode_object(pair.1)?;
+                            arguments.info_hash = Some(s.as_slice().try_into()?);
                         }
                         (b"implied_port", _) => {
                             let i = u64::decode_bencode_object(pair.1)?;
@@ -227,13 +289,15 @@ impl FromBencode for ExtMessage {
                             let s = String::decode_bencode_object(pair.1)?;
                             arguments.token = Some(s);
                         }
-                        _ => panic!(),
+                        // "want", "noseed" and friends from later BEPs
+                        _ => {}
                     }
                 }
 
                 Ok(ExtMessage {
                     inner: Message::Query(arguments),
                     transaction_id,
+                    version,
                 })
             }
             Message::Response(mut values) => {
@@ -242,27 +306,31 @@ impl FromBencode for ExtMessage {
                 while let Some(pair) = dict.next_pair()? {
                     match pair {
                         (b"id", _) => {
-                            let s = String::decode_bencode_object(pair.1)?;
-                            values.id = s.as_bytes().try_into()?;
+                            let AsString(s) = AsString::decode_bencode_object(pair.1)?;
+                            values.id = s.as_slice().try_into()?;
                         }
-                        (b"nodes", _) => {
-                            let s = String::decode_bencode_object(pair.1)?;
-                            let v = s.as_bytes();
-
-                            let nodes = v
-                                .chunks_exact(26)
-                                .map(|x| x.to_vec().into())
-                                .collect::<Vec<_>>();
-
-                            values.nodes = Some(nodes);
+                        // both families end up in the same list, BEP 32
+                        (b"nodes" | b"nodes6", _) => {
+                            let len = match pair.0 {
+                                b"nodes" => IPV4_NODE,
+                                _ => IPV6_NODE,
+                            };
+                            let AsString(v) = AsString::decode_bencode_object(pair.1)?;
+
+                            let nodes = v.chunks_exact(len).map(|x| x.to_vec().into());
+                            values.nodes.get_or_insert_with(Vec::new).extend(nodes);
                         }
+                        // peers are <4:ipv4><2:port> or <16:ipv6><2:port>, anything else is
+                        // skipped
                         (b"values", _) => {
                             let mut list = pair.1.try_into_list()?;
-                            let mut res: Vec<CompactNode> = Vec::new();
+                            let mut res: Vec<SocketAddr> = Vec::new();
 
                             while let Some(v) = list.next_object()? {
-                                let s = String::decode_bencode_object(v)?;
-                                res.push(s.as_bytes().to_vec().into());
+                                let AsString(v) = AsString::decode_bencode_object(v)?;
+                                if v.len() == 6 || v.len() == 18 {
+                                    res.extend(helpers::compact_peers(&v, v.len() == 18));
+                                }
                             }
 
                             values.values = Some(res);
@@ -279,20 +347,26 @@ impl FromBencode for ExtMessage {
                 Ok(ExtMessage {
                     inner: Message::Response(values),
                     transaction_id,
+                    version,
                 })
             }
             Message::Err(mut e) => {
                 let mut list = dict.try_into_list()?;
 
-                let code = list.next_object()?.unwrap();
-                e.kind = ErrorKind::try_from(i64::decode_bencode_object(code)?).unwrap();
+                let code = list
+                    .next_object()?
+                    .ok_or_else(|| decoding::Error::missing_field("error code"))?;
+                e.kind = ErrorKind::from(i64::decode_bencode_object(code)?);
 
-                let description = list.next_object()?.unwrap();
+                let description = list
+                    .next_object()?
+                    .ok_or_else(|| decoding::Error::missing_field("error message"))?;
                 e.description = String::decode_bencode_object(description)?;
 
                 Ok(ExtMessage {
                     inner: Message::Err(e),
                     transaction_id,
+                    version,
                 })
             }
         }
@@ -307,7 +381,11 @@ impl ToBencode for ExtMessage {
 
         let message = <Vec<u8>>::try_from(self).unwrap();
         tokens.push((b"y", message.clone()));
-        tokens.push((b"t", self.transaction_id.clone().into()));
+        tokens.push((b"t", self.transaction_id.clone()));
+
+        if let Some(version) = &self.version {
+            tokens.push((b"v", version.clone()));
+        }
 
         if let Message::Query(args) = &self.inner {
             let method = <Vec<u8>>::from(&args.method);
@@ -399,20 +477,17 @@ impl ToBencode for Values {
         tokens.push((b"id", self.id.to_vec()));
 
         if let Some(nodes) = &self.nodes {
-            let v: Vec<_> = nodes
-                .iter()
-                .flat_map(|n| <Vec<u8>>::try_from(n).unwrap())
-                .collect();
-
-            tokens.push((b"nodes", v));
-        }
+            let (v6, v4): (Vec<_>, Vec<_>) = nodes.iter().partition(|n| n.ip.is_ipv6());
+            let compact = |nodes: Vec<&CompactNode>| -> Vec<u8> {
+                nodes.into_iter().flat_map(<Vec<u8>>::from).collect()
+            };
 
-        if let Some(values) = &self.values {
-            let v = values
-                .iter()
-                .flat_map(|v| <Vec<u8>>::try_from(v).unwrap())
-                .collect();
-            tokens.push((b"values", v));
+            if !v4.is_empty() || v6.is_empty() {
+                tokens.push((b"nodes", compact(v4)));
+            }
+            if !v6.is_empty() {
+                tokens.push((b"nodes6", compact(v6)));
+            }
         }
 
         if let Some(token) = &self.token {
@@ -421,15 +496,15 @@ impl ToBencode for Values {
 
         encoder.emit_unsorted_dict(|e| {
             for (k, v) in tokens {
-                match k {
-                    b"values" => {
-                        e.emit_pair(k, v.chunks_exact(26).map(AsString).collect::<Vec<_>>())?;
-                    }
-                    _ => {
-                        let v = AsString(v);
-                        e.emit_pair(k, v)?;
-                    }
-                }
+                e.emit_pair(k, AsString(v))?;
+            }
+
+            if let Some(values) = &self.values {
+                let values: Vec<_> = values
+                    .iter()
+                    .map(|v| AsString(helpers::compact(v)))
+                    .collect();
+                e.emit_pair(b"values", values)?;
             }
 
             Ok(())
@@ -448,6 +523,138 @@ impl ToBencode for Error {
     }
 }
 
+// how long a node gets to answer any of our queries, and how often it's asked again after that
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+pub const QUERY_RETRIES: u8 = 1;
+
+// what a node answered to one of our queries
+#[derive(Debug)]
+pub struct Answer {
+    pub values: Values,
+    pub version: Option<Vec<u8>>,
+}
+
+struct Transaction {
+    addr: SocketAddr,
+    reply: oneshot::Sender<ExtMessage>,
+}
+
+// sends our queries and hands every answer to the query it belongs to, by transaction id and the
+// node it went to. Cheap to clone, every query is a future of its own
+#[derive(Clone)]
+pub struct KrpcClient {
+    socket: Arc<UdpSocket>,
+    transactions: Arc<Mutex<HashMap<Vec<u8>, Transaction>>>,
+    timeout: Duration,
+    retries: u8,
+}
+
+// forgets the transaction however the query ends, dropped futures included
+struct Outstanding<'a> {
+    client: &'a KrpcClient,
+    transaction_id: Vec<u8>,
+}
+
+impl Drop for Outstanding<'_> {
+    fn drop(&mut self) {
+        self.client.transactions().remove(&self.transaction_id);
+    }
+}
+
+impl KrpcClient {
+    pub fn new(socket: Arc<UdpSocket>) -> Self {
+        Self {
+            socket,
+            transactions: Default::default(),
+            timeout: QUERY_TIMEOUT,
+            retries: QUERY_RETRIES,
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_retries(&mut self, retries: u8) {
+        self.retries = retries;
+    }
+
+    fn transactions(&self) -> std::sync::MutexGuard<'_, HashMap<Vec<u8>, Transaction>> {
+        self.transactions.lock().unwrap()
+    }
+
+    // a node that still owes us an answer
+    pub fn outstanding(&self, addr: SocketAddr) -> bool {
+        self.transactions().values().any(|t| t.addr == addr)
+    }
+
+    // asked again after every timeout with the same transaction id, an answer to the first try
+    // that shows up late still counts. Errors the node sends back fail the query
+    pub async fn query(&self, addr: SocketAddr, args: Arguments) -> Result<Answer, Report> {
+        let (tx, mut rx) = oneshot::channel();
+        let mut message: ExtMessage = Message::Query(args).into();
+        {
+            let mut transactions = self.transactions();
+            while transactions.contains_key(&message.transaction_id) {
+                message.transaction_id = rand::thread_rng().gen::<[u8; 2]>().to_vec();
+            }
+            let transaction = Transaction { addr, reply: tx };
+            transactions.insert(message.transaction_id.clone(), transaction);
+        }
+        let _outstanding = Outstanding {
+            client: self,
+            transaction_id: message.transaction_id.clone(),
+        };
+
+        for _ in 0..=self.retries {
+            self.send(&message, addr).await?;
+
+            let answer = match timeout(self.timeout, &mut rx).await {
+                Ok(answer) => answer.map_err(|_| GeneralError::BrokenPipe)?,
+                Err(_) => continue,
+            };
+            return match answer.inner {
+                Message::Response(values) => Ok(Answer {
+                    values,
+                    version: answer.version,
+                }),
+                Message::Err(e) => Err(GeneralError::UnexpectedResponse(e.description).into()),
+                Message::Query(_) => Err(GeneralError::UnexpectedResponse("query".into()).into()),
+            };
+        }
+
+        Err(GeneralError::Timeout(Some(addr)).into())
+    }
+
+    // false unless the message answers one of our queries
+    pub fn received(&self, message: ExtMessage, addr: SocketAddr) -> bool {
+        if let Message::Query(_) = message.inner {
+            return false;
+        }
+
+        let mut transactions = self.transactions();
+        match transactions.get(&message.transaction_id) {
+            Some(transaction) if transaction.addr == addr => {}
+            // late, or meant for somebody else
+            _ => return false,
+        }
+        let transaction = transactions.remove(&message.transaction_id).unwrap();
+        let _ = transaction.reply.send(message);
+
+        true
+    }
+
+    pub async fn send(&self, message: &ExtMessage, addr: SocketAddr) -> Result<(), Report> {
+        let bytes = message
+            .to_bencode()
+            .map_err(|e| GeneralError::MalformedPacket(e.to_string()))?;
+        let addr = helpers::mapped(&self.socket.local_addr()?, addr);
+        self.socket.send_to(&bytes, addr).await?;
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     fn compact_node_gen() -> String {
@@ -487,7 +694,8 @@ mod tests {
 
         let real = ExtMessage {
             inner,
-            transaction_id: "aa".to_owned(),
+            transaction_id: b"aa".to_vec(),
+            version: None,
         };
 
         let decoded = real.to_bencode().unwrap();
@@ -513,7 +721,8 @@ mod tests {
 
         let real = ExtMessage {
             inner,
-            transaction_id: "aa".to_owned(),
+            transaction_id: b"aa".to_vec(),
+            version: None,
         };
 
         let decoded = real.to_bencode().unwrap();
@@ -539,7 +748,8 @@ mod tests {
 
         let real = ExtMessage {
             inner,
-            transaction_id: "aa".to_owned(),
+            transaction_id: b"aa".to_vec(),
+            version: None,
         };
 
         let decoded = real.to_bencode().unwrap();
@@ -566,7 +776,8 @@ mod tests {
 
         let real = ExtMessage {
             inner,
-            transaction_id: "aa".to_owned(),
+            transaction_id: b"aa".to_vec(),
+            version: None,
         };
 
         let decoded = real.to_bencode().unwrap();
@@ -590,7 +801,8 @@ mod tests {
 
         let real = ExtMessage {
             inner,
-            transaction_id: "aa".to_owned(),
+            transaction_id: b"aa".to_vec(),
+            version: None,
         };
 
         let decoded = real.to_bencode().unwrap();
@@ -622,7 +834,8 @@ mod tests {
 
         let real = ExtMessage {
             inner,
-            transaction_id: "aa".to_owned(),
+            transaction_id: b"aa".to_vec(),
+            version: None,
         };
 
         let decoded = real.to_bencode().unwrap();
@@ -658,7 +871,8 @@ mod tests {
 
         let real = ExtMessage {
             inner,
-            transaction_id: "aa".to_owned(),
+            transaction_id: b"aa".to_vec(),
+            version: None,
         };
 
         let decoded = real.to_bencode().unwrap();
@@ -669,19 +883,14 @@ mod tests {
 
     #[test]
     fn test_response_get_peers() {
-        let values = (0..2).map(|_| compact_node_gen()).collect::<Vec<_>>();
+        let v = b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:\x0a\x00\x00\x01\x1a\xe16:\xc0\x00\x02\x07\xc8\xd5ee1:t2:aa1:y1:re";
 
-        let v = format!(
-            "d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl26:{}26:{}ee1:t2:aa1:y1:re",
-            values[0], values[1],
-        );
-
-        let bencoded = ExtMessage::from_bencode(v.as_bytes()).unwrap();
+        let bencoded = ExtMessage::from_bencode(v).unwrap();
 
-        let values: Vec<_> = values
-            .iter()
-            .map(|v| CompactNode::try_from(v.as_bytes().to_vec()).unwrap())
-            .collect();
+        let values = vec![
+            "10.0.0.1:6881".parse().unwrap(),
+            "192.0.2.7:51413".parse().unwrap(),
+        ];
 
         let inner = Message::Response(Values {
             id: "abcdefghij0123456789".as_bytes().try_into().unwrap(),
@@ -692,13 +901,14 @@ mod tests {
 
         let real = ExtMessage {
             inner,
-            transaction_id: "aa".to_owned(),
+            transaction_id: b"aa".to_vec(),
+            version: None,
         };
 
         let decoded = real.to_bencode().unwrap();
 
         assert_eq!(bencoded, real);
-        assert_eq!(v.as_bytes(), decoded);
+        assert_eq!(v.as_slice(), decoded);
     }
 
     #[test]
@@ -728,7 +938,8 @@ mod tests {
 
         let real = ExtMessage {
             inner,
-            transaction_id: "aa".to_owned(),
+            transaction_id: b"aa".to_vec(),
+            version: None,
         };
 
         let decoded = real.to_bencode().unwrap();
@@ -737,6 +948,37 @@ mod tests {
         assert_eq!(v.as_bytes(), decoded);
     }
 
+    #[test]
+    fn test_response_ipv6() {
+        let v = b"d1:rd2:id20:abcdefghij01234567896:nodes638:mnopqrstuvwxyz123456\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe16:valuesl18:\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\xc8\xd56:\x0a\x00\x00\x01\x1a\xe1ee1:t2:aa1:y1:re";
+
+        let bencoded = ExtMessage::from_bencode(v).unwrap();
+
+        let inner = Message::Response(Values {
+            id: "abcdefghij0123456789".as_bytes().try_into().unwrap(),
+            nodes: Some(vec![CompactNode {
+                id: "mnopqrstuvwxyz123456".as_bytes().try_into().unwrap(),
+                ip: "[2001:db8::1]:6881".parse().unwrap(),
+            }]),
+            values: Some(vec![
+                "[2001:db8::2]:51413".parse().unwrap(),
+                "10.0.0.1:6881".parse().unwrap(),
+            ]),
+            token: None,
+        });
+
+        let real = ExtMessage {
+            inner,
+            transaction_id: b"aa".to_vec(),
+            version: None,
+        };
+
+        let decoded = real.to_bencode().unwrap();
+
+        assert_eq!(bencoded, real);
+        assert_eq!(v.as_slice(), decoded);
+    }
+
     #[test]
     fn test_response_announce_peer() {
         let v = b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
@@ -752,7 +994,8 @@ mod tests {
 
         let real = ExtMessage {
             inner,
-            transaction_id: "aa".to_owned(),
+            transaction_id: b"aa".to_vec(),
+            version: None,
         };
 
         let decoded = real.to_bencode().unwrap();
@@ -773,7 +1016,8 @@ mod tests {
 
         let real = ExtMessage {
             inner,
-            transaction_id: "aa".to_owned(),
+            transaction_id: b"aa".to_vec(),
+            version: None,
         };
 
         let decoded = real.to_bencode().unwrap();
@@ -781,4 +1025,82 @@ mod tests {
         assert_eq!(bencoded, real);
         assert_eq!(v.as_slice(), decoded);
     }
+
+    #[test]
+    fn test_version() {
+        let v = b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:v4:UT\x03\x051:y1:re";
+        let bencoded = ExtMessage::from_bencode(v).unwrap();
+
+        assert_eq!(bencoded.version.as_deref(), Some(b"UT\x03\x05".as_slice()));
+        assert_eq!(bencoded.to_bencode().unwrap(), v.as_slice());
+        assert_eq!(client_name(b"UT\x03\x05"), "UT 3.5");
+        assert_eq!(client_name(b"\xff\x00"), "ff00");
+
+        let ours = ExtMessage::from(Message::Response(Values::default()));
+        assert_eq!(ours.version, Some(CLIENT_VERSION.to_vec()));
+    }
+
+    #[test]
+    fn test_binary_transaction_id() {
+        let v = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:\xff\x001:y1:qe";
+        let query = ExtMessage::from_bencode(v).unwrap();
+        assert_eq!(query.transaction_id, b"\xff\x00");
+
+        let reply = query.reply(Message::Response(Values::default()));
+        assert_eq!(reply.transaction_id, query.transaction_id);
+        let encoded = reply.to_bencode().unwrap();
+        assert!(encoded.windows(7).any(|w| w == b"1:t2:\xff\x00"));
+    }
+
+    #[tokio::test]
+    async fn test_client() -> Result<(), color_eyre::Report> {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        let node = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = node.local_addr()?;
+        let mut client = KrpcClient::new(socket.clone());
+        client.set_timeout(Duration::from_millis(100));
+
+        // whatever arrives on our socket goes to the client, like the DHT does it
+        let reader = {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+                    client.received(ExtMessage::from_bencode(&buf[..n]).unwrap(), from);
+                }
+            })
+        };
+
+        // the node only answers when it's asked again
+        let server = tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            node.recv_from(&mut buf).await?;
+            let (n, from) = node.recv_from(&mut buf).await?;
+
+            let query = ExtMessage::from_bencode(&buf[..n]).unwrap();
+            let values = Values {
+                id: [2u8; 20],
+                ..Default::default()
+            };
+            let reply = query.reply(Message::Response(values)).to_bencode().unwrap();
+            node.send_to(&reply, from).await?;
+
+            Ok::<_, color_eyre::Report>(node)
+        });
+
+        let answer = client.query(addr, Arguments::default()).await?;
+        assert_eq!(answer.values.id, [2u8; 20]);
+        assert_eq!(answer.version, Some(CLIENT_VERSION.to_vec()));
+        assert!(!client.outstanding(addr));
+
+        // and never again
+        let _node = server.await??;
+        client.set_retries(0);
+        assert!(client.query(addr, Arguments::default()).await.is_err());
+        assert!(!client.outstanding(addr));
+
+        reader.abort();
+
+        Ok(())
+    }
 }
@@ -0,0 +1,48 @@
+pub mod bencode;
+pub mod cache;
+pub mod config;
+pub mod data;
+pub mod demux;
+pub mod dht;
+pub mod disk;
+pub mod download;
+pub mod extensions;
+pub mod external_ip;
+pub mod fetch;
+pub mod framing;
+#[cfg(feature = "fuse")]
+pub mod fuse;
+pub mod helpers;
+pub mod krpc;
+pub mod limit;
+pub mod lsd;
+pub mod manager;
+pub mod metadata;
+pub mod monitor;
+pub mod mse;
+pub mod peer;
+pub mod peer_id;
+pub mod pex;
+pub mod picker;
+pub mod piece_manager;
+pub mod port_mapping;
+pub mod proxy;
+pub mod pwp;
+pub mod rpc;
+pub mod settings;
+pub mod shutdown;
+pub mod sqlite;
+pub mod stats;
+pub mod storage;
+pub mod stream;
+#[cfg(test)]
+mod testing;
+pub mod torrent;
+pub mod trace;
+pub mod tracker;
+pub mod tracker_session;
+pub mod transport;
+pub mod udp;
+pub mod upload;
+pub mod utp;
+pub mod webseed;
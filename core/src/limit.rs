@@ -0,0 +1,167 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::{sleep, Instant};
+
+// bytes per second, refilled continuously. A transfer may take more than there is and leave the
+// bucket in debt, whoever comes next waits it off
+#[derive(Debug)]
+pub struct TokenBucket {
+    inner: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    // 0 doesn't limit anything
+    pub fn new(rate: u64) -> Self {
+        Self {
+            inner: Mutex::new(Bucket {
+                rate,
+                tokens: rate as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.inner.lock().unwrap().rate
+    }
+
+    // takes `n` bytes worth and tells how long to wait before they may go
+    fn reserve(&self, n: usize, now: Instant) -> Duration {
+        let mut bucket = self.inner.lock().unwrap();
+        if bucket.rate == 0 {
+            return Duration::ZERO;
+        }
+
+        // no more than a second's worth piles up while nothing is transferred
+        let rate = bucket.rate as f64;
+        let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate) - n as f64;
+        bucket.last = now;
+
+        match bucket.tokens < 0.0 {
+            true => Duration::from_secs_f64(-bucket.tokens / rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+// the buckets a transfer has to get through, the session's and usually the torrent's own
+#[derive(Debug, Clone, Default)]
+pub struct RateLimit {
+    buckets: Vec<Arc<TokenBucket>>,
+}
+
+impl RateLimit {
+    pub fn new(rate: u64) -> Self {
+        Self::default().and(rate)
+    }
+
+    // another bucket on top, unless it wouldn't limit anything
+    pub fn and(&self, rate: u64) -> Self {
+        let mut limit = self.clone();
+        if rate > 0 {
+            limit.buckets.push(Arc::new(TokenBucket::new(rate)));
+        }
+        limit
+    }
+
+    pub fn buckets(&self) -> &[Arc<TokenBucket>] {
+        &self.buckets
+    }
+
+    // resolves once `n` bytes may be transferred
+    pub async fn acquire(&self, n: usize) {
+        let wait = self.delay(n, Instant::now());
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+
+    // every bucket pays, the slowest one decides
+    fn delay(&self, n: usize, now: Instant) -> Duration {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.reserve(n, now))
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    pub upload: RateLimit,
+    pub download: RateLimit,
+}
+
+impl Limits {
+    // bytes per second each way, 0 for no limit
+    pub fn new(upload: u64, download: u64) -> Self {
+        Self {
+            upload: RateLimit::new(upload),
+            download: RateLimit::new(download),
+        }
+    }
+
+    // a torrent's own limits, the session's still apply
+    pub fn and(&self, upload: u64, download: u64) -> Self {
+        Self {
+            upload: self.upload.and(upload),
+            download: self.download.and(download),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(d: Duration) -> u64 {
+        (d.as_secs_f64() * 1000.0).round() as u64
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(1000);
+        let now = Instant::now();
+
+        // a second's worth goes right away, the rest has to wait for the refill
+        assert_eq!(millis(bucket.reserve(1000, now)), 0);
+        assert_eq!(millis(bucket.reserve(500, now)), 500);
+        let half = now + Duration::from_millis(500);
+        assert_eq!(millis(bucket.reserve(500, half)), 500);
+
+        // idling doesn't save up more than a second
+        let later = now + Duration::from_secs(10);
+        assert_eq!(millis(bucket.reserve(1000, later)), 0);
+        assert_eq!(millis(bucket.reserve(100, later)), 100);
+
+        // 0 doesn't limit anything
+        let unlimited = TokenBucket::new(0);
+        assert_eq!(unlimited.reserve(1 << 20, later), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let now = Instant::now();
+        let session = Limits::new(1000, 0);
+        let torrent = session.and(100, 0);
+        assert_eq!(torrent.upload.buckets().len(), 2);
+        assert!(torrent.download.buckets().is_empty());
+
+        // the torrent's own bucket runs dry first
+        assert_eq!(millis(torrent.upload.delay(200, now)), 1000);
+        // the session's is shared, the other torrents feel what this one took
+        assert_eq!(millis(session.upload.delay(900, now)), 100);
+        assert_eq!(torrent.download.delay(1 << 20, now), Duration::ZERO);
+    }
+}
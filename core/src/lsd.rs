@@ -0,0 +1,292 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use color_eyre::Report;
+use rand::{distributions::Alphanumeric, Rng};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, Receiver, Sender},
+    time::interval,
+};
+use tracing::debug;
+
+use crate::data::{GeneralError, Peer, Peers};
+
+// BEP 14, only the IPv4 group, the IPv6 one is hardly ever listened on
+pub const LSD_GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const LSD_PORT: u16 = 6771;
+// every torrent gets announced this often, and no more than once within ANNOUNCE_CHECK
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// a torrent that was just added waits at most this long for its first announce
+const ANNOUNCE_CHECK: Duration = Duration::from_secs(60);
+// info hashes per announce, keeps it well within a single datagram
+const MAX_HASHES: usize = 16;
+const MAX_ANNOUNCE: usize = 1400;
+
+// what gets multicast, and what the others on the network send us
+#[derive(Debug, Clone, PartialEq)]
+pub struct Announce {
+    pub port: u16,
+    pub hashes: Vec<[u8; 20]>,
+    // ours come back to us as well, this is how we tell them apart
+    pub cookie: Option<String>,
+}
+
+impl Announce {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {LSD_GROUP}:{LSD_PORT}\r\nPort: {}\r\n",
+            self.port
+        );
+        for hash in &self.hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {cookie}\r\n"));
+        }
+        message.push_str("\r\n\r\n");
+
+        message.into_bytes()
+    }
+
+    // header names are case insensitive, anything we don't know about is skipped
+    pub fn parse(datagram: &[u8]) -> Result<Self, GeneralError> {
+        let malformed = |e: &str| GeneralError::MalformedPacket(format!("LSD announce: {e}"));
+
+        let text = std::str::from_utf8(datagram).map_err(|_| malformed("not UTF-8"))?;
+        let mut lines = text.split("\r\n");
+        if lines.next() != Some("BT-SEARCH * HTTP/1.1") {
+            return Err(malformed("not a BT-SEARCH"));
+        }
+
+        let mut announce = Self {
+            port: 0,
+            hashes: Vec::new(),
+            cookie: None,
+        };
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+
+            match name.trim().to_lowercase().as_str() {
+                "port" => announce.port = value.parse().map_err(|_| malformed("bad port"))?,
+                "infohash" => {
+                    let mut hash = [0u8; 20];
+                    hex::decode_to_slice(value, &mut hash)
+                        .map_err(|_| malformed("bad info hash"))?;
+                    announce.hashes.push(hash);
+                }
+                "cookie" => announce.cookie = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+
+        if announce.port == 0 {
+            return Err(malformed("missing port"));
+        }
+        if announce.hashes.is_empty() {
+            return Err(malformed("missing info hash"));
+        }
+
+        Ok(announce)
+    }
+}
+
+// addresses that can't be anywhere but on a network of our own
+pub fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            // unique local fc00::/7 and link-local fe80::/10
+            ip.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+enum Command {
+    Add([u8; 20], Sender<Peers>),
+    Remove([u8; 20]),
+}
+
+struct Local {
+    peer_tx: Sender<Peers>,
+    announced: Option<Instant>,
+}
+
+// announces our torrents to the local network and hands whoever else announces them to their
+// routers, LAN peers are the fastest there are and cost nothing
+pub struct Lsd {
+    socket: UdpSocket,
+    // where peers reach us, not the port the announces come from
+    port: u16,
+    cookie: String,
+    commands: Receiver<Command>,
+    torrents: HashMap<[u8; 20], Local>,
+}
+
+#[derive(Clone)]
+pub struct LsdHandle(Sender<Command>);
+
+impl Lsd {
+    pub fn new(port: u16) -> Result<(Self, LsdHandle), Report> {
+        let (tx, commands) = mpsc::channel(16);
+        let cookie = rand::thread_rng()
+            .sample_iter(Alphanumeric)
+            .take(8)
+            .map(char::from)
+            .collect();
+
+        let lsd = Self {
+            socket: bind()?,
+            port,
+            cookie,
+            commands,
+            torrents: HashMap::new(),
+        };
+
+        Ok((lsd, LsdHandle(tx)))
+    }
+
+    pub async fn run(mut self) -> Result<(), Report> {
+        let mut check = interval(ANNOUNCE_CHECK);
+        let mut buf = vec![0u8; MAX_ANNOUNCE];
+
+        loop {
+            tokio::select! {
+                received = self.socket.recv_from(&mut buf) => {
+                    let (n, addr) = received?;
+                    match Announce::parse(&buf[..n]) {
+                        Ok(announce) => self.received(announce, addr),
+                        Err(e) => debug!("[{addr}] {e}"),
+                    }
+                }
+                command = self.commands.recv() => match command {
+                    Some(Command::Add(hash, peer_tx)) => {
+                        self.torrents.insert(hash, Local { peer_tx, announced: None });
+                    }
+                    Some(Command::Remove(hash)) => {
+                        self.torrents.remove(&hash);
+                    }
+                    None => return Ok(()),
+                },
+                _ = check.tick() => self.announce().await,
+            }
+        }
+    }
+
+    fn received(&self, announce: Announce, addr: SocketAddr) {
+        if announce.cookie.as_ref() == Some(&self.cookie) {
+            return;
+        }
+
+        let peer = Peer {
+            id: None,
+            addr: SocketAddr::new(addr.ip(), announce.port),
+        };
+        for hash in &announce.hashes {
+            if let Some(local) = self.torrents.get(hash) {
+                debug!("[{}] found on the local network", peer.addr);
+                // the router is busy, the next announce brings the peer around again
+                let _ = local.peer_tx.try_send(vec![peer.clone()]);
+            }
+        }
+    }
+
+    // every torrent that's due, as few datagrams as it takes
+    async fn announce(&mut self) {
+        let now = Instant::now();
+        let due: Vec<[u8; 20]> = self
+            .torrents
+            .iter_mut()
+            .filter(|(_, local)| {
+                local
+                    .announced
+                    .map_or(true, |at| now.duration_since(at) >= ANNOUNCE_INTERVAL)
+            })
+            .map(|(hash, local)| {
+                local.announced = Some(now);
+                *hash
+            })
+            .collect();
+
+        for hashes in due.chunks(MAX_HASHES) {
+            let announce = Announce {
+                port: self.port,
+                hashes: hashes.to_vec(),
+                cookie: Some(self.cookie.clone()),
+            };
+            let group = SocketAddrV4::new(LSD_GROUP, LSD_PORT);
+            if let Err(e) = self.socket.send_to(&announce.to_bytes(), group).await {
+                debug!("failed to announce to the local network: {e}");
+            }
+        }
+    }
+}
+
+impl LsdHandle {
+    // announced from now on, the peers that turn up get sent to `peer_tx`
+    pub async fn add(&self, hash: [u8; 20], peer_tx: Sender<Peers>) {
+        let _ = self.0.send(Command::Add(hash, peer_tx)).await;
+    }
+
+    pub async fn remove(&self, hash: [u8; 20]) {
+        let _ = self.0.send(Command::Remove(hash)).await;
+    }
+}
+
+// every client on the host listens on the same port, the group's datagrams go to all of them
+fn bind() -> Result<UdpSocket, Report> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LSD_PORT)).into())?;
+    socket.join_multicast_v4(&LSD_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    // other clients on this host hear us as well, ours get recognized by the cookie
+    socket.set_multicast_loop_v4(true)?;
+
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_announce() -> Result<(), Report> {
+        let announce = Announce {
+            port: 6881,
+            hashes: vec![[0xab; 20], [1u8; 20]],
+            cookie: Some("everlasting".to_owned()),
+        };
+        let bytes = announce.to_bytes();
+        assert!(bytes.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n"));
+        assert!(bytes.ends_with(b"\r\n\r\n\r\n"));
+        assert_eq!(Announce::parse(&bytes)?, announce);
+
+        // what other clients send, upper case hashes and no cookie
+        let other = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 51413\r\nInfohash: {}\r\n\r\n\r\n",
+            "AB".repeat(20)
+        );
+        let other = Announce::parse(other.as_bytes())?;
+        assert_eq!(other.port, 51413);
+        assert_eq!(other.hashes, vec![[0xab; 20]]);
+        assert_eq!(other.cookie, None);
+
+        assert!(Announce::parse(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n").is_err());
+        assert!(Announce::parse(b"BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\n\r\n").is_err());
+
+        assert!(is_local("192.168.1.20".parse()?));
+        assert!(is_local("fe80::1".parse()?));
+        assert!(!is_local("203.0.113.7".parse()?));
+
+        Ok(())
+    }
+}
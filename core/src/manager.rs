@@ -0,0 +1,532 @@
+use std::{collections::HashMap, future::Future, ops::Range, sync::Arc, time::Duration};
+
+use color_eyre::Report;
+use tokio::{
+    net::UdpSocket,
+    sync::{
+        mpsc::{self, Receiver},
+        Mutex, Notify, RwLock,
+    },
+    task::AbortHandle,
+    time::sleep,
+};
+use tracing::debug;
+
+use crate::{
+    config::Context,
+    data::{GeneralError, Peer, TorrentInfo},
+    demux::Datagram,
+    dht::{self, Announcer, DhtHandle},
+    extensions::ExtensionRegistry,
+    lsd::LsdHandle,
+    metadata::Metadata,
+    peer::{Incoming, Router},
+    pex::Pex,
+    picker::Priority,
+    shutdown::{Shutdown, Signal},
+    sqlite::{Database, Kind},
+    stats::Rates,
+    torrent::{AddOptions, State, Torrent},
+    tracker::{Scraper, Trackers, UdpTracker, SCRAPE_INTERVAL},
+    tracker_session::Transactions,
+    utp::UtpSocket,
+};
+
+// how long the trackers of a removed torrent get to hear that it stopped, and everything else to
+// wrap up when the session shuts down
+const STOP_GRACE: Duration = Duration::from_secs(5);
+// pieces hashed while holding on to the torrent, anyone else waits no longer than that
+const CHECK_BATCH: usize = 64;
+
+// everything that runs on behalf of a single torrent, all of it stops once the handle is dropped
+struct Handle {
+    torrent: Arc<RwLock<Torrent>>,
+    reannounce: Arc<Notify>,
+    tasks: Vec<AbortHandle>,
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.tasks.iter().for_each(AbortHandle::abort);
+    }
+}
+
+// hashes the files on a thread of its own, a batch at a time so the torrent isn't locked for the
+// whole check. What it found ends up in the history. A pause ends it, anything else that goes
+// wrong fails the torrent
+fn check_files(torrent: Arc<RwLock<Torrent>>, db: Database) {
+    tokio::task::spawn_blocking(move || loop {
+        let mut torrent = torrent.blocking_write();
+        match torrent.check_files(CHECK_BATCH) {
+            Ok(false) => continue,
+            Ok(true) => {
+                if let Some((verified, total)) = torrent.verified() {
+                    let hash = torrent.info().hash;
+                    if let Err(e) = db.record_check(&hash, verified, total) {
+                        debug!("failed to record history: {e}");
+                    }
+                }
+                return;
+            }
+            Err(e) if torrent.state() == State::CheckingFiles => {
+                let _ = torrent.fail(e.to_string());
+                return;
+            }
+            Err(_) => return,
+        }
+    });
+}
+
+fn spawn<F>(f: F) -> AbortHandle
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(f).abort_handle()
+}
+
+// owns every torrent of a session, each with trackers, a router and peers of its own, while the
+// socket, the DHT and the database are shared between them
+pub struct TorrentManager {
+    ctx: Context,
+    db: Database,
+    socket: Arc<UdpSocket>,
+    // UDP trackers of every torrent answer on the same socket
+    transactions: Arc<Transactions>,
+    dht: Option<(Arc<Mutex<Announcer>>, DhtHandle)>,
+    // torrents that aren't private get announced to the local network
+    lsd: Option<LsdHandle>,
+    // peers get dialed over uTP first when there is one
+    utp: Option<Arc<UtpSocket>>,
+    // peers that dial us get sorted out by info hash
+    incoming: Incoming,
+    torrents: HashMap<[u8; 20], Handle>,
+    shutdown: Shutdown,
+}
+
+impl TorrentManager {
+    pub fn new(
+        ctx: Context,
+        db: Database,
+        socket: Arc<UdpSocket>,
+        datagrams: Receiver<Datagram>,
+    ) -> Self {
+        let transactions = Arc::new(Transactions::default());
+        tokio::spawn(UdpTracker::listen(datagrams, transactions.clone()));
+
+        Self {
+            ctx,
+            db,
+            socket,
+            transactions,
+            dht: None,
+            lsd: None,
+            utp: None,
+            incoming: Incoming::default(),
+            torrents: HashMap::new(),
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    // torrents that aren't private look for peers on the DHT as well
+    pub fn set_dht(&mut self, handle: DhtHandle) {
+        let announcer = Arc::new(Mutex::new(Announcer::new(dht::REANNOUNCE_INTERVAL)));
+        tokio::spawn(dht::reannounce(announcer.clone(), handle.clone()));
+
+        self.dht = Some((announcer, handle));
+    }
+
+    pub fn set_lsd(&mut self, handle: LsdHandle) {
+        self.lsd = Some(handle);
+    }
+
+    pub fn set_utp(&mut self, utp: Arc<UtpSocket>) {
+        self.utp = Some(utp);
+    }
+
+    // what the peer listener hands its connections to
+    pub fn incoming(&self) -> Incoming {
+        self.incoming.clone()
+    }
+
+    // for anything outside of the manager that should wrap up before the session is gone
+    pub fn signal(&self) -> Signal {
+        self.shutdown.signal()
+    }
+
+    pub async fn add(
+        &mut self,
+        info: TorrentInfo,
+        options: AddOptions,
+    ) -> Result<Arc<RwLock<Torrent>>, Report> {
+        let hash = info.hash;
+        if self.torrents.contains_key(&hash) {
+            return Err(GeneralError::DuplicateTorrent(hex::encode(hash)).into());
+        }
+
+        let limits = self
+            .ctx
+            .limits
+            .and(options.upload_limit, options.download_limit);
+        let options = AddOptions {
+            root: options
+                .root
+                .or_else(|| Some(self.ctx.config.download_dir.clone())),
+            ..options
+        };
+        let mut torrent = Torrent::new(info.clone(), options)?;
+        // pieces an earlier session verified are trusted, without them the files get hashed
+        if torrent.state() == State::CheckingFiles {
+            if let Some(resume) = self.db.resume_data(&hash)? {
+                torrent.restore(&resume)?;
+            }
+        }
+        let check = torrent.state() == State::CheckingFiles;
+
+        let name = info.info.as_ref().map(|info| info.mode.name());
+        self.db
+            .record(&hash, Kind::Added, name.unwrap_or_default())?;
+        self.db.follow(hash, torrent.subscribe());
+        let stats = torrent.stats();
+        let mut tasks = vec![self.db.follow_transfer(hash, stats.clone())];
+
+        // the sessions follow the torrent's state to know what to tell the trackers
+        let peer_id = self.ctx.peer_id(&info);
+        let key = self.db.announce_key(&hash)?;
+        let (mut trackers, mut tracker_rx) = Trackers::new(
+            &self.ctx,
+            &info,
+            self.socket.clone(),
+            self.transactions.clone(),
+            peer_id,
+            key,
+            &torrent,
+        )?;
+        let udp = UdpTracker::new(
+            &self.ctx,
+            &info,
+            self.socket.clone(),
+            self.transactions.clone(),
+            peer_id,
+            key,
+            &torrent,
+        )?;
+        let reannounce = trackers.reannouncer();
+        trackers.set_shutdown(self.shutdown.signal());
+        let mut swarm = udp.swarm();
+
+        let torrent = Arc::new(RwLock::new(torrent));
+        tasks.push(self.db.follow_resume(hash, torrent.clone()));
+        if check {
+            check_files(torrent.clone(), self.db.clone());
+        }
+
+        // magnet links only come with the info hash, peers send us the rest
+        let mut extensions = ExtensionRegistry::default();
+        let metadata = info.info.is_none().then(|| Arc::new(Metadata::new(hash)));
+        if let Some(metadata) = &metadata {
+            extensions.register_handler(metadata.clone());
+        }
+
+        // trackers, the DHT, the local network and other peers all feed the router, it doesn't
+        // care where a peer came from
+        let (peer_tx, peer_rx) = mpsc::channel(self.ctx.config.tunables.channel_capacity);
+        // magnet links may name a few peers themselves
+        if !info.announce.peers.is_empty() {
+            let _ = peer_tx.try_send(info.announce.peers.iter().map(Peer::from).collect());
+        }
+
+        // private torrents keep their peers to the tracker, BEP 27
+        let private = info.info.as_ref().and_then(|info| info.private).is_some();
+        if !private {
+            extensions.register_handler(Arc::new(Pex::new(peer_tx.clone())));
+
+            if let Some((announcer, handle)) = &self.dht {
+                announcer.lock().await.add(hash);
+                let (announcer, handle) = (announcer.clone(), handle.clone());
+                tasks.push(spawn(dht::find_peers(
+                    announcer,
+                    handle,
+                    hash,
+                    peer_tx.clone(),
+                )));
+            }
+            if let Some(lsd) = &self.lsd {
+                lsd.add(hash, peer_tx.clone()).await;
+            }
+        }
+
+        let tracker_tx = peer_tx.clone();
+        tasks.push(spawn(async move {
+            while let Some(peers) = tracker_rx.recv().await {
+                if tracker_tx.send(peers).await.is_err() {
+                    break;
+                }
+            }
+        }));
+
+        // the scraper only speaks HTTP, UDP trackers scrape on their own
+        let scraper = Scraper::new(&self.ctx, vec![torrent.clone()], SCRAPE_INTERVAL)?;
+        tasks.push(spawn(scraper.run()));
+
+        let scraped = torrent.clone();
+        tasks.push(spawn(async move {
+            while swarm.changed().await.is_ok() {
+                let status = swarm.borrow_and_update().clone();
+                if let Some(status) = status {
+                    scraped.write().await.scraped(status);
+                }
+            }
+        }));
+
+        let db = self.db.clone();
+        tasks.push(spawn(async move {
+            if let Err(e) = trackers.run().await {
+                let _ = db.record(&hash, Kind::TrackerError, e.to_string());
+            }
+        }));
+        tasks.push(spawn(udp.run()));
+
+        let mut router = Router::new(&self.ctx, Arc::new(info), peer_id, peer_rx);
+        router.set_reputation(self.db.clone());
+        router.set_data(torrent.clone());
+        router.set_stats(stats);
+        router.set_limits(limits);
+        router.set_shutdown(self.shutdown.signal());
+        router.set_extensions(Arc::new(extensions));
+        let capacity = self.ctx.config.tunables.channel_capacity;
+        router.set_incoming(self.incoming.register(hash, capacity));
+        if let Some(utp) = &self.utp {
+            router.set_utp(utp.clone());
+        }
+        if let Some(metadata) = metadata {
+            router.set_metadata(metadata);
+        }
+        tasks.push(spawn(router.run()));
+
+        debug!("added [{}]", hex::encode(hash));
+        self.torrents.insert(
+            hash,
+            Handle {
+                torrent: torrent.clone(),
+                reannounce,
+                tasks,
+            },
+        );
+
+        Ok(torrent)
+    }
+
+    pub fn get(&self, hash: &[u8; 20]) -> Option<Arc<RwLock<Torrent>>> {
+        self.torrents.get(hash).map(|handle| handle.torrent.clone())
+    }
+
+    pub fn torrents(&self) -> Vec<Arc<RwLock<Torrent>>> {
+        self.torrents
+            .values()
+            .map(|handle| handle.torrent.clone())
+            .collect()
+    }
+
+    fn handle(&self, hash: &[u8; 20]) -> Result<&Handle, Report> {
+        self.torrents
+            .get(hash)
+            .ok_or_else(|| GeneralError::UnknownTorrent(hex::encode(hash)).into())
+    }
+
+    // the trackers get told right away, peers stop getting pieces from us
+    pub async fn pause(&self, hash: &[u8; 20]) -> Result<(), Report> {
+        self.handle(hash)?.torrent.write().await.pause()
+    }
+
+    // a torrent that failed gets its files checked again before it goes on
+    pub async fn resume(&self, hash: &[u8; 20]) -> Result<(), Report> {
+        let torrent = &self.handle(hash)?.torrent;
+        let mut guard = torrent.write().await;
+        guard.resume()?;
+
+        if guard.state() == State::CheckingFiles {
+            check_files(torrent.clone(), self.db.clone());
+        }
+
+        Ok(())
+    }
+
+    // files are numbered in the order the torrent lists them
+    pub async fn set_priority(
+        &self,
+        hash: &[u8; 20],
+        file: usize,
+        priority: Priority,
+    ) -> Result<(), Report> {
+        self.handle(hash)?
+            .torrent
+            .write()
+            .await
+            .set_file_priority(file, priority)
+    }
+
+    // the pieces of this byte range of a file go ahead of everything else, the file's first and
+    // last pieces included
+    pub async fn prioritize_range(
+        &self,
+        hash: &[u8; 20],
+        file: usize,
+        range: Range<u64>,
+    ) -> Result<(), Report> {
+        self.handle(hash)?
+            .torrent
+            .write()
+            .await
+            .prioritize_range(file, range)
+    }
+
+    // hashes every piece on disk again, the ones that don't match get downloaded again
+    pub async fn recheck(&self, hash: &[u8; 20]) -> Result<(), Report> {
+        let torrent = &self.handle(hash)?.torrent;
+        torrent.write().await.recheck()?;
+        check_files(torrent.clone(), self.db.clone());
+
+        Ok(())
+    }
+
+    // the files stay where they are, the resume data gets saved one last time so adding the
+    // torrent again doesn't mean downloading it again
+    pub async fn remove(&mut self, hash: &[u8; 20]) -> Result<(), Report> {
+        let handle = self
+            .torrents
+            .remove(hash)
+            .ok_or_else(|| GeneralError::UnknownTorrent(hex::encode(hash)))?;
+
+        self.incoming.unregister(hash);
+        if let Some((announcer, _)) = &self.dht {
+            announcer.lock().await.remove(hash);
+        }
+        if let Some(lsd) = &self.lsd {
+            lsd.remove(*hash).await;
+        }
+
+        {
+            let mut torrent = handle.torrent.write().await;
+            if torrent.state() != State::Paused {
+                torrent.pause()?;
+            }
+            if let Some(data) = torrent.resume_data() {
+                self.db.save_resume_data(hash, &data)?;
+            }
+        }
+        self.db.record(hash, Kind::Removed, "")?;
+
+        tokio::spawn(async move {
+            sleep(STOP_GRACE).await;
+            drop(handle);
+        });
+
+        Ok(())
+    }
+
+    // trackers hear that we stopped and routers hang up on their peers, writing out the blocks
+    // they delivered. Then every torrent's files get synced and its resume data saved, so the next
+    // session picks up right here
+    pub async fn shutdown(&mut self) -> Result<(), Report> {
+        if !self.shutdown.trigger(STOP_GRACE).await {
+            debug!("not everything wrapped up in time");
+        }
+
+        for (hash, handle) in self.torrents.drain() {
+            let mut torrent = handle.torrent.write().await;
+            if let Err(e) = torrent.sync() {
+                debug!("failed to sync [{}]: {e}", hex::encode(hash));
+            }
+            if let Some(data) = torrent.resume_data() {
+                self.db.save_resume_data(&hash, &data)?;
+            }
+        }
+
+        self.db.flush()
+    }
+
+    pub fn rates(&self) -> &Rates {
+        &self.ctx.rates
+    }
+
+    // the torrent announces to its trackers right away, as far as their min interval lets. One
+    // asked for during an announce happens right after it
+    pub fn reannounce(&self, hash: &[u8; 20]) -> Result<(), Report> {
+        self.handle(hash)?.reannounce.notify_one();
+        Ok(())
+    }
+
+    pub fn reannounce_all(&self) {
+        for handle in self.torrents.values() {
+            handle.reannounce.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, testing::TempDir};
+
+    #[tokio::test]
+    async fn test_manager() -> Result<(), Report> {
+        let dir = TempDir::new();
+        let db = Database::open(dir.join("db"))?;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        let ctx = Context::new(Config::default(), 6881, ExtensionRegistry::default());
+        let (_datagram_tx, datagrams) = mpsc::channel(1);
+        let mut manager = TorrentManager::new(ctx, db.clone(), socket, datagrams);
+
+        // magnet links are fine without the info dictionary, it gets fetched from peers
+        let (a, b) = (
+            TorrentInfo {
+                hash: [1u8; 20],
+                ..Default::default()
+            },
+            TorrentInfo {
+                hash: [2u8; 20],
+                ..Default::default()
+            },
+        );
+        manager.add(a.clone(), AddOptions::default()).await?;
+        manager.add(b, AddOptions::default()).await?;
+        assert_eq!(manager.torrents().len(), 2);
+        let Err(e) = manager.add(a, AddOptions::default()).await else {
+            panic!("added the same torrent twice");
+        };
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(GeneralError::DuplicateTorrent(_))
+        ));
+
+        manager.pause(&[1u8; 20]).await?;
+        let torrent = manager.get(&[1u8; 20]).unwrap();
+        assert_eq!(torrent.read().await.state(), State::Paused);
+        assert_eq!(
+            manager.get(&[2u8; 20]).unwrap().read().await.state(),
+            State::DownloadingMetadata
+        );
+        manager.resume(&[1u8; 20]).await?;
+        assert_eq!(torrent.read().await.state(), State::DownloadingMetadata);
+
+        // kept for the tracker task until it gets around to it
+        manager.reannounce(&[2u8; 20])?;
+        assert!(manager.reannounce(&[3u8; 20]).is_err());
+
+        manager.remove(&[1u8; 20]).await?;
+        assert!(manager.get(&[1u8; 20]).is_none());
+        assert_eq!(torrent.read().await.state(), State::Paused);
+        assert!(manager.pause(&[1u8; 20]).await.is_err());
+        assert!(manager.remove(&[1u8; 20]).await.is_err());
+
+        let history = db.history(&[1u8; 20])?;
+        assert!(history.iter().any(|entry| entry.kind == Kind::Removed));
+
+        // the router and the trackers of the other one are done before this returns
+        manager.shutdown().await?;
+        assert!(manager.torrents().is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,334 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Mutex,
+};
+
+use bendy::decoding::FromBencode;
+use bytes::Bytes;
+use color_eyre::Report;
+use tokio::sync::watch;
+use tracing::debug;
+
+use crate::{
+    data::{GeneralError, Info},
+    extensions::{Extension, ExtensionHandler, Handshake, Message, MsgType, Value},
+    piece_manager::sha1,
+    pwp::Request,
+};
+
+// BEP 9 splits the info dictionary into pieces of 16 KiB, only the last one may be shorter
+pub const METADATA_PIECE: usize = 1 << 14;
+// anything larger than this is a peer trying to make us allocate
+const MAX_METADATA: usize = 1 << 24;
+
+#[derive(Default)]
+struct Pieces {
+    size: Option<usize>,
+    // what each peer says the size is, and who the one we went with came from
+    sizes: HashMap<SocketAddr, usize>,
+    source: Option<SocketAddr>,
+    // peers whose size failed the hash check, they don't get asked for anything anymore
+    banned: HashSet<SocketAddr>,
+    pieces: Vec<Option<Vec<u8>>>,
+    // who we asked for which piece, they're up for grabs again once the peer goes away
+    requested: HashMap<u32, SocketAddr>,
+    // the verified info dictionary, served to peers that ask for it
+    raw: Option<Vec<u8>>,
+}
+
+impl Pieces {
+    // goes with the size most of the peers we still trust agree on
+    fn pick_size(&mut self) {
+        let mut votes: HashMap<usize, usize> = HashMap::new();
+        for size in self.sizes.values() {
+            *votes.entry(*size).or_default() += 1;
+        }
+        let Some((size, _)) = votes.into_iter().max_by_key(|&(size, n)| (n, size)) else {
+            return;
+        };
+
+        self.size = Some(size);
+        self.source = self
+            .sizes
+            .iter()
+            .find(|(_, s)| **s == size)
+            .map(|(peer, _)| *peer);
+        self.pieces = vec![None; size.div_ceil(METADATA_PIECE)];
+        self.requested.clear();
+    }
+}
+
+// the ut_metadata extension: fetches the info dictionary of a magnet link from peers and serves it
+// once we have it
+pub struct Metadata {
+    hash: [u8; 20],
+    inner: Mutex<Pieces>,
+    info: watch::Sender<Option<Info>>,
+}
+
+impl Metadata {
+    pub fn new(hash: [u8; 20]) -> Self {
+        Self {
+            hash,
+            inner: Mutex::new(Pieces::default()),
+            info: watch::channel(None).0,
+        }
+    }
+
+    // for torrents that came with their info dictionary, nothing gets fetched
+    pub fn with_raw(hash: [u8; 20], raw: Vec<u8>) -> Self {
+        let metadata = Self::new(hash);
+        {
+            let mut inner = metadata.inner.lock().unwrap();
+            inner.size = Some(raw.len());
+            inner.raw = Some(raw);
+        }
+
+        metadata
+    }
+
+    // yields the info dictionary once it's been fetched and verified
+    pub fn subscribe(&self) -> watch::Receiver<Option<Info>> {
+        self.info.subscribe()
+    }
+
+    pub fn complete(&self) -> bool {
+        self.inner.lock().unwrap().raw.is_some()
+    }
+
+    // pieces to ask the peer for, every piece nobody else was asked for yet
+    pub fn claim(&self, peer: SocketAddr) -> Vec<u32> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.raw.is_some() || inner.banned.contains(&peer) {
+            return Vec::new();
+        }
+
+        let missing: Vec<u32> = (0..inner.pieces.len() as u32)
+            .filter(|i| inner.pieces[*i as usize].is_none() && !inner.requested.contains_key(i))
+            .collect();
+        for &i in &missing {
+            inner.requested.insert(i, peer);
+        }
+
+        missing
+    }
+
+    pub fn peer_gone(&self, peer: SocketAddr) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.requested.retain(|_, p| *p != peer);
+        inner.sizes.remove(&peer);
+    }
+
+    // the payload of a request for one of the pieces
+    pub fn request(piece: u32) -> Bytes {
+        let m = Message::Extension(Extension::Metadata {
+            msg_type: MsgType::Request,
+            piece,
+            total_size: None,
+            payload: None,
+        });
+
+        m.payload().into()
+    }
+
+    fn received(&self, peer: SocketAddr, piece: u32, data: Vec<u8>) -> Result<(), Report> {
+        let mut inner = self.inner.lock().unwrap();
+        let size = inner.size.ok_or(GeneralError::MissingInfo)?;
+
+        let begin = piece as usize * METADATA_PIECE;
+        let expected = METADATA_PIECE.min(size.saturating_sub(begin));
+        if inner.requested.get(&piece) != Some(&peer) || data.len() != expected {
+            return Err(GeneralError::UnexpectedResponse(format!("metadata piece {piece}")).into());
+        }
+        inner.requested.remove(&piece);
+        inner.pieces[piece as usize] = Some(data);
+
+        if inner.pieces.iter().any(Option::is_none) {
+            return Ok(());
+        }
+
+        let raw: Vec<u8> = inner
+            .pieces
+            .iter_mut()
+            .flat_map(|p| p.take().unwrap())
+            .collect();
+        // no telling which peer lied, so everything gets fetched again. Except the size, which
+        // lays out every piece, whoever told us that one doesn't get believed a second time
+        if sha1(&raw) != self.hash {
+            if let Some(source) = inner.source.take() {
+                debug!("{source} sent a metadata size that doesn't check out");
+                inner.sizes.remove(&source);
+                inner.banned.insert(source);
+            }
+            inner.size = None;
+            inner.pieces.clear();
+            inner.pick_size();
+
+            return Err(GeneralError::InvalidPieceHash.into());
+        }
+
+        let info =
+            Info::from_bencode(&raw).map_err(|e| GeneralError::MalformedPacket(e.to_string()))?;
+        debug!("fetched {} bytes of metadata", raw.len());
+        inner.raw = Some(raw);
+        self.info.send_replace(Some(info));
+
+        Ok(())
+    }
+
+    // a piece of our own copy, None while we don't have one
+    fn serve(&self, piece: u32) -> Option<(usize, Vec<u8>)> {
+        let inner = self.inner.lock().unwrap();
+        let raw = inner.raw.as_ref()?;
+
+        let begin = piece as usize * METADATA_PIECE;
+        let data = raw.get(begin..(begin + METADATA_PIECE).min(raw.len()))?;
+
+        Some((raw.len(), data.to_vec()))
+    }
+}
+
+impl ExtensionHandler for Metadata {
+    fn name(&self) -> &str {
+        "ut_metadata"
+    }
+
+    fn handshake(&self) -> Vec<(String, Value)> {
+        let inner = self.inner.lock().unwrap();
+
+        match &inner.raw {
+            Some(raw) => vec![("metadata_size".to_owned(), Value::Int(raw.len() as i64))],
+            None => Vec::new(),
+        }
+    }
+
+    fn peer_handshake(&self, peer: SocketAddr, h: &Handshake) {
+        let Some(Value::Int(size)) = h.extra.get("metadata_size") else {
+            return;
+        };
+        let mut inner = self.inner.lock().unwrap();
+
+        match usize::try_from(*size) {
+            Ok(size) if size > 0 && size <= MAX_METADATA && !inner.banned.contains(&peer) => {
+                inner.sizes.insert(peer, size);
+            }
+            _ => return,
+        }
+
+        // once the pieces are laid out they stay that way, a wrong size fails the hash check
+        // later on
+        if inner.size.is_none() {
+            inner.pick_size();
+        }
+    }
+
+    fn message(&self, peer: SocketAddr, payload: Bytes) -> Result<Option<Bytes>, Report> {
+        let Some(Message::Extension(Extension::Metadata {
+            msg_type,
+            piece,
+            payload,
+            ..
+        })) = Message::from_request(&payload)
+        else {
+            return Err(GeneralError::MalformedPacket("ut_metadata".to_owned()).into());
+        };
+
+        let reply = match msg_type {
+            MsgType::Request => match self.serve(piece) {
+                Some((size, data)) => Extension::Metadata {
+                    msg_type: MsgType::Data,
+                    piece,
+                    total_size: Some(size as u32),
+                    payload: Some(data),
+                },
+                None => Extension::Metadata {
+                    msg_type: MsgType::Reject,
+                    piece,
+                    total_size: None,
+                    payload: None,
+                },
+            },
+            MsgType::Data => {
+                self.received(peer, piece, payload.unwrap_or_default())?;
+                return Ok(None);
+            }
+            MsgType::Reject => {
+                self.inner.lock().unwrap().requested.remove(&piece);
+                return Ok(None);
+            }
+        };
+
+        Ok(Some(Message::Extension(reply).payload().into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata() -> Result<(), Report> {
+        let raw = b"d6:lengthi8e4:name4:data12:piece lengthi4e6:pieces0:e".to_vec();
+        let hash = sha1(&raw);
+        let seed = Metadata::with_raw(hash, raw.clone());
+        let leech = Metadata::new(hash);
+        let (a, b): (SocketAddr, SocketAddr) = ("10.0.0.1:6881".parse()?, "10.0.0.2:6881".parse()?);
+
+        let h = Handshake {
+            extra: seed.handshake().into_iter().collect(),
+            ..Default::default()
+        };
+        assert!(leech.claim(a).is_empty());
+        leech.peer_handshake(a, &h);
+        assert_eq!(leech.claim(a), vec![0]);
+        assert!(leech.claim(b).is_empty());
+
+        // the peer went away, someone else gets asked instead
+        leech.peer_gone(a);
+        assert_eq!(leech.claim(b), vec![0]);
+
+        let reply = seed.message(b, Metadata::request(0))?.unwrap();
+        assert!(leech.message(a, reply.clone()).is_err());
+        assert!(leech.message(b, reply)?.is_none());
+        assert!(leech.complete());
+        assert!(leech.subscribe().borrow().is_some());
+
+        // a peer lying about the size gets caught by the hash check, the next one gets a chance
+        let leech = Metadata::new(hash);
+        let liar = Handshake {
+            extra: [("metadata_size".to_owned(), Value::Int(10))].into(),
+            ..Default::default()
+        };
+        leech.peer_handshake(a, &liar);
+        leech.peer_handshake(b, &h);
+        assert_eq!(leech.claim(a), vec![0]);
+        let junk = Message::Extension(Extension::Metadata {
+            msg_type: MsgType::Data,
+            piece: 0,
+            total_size: Some(10),
+            payload: Some(vec![0; 10]),
+        })
+        .payload();
+        assert!(leech.message(a, junk.into()).is_err());
+        assert!(leech.claim(a).is_empty());
+        assert_eq!(leech.claim(b), vec![0]);
+        let reply = seed.message(b, Metadata::request(0))?.unwrap();
+        assert!(leech.message(b, reply)?.is_none());
+        assert!(leech.complete());
+
+        // nothing to serve without the metadata
+        let reply = Metadata::new(hash)
+            .message(a, Metadata::request(0))?
+            .unwrap();
+        assert!(matches!(
+            Message::from_request(&reply),
+            Some(Message::Extension(Extension::Metadata {
+                msg_type: MsgType::Reject,
+                ..
+            }))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,316 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{watch, Mutex};
+
+use crate::{
+    data::Status,
+    manager::TorrentManager,
+    rpc::state_name,
+    stats::{PeerRates, PeerStatus, Rate},
+    torrent::{State, Torrent},
+};
+
+// how often the dashboard gets a new picture of the session
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileSnapshot {
+    // relative to the download directory
+    pub path: PathBuf,
+    pub size: u64,
+    pub complete: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerSnapshot {
+    pub addr: SocketAddr,
+    pub status: PeerStatus,
+    pub rates: PeerRates,
+    // how much of the torrent the peer has, between 0 and 1
+    pub completed: f64,
+}
+
+// a torrent the way the dashboard shows it, nothing in here holds on to the torrent
+#[derive(Debug, Clone, PartialEq)]
+pub struct TorrentSnapshot {
+    pub hash: [u8; 20],
+    // the info hash until a magnet link got its metadata
+    pub name: String,
+    pub state: State,
+    // the state with how far the file check got, like `remote list` prints it
+    pub status: String,
+    pub size: u64,
+    pub left: u64,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    // the torrent's Rate each way
+    pub up: u64,
+    pub down: u64,
+    // the ones sending us the most first
+    pub peers: Vec<PeerSnapshot>,
+    pub files: Vec<FileSnapshot>,
+    pub trackers: Vec<String>,
+    // Torrent::swarm as of the snapshot
+    pub swarm: Option<Status>,
+}
+
+impl TorrentSnapshot {
+    fn sample(
+        torrent: &Torrent,
+        transfer: &mut Transfer,
+        rates: &HashMap<SocketAddr, PeerRates>,
+        now: Instant,
+    ) -> Self {
+        let info = torrent.info();
+        let stats = torrent.stats();
+        let (uploaded, downloaded, left) = stats.up_down_left();
+        let (up, down) = transfer.update(uploaded, downloaded, now);
+
+        let pieces = info.info.as_ref().map(|info| info.pieces.len());
+        let mut peers: Vec<_> = stats
+            .peers()
+            .into_iter()
+            .map(|(addr, status)| PeerSnapshot {
+                addr,
+                completed: status.completed(pieces.unwrap_or_default()),
+                status,
+                rates: rates.get(&addr).copied().unwrap_or_default(),
+            })
+            .collect();
+        peers.sort_by_key(|peer| (Reverse(peer.rates.down), peer.addr));
+
+        let files = match &info.info {
+            Some(metainfo) => metainfo
+                .mode
+                .files(Path::new(""))
+                .into_iter()
+                .enumerate()
+                .map(|(i, (path, size))| FileSnapshot {
+                    path,
+                    size,
+                    // empty files have no pieces to wait for
+                    complete: size == 0 || torrent.has_range(i, 0..size).unwrap_or(false),
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        Self {
+            hash: info.hash,
+            name: info
+                .info
+                .as_ref()
+                .map(|info| info.mode.name())
+                .unwrap_or_else(|| hex::encode(info.hash)),
+            state: torrent.state(),
+            status: state_name(torrent),
+            size: info.length() as u64,
+            left,
+            uploaded,
+            downloaded,
+            up,
+            down,
+            peers,
+            files,
+            trackers: info
+                .announce
+                .tiers
+                .iter()
+                .flatten()
+                .map(|t| t.to_string())
+                .collect(),
+            swarm: torrent.swarm().cloned(),
+        }
+    }
+
+    // between 0 and 1, nothing is known before the metadata came in
+    pub fn progress(&self) -> f64 {
+        match self.size {
+            0 => 0.0,
+            size => size.saturating_sub(self.left) as f64 / size as f64,
+        }
+    }
+
+    // at the current rate, None while nothing comes in
+    pub fn eta(&self) -> Option<Duration> {
+        (self.state == State::Downloading && self.left > 0 && self.down > 0)
+            .then(|| Duration::from_secs(self.left / self.down))
+    }
+}
+
+// everything the dashboard draws, one of these every SAMPLE_INTERVAL
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    pub session: PeerRates,
+    // sorted by name, so rows don't jump around between samples
+    pub torrents: Vec<TorrentSnapshot>,
+}
+
+// the torrents only count bytes, their rates come from how much the counts moved between samples
+#[derive(Debug)]
+struct Transfer {
+    up: Rate,
+    down: Rate,
+    last: (u64, u64),
+}
+
+impl Transfer {
+    fn new(uploaded: u64, downloaded: u64, now: Instant) -> Self {
+        Self {
+            up: Rate::new(now),
+            down: Rate::new(now),
+            last: (uploaded, downloaded),
+        }
+    }
+
+    fn update(&mut self, uploaded: u64, downloaded: u64, now: Instant) -> (u64, u64) {
+        let (last_up, last_down) = self.last;
+        self.up.add(uploaded.saturating_sub(last_up), now);
+        self.down.add(downloaded.saturating_sub(last_down), now);
+        self.last = (uploaded, downloaded);
+
+        (self.up.per_second(now), self.down.per_second(now))
+    }
+}
+
+// samples the session for the dashboard, which gets the latest one over a watch channel and never
+// waits on a lock the engine holds
+pub struct Monitor {
+    manager: Arc<Mutex<TorrentManager>>,
+    tx: watch::Sender<Snapshot>,
+    transfers: HashMap<[u8; 20], Transfer>,
+    interval: Duration,
+}
+
+impl Monitor {
+    pub fn new(manager: Arc<Mutex<TorrentManager>>) -> (Self, watch::Receiver<Snapshot>) {
+        let (tx, rx) = watch::channel(Snapshot::default());
+        let monitor = Self {
+            manager,
+            tx,
+            transfers: HashMap::new(),
+            interval: SAMPLE_INTERVAL,
+        };
+
+        (monitor, rx)
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    // until the dashboard is gone
+    pub async fn run(mut self) {
+        let mut tick = tokio::time::interval(self.interval);
+        loop {
+            tick.tick().await;
+            let snapshot = self.sample(Instant::now()).await;
+            if self.tx.send(snapshot).is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn sample(&mut self, now: Instant) -> Snapshot {
+        let (session, rates, torrents) = {
+            let manager = self.manager.lock().await;
+            let rates = manager.rates();
+            (rates.session(), rates.peers(), manager.torrents())
+        };
+        let rates: HashMap<_, _> = rates.into_iter().collect();
+
+        let mut snapshots = Vec::with_capacity(torrents.len());
+        for torrent in torrents {
+            let torrent = torrent.read().await;
+            let (uploaded, downloaded, _) = torrent.stats().up_down_left();
+            let transfer = self
+                .transfers
+                .entry(torrent.info().hash)
+                .or_insert_with(|| Transfer::new(uploaded, downloaded, now));
+            snapshots.push(TorrentSnapshot::sample(&torrent, transfer, &rates, now));
+        }
+
+        // removed torrents take their rates along
+        self.transfers
+            .retain(|hash, _| snapshots.iter().any(|s| &s.hash == hash));
+        snapshots.sort_by(|a, b| a.name.cmp(&b.name).then(a.hash.cmp(&b.hash)));
+
+        Snapshot {
+            session,
+            torrents: snapshots,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data::TorrentInfo, torrent::AddOptions};
+    use color_eyre::Report;
+
+    #[test]
+    fn test_snapshot() -> Result<(), Report> {
+        let info = TorrentInfo {
+            hash: [7u8; 20],
+            ..Default::default()
+        };
+        let torrent = Torrent::new(info, AddOptions::default())?;
+        let stats = torrent.stats();
+        let now = Instant::now();
+        let secs = |n| now + Duration::from_secs(n);
+
+        // whatever was moved before the first sample doesn't count towards the rates
+        stats.downloaded(5000);
+        let mut transfer = Transfer::new(0, 5000, now);
+        stats.downloaded(2000);
+        stats.uploaded(1000);
+        let peer: SocketAddr = "10.0.0.1:6881".parse()?;
+        let connected = stats.connected(peer);
+        connected.report(PeerStatus {
+            seed: true,
+            ..Default::default()
+        });
+        let rates = HashMap::from([(
+            peer,
+            PeerRates {
+                down: 300,
+                ..Default::default()
+            },
+        )]);
+
+        let snapshot = TorrentSnapshot::sample(&torrent, &mut transfer, &rates, secs(1));
+        assert_eq!(snapshot.name, hex::encode([7u8; 20]));
+        assert_eq!(snapshot.state, State::DownloadingMetadata);
+        assert_eq!((snapshot.downloaded, snapshot.uploaded), (7000, 1000));
+        assert_eq!((snapshot.down, snapshot.up), (200, 100));
+        assert_eq!(snapshot.peers.len(), 1);
+        assert_eq!(snapshot.peers[0].rates.down, 300);
+        assert_eq!(snapshot.peers[0].completed, 1.0);
+        assert!(snapshot.files.is_empty());
+        assert_eq!(snapshot.progress(), 0.0);
+        assert_eq!(snapshot.eta(), None);
+
+        // the peer is gone as soon as its connection is
+        drop(connected);
+        assert!(stats.peers().is_empty());
+
+        let downloading = TorrentSnapshot {
+            state: State::Downloading,
+            size: 1000,
+            left: 250,
+            down: 50,
+            ..snapshot
+        };
+        assert_eq!(downloading.progress(), 0.75);
+        assert_eq!(downloading.eta(), Some(Duration::from_secs(5)));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,523 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, BytesMut};
+use color_eyre::Report;
+use crypto::{digest::Digest, rc4::Rc4, sha1::Sha1, symmetriccipher::SynchronousStreamCipher};
+use num::BigUint;
+use rand::Rng;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::{
+    data::GeneralError,
+    transport::{ReadHalf, Stream, WriteHalf},
+};
+
+// Message Stream Encryption, the Diffie-Hellman group every client uses: a 768-bit prime and 2
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u8 = 2;
+const KEY_LEN: usize = 96;
+// random bytes after the public keys and the crypto fields, so the lengths give nothing away
+const MAX_PAD: usize = 512;
+// verification constant, both sides look for it to know where the encrypted part starts
+const VC: [u8; 8] = [0; 8];
+// the first kilobyte of RC4 output is weak and gets thrown away
+const DISCARD: usize = 1024;
+
+// crypto_provide and crypto_select bits
+const PLAINTEXT: u32 = 0x01;
+const RC4: u32 = 0x02;
+
+// the first bytes of a plaintext handshake, anything else an inbound peer sends is a public key
+pub const PROTOCOL: &[u8; 20] = b"\x13BitTorrent protocol";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum Encryption {
+    // plaintext only, peers that open with a public key get turned away
+    Disabled,
+    // RC4 where the peer supports it, plaintext with the ones that don't
+    #[default]
+    Preferred,
+    // RC4 or no connection at all
+    Required,
+}
+
+impl Encryption {
+    // what we offer when we dial
+    fn provide(self) -> u32 {
+        match self {
+            Encryption::Required => RC4,
+            _ => RC4 | PLAINTEXT,
+        }
+    }
+
+    // what we pick from what a peer that dialed us offered
+    fn select(self, provide: u32) -> Option<u32> {
+        if provide & RC4 != 0 {
+            Some(RC4)
+        } else if provide & PLAINTEXT != 0 && self != Encryption::Required {
+            Some(PLAINTEXT)
+        } else {
+            None
+        }
+    }
+
+    // whether an inbound peer that sent this in the clear gets to keep talking
+    pub fn allows(self, plaintext: bool) -> bool {
+        match self {
+            Encryption::Disabled => plaintext,
+            Encryption::Preferred => true,
+            Encryption::Required => !plaintext,
+        }
+    }
+}
+
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    // 160 bits of private key are what the spec asks for
+    fn generate() -> Self {
+        let private: [u8; 20] = rand::thread_rng().gen();
+        let private = BigUint::from_bytes_be(&private);
+        let public = BigUint::from(GENERATOR).modpow(&private, &prime());
+
+        Self {
+            private,
+            public: to_key(&public),
+        }
+    }
+
+    fn secret(&self, theirs: &[u8]) -> Result<[u8; KEY_LEN], Report> {
+        let prime = prime();
+        let theirs = BigUint::from_bytes_be(theirs);
+
+        // 0, 1 and p - 1 would leave the shared secret up to whoever sent them
+        if theirs <= BigUint::from(1u8) || theirs >= &prime - 1u8 {
+            return Err(GeneralError::Encryption("weak public key".to_owned()).into());
+        }
+
+        Ok(to_key(&theirs.modpow(&self.private, &prime)))
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME.as_bytes(), 16).unwrap()
+}
+
+// public keys and the shared secret are always sent and hashed as 96 bytes
+fn to_key(n: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn sha1(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.input(part);
+    }
+
+    let mut out = [0; 20];
+    hasher.result(&mut out);
+    out
+}
+
+fn xor(a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    let mut out = a;
+    out.iter_mut().zip(b).for_each(|(a, b)| *a ^= b);
+    out
+}
+
+fn padding() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0..=MAX_PAD);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+// "keyA" encrypts what the dialing side sends, "keyB" what it receives
+fn cipher(name: &[u8], secret: &[u8], hash: &[u8; 20]) -> Rc4 {
+    let mut rc4 = Rc4::new(&sha1(&[name, secret, hash]));
+    apply(&mut rc4, &mut [0; DISCARD]);
+    rc4
+}
+
+fn apply(rc4: &mut Rc4, buf: &mut [u8]) {
+    let mut out = [0; 1024];
+    for chunk in buf.chunks_mut(out.len()) {
+        let out = &mut out[..chunk.len()];
+        rc4.process(chunk, out);
+        chunk.copy_from_slice(out);
+    }
+}
+
+async fn fill(r: &mut ReadHalf, buffer: &mut BytesMut, len: usize) -> Result<(), Report> {
+    while buffer.len() < len {
+        if 0 == r.read_buf(buffer).await? {
+            return Err(GeneralError::BrokenPipe.into());
+        }
+    }
+
+    Ok(())
+}
+
+// skips the peer's padding up to and including `pattern`, which has to show up within `limit`
+// bytes
+async fn sync(
+    r: &mut ReadHalf,
+    buffer: &mut BytesMut,
+    pattern: &[u8],
+    limit: usize,
+) -> Result<(), Report> {
+    loop {
+        if let Some(pos) = buffer.windows(pattern.len()).position(|w| w == pattern) {
+            buffer.advance(pos + pattern.len());
+            return Ok(());
+        }
+        if buffer.len() >= limit {
+            return Err(GeneralError::Encryption("no sync point".to_owned()).into());
+        }
+
+        fill(r, buffer, buffer.len() + 1).await?;
+    }
+}
+
+// what's left of the handshake is the start of the stream, bytes read past its end included.
+// `payload` was decrypted already, `buffer` wasn't
+fn wrap(
+    r: ReadHalf,
+    w: WriteHalf,
+    keys: Option<(Rc4, Rc4)>,
+    payload: BytesMut,
+    mut buffer: BytesMut,
+) -> Result<Stream, Report> {
+    let peer = w.peer_addr()?;
+    let (mut incoming, outgoing) = keys.unzip();
+    if let Some(rc4) = &mut incoming {
+        apply(rc4, &mut buffer);
+    }
+    let mut read = payload;
+    read.unsplit(buffer);
+
+    let reader = Reader {
+        inner: r,
+        cipher: incoming,
+        buffer: read,
+    };
+
+    Ok(match outgoing {
+        Some(cipher) => {
+            let writer = Writer {
+                inner: w,
+                cipher,
+                pending: BytesMut::new(),
+                accepted: 0,
+            };
+            Stream::new(reader, writer, peer)
+        }
+        None => Stream::new(reader, w, peer),
+    })
+}
+
+// we dialed, so our public key goes first. The torrent's info hash keys the stream, a peer that
+// doesn't have it can't tell what we're after
+pub async fn initiate(
+    stream: Stream,
+    hash: &[u8; 20],
+    encryption: Encryption,
+) -> Result<Stream, Report> {
+    let Stream {
+        reader: mut r,
+        writer: mut w,
+    } = stream;
+
+    let keys = KeyPair::generate();
+    w.write_all(&[&keys.public[..], &padding()].concat())
+        .await?;
+
+    let mut theirs = [0; KEY_LEN];
+    r.read_exact(&mut theirs).await?;
+    let secret = keys.secret(&theirs)?;
+    let mut outgoing = cipher(b"keyA", &secret, hash);
+    let mut incoming = cipher(b"keyB", &secret, hash);
+
+    // the handshake follows in the encrypted stream rather than as the initial payload
+    let pad = padding();
+    let mut payload = Vec::with_capacity(VC.len() + 8 + pad.len());
+    payload.extend(VC);
+    payload.extend(encryption.provide().to_be_bytes());
+    payload.extend((pad.len() as u16).to_be_bytes());
+    payload.extend(pad);
+    payload.extend(0u16.to_be_bytes());
+    apply(&mut outgoing, &mut payload);
+
+    let req1 = sha1(&[b"req1", &secret]);
+    let req2 = xor(sha1(&[b"req2", hash]), sha1(&[b"req3", &secret]));
+    w.write_all(&[&req1[..], &req2, &payload].concat()).await?;
+
+    // their padding ends where the encrypted verification constant starts
+    let mut vc = VC;
+    apply(&mut incoming.clone(), &mut vc);
+    let mut buffer = BytesMut::new();
+    sync(&mut r, &mut buffer, &vc, MAX_PAD + vc.len()).await?;
+    apply(&mut incoming, &mut vc);
+
+    fill(&mut r, &mut buffer, 6).await?;
+    let mut fields = buffer.split_to(6);
+    apply(&mut incoming, &mut fields);
+    let select = fields.get_u32();
+    let pad = fields.get_u16() as usize;
+    if pad > MAX_PAD {
+        return Err(GeneralError::Encryption(format!("{pad} bytes of padding")).into());
+    }
+
+    fill(&mut r, &mut buffer, pad).await?;
+    apply(&mut incoming, &mut buffer.split_to(pad));
+
+    match select & encryption.provide() {
+        RC4 if select == RC4 => wrap(r, w, Some((incoming, outgoing)), BytesMut::new(), buffer),
+        PLAINTEXT if select == PLAINTEXT => wrap(r, w, None, BytesMut::new(), buffer),
+        _ => Err(GeneralError::Encryption(format!("peer selected {select:#x}")).into()),
+    }
+}
+
+// the peer dialed and opened with its public key instead of a handshake, `prefix` is what was
+// read to tell the two apart. Only the torrents in `hashes` can be asked for, the returned hash
+// is the one the peer picked
+pub async fn respond(
+    stream: Stream,
+    prefix: BytesMut,
+    hashes: &[[u8; 20]],
+    encryption: Encryption,
+) -> Result<(Stream, [u8; 20]), Report> {
+    let Stream {
+        reader: mut r,
+        writer: mut w,
+    } = stream;
+    let mut buffer = prefix;
+
+    fill(&mut r, &mut buffer, KEY_LEN).await?;
+    let theirs = buffer.split_to(KEY_LEN);
+
+    let keys = KeyPair::generate();
+    w.write_all(&[&keys.public[..], &padding()].concat())
+        .await?;
+    let secret = keys.secret(&theirs)?;
+
+    let req1 = sha1(&[b"req1", &secret]);
+    sync(&mut r, &mut buffer, &req1, MAX_PAD + req1.len()).await?;
+
+    fill(&mut r, &mut buffer, 20).await?;
+    let req2 = buffer.split_to(20);
+    let req3 = sha1(&[b"req3", &secret]);
+    let hash = *hashes
+        .iter()
+        .find(|hash| xor(sha1(&[b"req2", &hash[..]]), req3) == req2[..])
+        .ok_or_else(|| GeneralError::UnknownTorrent("obfuscated".to_owned()))?;
+
+    let mut incoming = cipher(b"keyA", &secret, &hash);
+    let mut outgoing = cipher(b"keyB", &secret, &hash);
+
+    fill(&mut r, &mut buffer, VC.len() + 6).await?;
+    let mut fields = buffer.split_to(VC.len() + 6);
+    apply(&mut incoming, &mut fields);
+    if fields.split_to(VC.len())[..] != VC {
+        return Err(GeneralError::Encryption("wrong verification constant".to_owned()).into());
+    }
+    let provide = fields.get_u32();
+    let pad = fields.get_u16() as usize;
+    if pad > MAX_PAD {
+        return Err(GeneralError::Encryption(format!("{pad} bytes of padding")).into());
+    }
+
+    fill(&mut r, &mut buffer, pad + 2).await?;
+    let mut fields = buffer.split_to(pad + 2);
+    apply(&mut incoming, &mut fields);
+    fields.advance(pad);
+    let ia = fields.get_u16() as usize;
+
+    // the initial payload usually is the peer's handshake, it gets read before anything else
+    fill(&mut r, &mut buffer, ia).await?;
+    let mut payload = buffer.split_to(ia);
+    apply(&mut incoming, &mut payload);
+
+    let select = encryption
+        .select(provide)
+        .ok_or_else(|| GeneralError::Encryption(format!("peer provided {provide:#x}")))?;
+    let mut reply = [&VC[..], &select.to_be_bytes(), &0u16.to_be_bytes()].concat();
+    apply(&mut outgoing, &mut reply);
+    w.write_all(&reply).await?;
+
+    let keys = (select == RC4).then_some((incoming, outgoing));
+    Ok((wrap(r, w, keys, payload, buffer)?, hash))
+}
+
+// the incoming side of the stream, the bytes read along with the handshake come first
+struct Reader {
+    inner: ReadHalf,
+    cipher: Option<Rc4>,
+    buffer: BytesMut,
+}
+
+impl AsyncRead for Reader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.buffer.is_empty() {
+            let len = this.buffer.len().min(buf.remaining());
+            buf.put_slice(&this.buffer.split_to(len));
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(rc4) = &mut this.cipher {
+            apply(rc4, &mut buf.filled_mut()[filled..]);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+// the outgoing side of an RC4 stream. Bytes are encrypted once, a write that didn't get through
+// completely is expected to be retried with the same buffer, the way `write_all` does
+struct Writer {
+    inner: WriteHalf,
+    cipher: Rc4,
+    pending: BytesMut,
+    accepted: usize,
+}
+
+impl Writer {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.advance(n);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Writer {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.pending.is_empty() {
+            this.pending.extend_from_slice(buf);
+            apply(&mut this.cipher, &mut this.pending);
+            this.accepted = buf.len();
+        }
+        ready!(this.poll_pending(cx))?;
+
+        Poll::Ready(Ok(this.accepted))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::{duplex, split};
+
+    use super::*;
+
+    fn pair() -> (Stream, Stream) {
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let (a, b) = duplex(4096);
+        let (ar, aw) = split(a);
+        let (br, bw) = split(b);
+
+        (Stream::new(ar, aw, addr), Stream::new(br, bw, addr))
+    }
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(prime().bits(), 768);
+
+        let (a, b) = (KeyPair::generate(), KeyPair::generate());
+        assert_eq!(a.secret(&b.public).unwrap(), b.secret(&a.public).unwrap());
+
+        assert!(a.secret(&[0; KEY_LEN]).is_err());
+        assert!(a.secret(&to_key(&(prime() - 1u8))).is_err());
+    }
+
+    #[test]
+    fn test_select() {
+        assert_eq!(Encryption::Preferred.select(RC4 | PLAINTEXT), Some(RC4));
+        assert_eq!(Encryption::Preferred.select(PLAINTEXT), Some(PLAINTEXT));
+        assert_eq!(Encryption::Required.select(PLAINTEXT), None);
+        assert!(!Encryption::Required.allows(true));
+        assert!(!Encryption::Disabled.allows(false));
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let hash = [7; 20];
+        let hashes = [[1; 20], hash];
+        let (a, b) = pair();
+
+        let (a, b) = tokio::join!(
+            async {
+                let mut a = initiate(a, &hash, Encryption::Required).await.unwrap();
+                a.writer.write_all(PROTOCOL).await.unwrap();
+                a
+            },
+            respond(b, BytesMut::new(), &hashes, Encryption::Preferred)
+        );
+        let (mut a, (mut b, picked)) = (a, b.unwrap());
+        assert_eq!(picked, hash);
+
+        let mut buf = [0; 20];
+        b.reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, PROTOCOL);
+
+        let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        let (_, received) = tokio::join!(b.writer.write_all(&data), async {
+            let mut buf = vec![0; data.len()];
+            a.reader.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+        assert_eq!(received, data);
+
+        // a torrent we don't have
+        let (a, b) = pair();
+        let (_, b) = tokio::join!(
+            initiate(a, &[9; 20], Encryption::Preferred),
+            respond(b, BytesMut::new(), &hashes, Encryption::Preferred)
+        );
+        assert!(b.is_err());
+    }
+}
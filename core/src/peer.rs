@@ -0,0 +1,1531 @@
+use bendy::encoding::ToBencode;
+use bitvec::{order::Msb0, vec::BitVec};
+use bytes::{Buf, Bytes, BytesMut};
+use chrono::Utc;
+use futures_util::{stream::FuturesUnordered, Future, StreamExt};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{Cursor, Write},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::TcpListener,
+    sync::{
+        broadcast,
+        mpsc::{self, Receiver, Sender, UnboundedReceiver},
+        RwLock, Semaphore,
+    },
+    task::JoinSet,
+};
+
+use color_eyre::Report;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::{interval_at, sleep, timeout, Instant},
+};
+use tracing::debug;
+
+use crate::{
+    config::{Context, Tunables},
+    data::{GeneralError, Peers, TorrentInfo},
+    download::{Downloader, Pipeline},
+    extensions::{self, ExtensionRegistry, POLL_INTERVAL},
+    framing::FrameReader,
+    helpers::Timer,
+    limit::Limits,
+    lsd,
+    metadata::Metadata,
+    mse::{self, Encryption, PROTOCOL},
+    piece_manager::BitField,
+    proxy::Proxy,
+    shutdown::Signal,
+    sqlite::{Database, PeerEvent},
+    stats::{Connected, PeerStatus, Rates, Stats},
+    torrent::Torrent,
+    trace::{Direction, Inspector},
+    transport::{Dialer, ReadHalf, Stream, Transport, WriteHalf},
+    upload::{Uploader, RECHOKE},
+    utp::UtpSocket,
+    webseed::WebSeed,
+};
+
+use crate::pwp::*;
+
+// how often a connection checks whether the peer still has anything we want, on top of whenever
+// it tells us about new pieces
+const INTEREST_CHECK: Duration = Duration::from_secs(5);
+// how often outstanding requests get checked for whether the peer is snubbing us
+const SNUB_CHECK: Duration = Duration::from_secs(5);
+// how often a connection tells the torrent's counters how it's doing, for the dashboard
+const STATUS_REPORT: Duration = Duration::from_secs(1);
+
+pub struct Router {
+    pub torrent: Arc<TorrentInfo>,
+    pub peer_id: [u8; 20],
+    // announced to peers in a Port message, they can use it to reach our DHT node
+    pub port: u16,
+    pub extensions: Arc<ExtensionRegistry>,
+    pub bitfield: Vec<u64>,
+    pub peers: HashMap<SocketAddr, Connection>,
+    pub peer_rx: Receiver<Peers>,
+    // peers that treated us well in earlier sessions get connected to first
+    pub reputation: Option<Database>,
+    // pieces we just verified, every connection tells its peer about them
+    pub have_tx: broadcast::Sender<usize>,
+    // blocks that came in while other connections were asked for them as well
+    pub cancel_tx: broadcast::Sender<(SocketAddr, Block)>,
+    pub suppress_have: bool,
+    pub tunables: Tunables,
+    pub inspector: Option<Inspector>,
+    // where blocks get read from and written to, nothing gets transferred without it
+    pub data: Option<Arc<RwLock<Torrent>>>,
+    // the torrent's counters, connections count themselves in while they're up
+    pub stats: Option<Arc<Stats>>,
+    pub upload_slots: Arc<Semaphore>,
+    pub slot_count: usize,
+    // one permit for every connection, in either direction
+    pub peer_slots: Arc<Semaphore>,
+    // what every connection sends and receives, shared by the session
+    pub rates: Arc<Rates>,
+    // fetches the info dictionary of magnet links, also registered with the extensions
+    pub metadata: Option<Arc<Metadata>>,
+    // peers the listener accepted for this torrent
+    pub incoming: Option<Receiver<Inbound>>,
+    // how peers get dialed, TCP unless there's a uTP socket
+    pub dialer: Dialer,
+    // web seeds go through it as well
+    pub proxy: Option<Proxy>,
+    pub encryption: Encryption,
+    // the session's limits and the torrent's own
+    pub limits: Limits,
+    pub shutdown: Signal,
+}
+
+impl Router {
+    pub fn new(
+        ctx: &Context,
+        torrent: Arc<TorrentInfo>,
+        peer_id: [u8; 20],
+        peer_rx: Receiver<Peers>,
+    ) -> Self {
+        let mut dialer = Dialer::default();
+        if let Some(proxy) = &ctx.config.proxy {
+            dialer.set_proxy(proxy.clone());
+        }
+
+        Router {
+            peer_rx,
+            torrent,
+            peer_id,
+            port: ctx.port,
+            extensions: ctx.extensions.clone(),
+            peers: HashMap::new(),
+            bitfield: Vec::new(),
+            reputation: None,
+            have_tx: broadcast::channel(64).0,
+            cancel_tx: broadcast::channel(64).0,
+            suppress_have: ctx.config.suppress_have,
+            tunables: ctx.config.tunables.clone(),
+            inspector: ctx.inspector.clone(),
+            data: None,
+            stats: None,
+            upload_slots: Arc::new(Semaphore::new(ctx.config.upload_slots)),
+            slot_count: ctx.config.upload_slots,
+            peer_slots: Arc::new(Semaphore::new(ctx.config.max_peers)),
+            rates: ctx.rates.clone(),
+            metadata: None,
+            incoming: None,
+            dialer,
+            proxy: ctx.config.proxy.clone(),
+            encryption: ctx.config.encryption,
+            limits: ctx.limits.clone(),
+            shutdown: Signal::default(),
+        }
+    }
+
+    pub fn set_reputation(&mut self, db: Database) {
+        self.reputation = Some(db);
+    }
+
+    pub fn set_data(&mut self, torrent: Arc<RwLock<Torrent>>) {
+        self.data = Some(torrent);
+    }
+
+    pub fn set_stats(&mut self, stats: Arc<Stats>) {
+        self.stats = Some(stats);
+    }
+
+    pub fn set_metadata(&mut self, metadata: Arc<Metadata>) {
+        self.metadata = Some(metadata);
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn set_shutdown(&mut self, shutdown: Signal) {
+        self.shutdown = shutdown;
+    }
+
+    pub fn set_incoming(&mut self, incoming: Receiver<Inbound>) {
+        self.incoming = Some(incoming);
+    }
+
+    pub fn set_utp(&mut self, utp: Arc<UtpSocket>) {
+        self.dialer.set_utp(utp);
+    }
+
+    // the extensions of a single torrent, instead of the ones the session started with
+    pub fn set_extensions(&mut self, extensions: Arc<ExtensionRegistry>) {
+        self.extensions = extensions;
+    }
+
+    pub async fn run(mut self) {
+        let handshake = Arc::new(Handshake::new(self.torrent.hash, self.peer_id));
+
+        // magnet links don't know the piece count before the metadata came in
+        let pieces = self
+            .torrent
+            .info
+            .as_ref()
+            .map(|info| info.pieces.len())
+            .unwrap_or_default();
+
+        let port = self.port;
+        // connections and everything else we spawn go down with the router
+        let mut tasks = JoinSet::new();
+
+        // the torrent can start downloading as soon as a peer gave us its info dictionary
+        if let (Some(metadata), Some(torrent)) = (&self.metadata, &self.data) {
+            let mut info_rx = metadata.subscribe();
+            let torrent = torrent.clone();
+
+            tasks.spawn(async move {
+                let received = async {
+                    let info = info_rx.wait_for(Option::is_some).await?.clone().unwrap();
+                    let mut torrent = torrent.write().await;
+                    torrent.metadata_received(info)?;
+                    torrent.files_checked()
+                };
+
+                if let Err(e) = received.await {
+                    debug!("can't start with the received metadata: {e}");
+                }
+            });
+        }
+
+        // blocks from every connection end up in the same pipeline, which writes them to disk. It
+        // runs on its own so it gets to finish what's queued after the connections are gone
+        let (block_tx, pipeline) = match self.data.clone() {
+            Some(torrent) => {
+                let capacity = self.tunables.channel_capacity;
+                let (pipeline, block_tx) = Pipeline::new(
+                    torrent,
+                    self.have_tx.clone(),
+                    self.cancel_tx.clone(),
+                    capacity,
+                );
+                (Some(block_tx), Some(tokio::spawn(pipeline.run())))
+            }
+            None => (None, None),
+        };
+
+        // mirrors named in the torrent download alongside the peers
+        if let (Some(torrent), Some(block_tx)) = (&self.data, &block_tx) {
+            for url in &self.torrent.webseeds {
+                let downloader = Downloader::new(torrent.clone(), block_tx.clone());
+                let connect_timeout = self.tunables.peer_connect_timeout;
+                let seed = WebSeed::new(
+                    url.clone(),
+                    torrent.clone(),
+                    downloader,
+                    connect_timeout,
+                    self.proxy.as_ref(),
+                );
+                let seed = match seed {
+                    Ok(seed) => seed.limit(self.limits.download.clone()),
+                    Err(e) => {
+                        debug!("[{url}] skipping webseed: {e}");
+                        continue;
+                    }
+                };
+
+                let url = url.clone();
+                tasks.spawn(async move {
+                    if let Err(e) = seed.run().await {
+                        debug!("[{url}] webseed gave up: {e}");
+                    }
+                });
+            }
+        }
+
+        let mut incoming = self.incoming.take();
+        loop {
+            let mut peers = tokio::select! {
+                peers = self.peer_rx.recv() => match peers {
+                    Some(peers) => peers,
+                    None => break,
+                },
+                // peers that dialed us already sent their handshake, ours goes back
+                Some(inbound) = next_inbound(&mut incoming) => {
+                    let Ok(addr) = inbound.writer.peer_addr() else {
+                        continue;
+                    };
+                    let conn = Connection::accept(
+                        inbound,
+                        handshake.clone(),
+                        port,
+                        pieces,
+                        self.tunables.clone(),
+                        self.inspector.clone(),
+                        self.rates.clone(),
+                    );
+                    self.spawn_connection(&mut tasks, &block_tx, addr.ip(), conn);
+                    continue;
+                }
+                // finished connections don't need to be kept around
+                Some(_) = tasks.join_next() => continue,
+                _ = self.shutdown.recv() => break,
+            };
+
+            if let Some(db) = &self.reputation {
+                peers = db.rank(peers, |peer| peer.addr.ip());
+            }
+            // peers on our own network go first whatever their reputation, they're as fast as
+            // it gets and don't cost anything
+            peers.sort_by_key(|peer| !lsd::is_local(peer.addr.ip()));
+
+            for addrs in dual_stack(peers) {
+                let ip = addrs[0].ip();
+                let (dialer, handshake, tunables, inspector, rates) = (
+                    self.dialer.clone(),
+                    handshake.clone(),
+                    self.tunables.clone(),
+                    self.inspector.clone(),
+                    self.rates.clone(),
+                );
+                let encryption = self.encryption;
+                let conn = async move {
+                    let stream =
+                        establish(&dialer, &addrs, &handshake.hash, encryption, &tunables).await?;
+                    Connection::handshake(
+                        stream, handshake, port, pieces, tunables, inspector, rates,
+                    )
+                    .await
+                };
+                self.spawn_connection(&mut tasks, &block_tx, ip, conn);
+            }
+        }
+
+        // hanging up on every peer drops their senders, the pipeline writes out whatever they
+        // delivered before it stops
+        tasks.shutdown().await;
+        drop(block_tx);
+        if let Some(pipeline) = pipeline {
+            let _ = pipeline.await;
+        }
+    }
+
+    // runs a connection once `conn` is through the handshake, `ip` is who gets blamed if it
+    // never gets that far
+    fn spawn_connection<F>(
+        &self,
+        tasks: &mut JoinSet<()>,
+        block_tx: &Option<Sender<(SocketAddr, Block, Bytes)>>,
+        ip: IpAddr,
+        conn: F,
+    ) where
+        F: Future<Output = Result<Connection, Report>> + Send + 'static,
+    {
+        // held until the connection is gone, whether it failed to come up or the peer left
+        let Ok(permit) = self.peer_slots.clone().try_acquire_owned() else {
+            debug!("[{ip}] dropped, already connected to as many peers as allowed");
+            return;
+        };
+        let extensions = self.extensions.clone();
+        let db = self.reputation.clone();
+        let have_rx = self.have_tx.subscribe();
+        let cancel_rx = self.cancel_tx.subscribe();
+        let suppress_have = self.suppress_have;
+        let uploader = self
+            .data
+            .clone()
+            .map(|torrent| Uploader::new(torrent, self.upload_slots.clone(), self.slot_count));
+        let downloader = self
+            .data
+            .clone()
+            .zip(block_tx.clone())
+            .map(|(torrent, block_tx)| Downloader::new(torrent, block_tx));
+        let metadata = self.metadata.clone();
+        let limits = self.limits.clone();
+        let stats = self.stats.clone();
+
+        let f = async move {
+            let _permit = permit;
+            let conn = conn.await;
+            let ip = match &conn {
+                Ok(conn) => conn.inner.peer_addr().map(|addr| addr.ip()),
+                Err(_) => Ok(ip),
+            };
+
+            if let (Some(db), Ok(ip)) = (db, ip) {
+                let event = match conn {
+                    Ok(_) => PeerEvent::Connected,
+                    Err(_) => PeerEvent::ConnectFailed,
+                };
+                if let Err(e) = db.peer_event(&ip, event) {
+                    debug!("failed to record reputation of [{ip}]: {e}");
+                }
+            }
+
+            if let Ok(mut conn) = conn {
+                if let (Some(stats), Ok(addr)) = (stats, conn.inner.peer_addr()) {
+                    conn.set_connected(stats.connected(addr));
+                }
+                conn.set_limits(limits);
+                let transfer = (uploader, downloader);
+                conn.handle(
+                    extensions,
+                    have_rx,
+                    cancel_rx,
+                    suppress_have,
+                    transfer,
+                    metadata,
+                )
+                .await;
+            }
+        };
+
+        tasks.spawn(f);
+    }
+}
+
+// peers that showed up under an IPv4 and an IPv6 address (peers6, PEX) are dialed as one, they
+// can only be told apart by their id
+pub fn dual_stack(peers: Peers) -> Vec<Vec<SocketAddr>> {
+    let mut groups: Vec<Vec<SocketAddr>> = Vec::new();
+    let mut ids: HashMap<[u8; 20], usize> = HashMap::new();
+
+    for peer in peers {
+        let group = peer.id.and_then(|id| ids.get(&id).copied());
+
+        match group {
+            Some(i) if !groups[i].contains(&peer.addr) => groups[i].push(peer.addr),
+            Some(_) => {}
+            None => {
+                if let Some(id) = peer.id {
+                    ids.insert(id, groups.len());
+                }
+                groups.push(vec![peer.addr]);
+            }
+        }
+    }
+
+    groups
+}
+
+// IPv6 first, then alternating between the families so a broken one can't hold up the other
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|addr| addr.is_ipv6());
+    let mut out = Vec::with_capacity(addrs.len());
+
+    for i in 0..v6.len().max(v4.len()) {
+        out.extend(v6.get(i).copied());
+        out.extend(v4.get(i).copied());
+    }
+
+    out
+}
+
+// Happy Eyeballs: every attempt gets a head start on the next one unless it fails early, the
+// first to connect wins and the others get dropped. RFC 8305 suggests a stagger of 250ms
+pub async fn dial<T: Transport>(
+    transport: &T,
+    addrs: &[SocketAddr],
+    stagger: Duration,
+    connect_timeout: Duration,
+) -> Result<(Stream, SocketAddr), Report> {
+    let connect = |addr| async move {
+        let stream = transport.connect(addr, connect_timeout).await?;
+        Ok::<_, Report>((stream, addr))
+    };
+
+    let mut queue = interleave(addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last = None;
+
+    loop {
+        if attempts.is_empty() {
+            match queue.next() {
+                Some(addr) => attempts.push(connect(addr)),
+                None => break,
+            }
+        }
+
+        tokio::select! {
+            Some(attempt) = attempts.next() => match attempt {
+                Ok(x) => return Ok(x),
+                Err(e) => last = Some(e),
+            },
+            _ = sleep(stagger), if queue.len() > 0 => {
+                attempts.extend(queue.next().map(connect));
+            }
+        }
+    }
+
+    Err(last.unwrap_or_else(|| GeneralError::Timeout(None).into()))
+}
+
+// dials `addrs` and sets up encryption unless it's disabled. A peer that doesn't speak it gets
+// dialed again for a plaintext connection, as long as encryption is merely preferred
+pub async fn establish<T: Transport>(
+    transport: &T,
+    addrs: &[SocketAddr],
+    hash: &[u8; 20],
+    encryption: Encryption,
+    tunables: &Tunables,
+) -> Result<Stream, Report> {
+    let (stagger, limit) = (tunables.connect_stagger, tunables.peer_connect_timeout);
+    let (stream, addr) = dial(transport, addrs, stagger, limit).await?;
+    if encryption == Encryption::Disabled {
+        return Ok(stream);
+    }
+
+    let e = match timeout(limit, mse::initiate(stream, hash, encryption)).await {
+        Ok(Ok(stream)) => return Ok(stream),
+        Ok(Err(e)) => e,
+        Err(e) => e.into(),
+    };
+    if encryption == Encryption::Required {
+        return Err(e);
+    }
+
+    debug!("[{addr}] no encryption, falling back to plaintext: {e}");
+    Ok(dial(transport, &[addr], stagger, limit).await?.0)
+}
+
+// a peer that dialed us and named one of our torrents in its handshake
+pub struct Inbound {
+    pub reader: FrameReader<Message>,
+    pub writer: WriteHalf,
+    pub handshake: Handshake,
+}
+
+// the routers inbound connections get handed to, by the info hash they're for
+#[derive(Clone, Default)]
+pub struct Incoming {
+    torrents: Arc<Mutex<HashMap<[u8; 20], Sender<Inbound>>>>,
+}
+
+impl Incoming {
+    pub fn register(&self, hash: [u8; 20], capacity: usize) -> Receiver<Inbound> {
+        let (tx, rx) = mpsc::channel(capacity);
+        self.torrents.lock().unwrap().insert(hash, tx);
+        rx
+    }
+
+    pub fn unregister(&self, hash: &[u8; 20]) {
+        self.torrents.lock().unwrap().remove(hash);
+    }
+
+    // an encrypted handshake only names the torrent it's for in a hash of its info hash, every
+    // candidate has to be tried
+    fn hashes(&self) -> Vec<[u8; 20]> {
+        self.torrents.lock().unwrap().keys().copied().collect()
+    }
+
+    // a router that can't keep up doesn't get any more peers for now
+    fn route(&self, inbound: Inbound) -> Result<(), Report> {
+        let hash = inbound.handshake.hash;
+        let tx = self.torrents.lock().unwrap().get(&hash).cloned();
+        let tx = tx.ok_or_else(|| GeneralError::UnknownTorrent(hex::encode(hash)))?;
+
+        tx.try_send(inbound).map_err(|_| GeneralError::BrokenPipe)?;
+        Ok(())
+    }
+}
+
+// accepts the peers that found us through trackers, the DHT or PEX
+pub struct PeerListener {
+    listener: TcpListener,
+    // peers dialing us over uTP, they come in on our UDP port
+    utp: Option<Receiver<Stream>>,
+    incoming: Incoming,
+    tunables: Tunables,
+    encryption: Encryption,
+}
+
+impl PeerListener {
+    pub async fn bind(
+        addr: SocketAddr,
+        incoming: Incoming,
+        tunables: Tunables,
+    ) -> Result<Self, Report> {
+        let listener = TcpListener::bind(addr).await?;
+        debug!("accepting peers on {}", listener.local_addr()?);
+
+        Ok(Self {
+            listener,
+            utp: None,
+            incoming,
+            tunables,
+            encryption: Encryption::default(),
+        })
+    }
+
+    pub fn set_utp(&mut self, streams: Receiver<Stream>) {
+        self.utp = Some(streams);
+    }
+
+    pub fn set_encryption(&mut self, encryption: Encryption) {
+        self.encryption = encryption;
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Report> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub async fn run(mut self) -> Result<(), Report> {
+        loop {
+            let stream = tokio::select! {
+                accepted = self.listener.accept() => Stream::tcp(accepted?.0)?,
+                Some(stream) = next_inbound(&mut self.utp) => stream,
+            };
+            let peer = stream.writer.peer_addr()?;
+            let incoming = self.incoming.clone();
+            let tunables = self.tunables.clone();
+            let encryption = self.encryption;
+
+            tokio::spawn(async move {
+                if let Err(e) = accept(stream, incoming, tunables, encryption).await {
+                    debug!("[{peer}] inbound connection refused: {e}");
+                }
+            });
+        }
+    }
+}
+
+// the peer speaks first, its handshake tells us which torrent it wants. Encrypted peers open with
+// their public key instead
+async fn accept(
+    stream: Stream,
+    incoming: Incoming,
+    tunables: Tunables,
+    encryption: Encryption,
+) -> Result<(), Report> {
+    let handshake = async {
+        let Stream {
+            reader: mut r,
+            writer,
+        } = stream;
+
+        let mut prefix = [0; PROTOCOL.len()];
+        r.read_exact(&mut prefix).await?;
+        let plaintext = &prefix == PROTOCOL;
+        if !encryption.allows(plaintext) {
+            let kind = if plaintext { "plaintext" } else { "encrypted" };
+            return Err(GeneralError::Encryption(format!("{kind} peers aren't allowed")).into());
+        }
+
+        let prefix = BytesMut::from(&prefix[..]);
+        let (r, writer, mut buffer) = match plaintext {
+            true => (r, writer, prefix),
+            false => {
+                let stream = Stream { reader: r, writer };
+                let (stream, _) =
+                    mse::respond(stream, prefix, &incoming.hashes(), encryption).await?;
+                (stream.reader, stream.writer, BytesMut::new())
+            }
+        };
+
+        buffer.reserve(tunables.read_buffer);
+        let mut reader: FrameReader<Handshake> = FrameReader::from(r, buffer);
+        let handshake = reader.read_frame().await?.ok_or(GeneralError::BrokenPipe)?;
+        Ok::<_, Report>((reader, writer, handshake))
+    };
+    let (reader, writer, handshake) = timeout(tunables.peer_connect_timeout, handshake).await??;
+
+    incoming.route(Inbound {
+        reader: FrameReader::from(reader.inner, reader.buffer),
+        writer,
+        handshake,
+    })
+}
+
+// never resolves once there's nothing left to accept, so it can sit in a select
+async fn next_inbound<T>(incoming: &mut Option<Receiver<T>>) -> Option<T> {
+    match incoming {
+        Some(incoming) => incoming.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[derive(Debug)]
+pub struct Connection {
+    pub inner: WriteHalf,
+    pub buffer: BytesMut,
+    pub state: Arc<RwLock<State>>,
+    pub frame_rx: Receiver<Message>,
+    pub real_len: usize,
+    pub tunables: Tunables,
+    pub inspector: Option<Inspector>,
+    pub rates: Arc<Rates>,
+    pub limits: Limits,
+    // announced in a Port message once the handshakes are done
+    pub port: u16,
+    // whether our handshake set the fast extension bit, and once the peer's is in whether it did
+    // as well
+    pub fast: bool,
+    // counts the peer in with the torrent until the connection is gone
+    pub connected: Option<Connected>,
+    // pub piece_tx: Sender<Message>,
+}
+
+impl Connection {
+    pub fn new(
+        inner: WriteHalf,
+        frame_rx: Receiver<Message>,
+        real_len: usize,
+        tunables: Tunables,
+        inspector: Option<Inspector>,
+        rates: Arc<Rates>,
+    ) -> Self {
+        Self {
+            inner,
+            frame_rx,
+            real_len,
+            tunables,
+            inspector,
+            rates,
+            limits: Limits::default(),
+            port: 0,
+            fast: false,
+            connected: None,
+            buffer: BytesMut::new(),
+            state: Arc::new(RwLock::new(State::default())),
+        }
+    }
+
+    // we dialed, so our handshake goes first
+    pub async fn handshake(
+        stream: Stream,
+        handshake: Arc<Handshake>,
+        port: u16,
+        // piece_tx: Sender<Message>,
+        pieces: usize,
+        tunables: Tunables,
+        inspector: Option<Inspector>,
+        rates: Arc<Rates>,
+    ) -> Result<Connection, Report> {
+        let Stream {
+            reader: r,
+            writer: w,
+        } = stream;
+        let addr = w.peer_addr()?;
+
+        let (frame_tx, frame_rx) = mpsc::channel(tunables.channel_capacity);
+        // spawn the FramedReader
+        tokio::spawn(Connection::listen(
+            r,
+            frame_tx,
+            tunables.read_buffer,
+            inspector.clone(),
+        ));
+
+        let mut conn = Connection::new(w, frame_rx, pieces, tunables, inspector, rates);
+        conn.port = port;
+        conn.fast = handshake.fast();
+        conn.send(&Message::Handshake((*handshake).clone()), addr)
+            .await?;
+        debug!("handshake was sent to [{addr}] ...");
+
+        Ok(conn)
+    }
+
+    // the peer dialed us and sent its handshake already, ours goes back before anything else
+    pub async fn accept(
+        inbound: Inbound,
+        handshake: Arc<Handshake>,
+        port: u16,
+        pieces: usize,
+        tunables: Tunables,
+        inspector: Option<Inspector>,
+        rates: Arc<Rates>,
+    ) -> Result<Connection, Report> {
+        let Inbound {
+            reader,
+            writer,
+            handshake: theirs,
+        } = inbound;
+        let addr = writer.peer_addr()?;
+
+        // the connection answers it like the handshake of a peer we dialed
+        let (frame_tx, frame_rx) = mpsc::channel(tunables.channel_capacity);
+        let theirs = Message::Handshake(theirs);
+        if let Some(inspector) = &inspector {
+            inspector.inspect(addr, Direction::Inbound, &theirs);
+        }
+        frame_tx.send(theirs).await?;
+        tokio::spawn(Connection::frames(reader, frame_tx, inspector.clone()));
+
+        let mut conn = Connection::new(writer, frame_rx, pieces, tunables, inspector, rates);
+        conn.port = port;
+        conn.fast = handshake.fast();
+        conn.send(&Message::Handshake((*handshake).clone()), addr)
+            .await?;
+        debug!("accepted [{addr}]");
+
+        Ok(conn)
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn set_connected(&mut self, connected: Connected) {
+        self.connected = Some(connected);
+    }
+
+    pub async fn send(&mut self, message: &Message, peer: SocketAddr) -> Result<(), Report> {
+        // only the data counts, the protocol chatter around it shouldn't have to wait
+        if let Message::Piece { block, .. } = message {
+            self.limits.upload.acquire(block.len()).await;
+        }
+        if let Some(inspector) = &self.inspector {
+            inspector.inspect(peer, Direction::Outbound, message);
+        }
+        let frame = message.to_request();
+        self.inner.write_all(&frame).await?;
+        self.rates.uploaded(peer, frame.len());
+
+        Ok(())
+    }
+
+    pub async fn keep_alive(mut w: WriteHalf, interval: Duration) {
+        loop {
+            sleep(interval).await;
+            let src = 0u32.to_be_bytes();
+            let _ = w.write_all(&src).await;
+        }
+    }
+
+    pub async fn listen(
+        r: ReadHalf,
+        tx: Sender<Message>,
+        capacity: usize,
+        inspector: Option<Inspector>,
+    ) -> Result<(), Report> {
+        let peer_addr = r.peer_addr()?;
+        let inspect = |message: &Message| {
+            if let Some(inspector) = &inspector {
+                inspector.inspect(peer_addr, Direction::Inbound, message);
+            }
+        };
+
+        let mut reader: FrameReader<Handshake> = FrameReader::new(r, capacity);
+        if let Some(handshake) = reader.read_frame().await? {
+            debug!("[{}] received the handshake ...", peer_addr);
+
+            handshake.reserved;
+
+            if handshake.reserved[7] | 0x01 == 1 {
+                debug!("supports DHT: [{}]", peer_addr);
+            }
+
+            // the connection decides what to answer with
+            let handshake = Message::Handshake(handshake);
+            inspect(&handshake);
+            tx.send(handshake).await?;
+        }
+        // let mut reader: FrameReader<extensions::Handshake> = FrameReader::new(r);
+
+        let reader: FrameReader<Message> = FrameReader::from(reader.inner, reader.buffer);
+        Connection::frames(reader, tx, inspector).await
+    }
+
+    // everything after the handshake
+    pub async fn frames(
+        mut reader: FrameReader<Message>,
+        tx: Sender<Message>,
+        inspector: Option<Inspector>,
+    ) -> Result<(), Report> {
+        let peer_addr = reader.inner.peer_addr()?;
+
+        while let Some(frame) = reader.read_frame().await? {
+            debug!("received frame from [{}]", peer_addr);
+
+            if let Some(inspector) = &inspector {
+                inspector.inspect(peer_addr, Direction::Inbound, &frame);
+            }
+            tx.send(frame).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn handle(
+        mut self,
+        extensions: Arc<ExtensionRegistry>,
+        mut have_rx: broadcast::Receiver<usize>,
+        mut cancel_rx: broadcast::Receiver<(SocketAddr, Block)>,
+        suppress_have: bool,
+        (mut uploader, mut downloader): (Option<Uploader>, Option<Downloader>),
+        metadata: Option<Arc<Metadata>>,
+    ) {
+        let dst = self.inner.peer_addr().unwrap();
+
+        // caching
+        let mut have_buffer: Vec<usize> = Vec::with_capacity(64);
+        let mut timer = Timer::new(self.tunables.have_batch);
+        let mut pieces = PeerPieces::default();
+        let mut rechoke = interval_at(Instant::now() + RECHOKE, RECHOKE);
+        let mut poll = interval_at(Instant::now() + POLL_INTERVAL, POLL_INTERVAL);
+        let mut interest = interval_at(Instant::now() + INTEREST_CHECK, INTEREST_CHECK);
+        let mut snub = interval_at(Instant::now() + SNUB_CHECK, SNUB_CHECK);
+        let mut report = interval_at(Instant::now() + STATUS_REPORT, STATUS_REPORT);
+        // nothing goes out before what we have, which waits for the peer's handshake
+        let mut greeted = false;
+
+        extensions.connected(dst);
+        loop {
+            let message = tokio::select! {
+                message = self.frame_rx.recv() => match message {
+                    Some(message) => message,
+                    None => break,
+                },
+                Ok(index) = have_rx.recv() => {
+                    // the piece is part of what we greet the peer with otherwise
+                    if greeted && pieces.wants_have(index, suppress_have) {
+                        let _ = self.send(&Message::Have(index), dst).await;
+                    }
+                    // it may have been the last one the peer had for us
+                    if self.state.read().await.interested {
+                        let has = |i| pieces.has(i);
+                        self.update_interest(downloader.as_ref(), &has, dst).await;
+                    }
+                    continue;
+                }
+                Ok((from, block)) = cancel_rx.recv() => {
+                    let Some(downloader) = downloader.as_mut().filter(|_| from != dst) else {
+                        continue;
+                    };
+                    if downloader.cancel(block).await {
+                        let _ = self.send(&block.cancel(), dst).await;
+                    }
+                    continue;
+                }
+                Some(piece) = next_upload(&mut uploader) => {
+                    match piece {
+                        Ok(piece) => {
+                            let _ = self.send(&piece, dst).await;
+                        }
+                        Err(e) => debug!("[{dst}] can't serve request: {e}"),
+                    }
+                    continue;
+                }
+                _ = rechoke.tick(), if uploader.is_some() => {
+                    let rank = self.rates.rank(dst);
+                    if let Some(unchoke) = uploader.as_mut().and_then(|u| u.rechoke(rank)) {
+                        self.state.write().await.peer_choked = !unchoke;
+                        let m = match unchoke {
+                            true => Message::Unchoke,
+                            false => Message::Choke,
+                        };
+                        let _ = self.send(&m, dst).await;
+                    }
+                    for m in uploader.as_mut().map(Uploader::rejects).unwrap_or_default() {
+                        let _ = self.send(&m, dst).await;
+                    }
+                    continue;
+                }
+                _ = poll.tick() => {
+                    for m in extensions.poll(dst) {
+                        let _ = self.send(&m, dst).await;
+                    }
+                    continue;
+                }
+                // what we want changes without the peer saying anything, once the metadata is in
+                // or a file gets skipped
+                _ = interest.tick() => {
+                    let has = |i| pieces.has(i);
+                    self.update_interest(downloader.as_ref(), &has, dst).await;
+                    continue;
+                }
+                // a peer that sits on our requests gets them cancelled, the other connections
+                // pick up the pieces and it only gets the upload slots nobody else wants
+                now = snub.tick(), if downloader.is_some() => {
+                    let Some(downloader) = downloader.as_mut() else {
+                        continue;
+                    };
+                    let cancelled = downloader.snubbed(now).await;
+                    if !cancelled.is_empty() {
+                        debug!("[{dst}] snubbed us, {} requests reassigned", cancelled.len());
+                        self.rates.set_snubbed(dst, true);
+                    }
+                    for block in cancelled {
+                        let _ = self.send(&block.cancel(), dst).await;
+                    }
+                    continue;
+                }
+                _ = report.tick(), if self.connected.is_some() => {
+                    self.report(&pieces, &extensions, dst).await;
+                    continue;
+                }
+            };
+            self.rates.downloaded(dst, message.wire_len());
+
+            match &message {
+                Message::Handshake(h) if !greeted => {
+                    self.fast &= h.fast();
+                    self.greet(uploader.as_mut(), dst).await;
+                    greeted = true;
+                }
+                Message::Have(idx) => {
+                    timer.reset();
+                    have_buffer.push(*idx);
+                    pieces.have(*idx);
+
+                    // a single piece can only make us interested, not the other way around
+                    if !self.state.read().await.interested {
+                        let has = |i| i == *idx;
+                        self.update_interest(downloader.as_ref(), &has, dst).await;
+                    }
+                }
+                Message::BitField(bytes) => {
+                    pieces.bitfield(bytes);
+
+                    if let Some(downloader) = downloader.as_ref() {
+                        downloader
+                            .peer_bitfield(dst, BitField::from_bytes(bytes))
+                            .await;
+                    }
+                    let has = |i| pieces.has(i);
+                    self.update_interest(downloader.as_ref(), &has, dst).await;
+                }
+                Message::HaveAll => {
+                    pieces.have_all();
+
+                    if let Some(downloader) = downloader.as_ref() {
+                        downloader.peer_have_all(dst).await;
+                    }
+                    let has = |i| pieces.has(i);
+                    self.update_interest(downloader.as_ref(), &has, dst).await;
+                }
+                Message::HaveNone => {
+                    pieces.bitfield(&[]);
+
+                    if let Some(downloader) = downloader.as_ref() {
+                        downloader
+                            .peer_bitfield(dst, BitField::new(Vec::new()))
+                            .await;
+                    }
+                }
+                Message::Choke => {
+                    if let Some(downloader) = downloader.as_mut() {
+                        downloader.choked().await;
+                    }
+                }
+                Message::SuggestPiece(index) => {
+                    if let Some(downloader) = downloader.as_mut() {
+                        downloader.suggest(*index);
+                    }
+                }
+                Message::AllowedFast(index) => {
+                    if let Some(downloader) = downloader.as_mut() {
+                        downloader.allow(*index);
+                    }
+                }
+                Message::RejectRequest {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    let block = Block {
+                        index: *index,
+                        begin: *begin,
+                        length: *length,
+                    };
+                    if let Some(downloader) = downloader.as_mut() {
+                        for block in downloader.rejected(block).await {
+                            let _ = self.send(&block.cancel(), dst).await;
+                        }
+                    }
+                }
+                Message::Unchoke => {
+                    if let Some(downloader) = downloader.as_mut() {
+                        if downloader.is_snubbed() {
+                            self.rates.set_snubbed(dst, false);
+                        }
+                        downloader.unchoked();
+                    }
+                }
+                Message::Piece {
+                    index,
+                    begin,
+                    block,
+                } => {
+                    // the reader stops once the frames pile up, which slows the peer down as well
+                    self.limits.download.acquire(block.len()).await;
+                    if let Some(downloader) = downloader.as_mut() {
+                        let received = downloader.received(dst, *index, *begin, block.clone());
+                        if let Err(e) = received.await {
+                            debug!("[{dst}] unexpected block: {e}");
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            // check if timer elapsed on all messages
+            if timer.elapsed() && !have_buffer.is_empty() {
+                if let Some(downloader) = downloader.as_ref() {
+                    downloader.peer_have(dst, &have_buffer).await;
+                }
+                have_buffer.clear();
+            }
+
+            // simple state changes, the lock is held on a clone so replies can still be sent
+            let shared = self.state.clone();
+            let mut state = shared.write().await;
+            match message {
+                Message::Choke => {
+                    state.choked = true;
+                }
+                Message::Unchoke => {
+                    state.choked = false;
+                }
+                Message::Interested => {
+                    state.peer_interested = true;
+                    self.rates.set_interested(dst, true);
+
+                    if uploader.as_mut().is_some_and(Uploader::interested) {
+                        state.peer_choked = false;
+                        let _ = self.send(&Message::Unchoke, dst).await;
+                    }
+                }
+                Message::Uninterested => {
+                    state.peer_interested = false;
+                    self.rates.set_interested(dst, false);
+
+                    if uploader.as_mut().is_some_and(Uploader::uninterested) {
+                        state.peer_choked = true;
+                        let _ = self.send(&Message::Choke, dst).await;
+                    }
+                }
+                Message::Request {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    let block = Block {
+                        index,
+                        begin,
+                        length,
+                    };
+                    if let Some(Err(e)) = uploader.as_mut().map(|u| u.request(block)) {
+                        debug!("[{dst}] invalid request: {e}");
+                    }
+                }
+                Message::Cancel {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    if let Some(uploader) = uploader.as_mut() {
+                        uploader.cancel(Block {
+                            index,
+                            begin,
+                            length,
+                        });
+                    }
+                }
+                Message::Port(i) => {
+                    state.dht_port = Some(i);
+                }
+                // extension handshakes may only be sent once both sides set the bit
+                Message::Handshake(h) if h.reserved[5] & 0x10 != 0 => {
+                    let payload = extensions::Message::Handshake(extensions.handshake())
+                        .to_bencode()
+                        .map(Bytes::from);
+
+                    if let Ok(payload) = payload {
+                        let h = Message::Extended { id: 0, payload };
+                        let _ = self.send(&h, dst).await;
+                    }
+                }
+                Message::Extended { id, payload } => {
+                    match extensions.dispatch(dst, id, payload) {
+                        Ok(Some(reply)) => {
+                            let _ = self.send(&reply, dst).await;
+                        }
+                        Ok(None) => {}
+                        Err(e) => debug!("[{dst}] extended message {id}: {e}"),
+                    }
+
+                    // how many requests the peer queues decides how many we keep outstanding
+                    let reqq = extensions.reqq(dst).filter(|_| id == 0);
+                    if let Some((downloader, reqq)) = downloader.as_mut().zip(reqq) {
+                        downloader.set_limit(reqq as usize);
+                    }
+
+                    // its handshake told us whether the peer serves the metadata and how large
+                    // it is, all pieces nobody else is fetching get requested from it at once
+                    let serves = extensions.remote_id(dst, "ut_metadata").is_some();
+                    if let Some(metadata) = metadata.as_ref().filter(|_| id == 0 && serves) {
+                        for piece in metadata.claim(dst) {
+                            let request = Metadata::request(piece);
+                            if let Some(m) = extensions.encode(dst, "ut_metadata", request) {
+                                let _ = self.send(&m, dst).await;
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+            let choked = state.choked;
+            drop(state);
+
+            for m in uploader.as_mut().map(Uploader::rejects).unwrap_or_default() {
+                let _ = self.send(&m, dst).await;
+            }
+
+            // keeps the pipe to the peer full, every block that arrives makes room for the next
+            // request. A peer that chokes us may still serve its allowed fast pieces
+            if let Some(downloader) = downloader.as_mut() {
+                let has = |i| pieces.has(i);
+                let blocks = match choked {
+                    true => downloader.next_allowed(&has).await,
+                    false => downloader.next_blocks(&has).await,
+                };
+                for block in blocks {
+                    let _ = self.send(&block.request(), dst).await;
+                }
+            }
+        }
+
+        // whatever it was downloading goes back to the other connections
+        if let Some(mut downloader) = downloader {
+            downloader.gone(dst).await;
+        }
+        if let Some(metadata) = metadata {
+            metadata.peer_gone(dst);
+        }
+        extensions.disconnected(dst);
+        self.rates.peer_gone(dst);
+    }
+
+    // what we have goes right after the handshake or not at all, peers that never hear about our
+    // pieces never get interested in them
+    async fn greet(&mut self, uploader: Option<&mut Uploader>, dst: SocketAddr) {
+        let bitfield = match uploader.as_deref() {
+            Some(uploader) => uploader.bitfield(self.fast).await,
+            None => None,
+        };
+        // without the metadata there's nothing to have
+        if let Some(bitfield) = bitfield.or(self.fast.then_some(Message::HaveNone)) {
+            let _ = self.send(&bitfield, dst).await;
+        }
+        // our handshake sets the DHT bit, so the port has to follow
+        let _ = self.send(&Message::Port(self.port), dst).await;
+
+        let Some(uploader) = uploader.filter(|_| self.fast) else {
+            return;
+        };
+        uploader.set_fast(true);
+        if let IpAddr::V4(ip) = dst.ip() {
+            for m in uploader.allow_fast(ip).await {
+                let _ = self.send(&m, dst).await;
+            }
+        }
+    }
+
+    // interested for as long as the peer has a piece we still want, peers don't unchoke anyone
+    // who isn't, and those who are get choked by peers that have nothing for them
+    async fn update_interest(
+        &mut self,
+        downloader: Option<&Downloader>,
+        has: &(dyn Fn(usize) -> bool + Sync),
+        dst: SocketAddr,
+    ) {
+        let wants = match downloader {
+            Some(downloader) => downloader.wants(has).await,
+            None => false,
+        };
+
+        let mut state = self.state.write().await;
+        if state.interested == wants {
+            return;
+        }
+        state.interested = wants;
+        drop(state);
+
+        let m = match wants {
+            true => Message::Interested,
+            false => Message::Uninterested,
+        };
+        let _ = self.send(&m, dst).await;
+    }
+
+    async fn report(
+        &mut self,
+        pieces: &PeerPieces,
+        extensions: &ExtensionRegistry,
+        dst: SocketAddr,
+    ) {
+        let Some(connected) = &self.connected else {
+            return;
+        };
+
+        let state = self.state.read().await;
+        connected.report(PeerStatus {
+            client: extensions.client(dst),
+            choked: state.choked,
+            interested: state.interested,
+            peer_choked: state.peer_choked,
+            peer_interested: state.peer_interested,
+            pieces: pieces.count(),
+            seed: pieces.all,
+        });
+    }
+
+    pub async fn get_metadata(&self) {
+        // self.inner
+    }
+}
+
+// never resolves without an uploader or anything queued, so it can sit in a select
+async fn next_upload(uploader: &mut Option<Uploader>) -> Option<Result<Message, Report>> {
+    match uploader {
+        Some(uploader) if !uploader.is_empty() => uploader.next().await,
+        _ => futures_util::future::pending().await,
+    }
+}
+
+// what the other side told us it has, so we don't tell it about pieces it already knows
+#[derive(Debug, Default)]
+pub struct PeerPieces {
+    inner: BitVec<u8, Msb0>,
+    // a HaveAll, without the metadata we wouldn't know how many bits to set
+    all: bool,
+}
+
+impl PeerPieces {
+    pub fn bitfield(&mut self, bytes: &[u8]) {
+        self.inner = BitVec::from_slice(bytes);
+        self.all = false;
+    }
+
+    pub fn have_all(&mut self) {
+        self.inner.clear();
+        self.all = true;
+    }
+
+    pub fn have(&mut self, index: usize) {
+        if self.all {
+            return;
+        }
+        if index >= self.inner.len() {
+            self.inner.resize(index + 1, false);
+        }
+        self.inner.set(index, true);
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        self.all || self.inner.get(index).map(|b| *b).unwrap_or(false)
+    }
+
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.inner.iter_ones()
+    }
+
+    // of the ones we know about, a seed has all of them
+    pub fn count(&self) -> usize {
+        self.inner.count_ones()
+    }
+
+    // a Have the peer doesn't need is pure overhead, except for swarms that use them to account
+    // for what everybody completed
+    pub fn wants_have(&self, index: usize, suppress: bool) -> bool {
+        !suppress || !self.has(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Peer;
+    use crate::transport::Tcp;
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn test_dual_stack() {
+        let peer = |id: Option<u8>, addr: &str| Peer {
+            id: id.map(|id| [id; 20]),
+            addr: addr.parse().unwrap(),
+        };
+        let groups = dual_stack(vec![
+            peer(Some(1), "10.0.0.1:6881"),
+            peer(None, "10.0.0.2:6881"),
+            peer(Some(1), "[2001:db8::1]:6881"),
+            peer(Some(1), "10.0.0.1:6881"),
+            peer(None, "[2001:db8::2]:6881"),
+        ]);
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[0].len(), 2);
+
+        let addrs: Vec<SocketAddr> = ["10.0.0.1:1", "10.0.0.2:1", "[::1]:1"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert_eq!(interleave(&addrs), vec![addrs[2], addrs[0], addrs[1]]);
+    }
+
+    #[test]
+    fn test_dial() -> Result<(), Report> {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+            let open = listener.local_addr()?;
+            // the listener is gone again before we connect, so this gets refused right away
+            let closed = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+
+            let timeout = Tunables::default().peer_connect_timeout;
+            let (_, addr) = dial(&Tcp, &[closed, open], Duration::from_secs(60), timeout).await?;
+            assert_eq!(addr, open);
+            assert!(dial(&Tcp, &[closed], Duration::ZERO, timeout)
+                .await
+                .is_err());
+            assert!(dial(&Tcp, &[], Duration::ZERO, timeout).await.is_err());
+
+            // nobody answers the SYN on the listener's port, so it's TCP after all
+            let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+            let mut dialer = Dialer::default();
+            dialer.set_utp(UtpSocket::new(Arc::new(socket)));
+            let timeout = Duration::from_millis(200);
+            let (_, addr) = dial(&dialer, &[open], Duration::ZERO, timeout).await?;
+            assert_eq!(addr, open);
+
+            Ok(())
+        })
+    }
+
+    #[tokio::test]
+    async fn test_listener() -> Result<(), Report> {
+        let incoming = Incoming::default();
+        let mut rx = incoming.register([1u8; 20], 4);
+        let listener = PeerListener::bind(
+            "127.0.0.1:0".parse()?,
+            incoming.clone(),
+            Tunables::default(),
+        )
+        .await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(listener.run());
+
+        // peers asking for a torrent we don't have get hung up on
+        let mut stream = TcpStream::connect(addr).await?;
+        let theirs = Handshake::new([2u8; 20], [9u8; 20]);
+        stream
+            .write_all(&Message::Handshake(theirs).to_request())
+            .await?;
+        let mut buf = Vec::new();
+        assert_eq!(stream.read_to_end(&mut buf).await?, 0);
+
+        let mut stream = TcpStream::connect(addr).await?;
+        let theirs = Handshake::new([1u8; 20], [9u8; 20]);
+        stream
+            .write_all(&Message::Handshake(theirs).to_request())
+            .await?;
+        let inbound = rx.recv().await.unwrap();
+        assert_eq!(inbound.handshake.peer_id, [9u8; 20]);
+
+        // ours goes back, then the peer's handshake reaches the connection like any other frame
+        let ours = Arc::new(Handshake::new([1u8; 20], [7u8; 20]));
+        let tunables = Tunables::default();
+        let mut conn =
+            Connection::accept(inbound, ours, 6881, 0, tunables, None, Arc::default()).await?;
+        let mut buf = [0u8; 68];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!((&buf[28..48], &buf[48..]), (&[1u8; 20][..], &[7u8; 20][..]));
+        assert!(matches!(
+            conn.frame_rx.recv().await,
+            Some(Message::Handshake(h)) if h.peer_id == [9u8; 20]
+        ));
+
+        incoming.unregister(&[1u8; 20]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_listener() -> Result<(), Report> {
+        let hash = [1u8; 20];
+        let tunables = Tunables::default();
+
+        for (theirs, ours, encrypted) in [
+            (Encryption::Required, Encryption::Preferred, true),
+            // falls back to plaintext once the listener hung up on the encrypted handshake
+            (Encryption::Disabled, Encryption::Preferred, false),
+        ] {
+            let incoming = Incoming::default();
+            let mut rx = incoming.register(hash, 4);
+            let mut listener =
+                PeerListener::bind("127.0.0.1:0".parse()?, incoming, tunables.clone()).await?;
+            listener.set_encryption(theirs);
+            let addr = listener.local_addr()?;
+            tokio::spawn(listener.run());
+
+            let mut stream = establish(&Tcp, &[addr], &hash, ours, &tunables).await?;
+            let handshake = Message::Handshake(Handshake::new(hash, [9u8; 20])).to_request();
+            stream.writer.write_all(&handshake).await?;
+
+            let mut inbound = rx.recv().await.unwrap();
+            assert_eq!(inbound.handshake.peer_id, [9u8; 20]);
+            inbound.writer.write_all(b"pong").await?;
+            let mut buf = [0u8; 4];
+            stream.reader.read_exact(&mut buf).await?;
+            assert_eq!(&buf, b"pong");
+
+            // required encryption refuses plaintext peers
+            if encrypted {
+                let mut stream = TcpStream::connect(addr).await?;
+                stream.write_all(&handshake).await?;
+                let mut buf = Vec::new();
+                assert_eq!(stream.read_to_end(&mut buf).await?, 0);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_have_suppression() {
+        let mut pieces = PeerPieces::default();
+        assert!(pieces.wants_have(3, true));
+
+        // pieces 0 and 9 in wire order
+        pieces.bitfield(&[0b1000_0000, 0b0100_0000]);
+        assert!(pieces.has(0) && pieces.has(9) && !pieces.has(1));
+
+        pieces.have(1000);
+        assert!(pieces.has(1000));
+        assert_eq!(pieces.count(), 3);
+        assert!(!pieces.wants_have(9, true));
+        assert!(pieces.wants_have(9, false));
+        assert!(pieces.wants_have(2, true));
+
+        // a seed doesn't get to hear about anything
+        pieces.have_all();
+        assert!(pieces.has(2) && !pieces.wants_have(2, true));
+        pieces.bitfield(&[]);
+        assert!(!pieces.has(0) && pieces.wants_have(0, true));
+    }
+}
@@ -0,0 +1,96 @@
+use std::iter::{once, repeat};
+
+use rand::{distributions::Alphanumeric, Rng};
+
+// a version part of 0..64 fits in a single character
+const ALPHABET: &[u8; 64] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz.-";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum Style {
+    // -XV0100-<12 random>
+    #[default]
+    Azureus,
+    // X010-----<11 random>
+    Shadow,
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerIdConfig {
+    pub style: Style,
+    pub client: String,
+    pub version: Vec<u8>,
+    // some private trackers refuse to see the same id on different torrents
+    pub per_torrent: bool,
+}
+
+impl Default for PeerIdConfig {
+    fn default() -> Self {
+        Self {
+            style: Style::default(),
+            client: "XV".to_owned(),
+            version: parse_version(env!("CARGO_PKG_VERSION")),
+            per_torrent: false,
+        }
+    }
+}
+
+impl PeerIdConfig {
+    pub fn generate(&self) -> [u8; 20] {
+        let client = self.client.bytes().chain(repeat(b'-'));
+        let version = self.version.iter().map(|&v| ALPHABET[v as usize % 64]);
+
+        let prefix: Vec<u8> = match self.style {
+            Style::Azureus => once(b'-')
+                .chain(client.take(2))
+                .chain(version.chain(repeat(b'0')).take(4))
+                .chain(once(b'-'))
+                .collect(),
+            Style::Shadow => client
+                .take(1)
+                .chain(version.take(5).chain(repeat(b'-')).take(5))
+                .chain(*b"---")
+                .collect(),
+        };
+
+        let mut id = [0u8; 20];
+        let random = rand::thread_rng().sample_iter(&Alphanumeric);
+
+        for (b, x) in id.iter_mut().zip(prefix.into_iter().chain(random)) {
+            *b = x;
+        }
+
+        id
+    }
+}
+
+// "1.2.3" -> [1, 2, 3], anything that isn't a number counts as 0
+pub fn parse_version(s: &str) -> Vec<u8> {
+    s.split('.').map(|part| part.parse().unwrap_or(0)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_id_styles() {
+        let mut config = PeerIdConfig {
+            style: Style::Azureus,
+            client: "XV".to_owned(),
+            version: parse_version("1.2.3"),
+            per_torrent: false,
+        };
+
+        let id = config.generate();
+        assert_eq!(&id[..8], b"-XV1230-");
+        assert!(id[8..].iter().all(u8::is_ascii_alphanumeric));
+        assert_ne!(config.generate(), id);
+
+        config.style = Style::Shadow;
+        config.version = vec![1, 2, 42];
+        let id = config.generate();
+        assert_eq!(&id[..9], b"X12g-----");
+        assert!(id[9..].iter().all(u8::is_ascii_alphanumeric));
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Mutex,
+};
+
+use bytes::Bytes;
+use color_eyre::Report;
+use tokio::sync::mpsc::Sender;
+use tracing::debug;
+
+use crate::{
+    data::{GeneralError, Peer, Peers},
+    extensions::{Extension, ExtensionHandler, Message},
+    pwp::Request,
+};
+
+// peers that send more than this in a single message get ignored for the rest of it, BEP 11 asks
+// for no more than 50 added and 50 dropped ones
+const MAX_PEERS: usize = 50;
+
+#[derive(Default)]
+struct Swarm {
+    connected: HashSet<SocketAddr>,
+    // who every peer was told about so far, the next message only has the difference
+    told: HashMap<SocketAddr, HashSet<SocketAddr>>,
+}
+
+// the ut_pex extension: tells the peers that support it who else we're connected to, and hands
+// the peers they're connected to over to the router
+pub struct Pex {
+    inner: Mutex<Swarm>,
+    peer_tx: Sender<Peers>,
+}
+
+impl Pex {
+    pub fn new(peer_tx: Sender<Peers>) -> Self {
+        Self {
+            inner: Mutex::new(Swarm::default()),
+            peer_tx,
+        }
+    }
+
+    // whoever connected or went away since the peer's last message, None if nothing changed
+    fn update(&self, peer: SocketAddr) -> Option<Extension> {
+        let mut inner = self.inner.lock().unwrap();
+        let Swarm { connected, told } = &mut *inner;
+        let told = told.entry(peer).or_default();
+
+        let added: Vec<_> = connected
+            .iter()
+            .filter(|addr| **addr != peer && !told.contains(addr))
+            .take(MAX_PEERS)
+            .copied()
+            .collect();
+        let dropped: Vec<_> = told
+            .iter()
+            .filter(|addr| !connected.contains(addr))
+            .take(MAX_PEERS)
+            .copied()
+            .collect();
+
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        told.extend(&added);
+        dropped.iter().for_each(|addr| {
+            told.remove(addr);
+        });
+
+        Some(Extension::Pex { added, dropped })
+    }
+}
+
+impl ExtensionHandler for Pex {
+    fn name(&self) -> &str {
+        "ut_pex"
+    }
+
+    fn message(&self, peer: SocketAddr, payload: Bytes) -> Result<Option<Bytes>, Report> {
+        let Some(Message::Extension(Extension::Pex { added, .. })) =
+            Message::from_request(&payload)
+        else {
+            return Err(GeneralError::MalformedPacket("ut_pex".to_owned()).into());
+        };
+
+        let peers: Peers = {
+            let inner = self.inner.lock().unwrap();
+            added
+                .iter()
+                .take(MAX_PEERS)
+                .filter(|addr| !inner.connected.contains(addr))
+                .map(Peer::from)
+                .collect()
+        };
+        debug!("[{peer}] told us about {} new peers", peers.len());
+
+        // the router is busy dialing, these will come around again
+        if !peers.is_empty() {
+            let _ = self.peer_tx.try_send(peers);
+        }
+
+        Ok(None)
+    }
+
+    fn connected(&self, peer: SocketAddr) {
+        self.inner.lock().unwrap().connected.insert(peer);
+    }
+
+    fn disconnected(&self, peer: SocketAddr) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.connected.remove(&peer);
+        inner.told.remove(&peer);
+    }
+
+    fn poll(&self, peer: SocketAddr) -> Option<Bytes> {
+        let pex = self.update(peer)?;
+        Some(Message::Extension(pex).payload().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pex() -> Result<(), Report> {
+        let (peer_tx, mut peer_rx) = tokio::sync::mpsc::channel(4);
+        let pex = Pex::new(peer_tx);
+        let [a, b, c, d]: [SocketAddr; 4] = [
+            "10.0.0.1:6881".parse()?,
+            "10.0.0.2:6881".parse()?,
+            "[2001:db8::3]:6881".parse()?,
+            "10.0.0.4:6881".parse()?,
+        ];
+
+        pex.connected(a);
+        assert!(pex.update(a).is_none());
+
+        // everybody else the first time, only what changed after that
+        pex.connected(b);
+        pex.connected(c);
+        let Some(Extension::Pex { mut added, dropped }) = pex.update(a) else {
+            panic!("expected a PEX message");
+        };
+        added.sort();
+        assert_eq!((added, dropped), (vec![b, c], vec![]));
+        assert!(pex.update(a).is_none());
+
+        pex.disconnected(b);
+        assert_eq!(
+            pex.update(a),
+            Some(Extension::Pex {
+                added: vec![],
+                dropped: vec![b],
+            })
+        );
+
+        // peers we're connected to already don't get dialed again
+        let payload = Message::Extension(Extension::Pex {
+            added: vec![c, d],
+            dropped: vec![],
+        })
+        .payload();
+        assert!(pex.message(a, payload.into())?.is_none());
+        assert_eq!(peer_rx.try_recv()?, vec![Peer::from(&d)]);
+        assert!(pex.message(a, Bytes::from_static(b"i4e")).is_err());
+
+        Ok(())
+    }
+}
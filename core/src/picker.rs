@@ -0,0 +1,333 @@
+use std::{collections::HashMap, fmt, str::FromStr, sync::Arc};
+
+use rand::seq::SliceRandom;
+
+use crate::{
+    data::{GeneralError, Info},
+    piece_manager::BitField,
+};
+
+// decides which piece gets requested next, every torrent can bring its own
+pub trait PiecePicker: Send + Sync {
+    // `wanted` tells whether a piece is one the peer has and we still need
+    fn pick(&self, pieces: usize, wanted: &dyn Fn(usize) -> bool) -> Option<usize>;
+
+    // what the swarm has, for strategies that care how common a piece is
+    fn peer_bitfield(&mut self, _bitfield: &BitField) {}
+
+    fn peer_have(&mut self, _index: usize) {}
+
+    fn peer_gone(&mut self, _bitfield: &BitField) {}
+
+    // a piece of ours that was verified and written to disk
+    fn piece_completed(&mut self, _index: usize) {}
+}
+
+// front to back, what a player or an archive that's read while downloading wants
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Sequential;
+
+impl PiecePicker for Sequential {
+    fn pick(&self, pieces: usize, wanted: &dyn Fn(usize) -> bool) -> Option<usize> {
+        (0..pieces).find(|&i| wanted(i))
+    }
+}
+
+// pieces only a few peers have go first, so they're still around when those peers leave
+#[derive(Debug, Default, Clone)]
+pub struct RarestFirst {
+    // peers having each piece
+    availability: Vec<u32>,
+    // a rare piece takes long to come in and a peer without any pieces has nothing to trade, so
+    // the first few are random ones
+    random_first: usize,
+    completed: usize,
+}
+
+impl RarestFirst {
+    pub fn new(random_first: usize) -> Self {
+        Self {
+            random_first,
+            ..Default::default()
+        }
+    }
+
+    fn count(&mut self, index: usize) -> &mut u32 {
+        if index >= self.availability.len() {
+            self.availability.resize(index + 1, 0);
+        }
+        &mut self.availability[index]
+    }
+}
+
+impl PiecePicker for RarestFirst {
+    // ties go to the lowest index
+    fn pick(&self, pieces: usize, wanted: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let wanted = (0..pieces).filter(|&i| wanted(i));
+
+        if self.completed < self.random_first {
+            let wanted: Vec<_> = wanted.collect();
+            return wanted.choose(&mut rand::thread_rng()).copied();
+        }
+
+        wanted.min_by_key(|&i| self.availability.get(i).copied().unwrap_or(0))
+    }
+
+    fn peer_bitfield(&mut self, bitfield: &BitField) {
+        bitfield.ones().for_each(|i| *self.count(i) += 1);
+    }
+
+    fn peer_have(&mut self, index: usize) {
+        *self.count(index) += 1;
+    }
+
+    fn peer_gone(&mut self, bitfield: &BitField) {
+        for i in bitfield.ones() {
+            let count = self.count(i);
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    fn piece_completed(&mut self, _index: usize) {
+        self.completed += 1;
+    }
+}
+
+// byte ranges somebody is waiting for right now come first, whatever strategy is underneath
+// takes over once they're all in
+pub struct StreamingWindow {
+    // per file index, pieces in the order they should be fetched
+    windows: HashMap<usize, Vec<usize>>,
+    fallback: Box<dyn PiecePicker>,
+}
+
+impl StreamingWindow {
+    pub fn new(fallback: Box<dyn PiecePicker>) -> Self {
+        Self {
+            windows: HashMap::new(),
+            fallback,
+        }
+    }
+
+    // replaces any earlier window on the same file
+    pub fn set_window(&mut self, file: usize, pieces: Vec<usize>) {
+        self.windows.insert(file, pieces);
+    }
+
+    pub fn window(&self, file: usize) -> Option<&[usize]> {
+        self.windows.get(&file).map(Vec::as_slice)
+    }
+
+    pub fn clear_window(&mut self, file: usize) {
+        self.windows.remove(&file);
+    }
+
+    pub fn set_fallback(&mut self, fallback: Box<dyn PiecePicker>) {
+        self.fallback = fallback;
+    }
+}
+
+impl Default for StreamingWindow {
+    fn default() -> Self {
+        Self::new(Box::new(Sequential))
+    }
+}
+
+impl PiecePicker for StreamingWindow {
+    fn pick(&self, pieces: usize, wanted: &dyn Fn(usize) -> bool) -> Option<usize> {
+        self.windows
+            .values()
+            .flatten()
+            .copied()
+            .find(|&i| wanted(i))
+            .or_else(|| self.fallback.pick(pieces, wanted))
+    }
+
+    fn peer_bitfield(&mut self, bitfield: &BitField) {
+        self.fallback.peer_bitfield(bitfield);
+    }
+
+    fn peer_have(&mut self, index: usize) {
+        self.fallback.peer_have(index);
+    }
+
+    fn peer_gone(&mut self, bitfield: &BitField) {
+        self.fallback.peer_gone(bitfield);
+    }
+
+    fn piece_completed(&mut self, index: usize) {
+        self.fallback.piece_completed(index);
+    }
+}
+
+// the pickers that come with the client, by name
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    #[default]
+    Sequential,
+    // random pieces until `random_first` of them are in
+    RarestFirst {
+        random_first: usize,
+    },
+}
+
+impl Strategy {
+    pub fn picker(self) -> Box<dyn PiecePicker> {
+        match self {
+            Strategy::Sequential => Box::new(Sequential),
+            Strategy::RarestFirst { random_first } => Box::new(RarestFirst::new(random_first)),
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = GeneralError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let e = || GeneralError::InvalidStrategy(s.to_owned());
+
+        match s.trim().split_once(':') {
+            None if s.trim() == "sequential" => Ok(Strategy::Sequential),
+            None if s.trim() == "rarest-first" => Ok(Strategy::RarestFirst { random_first: 0 }),
+            Some(("rarest-first", n)) => Ok(Strategy::RarestFirst {
+                random_first: n.trim().parse().map_err(|_| e())?,
+            }),
+            _ => Err(e()),
+        }
+    }
+}
+
+// how much the user wants a file, pieces go in order of the most wanted file they're part of
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    // never requested, unless a piece is shared with a file that isn't skipped
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = GeneralError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "skip" => Ok(Priority::Skip),
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(GeneralError::InvalidPriority(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Priority::Skip => "skip",
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        })
+    }
+}
+
+type MakePicker = dyn Fn(&Info) -> Box<dyn PiecePicker> + Send + Sync;
+
+// options get cloned around, so they carry a way to build the picker instead of the picker itself
+#[derive(Clone)]
+pub struct PickerFactory(Arc<MakePicker>);
+
+impl PickerFactory {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&Info) -> Box<dyn PiecePicker> + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    pub fn build(&self, info: &Info) -> Box<dyn PiecePicker> {
+        (self.0)(info)
+    }
+}
+
+impl From<Strategy> for PickerFactory {
+    fn from(strategy: Strategy) -> Self {
+        Self::new(move |_| strategy.picker())
+    }
+}
+
+impl Default for PickerFactory {
+    fn default() -> Self {
+        Self::new(|_| Box::new(Sequential))
+    }
+}
+
+impl fmt::Debug for PickerFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PickerFactory")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rarest_first() {
+        let mut picker = RarestFirst::default();
+        let all = |_| true;
+
+        picker.peer_bitfield(&BitField::from_lazy(vec![0, 1, 2], 1));
+        picker.peer_bitfield(&BitField::from_lazy(vec![0, 2], 1));
+        picker.peer_have(0);
+        assert_eq!(picker.pick(3, &all), Some(1));
+        assert_eq!(picker.pick(3, &|i| i != 1), Some(2));
+
+        // the only peer with piece 1 left, it's as rare as the pieces nobody has
+        picker.peer_gone(&BitField::from_lazy(vec![0, 1, 2], 1));
+        assert_eq!(picker.pick(4, &all), Some(1));
+        assert_eq!(Sequential.pick(4, &|i| i > 1), Some(2));
+    }
+
+    #[test]
+    fn test_streaming_window() {
+        let mut picker = StreamingWindow::new(Box::new(RarestFirst::default()));
+        picker.peer_bitfield(&BitField::from_lazy(vec![0], 1));
+
+        picker.set_window(0, vec![5, 0]);
+        assert_eq!(picker.pick(8, &|i| i != 5), Some(0));
+
+        // the fallback knows piece 0 is the common one
+        picker.clear_window(0);
+        assert_eq!(picker.pick(8, &|_| true), Some(1));
+    }
+
+    #[test]
+    fn test_random_first() -> Result<(), GeneralError> {
+        let mut picker = "rarest-first:2".parse::<Strategy>()?.picker();
+        picker.peer_bitfield(&BitField::from_lazy(vec![0, 1, 2], 1));
+        picker.peer_have(0);
+
+        // whatever it picks has to be wanted
+        for _ in 0..16 {
+            assert!(matches!(picker.pick(4, &|i| i < 3), Some(0..=2)));
+        }
+        assert_eq!(picker.pick(4, &|_| false), None);
+
+        picker.piece_completed(3);
+        picker.piece_completed(1);
+        assert_eq!(picker.pick(4, &|i| i < 3), Some(1));
+
+        assert!(matches!("sequential".parse(), Ok(Strategy::Sequential)));
+        assert!(matches!(
+            "rarest-first".parse(),
+            Ok(Strategy::RarestFirst { random_first: 0 })
+        ));
+        assert!("rarest-first:".parse::<Strategy>().is_err());
+        assert!("random".parse::<Strategy>().is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1365 @@
+use std::net::SocketAddr;
+use std::ops::{BitAndAssign, BitXor, Range};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use ahash::{HashMap, HashMapExt};
+use bytes::Bytes;
+use color_eyre::Report;
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use tracing::debug;
+
+use crate::cache::ReadCache;
+use crate::config::BLOCK_SIZE;
+use crate::data::{GeneralError, Info, Mode, SHA1_LEN};
+use crate::disk::WriteBack;
+use crate::picker::{PiecePicker, Priority, Strategy, StreamingWindow};
+use crate::pwp::Block;
+use crate::stats::Stats;
+use crate::storage::{Allocation, FsStorage, Storage};
+
+#[derive(Debug, Clone)]
+pub struct BitField(Box<[usize]>);
+
+impl BitField {
+    pub fn new(v: Vec<usize>) -> Self {
+        BitField(v.into_boxed_slice())
+    }
+
+    pub fn from_lazy(value: Vec<usize>, real_len: usize) -> Self {
+        let mut v = vec![0usize; real_len];
+
+        for x in value {
+            let index = x / usize::BITS as usize;
+            let offset = x % usize::BITS as usize;
+
+            let i = v.get_mut(index).unwrap();
+            *i |= 1 << offset;
+        }
+
+        BitField(v.into_boxed_slice())
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        let bits = usize::BITS as usize;
+
+        self.0
+            .get(index / bits)
+            .map(|x| x & (1 << (index % bits)) != 0)
+            .unwrap_or(false)
+    }
+
+    pub fn set(&mut self, index: usize) {
+        let bits = usize::BITS as usize;
+
+        if index / bits >= self.0.len() {
+            let mut v = std::mem::take(&mut self.0).into_vec();
+            v.resize(index / bits + 1, 0);
+            self.0 = v.into_boxed_slice();
+        }
+        self.0[index / bits] |= 1 << (index % bits);
+    }
+
+    // indices of the pieces that are set
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.0.len() * usize::BITS as usize).filter(|&i| self.get(i))
+    }
+
+    // what a BitField message carries, the first piece in the high bit of the first byte
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let words = bytes.len().div_ceil(std::mem::size_of::<usize>());
+        let ones = (0..bytes.len() * 8).filter(|&i| bytes[i / 8] & (0x80 >> (i % 8)) != 0);
+
+        BitField::from_lazy(ones.collect(), words)
+    }
+
+    // and back, a byte for every 8 pieces with whatever is past the last one left at zero, peers
+    // hang up on anything else
+    pub fn to_bytes(&self, pieces: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; pieces.div_ceil(8)];
+        for i in self.ones().take_while(|&i| i < pieces) {
+            bytes[i / 8] |= 0x80 >> (i % 8);
+        }
+
+        bytes
+    }
+}
+
+impl BitAndAssign for BitField {
+    fn bitand_assign(&mut self, rhs: Self) {
+        let left = &mut self.0;
+        let right = rhs.0;
+
+        assert_eq!(left.len(), right.len());
+
+        left.iter_mut().zip(right.iter()).for_each(|(x, y)| *x &= y);
+    }
+}
+
+impl BitXor for BitField {
+    type Output = BitField;
+
+    fn bitxor(self, rhs: Self) -> Self::Output {
+        let left = self.0;
+        let right = rhs.0;
+
+        assert_eq!(left.len(), right.len());
+
+        let inner = left.iter().zip(right.iter()).map(|(x, y)| *x ^ y).collect();
+
+        BitField(inner)
+    }
+}
+
+pub struct DataManager<S = WriteBack<FsStorage>> {
+    // what every connected peer has, a new picker gets told about all of it
+    bitfield_map: HashMap<SocketAddr, BitField>,
+    pieces: PiecesWrapper<S>,
+    piece_len: u64,
+    picker: StreamingWindow,
+    // None once a picker of its own was set
+    strategy: Option<Strategy>,
+    // pieces connections are downloading and how many of them, nobody else gets them until
+    // they're flushed or released, unless there's nothing else left to download
+    in_flight: HashMap<usize, usize>,
+    // by file, and what that makes of each piece
+    file_priorities: Vec<Priority>,
+    priorities: Box<[Priority]>,
+    verify_uploads: bool,
+    cache: ReadCache,
+}
+
+impl DataManager {
+    pub fn new(info: Info) -> Self {
+        let storage = WriteBack::new(FsStorage::new(&info));
+        Self::with_storage(info, storage)
+    }
+
+    pub fn paths(&self) -> Vec<(PathBuf, u64)> {
+        self.pieces.paths()
+    }
+
+    // see FsStorage::set_root
+    pub fn set_root(&mut self, root: &Path) {
+        self.pieces.set_root(root);
+    }
+
+    pub fn set_allocation(&mut self, allocation: Allocation) {
+        self.pieces.storage.inner().set_allocation(allocation);
+    }
+
+    // bytes of verified pieces that may wait in memory to be written
+    pub fn set_write_cache(&mut self, capacity: usize) {
+        self.pieces.storage.set_capacity(capacity);
+    }
+
+    pub fn move_storage(&mut self, to: &Path) -> Result<(), Report> {
+        self.cache.clear();
+        self.pieces.move_storage(to)
+    }
+}
+
+impl<S: Storage> DataManager<S> {
+    pub fn with_storage(info: Info, storage: S) -> Self {
+        let piece_len = info.piece_length;
+        let files = info.mode.files(Path::new("")).len();
+        let pieces = info.pieces.len();
+
+        DataManager {
+            bitfield_map: HashMap::new(),
+            pieces: PiecesWrapper::with_storage(info, storage),
+            piece_len,
+            picker: StreamingWindow::default(),
+            strategy: Some(Strategy::default()),
+            in_flight: HashMap::new(),
+            file_priorities: vec![Priority::default(); files],
+            priorities: vec![Priority::default(); pieces].into_boxed_slice(),
+            verify_uploads: false,
+            cache: ReadCache::new(0),
+        }
+    }
+
+    // trust the data on disk and mark every piece as verified without hashing anything
+    pub fn assume_complete(&mut self) -> Result<(), Report> {
+        self.pieces.assume_complete()
+    }
+
+    // same, for the pieces an earlier session verified
+    pub fn assume_flushed(&mut self, indices: &[usize]) -> Result<(), Report> {
+        self.pieces.assume_flushed(indices)?;
+        indices.iter().for_each(|&i| self.picker.piece_completed(i));
+
+        Ok(())
+    }
+
+    // every piece is missing until `check` finds it on disk again, whatever is still waiting to be
+    // written goes out first so it's there to be found
+    pub fn forget(&mut self) -> Result<(), Report> {
+        self.cache.clear();
+        self.pieces.storage.drain()?;
+
+        let all: Vec<_> = (0..self.pieces.inner.len()).collect();
+        self.pieces.reset(&all)?;
+        self.pieces.storage.claim_files()
+    }
+
+    // the pieces in `indices` that are on disk, they count as verified from now on
+    pub fn check(&mut self, indices: Range<usize>) -> Result<Vec<usize>, Report> {
+        let found = self.pieces.check(indices)?;
+        found.iter().for_each(|&i| self.picker.piece_completed(i));
+
+        Ok(found)
+    }
+
+    // gets every piece written so far onto the disk, whatever the sync policy
+    pub fn sync(&mut self) -> Result<(), Report> {
+        self.pieces.storage.flush()
+    }
+
+    // sizes the files before the first piece gets written
+    pub fn allocate(&mut self) -> Result<(), Report> {
+        self.pieces.storage.allocate()
+    }
+
+    pub fn flushed_pieces(&self) -> Vec<usize> {
+        (0..self.pieces.inner.len())
+            .filter(|&i| self.pieces.flushed(i))
+            .collect()
+    }
+
+    // the pieces we can serve, in the form peers get told about them
+    pub fn bitfield(&self) -> Vec<u8> {
+        let pieces = self.pieces.inner.len();
+        let words = pieces.div_ceil(usize::BITS as usize);
+
+        BitField::from_lazy(self.flushed_pieces(), words).to_bytes(pieces)
+    }
+
+    // offset of a file within the torrent and its length
+    fn file_span(&self, file: usize) -> Result<(u64, u64), Report> {
+        let lengths: Vec<_> = self
+            .pieces
+            .mode
+            .files(Path::new(""))
+            .into_iter()
+            .map(|(_, length)| length)
+            .collect();
+
+        let length = *lengths.get(file).ok_or(GeneralError::NonExistentFile)?;
+        let start = lengths[..file].iter().sum::<u64>();
+
+        Ok((start, length))
+    }
+
+    // pieces covering a byte range of a single file
+    fn piece_span(&self, file: usize, range: Range<u64>) -> Result<Range<usize>, Report> {
+        let (start, length) = self.file_span(file)?;
+        if range.start >= range.end || range.end > length {
+            return Err(GeneralError::InvalidRange.into());
+        }
+
+        let piece = |offset: u64| (offset / self.piece_len) as usize;
+        Ok(piece(start + range.start)..piece(start + range.end - 1) + 1)
+    }
+
+    // prioritize a byte range of a single file so a player can seek within an incomplete
+    // download, replacing any earlier window on the same file
+    pub fn prioritize_range(&mut self, file: usize, range: Range<u64>) -> Result<(), Report> {
+        let span = self.piece_span(file, range)?;
+        let (start, length) = self.file_span(file)?;
+        let piece = |offset: u64| (offset / self.piece_len) as usize;
+
+        // players read the header and the index at the end of the container before anything
+        // else, so the edges of the file are always part of the window. They go after the read
+        // position and only if the span doesn't cover them already, the order is the priority
+        let mut window: Vec<_> = span.collect();
+        for edge in [piece(start), piece(start + length - 1)] {
+            if !window.contains(&edge) {
+                window.push(edge);
+            }
+        }
+
+        self.picker.set_window(file, window);
+
+        Ok(())
+    }
+
+    // a piece shared by two files goes with the one that's wanted more, so skipping a file may
+    // still leave the edges of it to be downloaded
+    pub fn set_file_priority(&mut self, file: usize, priority: Priority) -> Result<(), Report> {
+        *self
+            .file_priorities
+            .get_mut(file)
+            .ok_or(GeneralError::NonExistentFile)? = priority;
+
+        self.priorities.fill(Priority::Skip);
+        for (file, &priority) in self.file_priorities.iter().enumerate() {
+            let (_, length) = self.file_span(file)?;
+            if length == 0 {
+                continue;
+            }
+
+            for i in self.piece_span(file, 0..length)? {
+                self.priorities[i] = self.priorities[i].max(priority);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn file_priorities(&self) -> &[Priority] {
+        &self.file_priorities
+    }
+
+    // missing and not only part of skipped files
+    fn wanted(&self, index: usize) -> bool {
+        self.pieces.missing(index) && self.priorities.get(index) != Some(&Priority::Skip)
+    }
+
+    // whether a peer with the pieces `has` says it does has anything we still want
+    pub fn wants(&self, has: &dyn Fn(usize) -> bool) -> bool {
+        (0..self.pieces.inner.len()).any(|i| has(i) && self.wanted(i))
+    }
+
+    // whether a byte range of a file has been verified and written to disk
+    pub fn has_range(&self, file: usize, range: Range<u64>) -> Result<bool, Report> {
+        let span = self.piece_span(file, range)?;
+        Ok(span.into_iter().all(|i| self.pieces.flushed(i)))
+    }
+
+    // bytes of a single file, read through the storage so pieces that are still waiting to be
+    // written come out of memory. Every piece covering the range has to be flushed
+    pub fn read_range(&self, file: usize, range: Range<u64>) -> Result<Vec<u8>, Report> {
+        let span = self.piece_span(file, range.clone())?;
+        let (start, _) = self.file_span(file)?;
+        let (from, to) = (start + range.start, start + range.end);
+
+        let mut buf = Vec::with_capacity((range.end - range.start) as usize);
+        for index in span {
+            if !self.pieces.flushed(index) {
+                return Err(GeneralError::InvalidPieceIdx.into());
+            }
+
+            let offset = index as u64 * self.piece_len;
+            let end = (to - offset).min(self.pieces.inner[index].len as u64);
+            buf.extend(
+                self.pieces
+                    .read(index, from.saturating_sub(offset) as usize..end as usize)?,
+            );
+        }
+
+        Ok(buf)
+    }
+
+    pub fn complete(&self) -> bool {
+        self.pieces.inner.iter().all(Piece::complete)
+    }
+
+    pub fn set_block_size(&mut self, block_size: usize) {
+        self.pieces.set_block_size(block_size);
+    }
+
+    // a peer told us the piece is bad or reading it back failed its hash check
+    pub fn invalidate(&mut self, index: usize) -> Result<(), Report> {
+        self.cache.remove(index);
+        self.pieces.reset(&[index])
+    }
+
+    pub fn invalidate_missing(&mut self) -> Result<Vec<usize>, Report> {
+        let missing = self.pieces.invalidate_missing()?;
+        if !missing.is_empty() {
+            self.cache.clear();
+        }
+
+        Ok(missing)
+    }
+
+    // bytes we still need
+    pub fn left(&self) -> u64 {
+        self.pieces
+            .inner
+            .iter()
+            .filter(|piece| !piece.complete())
+            .map(|piece| piece.len as u64)
+            .sum()
+    }
+
+    // hash every piece read back from disk before it goes out, so bit rot on a long-running
+    // seedbox doesn't end up in the swarm
+    pub fn set_verify_uploads(&mut self, verify_uploads: bool) {
+        self.verify_uploads = verify_uploads;
+        // whatever is in there now was never hashed
+        self.cache.clear();
+    }
+
+    // in bytes, 0 reads every block straight from disk
+    pub fn set_read_cache(&mut self, capacity: usize) {
+        self.cache.resize(capacity);
+    }
+
+    // block of a flushed piece to upload to a peer, whole pieces get read and cached since the
+    // rest of them tends to be requested right after
+    pub fn read_block(
+        &mut self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> Result<Bytes, Report> {
+        if !self.pieces.flushed(index) {
+            return Err(GeneralError::InvalidPieceIdx.into());
+        }
+
+        let range = begin..begin + length;
+        let piece = match self.cache.get(index) {
+            Some(piece) => piece,
+            None if !self.verify_uploads && !self.cache.is_enabled() => {
+                return Ok(self.pieces.read(index, range)?.into());
+            }
+            None => {
+                let len = self.pieces.inner[index].len;
+                let piece = Bytes::from(self.pieces.read(index, 0..len)?);
+
+                // whatever is on disk went bad, better to fetch it again than to keep sending it
+                if self.verify_uploads {
+                    if let Err(e) = self.pieces.check_hash(index, &piece) {
+                        debug!("piece {index} is corrupt on disk, downloading it again");
+                        self.pieces.reset(&[index])?;
+
+                        return Err(e);
+                    }
+                }
+                self.cache.insert(index, piece.clone());
+
+                piece
+            }
+        };
+
+        if range.end > piece.len() {
+            return Err(GeneralError::InvalidRange.into());
+        }
+
+        Ok(piece.slice(range))
+    }
+
+    pub fn set_sync_policy(&mut self, sync: SyncPolicy) {
+        self.pieces.sync = sync;
+    }
+
+    // where discarded bytes get counted, the torrent's own counters normally
+    pub fn set_stats(&mut self, stats: Arc<Stats>) {
+        self.pieces.stats = stats;
+    }
+
+    pub fn clear_window(&mut self, file: usize) {
+        self.picker.clear_window(file);
+    }
+
+    // what gets picked once no streaming window wants anything
+    pub fn set_picker(&mut self, picker: Box<dyn PiecePicker>) {
+        self.strategy = None;
+        self.replace_picker(picker);
+    }
+
+    pub fn set_strategy(&mut self, strategy: Strategy) {
+        self.strategy = Some(strategy);
+        self.replace_picker(strategy.picker());
+    }
+
+    pub fn strategy(&self) -> Option<Strategy> {
+        self.strategy
+    }
+
+    // the new picker starts out knowing as much about the swarm and our pieces as the old one
+    fn replace_picker(&mut self, mut picker: Box<dyn PiecePicker>) {
+        self.bitfield_map
+            .values()
+            .for_each(|bitfield| picker.peer_bitfield(bitfield));
+        (0..self.pieces.inner.len())
+            .filter(|&i| self.pieces.flushed(i))
+            .for_each(|i| picker.piece_completed(i));
+
+        self.picker.set_fallback(picker);
+    }
+
+    // replaces whatever the peer told us before
+    pub fn peer_bitfield(&mut self, peer: SocketAddr, bitfield: BitField) {
+        self.peer_gone(peer);
+        self.picker.peer_bitfield(&bitfield);
+        self.bitfield_map.insert(peer, bitfield);
+    }
+
+    // a seed that said so with a HaveAll
+    pub fn peer_have_all(&mut self, peer: SocketAddr) {
+        let pieces = self.pieces.inner.len();
+        let words = pieces.div_ceil(usize::BITS as usize);
+
+        self.peer_bitfield(peer, BitField::from_lazy((0..pieces).collect(), words));
+    }
+
+    pub fn peer_have(&mut self, peer: SocketAddr, index: usize) {
+        let bitfield = self
+            .bitfield_map
+            .entry(peer)
+            .or_insert_with(|| BitField::new(Vec::new()));
+
+        // a peer that repeats itself doesn't make a piece any more common
+        if !bitfield.get(index) {
+            bitfield.set(index);
+            self.picker.peer_have(index);
+        }
+    }
+
+    pub fn peer_gone(&mut self, peer: SocketAddr) {
+        if let Some(bitfield) = self.bitfield_map.remove(&peer) {
+            self.picker.peer_gone(&bitfield);
+        }
+    }
+
+    // the picker's choice among the pieces of the most wanted files first
+    fn pick_wanted(&self, wanted: &dyn Fn(usize) -> bool) -> Option<usize> {
+        [Priority::High, Priority::Normal, Priority::Low]
+            .into_iter()
+            .find_map(|priority| {
+                let wanted = |i: usize| self.priorities[i] == priority && wanted(i);
+                self.picker.pick(self.pieces.inner.len(), &wanted)
+            })
+    }
+
+    // next piece to request from a peer with the given bitfield, streaming windows come first
+    // among the pieces of files that are wanted as much
+    pub fn pick_piece(&self, have: &BitField) -> Option<usize> {
+        self.pick_wanted(&|i: usize| have.get(i) && self.wanted(i))
+    }
+
+    // like pick_piece, but the piece is ours until it's been flushed or released again
+    pub fn claim_piece(&mut self, has: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let wanted = |i: usize| has(i) && self.wanted(i) && !self.in_flight.contains_key(&i);
+        let index = self
+            .pick_wanted(&wanted)
+            .or_else(|| self.endgame_piece(has))?;
+        *self.in_flight.entry(index).or_default() += 1;
+
+        Some(index)
+    }
+
+    // endgame: every missing piece is being downloaded already, a slow peer would hold up the
+    // last few, so they get requested from more peers at once, the least shared ones first
+    fn endgame_piece(&self, has: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let missing = (0..self.pieces.inner.len()).filter(|&i| self.wanted(i));
+        if missing.clone().any(|i| !self.in_flight.contains_key(&i)) {
+            return None;
+        }
+
+        missing
+            .filter(|&i| has(i))
+            .min_by_key(|i| self.in_flight.get(i).copied().unwrap_or(0))
+    }
+
+    // connections downloading a piece, more than one only in endgame
+    pub fn requesters(&self, index: usize) -> usize {
+        self.in_flight.get(&index).copied().unwrap_or(0)
+    }
+
+    // the connection working on it went away, the blocks it did deliver are kept
+    pub fn release_piece(&mut self, index: usize) {
+        if let Some(count) = self.in_flight.get_mut(&index) {
+            *count -= 1;
+            if *count == 0 {
+                self.in_flight.remove(&index);
+            }
+        }
+    }
+
+    // blocks of a piece that haven't arrived yet
+    pub fn missing_blocks(&self, index: usize) -> Vec<Block> {
+        let Some(piece) = self.pieces.inner.get(index) else {
+            return Vec::new();
+        };
+
+        (0..piece.written.len())
+            .filter(|&i| !piece.written[i])
+            .map(|i| {
+                let begin = i * piece.block_size;
+                Block {
+                    index,
+                    begin,
+                    length: piece.block_size.min(piece.len - begin),
+                }
+            })
+            .collect()
+    }
+
+    // stores a block a peer sent us, true once it completed a piece that then passed its hash
+    // check and got written to disk
+    pub async fn write_block(
+        &mut self,
+        index: usize,
+        begin: usize,
+        block: &[u8],
+    ) -> Result<bool, Report> {
+        self.pieces.write(index, begin, block).await?;
+        if self.pieces.flushed(index) || self.pieces.missing(index) {
+            return Ok(false);
+        }
+
+        // a piece that failed starts over and can be picked again right away
+        let flushed = self.pieces.flush_piece(index).await;
+        self.in_flight.remove(&index);
+        if flushed.is_ok() {
+            self.picker.piece_completed(index);
+        }
+
+        flushed.map(|_| true)
+    }
+}
+
+// piece: <len=0009+X><id=7><index><begin><block>
+#[derive(Clone)]
+pub struct Piece {
+    // we don't wanna initialize the box before we have some of its data since otherwise it would
+    // hold the entire torrent in memory
+    inner: Option<Box<[u8]>>,
+    // only the last piece can be shorter than the piece length
+    len: usize,
+    block_size: usize,
+    written: Box<[bool]>,
+    // rolling hash over the written prefix of the piece, blocks that arrive out of order wait in
+    // the buffer until the gap before them has been filled
+    hasher: Sha1,
+    hashed: usize,
+    flushed: bool,
+}
+
+impl Piece {
+    fn new(len: usize, block_size: usize) -> Self {
+        let written = vec![false; len.div_ceil(block_size)].into_boxed_slice();
+        Self {
+            inner: None,
+            len,
+            block_size,
+            written,
+            hasher: Sha1::new(),
+            hashed: 0,
+            flushed: false,
+        }
+    }
+
+    fn complete(&self) -> bool {
+        self.written.iter().all(|&b| b)
+    }
+
+    fn assume_complete(&mut self) {
+        self.written.iter_mut().for_each(|b| *b = true);
+        self.flushed = true;
+    }
+
+    // feed every block that directly follows the hashed prefix into the hasher
+    fn roll(&mut self) {
+        let Some(inner) = self.inner.as_deref() else {
+            return;
+        };
+
+        while self.hashed < self.len && self.written[self.hashed / self.block_size] {
+            let end = (self.hashed + self.block_size).min(self.len);
+            self.hasher.input(&inner[self.hashed..end]);
+            self.hashed = end;
+        }
+    }
+
+    fn digest(&self) -> Option<[u8; SHA1_LEN]> {
+        let mut hash = [0u8; SHA1_LEN];
+
+        if self.hashed == self.len {
+            // finishing consumes the state, keep ours intact in case the piece gets verified again
+            let mut hasher = self.hasher;
+            hasher.result(&mut hash);
+        } else {
+            let mut hasher = Sha1::new();
+            hasher.input(self.inner.as_deref()?);
+            hasher.result(&mut hash);
+        }
+
+        Some(hash)
+    }
+}
+
+// how hard we try to get flushed pieces onto the disk, syncing costs throughput but a power loss
+// can otherwise take out pieces that were already marked as verified
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum SyncPolicy {
+    // leave it to the page cache
+    #[default]
+    WriteBack,
+    PerPiece,
+    OnComplete,
+}
+
+pub struct PiecesWrapper<S = WriteBack<FsStorage>> {
+    piece_len: u64,
+    sync: SyncPolicy,
+    hashes: Box<[[u8; SHA1_LEN]]>,
+    inner: Box<[Piece]>,
+    mode: Mode,
+    stats: Arc<Stats>,
+    storage: S,
+}
+
+impl PiecesWrapper {
+    pub fn new(info: Info) -> Self {
+        let storage = WriteBack::new(FsStorage::new(&info));
+        Self::with_storage(info, storage)
+    }
+
+    pub fn paths(&self) -> Vec<(PathBuf, u64)> {
+        self.storage.inner().paths()
+    }
+
+    pub fn set_root(&mut self, root: &Path) {
+        self.storage.inner().set_root(root);
+    }
+
+    // a copy has to hash the same as what we verified before, otherwise the originals stay
+    pub fn move_storage(&mut self, to: &Path) -> Result<(), Report> {
+        let flushed: Vec<_> = (0..self.inner.len())
+            .filter(|&i| self.flushed(i))
+            .map(|i| (i, self.inner[i].len, self.hashes[i]))
+            .collect();
+
+        // only what's in the files gets moved
+        self.storage.drain()?;
+        self.storage.inner().move_storage(to, |storage| {
+            let corrupt = flushed.iter().find(|(i, len, expected)| {
+                let data = storage.read_block(*i, 0..*len);
+                data.map_or(true, |data| sha1(&data) != *expected)
+            });
+
+            match corrupt {
+                Some((index, ..)) => {
+                    debug!("piece {index} doesn't match after copying, keeping the originals");
+                    Err(GeneralError::InvalidPieceHash.into())
+                }
+                None => Ok(()),
+            }
+        })
+    }
+}
+
+impl<S: Storage> PiecesWrapper<S> {
+    pub fn with_storage(info: Info, storage: S) -> Self {
+        let piece_len = info.piece_length;
+        let hashes = info.pieces.clone();
+        let mode = info.mode.clone();
+
+        let mut pieces = Self {
+            piece_len,
+            sync: SyncPolicy::default(),
+            hashes,
+            inner: Box::new([]),
+            mode,
+            stats: Arc::default(),
+            storage,
+        };
+        pieces.set_block_size(BLOCK_SIZE);
+
+        pieces
+    }
+
+    // throws away whatever was written so far, only meant to be used before the download starts
+    pub fn set_block_size(&mut self, block_size: usize) {
+        let piece_len = self.piece_len;
+        let total: u64 = self
+            .mode
+            .files(Path::new(""))
+            .into_iter()
+            .map(|(_, length)| length)
+            .sum();
+
+        self.inner = (0..self.hashes.len() as u64)
+            .map(|i| piece_len.min(total.saturating_sub(i * piece_len)) as usize)
+            .map(|len| Piece::new(len, block_size))
+            .collect();
+    }
+
+    pub fn assume_complete(&mut self) -> Result<(), Report> {
+        let all: Vec<_> = (0..self.inner.len()).collect();
+        self.assume_flushed(&all)
+    }
+
+    pub fn assume_flushed(&mut self, indices: &[usize]) -> Result<(), Report> {
+        if indices.iter().any(|&index| index >= self.inner.len()) {
+            return Err(GeneralError::InvalidPieceIdx.into());
+        }
+        for &index in indices {
+            self.inner[index].assume_complete();
+        }
+
+        self.storage.verified(indices, true)
+    }
+
+    // hashes what's stored of each piece, a file that's missing or too short only costs the pieces
+    // in it. Every core gets a piece of its own until they're all done
+    pub fn check(&mut self, indices: Range<usize>) -> Result<Vec<usize>, Report> {
+        let indices: Vec<_> = indices.filter(|&i| i < self.inner.len()).collect();
+        let workers = thread::available_parallelism()
+            .map_or(1, usize::from)
+            .min(indices.len());
+
+        let next = AtomicUsize::new(0);
+        let found = Mutex::new(Vec::new());
+        let pieces = &*self;
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| {
+                    while let Some(&index) = indices.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        let len = pieces.inner[index].len;
+                        let stored = pieces.storage.contains(index, len)
+                            && pieces
+                                .storage
+                                .read_block(index, 0..len)
+                                .is_ok_and(|data| sha1(&data) == pieces.hashes[index]);
+
+                        if stored {
+                            found.lock().unwrap().push(index);
+                        }
+                    }
+                });
+            }
+        });
+
+        let mut found = found.into_inner().unwrap();
+        found.sort_unstable();
+        self.assume_flushed(&found)?;
+
+        Ok(found)
+    }
+
+    pub fn missing(&self, index: usize) -> bool {
+        self.inner
+            .get(index)
+            .map(|piece| !piece.complete())
+            .unwrap_or(false)
+    }
+
+    pub fn flushed(&self, index: usize) -> bool {
+        self.inner
+            .get(index)
+            .map(|piece| piece.flushed)
+            .unwrap_or(false)
+    }
+
+    pub async fn write(&mut self, index: usize, begin: usize, block: &[u8]) -> Result<(), Report> {
+        let piece = self
+            .inner
+            .get_mut(index)
+            .ok_or(GeneralError::InvalidPieceIdx)?;
+
+        if begin % piece.block_size != 0 || begin + block.len() > piece.len {
+            return Err(GeneralError::InvalidPieceIdx.into());
+        }
+
+        if piece.flushed || piece.written[begin / piece.block_size] {
+            self.stats.redundant(block.len() as u64);
+            return Ok(());
+        }
+
+        let len = piece.len;
+        let inner = piece
+            .inner
+            .get_or_insert_with(|| vec![0u8; len].into_boxed_slice());
+        inner[begin..begin + block.len()].copy_from_slice(block);
+
+        piece.written[begin / piece.block_size] = true;
+        piece.roll();
+
+        Ok(())
+    }
+
+    pub fn verify_piece(&self, index: usize) -> Result<(), Report> {
+        let piece = self.inner.get(index).ok_or(GeneralError::InvalidPieceIdx)?;
+        let expected = self
+            .hashes
+            .get(index)
+            .ok_or(GeneralError::InvalidPieceIdx)?;
+
+        let hash = piece
+            .digest()
+            .expect("piece doesn't exist despite being complete");
+
+        if &hash == expected {
+            Ok(())
+        } else {
+            Err(GeneralError::InvalidPieceHash.into())
+        }
+    }
+
+    // same as above but for data that doesn't live in memory, like pieces read back from disk
+    pub fn check_hash(&self, index: usize, data: &[u8]) -> Result<(), Report> {
+        let expected = self
+            .hashes
+            .get(index)
+            .ok_or(GeneralError::InvalidPieceIdx)?;
+
+        if &sha1(data) == expected {
+            Ok(())
+        } else {
+            Err(GeneralError::InvalidPieceHash.into())
+        }
+    }
+
+    // reads a range of a flushed piece back from storage
+    pub fn read(&self, index: usize, range: Range<usize>) -> Result<Vec<u8>, Report> {
+        let piece = self.inner.get(index).ok_or(GeneralError::InvalidPieceIdx)?;
+        if range.start > range.end || range.end > piece.len {
+            return Err(GeneralError::InvalidRange.into());
+        }
+
+        self.storage.read_block(index, range)
+    }
+
+    pub async fn flush_piece(&mut self, index: usize) -> Result<(), Report> {
+        let piece = self.inner.get(index).ok_or(GeneralError::InvalidPieceIdx)?;
+
+        // do we really need this closure?
+        if piece.flushed || !piece.complete() {
+            return Err(GeneralError::AlreadyFlushed.into());
+        }
+
+        // start the piece over, whatever we have of it is worthless
+        if let Err(e) = self.verify_piece(index) {
+            let piece = &mut self.inner[index];
+            self.stats.corrupt(piece.len as u64);
+            *piece = Piece::new(piece.len, piece.block_size);
+
+            return Err(e);
+        }
+
+        let piece = &self.inner[index];
+        let data = piece
+            .inner
+            .as_deref()
+            .ok_or(GeneralError::InvalidPieceIdx)?;
+        self.storage.write_block(index, 0, data)?;
+
+        if self.sync == SyncPolicy::PerPiece {
+            self.storage.flush()?;
+        }
+
+        // the data is stored now, no need to keep holding it in memory
+        let piece = &mut self.inner[index];
+        piece.flushed = true;
+        piece.inner = None;
+        self.storage.verified(&[index], true)?;
+
+        // finished files may get moved or stamped next, everything has to be in them by then
+        if self.inner.iter().all(|piece| piece.flushed) {
+            match self.sync {
+                SyncPolicy::OnComplete => self.storage.flush()?,
+                _ => self.storage.drain()?,
+            }
+        }
+
+        Ok(())
+    }
+
+    // flushed pieces whose bytes aren't stored anymore because a file got deleted or truncated
+    // while we weren't looking, they go back to missing so only those get downloaded again
+    pub fn invalidate_missing(&mut self) -> Result<Vec<usize>, Report> {
+        let missing: Vec<_> = (0..self.inner.len())
+            .filter(|&i| self.flushed(i) && !self.storage.contains(i, self.inner[i].len))
+            .collect();
+        self.reset(&missing)?;
+
+        Ok(missing)
+    }
+
+    // throws away pieces we thought we had, they have to be downloaded again
+    pub fn reset(&mut self, indices: &[usize]) -> Result<(), Report> {
+        for &index in indices {
+            let piece = self
+                .inner
+                .get_mut(index)
+                .ok_or(GeneralError::InvalidPieceIdx)?;
+            *piece = Piece::new(piece.len, piece.block_size);
+        }
+
+        self.storage.verified(indices, false)
+    }
+}
+
+pub(crate) fn sha1(data: &[u8]) -> [u8; SHA1_LEN] {
+    let mut hash = [0u8; SHA1_LEN];
+    let mut hasher = Sha1::new();
+    hasher.input(data);
+    hasher.result(&mut hash);
+
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use bendy::decoding::FromBencode;
+    use color_eyre::Report;
+    use rand::Rng;
+
+    use std::net::SocketAddr;
+
+    use crate::config::BLOCK_SIZE;
+    use crate::data::{Info, Mode, TorrentInfo, SHA1_LEN};
+    use crate::disk::WriteBack;
+    use crate::picker::{Priority, Sequential, Strategy};
+
+    use crate::storage::MemoryStorage;
+    use crate::testing::{multi_info, TempDir};
+
+    use super::{sha1, BitField, DataManager, Digest, PiecesWrapper, Sha1, Stats};
+
+    #[test]
+    fn test_map_piece_to_file() -> Result<(), Report> {
+        let torrent = std::fs::read("/home/mikoto/everlasting/music.torrent")?;
+        let torrent = TorrentInfo::from_bencode(&torrent).unwrap();
+
+        let info = torrent.info.unwrap();
+        let piece_len = info.piece_length;
+        let pieces_len = info.pieces.len();
+
+        let files = if let Mode::Multi { files, .. } = info.mode {
+            files
+        } else {
+            panic!();
+        };
+
+        let index = rand::thread_rng().gen_range(0..pieces_len);
+        let piece_offset = piece_len * index as u64;
+        dbg!(&piece_offset);
+        let lengths = files.iter().map(|f| f.length);
+
+        let n = (0..files.len())
+            .find(|&i| lengths.clone().take(i).sum::<u64>() >= piece_offset)
+            .unwrap()
+            - 1;
+        let total = lengths.clone().take(n).sum::<u64>();
+        dbg!(&total, &piece_offset);
+
+        assert!(total < piece_offset);
+        // figure out why this doesn't work next time :D
+        // assert!(piece_offset < total);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_window() -> Result<(), Report> {
+        let files = [("cover.jpg", 100), ("track.flac", 1000)];
+        let info = multi_info("album", &files, 64, vec![[0u8; 20]; 18]);
+        let mut manager = DataManager::new(info);
+
+        // bytes 500..600 of the second file live in pieces 9 and 10
+        manager.prioritize_range(1, 500..600)?;
+        let have = BitField::from_lazy((0..18).collect(), 1);
+        assert_eq!(manager.pick_piece(&have), Some(9));
+
+        // the first piece of the file comes right after the window
+        let have = BitField::from_lazy(vec![0, 1, 3, 17], 1);
+        assert_eq!(manager.pick_piece(&have), Some(1));
+
+        // a span starting at the beginning of the file already has its first piece
+        manager.prioritize_range(1, 0..600)?;
+        assert_eq!(
+            manager.picker.window(1),
+            Some(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 17][..])
+        );
+
+        assert!(manager.prioritize_range(1, 900..1001).is_err());
+        assert!(manager.prioritize_range(2, 0..1).is_err());
+
+        manager.clear_window(1);
+        assert_eq!(manager.pick_piece(&have), Some(0));
+
+        // the last piece only holds the remaining 12 bytes
+        assert_eq!(manager.left(), 1100);
+        assert!(manager.paths()[0].0.ends_with("album/cover.jpg.part"));
+        manager.assume_complete()?;
+        assert_eq!(manager.left(), 0);
+        assert!(manager.paths()[1].0.ends_with("album/track.flac"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_incremental_hash() -> Result<(), Report> {
+        let data: Vec<u8> = (0..2 * BLOCK_SIZE + 100).map(|i| i as u8).collect();
+
+        let mut expected = [0u8; SHA1_LEN];
+        let mut hasher = Sha1::new();
+        hasher.input(&data);
+        hasher.result(&mut expected);
+
+        let info = Info {
+            mode: Mode::Single {
+                name: "data".to_owned(),
+                length: data.len() as u64,
+                md5sum: None,
+            },
+            piece_length: data.len() as u64,
+            pieces: vec![expected].into_boxed_slice(),
+            ..Default::default()
+        };
+        let mut pieces = PiecesWrapper::new(info);
+
+        // the last block shows up first and has to wait for the gap to be filled
+        let blocks: Vec<_> = data.chunks(BLOCK_SIZE).enumerate().collect();
+        for &(i, block) in [blocks[2], blocks[0], blocks[1]].iter() {
+            futures::executor::block_on(pieces.write(0, i * BLOCK_SIZE, block))?;
+        }
+
+        assert_eq!(pieces.inner[0].hashed, data.len());
+        assert!(pieces.inner[0].complete());
+        pieces.verify_piece(0)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wasted_bytes() -> Result<(), Report> {
+        let info = Info {
+            mode: Mode::Single {
+                name: "data".to_owned(),
+                length: 100,
+                md5sum: None,
+            },
+            piece_length: 100,
+            pieces: vec![[0u8; SHA1_LEN]].into_boxed_slice(),
+            ..Default::default()
+        };
+        let mut pieces = PiecesWrapper::new(info);
+        let stats = std::sync::Arc::new(Stats::default());
+        pieces.stats = stats.clone();
+
+        futures::executor::block_on(async {
+            pieces.write(0, 0, &[1u8; 100]).await?;
+            pieces.write(0, 0, &[1u8; 100]).await?;
+            assert_eq!(stats.corrupt_redundant(), (0, 100));
+
+            // the hash doesn't match, so the piece has to be downloaded all over again
+            assert!(pieces.flush_piece(0).await.is_err());
+            assert!(pieces.missing(0));
+
+            Ok::<_, Report>(())
+        })?;
+        assert_eq!(stats.corrupt_redundant(), (100, 100));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_storage() -> Result<(), Report> {
+        let data: Vec<u8> = (0..150).map(|i| i as u8).collect();
+        let info = Info {
+            mode: Mode::Single {
+                name: "data".to_owned(),
+                length: data.len() as u64,
+                md5sum: None,
+            },
+            piece_length: 100,
+            pieces: vec![sha1(&data[..100]), sha1(&data[100..])].into_boxed_slice(),
+            ..Default::default()
+        };
+        let mut pieces = PiecesWrapper::with_storage(info, MemoryStorage::default());
+
+        futures::executor::block_on(async {
+            for (i, piece) in data.chunks(100).enumerate() {
+                pieces.write(i, 0, piece).await?;
+                pieces.flush_piece(i).await?;
+            }
+
+            Ok::<_, Report>(())
+        })?;
+        assert_eq!(pieces.read(1, 10..50)?, &data[110..]);
+        assert!(pieces.read(1, 10..51).is_err());
+
+        pieces.reset(&[0])?;
+        assert!(pieces.read(0, 0..1).is_err());
+        assert!(pieces.invalidate_missing()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_range() -> Result<(), Report> {
+        let data: Vec<u8> = (0..150).map(|i| i as u8).collect();
+        let pieces = vec![sha1(&data[..100]), sha1(&data[100..])];
+        let info = multi_info("dir", &[("a", 60), ("b", 90)], 100, pieces);
+        // nothing reaches the memory storage underneath, it all stays queued
+        let mut storage = WriteBack::new(MemoryStorage::default());
+        storage.set_capacity(1 << 20);
+        let mut manager = DataManager::with_storage(info, storage);
+
+        assert!(manager.read_range(1, 0..10).is_err());
+        futures::executor::block_on(async {
+            for (i, piece) in data.chunks(100).enumerate() {
+                manager.write_block(i, 0, piece).await?;
+            }
+
+            Ok::<_, Report>(())
+        })?;
+
+        assert_eq!(manager.read_range(1, 10..80)?, &data[70..140]);
+        assert_eq!(manager.read_range(0, 0..60)?, &data[..60]);
+        assert!(manager.read_range(1, 10..91).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_priorities() -> Result<(), Report> {
+        let files = [("a", 100), ("b", 150), ("c", 150)];
+        let info = multi_info("dir", &files, 100, vec![[0u8; 20]; 4]);
+        let mut manager = DataManager::with_storage(info, MemoryStorage::default());
+
+        // b shares its last piece with c, which keeps it wanted
+        manager.set_file_priority(1, Priority::Skip)?;
+        manager.set_file_priority(2, Priority::High)?;
+        assert!(manager.set_file_priority(3, Priority::Low).is_err());
+        assert_eq!(
+            manager.file_priorities(),
+            [Priority::Normal, Priority::Skip, Priority::High]
+        );
+
+        assert_eq!(manager.claim_piece(&|_| true), Some(2));
+        assert_eq!(manager.claim_piece(&|_| true), Some(3));
+        assert_eq!(manager.claim_piece(&|_| true), Some(0));
+        // not even endgame gets to the skipped one
+        assert_eq!(manager.claim_piece(&|i| i == 1), None);
+        assert_eq!(manager.pick_piece(&BitField::from_lazy(vec![1], 1)), None);
+
+        manager.set_file_priority(1, Priority::Low)?;
+        assert_eq!(manager.claim_piece(&|_| true), Some(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_availability() -> Result<(), Report> {
+        let info = Info {
+            mode: Mode::Single {
+                name: "data".to_owned(),
+                length: 400,
+                md5sum: None,
+            },
+            piece_length: 100,
+            pieces: vec![[0u8; 20]; 4].into_boxed_slice(),
+            ..Default::default()
+        };
+        let mut manager = DataManager::with_storage(info, MemoryStorage::default());
+        let (a, b): (SocketAddr, SocketAddr) = ("10.0.0.1:6881".parse()?, "10.0.0.2:6881".parse()?);
+
+        manager.peer_bitfield(a, BitField::from_lazy(vec![0, 1, 2, 3], 1));
+        manager.peer_bitfield(b, BitField::from_lazy(vec![0, 1, 3], 1));
+        manager.peer_have(b, 3);
+        assert_eq!(manager.strategy(), Some(Strategy::Sequential));
+        assert_eq!(manager.claim_piece(&|_| true), Some(0));
+
+        // the new picker learns about the peers that are already connected
+        manager.set_strategy(Strategy::RarestFirst { random_first: 0 });
+        assert_eq!(manager.claim_piece(&|_| true), Some(2));
+
+        // b only has piece 2 now, it's the common one
+        manager.peer_bitfield(b, BitField::from_lazy(vec![2], 1));
+        assert_eq!(manager.claim_piece(&|_| true), Some(1));
+        manager.peer_gone(a);
+        manager.peer_have(b, 3);
+        assert_eq!(manager.claim_piece(&|_| true), Some(3));
+
+        // every piece is being downloaded, endgame hands them out a second time
+        assert_eq!(manager.claim_piece(&|i| i > 0), Some(1));
+        assert_eq!(manager.requesters(1), 2);
+        manager.release_piece(1);
+        assert_eq!(manager.requesters(1), 1);
+
+        // random pieces until one of them is in, which resuming counts as well
+        manager.set_strategy(Strategy::RarestFirst { random_first: 1 });
+        manager.assume_flushed(&[3])?;
+        manager.release_piece(1);
+        manager.release_piece(2);
+        assert_eq!(manager.claim_piece(&|_| true), Some(1));
+
+        manager.set_picker(Box::new(Sequential));
+        assert_eq!(manager.strategy(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitfield_bytes() -> Result<(), Report> {
+        let info = Info {
+            mode: Mode::Single {
+                name: "data".to_owned(),
+                length: 100,
+                md5sum: None,
+            },
+            piece_length: 10,
+            pieces: vec![[0u8; 20]; 10].into_boxed_slice(),
+            ..Default::default()
+        };
+        let mut manager = DataManager::with_storage(info, MemoryStorage::default());
+        assert_eq!(manager.bitfield(), [0, 0]);
+
+        // a byte for every 8 pieces, the first one in the high bit
+        manager.assume_flushed(&[0, 7, 9])?;
+        assert_eq!(manager.bitfield(), [0b1000_0001, 0b0100_0000]);
+
+        // whatever is past the last piece stays zero on the way out
+        let bitfield = BitField::from_bytes(&[0xff, 0xff]);
+        assert_eq!(bitfield.ones().count(), 16);
+        assert_eq!(bitfield.to_bytes(10), [0xff, 0b1100_0000]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalidate_missing() -> Result<(), Report> {
+        let tmp = TempDir::new();
+        let files = [("a", 10), ("b", 10), ("c", 10)];
+        let info = multi_info("album", &files, 4, vec![[0u8; 20]; 8]);
+        let mut pieces = PiecesWrapper::new(info);
+        pieces.set_root(&tmp);
+        pieces.assume_complete()?;
+
+        // b got deleted and c lost its last 3 bytes
+        std::fs::create_dir_all(tmp.join("album"))?;
+        std::fs::write(tmp.join("album/a"), [0u8; 10])?;
+        std::fs::write(tmp.join("album/c"), [0u8; 7])?;
+
+        assert_eq!(pieces.invalidate_missing()?, vec![2, 3, 4, 6, 7]);
+        assert!(pieces.missing(2) && !pieces.missing(5));
+        assert!(tmp.join("album/c.part").exists());
+        assert!(pieces.invalidate_missing()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_storage() -> Result<(), Report> {
+        let tmp = TempDir::new();
+        let (from, to) = (tmp.join("downloads"), tmp.join("completed"));
+        let info = multi_info("album", &[("a", 3), ("sub/b", 2)], 8, vec![[0u8; 20]]);
+        let mut pieces = PiecesWrapper::new(info);
+        pieces.set_root(&from);
+        pieces.assume_complete()?;
+
+        std::fs::create_dir_all(from.join("album/sub"))?;
+        std::fs::write(from.join("album/a"), b"abc")?;
+        std::fs::write(from.join("album/sub/b"), b"de")?;
+
+        pieces.move_storage(&to)?;
+        assert_eq!(std::fs::read(to.join("album/sub/b"))?, b"de");
+        assert_eq!(pieces.read(0, 0..5)?, b"abcde");
+        assert!(!from.join("album").exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,479 @@
+use std::{
+    fmt, fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use color_eyre::Report;
+use tokio::{
+    net::UdpSocket,
+    time::{sleep, timeout},
+};
+use tracing::debug;
+use url::Url;
+
+use crate::{data::GeneralError, shutdown::Signal};
+
+const NAT_PMP_PORT: u16 = 5351;
+const SSDP_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+const SEARCH: &str = "M-SEARCH * HTTP/1.1\r\n\
+    HOST: 239.255.255.250:1900\r\n\
+    ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+    MAN: \"ssdp:discover\"\r\n\
+    MX: 2\r\n\r\n";
+// what we ask the router for, mappings get renewed halfway through
+const LEASE: Duration = Duration::from_secs(60 * 60);
+// NAT-PMP asks again after 250ms and waits twice as long every time, RFC 6886
+const PMP_TIMEOUT: Duration = Duration::from_millis(250);
+const PMP_TRIES: u32 = 4;
+// gateways answer the search within MX seconds
+const SSDP_TIMEOUT: Duration = Duration::from_secs(3);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+// routers that only keep mappings until they're removed, UPnP wants a lease of 0 then
+const ONLY_PERMANENT_LEASES: u16 = 725;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    fn opcode(self) -> u8 {
+        match self {
+            Protocol::Udp => 1,
+            Protocol::Tcp => 2,
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "TCP"),
+            Protocol::Udp => write!(f, "UDP"),
+        }
+    }
+}
+
+// a home router that lets us ask for a forward, some speak both and get asked twice
+#[derive(Debug, Clone)]
+enum Gateway {
+    Pmp(SocketAddrV4),
+    Upnp {
+        control: Url,
+        service: String,
+        // the address the router forwards to, the one we reach it from
+        local: IpAddr,
+        client: reqwest::Client,
+    },
+}
+
+impl fmt::Display for Gateway {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Gateway::Pmp(addr) => write!(f, "{addr}"),
+            Gateway::Upnp { control, .. } => write!(f, "{control}"),
+        }
+    }
+}
+
+impl Gateway {
+    // the lease the router granted, zero for one that lasts until it gets removed
+    async fn map(
+        &self,
+        protocol: Protocol,
+        port: u16,
+        lease: Duration,
+    ) -> Result<Duration, Report> {
+        match self {
+            Gateway::Pmp(addr) => {
+                let request = pmp_request(protocol, port, lease);
+                let (external, lifetime) = pmp_response(protocol, &pmp(*addr, &request).await?)?;
+                if external != port {
+                    debug!("[{self}] forwards {protocol} port {external} instead of {port}");
+                }
+
+                Ok(lifetime)
+            }
+            Gateway::Upnp { local, .. } => {
+                let args = |lease: Duration| {
+                    vec![
+                        ("NewRemoteHost", String::new()),
+                        ("NewExternalPort", port.to_string()),
+                        ("NewProtocol", protocol.to_string()),
+                        ("NewInternalPort", port.to_string()),
+                        ("NewInternalClient", local.to_string()),
+                        ("NewEnabled", "1".to_owned()),
+                        ("NewPortMappingDescription", "everlasting".to_owned()),
+                        ("NewLeaseDuration", lease.as_secs().to_string()),
+                    ]
+                };
+
+                match self.soap("AddPortMapping", &args(lease)).await {
+                    Ok(_) => Ok(lease),
+                    Err(e) => match e.downcast_ref() {
+                        Some(GeneralError::UpnpFault(ONLY_PERMANENT_LEASES)) => {
+                            self.soap("AddPortMapping", &args(Duration::ZERO)).await?;
+                            Ok(Duration::ZERO)
+                        }
+                        _ => Err(e),
+                    },
+                }
+            }
+        }
+    }
+
+    async fn unmap(&self, protocol: Protocol, port: u16) -> Result<(), Report> {
+        match self {
+            // a lease of nothing removes it
+            Gateway::Pmp(addr) => {
+                let request = pmp_request(protocol, port, Duration::ZERO);
+                pmp_response(protocol, &pmp(*addr, &request).await?)?;
+            }
+            Gateway::Upnp { .. } => {
+                let args = [
+                    ("NewRemoteHost", String::new()),
+                    ("NewExternalPort", port.to_string()),
+                    ("NewProtocol", protocol.to_string()),
+                ];
+                self.soap("DeletePortMapping", &args).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn soap(&self, action: &str, args: &[(&str, String)]) -> Result<String, Report> {
+        let Gateway::Upnp {
+            control,
+            service,
+            client,
+            ..
+        } = self
+        else {
+            unreachable!("NAT-PMP doesn't speak SOAP");
+        };
+
+        let args: String = args
+            .iter()
+            .map(|(name, value)| format!("<{name}>{value}</{name}>"))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{service}\">{args}</u:{action}></s:Body></s:Envelope>"
+        );
+
+        let response = client
+            .post(control.clone())
+            .header("Content-Type", "text/xml; charset=\"utf-8\"")
+            .header("SOAPAction", format!("\"{service}#{action}\""))
+            .body(body)
+            .send()
+            .await?;
+        let status = response.status();
+        let text = response.text().await?;
+        if status.is_success() {
+            return Ok(text);
+        }
+
+        // faults come with a status of 500 and the reason in the body
+        match tag(&text, "errorCode").and_then(|code| code.parse().ok()) {
+            Some(code) => Err(GeneralError::UpnpFault(code).into()),
+            None => Err(GeneralError::UnexpectedResponse(status.to_string()).into()),
+        }
+    }
+}
+
+// keeps the listen port forwarded on whatever routers there are for as long as the session runs,
+// so peers and DHT nodes can reach us from the outside
+pub struct PortMapper {
+    port: u16,
+    lease: Duration,
+    shutdown: Signal,
+}
+
+impl PortMapper {
+    pub fn new(port: u16) -> Self {
+        Self {
+            port,
+            lease: LEASE,
+            shutdown: Signal::default(),
+        }
+    }
+
+    // the forwards get removed once it fires
+    pub fn set_shutdown(&mut self, shutdown: Signal) {
+        self.shutdown = shutdown;
+    }
+
+    pub async fn run(mut self) {
+        let port = self.port;
+        let gateways = discover().await;
+        if gateways.is_empty() {
+            return debug!("no router to forward port {port} on");
+        }
+
+        loop {
+            let mut renew = self.lease;
+            for gateway in &gateways {
+                for protocol in [Protocol::Tcp, Protocol::Udp] {
+                    match gateway.map(protocol, port, self.lease).await {
+                        Ok(lease) if !lease.is_zero() => renew = renew.min(lease),
+                        Ok(_) => {}
+                        Err(e) => {
+                            debug!("[{gateway}] failed to forward {protocol} port {port}: {e}")
+                        }
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = sleep(renew / 2) => {}
+                _ = self.shutdown.recv() => break,
+            }
+        }
+
+        for gateway in &gateways {
+            for protocol in [Protocol::Tcp, Protocol::Udp] {
+                if let Err(e) = gateway.unmap(protocol, port).await {
+                    debug!("[{gateway}] failed to remove the {protocol} forward: {e}");
+                }
+            }
+        }
+    }
+}
+
+// both kinds are looked for at the same time, neither is worth waiting on the other for
+async fn discover() -> Vec<Gateway> {
+    let (pmp, upnp) = tokio::join!(discover_pmp(), discover_upnp());
+
+    [pmp, upnp]
+        .into_iter()
+        .filter_map(|gateway| {
+            gateway
+                .map_err(|e| debug!("no port mapping this way: {e}"))
+                .ok()
+        })
+        .collect()
+}
+
+// NAT-PMP has to be asked at the default gateway, asking it for our external address tells us
+// whether it's listening
+async fn discover_pmp() -> Result<Gateway, Report> {
+    let gateway = default_gateway().ok_or(GeneralError::UnexpectedResponse(
+        "no default gateway".to_owned(),
+    ))?;
+    let addr = SocketAddrV4::new(gateway, NAT_PMP_PORT);
+
+    let response = pmp(addr, &[0, 0]).await?;
+    if response.len() < 12 || response[1] != 128 || response[2..4] != [0, 0] {
+        return Err(GeneralError::UnexpectedResponse("NAT-PMP refused".to_owned()).into());
+    }
+
+    Ok(Gateway::Pmp(addr))
+}
+
+// the first gateway that answers the search and has a WAN connection to forward on
+async fn discover_upnp() -> Result<Gateway, Report> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.send_to(SEARCH.as_bytes(), SSDP_GROUP).await?;
+
+    let mut buf = vec![0u8; 2048];
+    let (n, addr) = timeout(SSDP_TIMEOUT, socket.recv_from(&mut buf))
+        .await
+        .map_err(|_| GeneralError::Timeout(None))??;
+    let location = location(&buf[..n])
+        .ok_or_else(|| GeneralError::UnexpectedResponse("SSDP answer without a location".into()))?;
+
+    // the router is right here, no proxy is going to reach it
+    let client = reqwest::Client::builder()
+        .no_proxy()
+        .timeout(HTTP_TIMEOUT)
+        .build()?;
+    let description = client.get(location.clone()).send().await?.text().await?;
+    let (service, control) = wan_service(&description)
+        .ok_or_else(|| GeneralError::UnexpectedResponse("gateway without a WAN service".into()))?;
+
+    Ok(Gateway::Upnp {
+        control: location.join(control)?,
+        service: service.to_owned(),
+        local: local_ip(addr)?,
+        client,
+    })
+}
+
+// asks until the gateway answers or we gave it long enough
+async fn pmp(gateway: SocketAddrV4, request: &[u8]) -> Result<Vec<u8>, Report> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(gateway).await?;
+
+    let mut buf = [0u8; 16];
+    let mut wait = PMP_TIMEOUT;
+    for _ in 0..PMP_TRIES {
+        socket.send(request).await?;
+        if let Ok(n) = timeout(wait, socket.recv(&mut buf)).await {
+            return Ok(buf[..n?].to_vec());
+        }
+        wait *= 2;
+    }
+
+    Err(GeneralError::Timeout(Some(gateway.into())).into())
+}
+
+fn pmp_request(protocol: Protocol, port: u16, lease: Duration) -> [u8; 12] {
+    let mut request = [0u8; 12];
+    request[1] = protocol.opcode();
+    request[4..6].copy_from_slice(&port.to_be_bytes());
+    // the external port we'd like, removing a mapping asks for none
+    let external = if lease.is_zero() { 0 } else { port };
+    request[6..8].copy_from_slice(&external.to_be_bytes());
+    request[8..12].copy_from_slice(&(lease.as_secs() as u32).to_be_bytes());
+
+    request
+}
+
+// the external port and how long the gateway keeps it
+fn pmp_response(protocol: Protocol, response: &[u8]) -> Result<(u16, Duration), GeneralError> {
+    let malformed = || GeneralError::MalformedPacket("NAT-PMP mapping".to_owned());
+    if response.len() != 16 || response[0] != 0 || response[1] != 128 + protocol.opcode() {
+        return Err(malformed());
+    }
+
+    let read = |range: std::ops::Range<usize>| {
+        response[range].iter().fold(0u32, |n, &b| n << 8 | b as u32)
+    };
+    match read(2..4) {
+        0 => Ok((
+            read(10..12) as u16,
+            Duration::from_secs(read(12..16) as u64),
+        )),
+        code => Err(GeneralError::UnexpectedResponse(format!(
+            "NAT-PMP result code {code}"
+        ))),
+    }
+}
+
+// LOCATION of the device description, header names are case insensitive
+fn location(response: &[u8]) -> Option<Url> {
+    String::from_utf8_lossy(response)
+        .split("\r\n")
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("location"))
+        .and_then(|(_, value)| Url::parse(value.trim()).ok())
+}
+
+// the service type and control URL of the connection that leads outside, over IP or PPP
+fn wan_service(description: &str) -> Option<(&str, &str)> {
+    description.split("<service>").skip(1).find_map(|service| {
+        let kind = tag(service, "serviceType")?;
+        let wan = kind.contains(":WANIPConnection:") || kind.contains(":WANPPPConnection:");
+
+        wan.then_some((kind, tag(service, "controlURL")?))
+    })
+}
+
+// what's between <name> and </name>, good enough for what routers send
+fn tag<'a>(text: &'a str, name: &str) -> Option<&'a str> {
+    let start = text.find(&format!("<{name}>"))? + name.len() + 2;
+    let end = start + text[start..].find(&format!("</{name}>"))?;
+
+    Some(text[start..end].trim())
+}
+
+// the address we reach `to` from, connecting a UDP socket only picks a route
+fn local_ip(to: SocketAddr) -> Result<IpAddr, Report> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(to)?;
+
+    Ok(socket.local_addr()?.ip())
+}
+
+// the kernel's route to everywhere, other systems go without NAT-PMP
+fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = fs::read_to_string("/proc/net/route").ok()?;
+
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        // in host byte order
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+
+        Some(Ipv4Addr::from(gateway.to_ne_bytes())).filter(|ip| !ip.is_unspecified())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_nat_pmp() -> Result<(), Report> {
+        let request = pmp_request(Protocol::Tcp, 6881, LEASE);
+        assert_eq!(
+            request,
+            [0, 2, 0, 0, 0x1a, 0xe1, 0x1a, 0xe1, 0, 0, 0x0e, 0x10]
+        );
+
+        // a gateway that grants half of what was asked and then refuses
+        let gateway = UdpSocket::bind("127.0.0.1:0").await?;
+        let SocketAddr::V4(addr) = gateway.local_addr()? else {
+            unreachable!();
+        };
+        tokio::spawn(async move {
+            let mut buf = [0u8; 12];
+            for result in [0u8, 2] {
+                let (_, from) = gateway.recv_from(&mut buf).await?;
+                let mut response = [0u8; 16];
+                response[1] = 128 + buf[1];
+                response[3] = result;
+                response[8..12].copy_from_slice(&buf[4..8]);
+                response[12..16].copy_from_slice(&1800u32.to_be_bytes());
+                gateway.send_to(&response, from).await?;
+            }
+
+            Ok::<_, Report>(())
+        });
+
+        let gateway = Gateway::Pmp(addr);
+        assert_eq!(
+            gateway.map(Protocol::Udp, 6881, LEASE).await?,
+            Duration::from_secs(1800)
+        );
+        assert!(gateway.map(Protocol::Udp, 6881, LEASE).await.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_upnp_description() -> Result<(), Report> {
+        let response = b"HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nLocation: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        let location = location(response).unwrap();
+        assert_eq!(location.as_str(), "http://192.168.1.1:5000/rootDesc.xml");
+
+        let description = "<root><device><serviceList>\
+            <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+            <controlURL>/ctl/L3F</controlURL></service>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+            <controlURL>/ctl/IPConn</controlURL></service>\
+            </serviceList></device></root>";
+        let (service, control) = wan_service(description).unwrap();
+        assert_eq!(service, "urn:schemas-upnp-org:service:WANIPConnection:1");
+        assert_eq!(
+            location.join(control)?.as_str(),
+            "http://192.168.1.1:5000/ctl/IPConn"
+        );
+
+        assert_eq!(wan_service("<root></root>"), None);
+        assert_eq!(
+            tag("<errorCode> 725 </errorCode>", "errorCode"),
+            Some("725")
+        );
+
+        Ok(())
+    }
+}
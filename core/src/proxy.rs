@@ -0,0 +1,197 @@
+use std::{net::SocketAddr, str::FromStr};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use color_eyre::Report;
+use serde::{Deserialize, Deserializer};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_socks::tcp::Socks5Stream;
+use url::Url;
+
+use crate::data::GeneralError;
+
+// more than any proxy needs to say it let us through
+const MAX_RESPONSE: usize = 8 << 10;
+
+// where everything that goes out over TCP is sent instead, trackers and web seeds as well as
+// peers. socks5://[user:password@]host[:port] or http://[user:password@]host[:port], UDP can't
+// be proxied this way, so UDP trackers, the DHT and uTP go around it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Proxy {
+    url: Url,
+}
+
+impl FromStr for Proxy {
+    type Err = GeneralError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let e = || GeneralError::InvalidProxy(s.to_owned());
+        let mut url = Url::parse(s).map_err(|_| e())?;
+
+        let port = match url.scheme() {
+            "socks5" | "socks5h" => 1080,
+            "http" => 80,
+            _ => return Err(e()),
+        };
+        if url.host_str().is_none() {
+            return Err(e());
+        }
+        if url.port().is_none() {
+            url.set_port(Some(port)).map_err(|_| e())?;
+        }
+
+        Ok(Self { url })
+    }
+}
+
+impl<'de> Deserialize<'de> for Proxy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Proxy {
+    // host:port of the proxy itself
+    fn server(&self) -> String {
+        let host = self.url.host_str().unwrap_or_default();
+        let port = self.url.port().unwrap_or_default();
+
+        format!("{host}:{port}")
+    }
+
+    fn credentials(&self) -> Option<(String, String)> {
+        if self.url.username().is_empty() {
+            return None;
+        }
+        let decode = |s: &str| urlencoding::decode(s).map_or_else(|_| s.to_owned(), Into::into);
+
+        Some((
+            decode(self.url.username()),
+            decode(self.url.password().unwrap_or_default()),
+        ))
+    }
+
+    // reqwest speaks both kinds of proxies on its own
+    pub fn reqwest(&self) -> Result<reqwest::Proxy, Report> {
+        Ok(reqwest::Proxy::all(self.url.as_str())?)
+    }
+
+    // a connection to `addr` by way of the proxy, the stream carries the peer's bytes once this
+    // returns
+    pub async fn connect(&self, addr: SocketAddr) -> Result<TcpStream, Report> {
+        if self.url.scheme() == "http" {
+            return self.tunnel(addr).await;
+        }
+
+        let server = self.server();
+        let stream = match self.credentials() {
+            Some((user, password)) => {
+                Socks5Stream::connect_with_password(server.as_str(), addr, &user, &password).await?
+            }
+            None => Socks5Stream::connect(server.as_str(), addr).await?,
+        };
+
+        Ok(stream.into_inner())
+    }
+
+    // HTTP CONNECT, the response is read a byte at a time so nothing after it gets swallowed
+    async fn tunnel(&self, addr: SocketAddr) -> Result<TcpStream, Report> {
+        let mut stream = TcpStream::connect(self.server()).await?;
+
+        let mut request = format!("CONNECT {addr} HTTP/1.1\r\nHost: {addr}\r\n");
+        if let Some((user, password)) = self.credentials() {
+            let token = STANDARD.encode(format!("{user}:{password}"));
+            request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() == MAX_RESPONSE {
+                return Err(
+                    GeneralError::UnexpectedResponse("endless proxy response".into()).into(),
+                );
+            }
+            response.push(stream.read_u8().await?);
+        }
+
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        match status.split_whitespace().nth(1) {
+            Some("200") => Ok(stream),
+            _ => Err(GeneralError::UnexpectedResponse(status.to_owned()).into()),
+        }
+    }
+}
+
+// trackers and web seeds go through the proxy as well, when there is one
+pub fn http_client(proxy: Option<&Proxy>) -> Result<reqwest::ClientBuilder, Report> {
+    let builder = reqwest::ClientBuilder::new();
+
+    Ok(match proxy {
+        Some(proxy) => builder.proxy(proxy.reqwest()?),
+        None => builder,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_proxy() -> Result<(), Report> {
+        let proxy: Proxy = "socks5://proxy.lan".parse()?;
+        assert_eq!(proxy.server(), "proxy.lan:1080");
+        assert_eq!(proxy.credentials(), None);
+        assert!("ftp://proxy.lan".parse::<Proxy>().is_err());
+        assert!("proxy.lan:1080".parse::<Proxy>().is_err());
+
+        // a proxy that lets one peer connection through and turns the next one away
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://me:p%40ss@{}", listener.local_addr()?);
+        let proxy: Proxy = url.parse()?;
+        assert_eq!(
+            proxy.credentials(),
+            Some(("me".to_owned(), "p@ss".to_owned()))
+        );
+
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for response in [
+                "200 Connection established",
+                "407 Proxy Authentication Required",
+            ] {
+                let (mut stream, _) = listener.accept().await?;
+                let mut buf = vec![0u8; 1024];
+                let n = stream.read(&mut buf).await?;
+                requests.push(String::from_utf8_lossy(&buf[..n]).into_owned());
+
+                stream
+                    .write_all(format!("HTTP/1.1 {response}\r\n\r\nhello").as_bytes())
+                    .await?;
+            }
+
+            Ok::<_, Report>(requests)
+        });
+
+        let peer: SocketAddr = "10.0.0.1:6881".parse()?;
+        let mut stream = proxy.connect(peer).await?;
+        let mut hello = [0u8; 5];
+        stream.read_exact(&mut hello).await?;
+        assert_eq!(&hello, b"hello");
+        assert!(proxy.connect(peer).await.is_err());
+
+        let requests = server.await??;
+        assert!(requests[0].starts_with("CONNECT 10.0.0.1:6881 HTTP/1.1\r\n"));
+        assert!(requests[0].contains("Proxy-Authorization: Basic bWU6cEBzcw==\r\n"));
+
+        Ok(())
+    }
+}
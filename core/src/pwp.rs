@@ -0,0 +1,611 @@
+use std::{fmt, io::Cursor, net::Ipv4Addr};
+
+use bytes::{Buf, Bytes};
+
+use crate::{
+    framing::{ParseCheck, ParseError},
+    piece_manager::sha1,
+};
+
+// largest block we accept in a piece message, 16 KiB is the norm but some clients go higher
+pub const MAX_BLOCK: usize = 1 << 17;
+// pieces a peer may request from us while choked, BEP 6 suggests 10
+pub const ALLOWED_FAST: usize = 10;
+
+// a range of a piece as it appears in requests and cancels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Block {
+    pub index: usize,
+    pub begin: usize,
+    pub length: usize,
+}
+
+impl Block {
+    pub fn request(&self) -> Message {
+        Message::Request {
+            index: self.index,
+            begin: self.begin,
+            length: self.length,
+        }
+    }
+
+    pub fn cancel(&self) -> Message {
+        Message::Cancel {
+            index: self.index,
+            begin: self.begin,
+            length: self.length,
+        }
+    }
+
+    pub fn reject(&self) -> Message {
+        Message::RejectRequest {
+            index: self.index,
+            begin: self.begin,
+            length: self.length,
+        }
+    }
+}
+
+// choked and interested are about us, the peer_ ones about how we treat the peer
+#[derive(Debug)]
+pub struct State {
+    pub choked: bool,
+    pub interested: bool,
+    pub peer_choked: bool,
+    pub peer_interested: bool,
+    pub dht_port: Option<u16>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            choked: true,
+            interested: false,
+            peer_choked: true,
+            peer_interested: false,
+            dht_port: None,
+        }
+    }
+}
+
+pub trait Request {
+    fn to_request(&self) -> Vec<u8>;
+    fn from_request(v: &[u8]) -> Option<Self>
+    where
+        Self: Sized;
+}
+
+// the peer id will presumably be sent after the recipient sends its own handshake
+#[derive(Clone, Debug)]
+pub struct Handshake {
+    pub pstr: Option<String>,
+    pub reserved: [u8; 8],
+    pub hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub payload: Option<Vec<u8>>,
+}
+
+impl Handshake {
+    pub fn new(hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        let mut reserved = [0u8; 8];
+
+        // DHT protocol
+        reserved[7] |= 0x01;
+        // extension protocol
+        reserved[5] |= 0x10;
+        // fast extension
+        reserved[7] |= 0x04;
+
+        Self {
+            pstr: Some("BitTorrent protocol".to_owned()),
+            reserved,
+            hash,
+            peer_id,
+            payload: None,
+        }
+    }
+
+    // BEP 6, the fast messages may only be sent once both sides set the bit
+    pub fn fast(&self) -> bool {
+        self.reserved[7] & 0x04 != 0
+    }
+
+    // pub fn extensions(&self) -> Option<()> {
+    //     if self.reserved[7] |= 0x04 {
+    //         println!("Fast Peers Extensions");
+    //     }
+    //     if self.reserved[7] |= 0x01 {
+    //         println!("");
+    //     }
+    // }
+}
+
+impl Request for Handshake {
+    fn to_request(&self) -> Vec<u8> {
+        let pstr = self.pstr.as_ref().unwrap();
+        [
+            &[pstr.len() as u8],
+            pstr.as_bytes(),
+            &self.reserved,
+            &self.hash,
+            &self.peer_id,
+        ]
+        .concat()
+    }
+
+    fn from_request(v: &[u8]) -> Option<Self> {
+        let reserved = v[20..28].try_into().ok()?;
+        let hash = v[28..48].try_into().ok()?;
+        let peer_id = v[48..68].try_into().ok()?;
+        let payload = if !v[68..].is_empty() {
+            Some(v[68..].to_vec())
+        } else {
+            None
+        };
+
+        Some(Self {
+            pstr: None,
+            reserved,
+            hash,
+            peer_id,
+            payload,
+        })
+    }
+}
+
+impl ParseCheck for Handshake {
+    // <1:pstrlen><19:pstr><8:reserved><20:info_hash><20:peer_id>
+
+    fn check(v: &mut Cursor<&[u8]>) -> Result<(), ParseError> {
+        if v.remaining() < 68 {
+            return Err(ParseError::Incomplete);
+        }
+        v.advance(68);
+
+        Ok(())
+    }
+
+    fn parse(v: Bytes) -> Result<Self, ParseError>
+    where
+        Self: Sized,
+    {
+        if v.len() < 68 {
+            return Err(ParseError::Incomplete);
+        }
+
+        let reserved = v[20..28].try_into().unwrap();
+        let hash = v[28..48].try_into().unwrap();
+        let peer_id = v[48..68].try_into().unwrap();
+
+        Ok(Self {
+            pstr: None,
+            reserved,
+            hash,
+            peer_id,
+            payload: None,
+        })
+    }
+}
+
+#[repr(i8)]
+#[derive(Clone, Debug)]
+pub enum Message {
+    Handshake(Handshake) = -1,
+    Choke,
+    Unchoke,
+    Interested,
+    Uninterested,
+    Have(usize),
+    // the payload as sent, a bit per piece with the first one in the high bit of the first byte
+    BitField(Bytes),
+    Request {
+        index: usize,
+        begin: usize,
+        length: usize,
+    },
+    Piece {
+        index: usize,
+        begin: usize,
+        block: Bytes,
+    },
+    Cancel {
+        index: usize,
+        begin: usize,
+        length: usize,
+    },
+    Port(u16),
+    // BEP 6, what the fast extension adds
+    SuggestPiece(usize) = 13,
+    // stand in for a BitField with every bit set or none at all
+    HaveAll,
+    HaveNone,
+    RejectRequest {
+        index: usize,
+        begin: usize,
+        length: usize,
+    },
+    AllowedFast(usize),
+    // raw payload under the receiver's id, the extension registry knows what to make of it
+    Extended {
+        id: u8,
+        payload: Bytes,
+    } = 20,
+    KeepAlive,
+}
+
+impl Request for Message {
+    fn to_request(&self) -> Vec<u8> {
+        use Message::*;
+
+        let len = |i: u32| i.to_be_bytes();
+
+        match self {
+            Handshake(handshake) => handshake.to_request(),
+            Choke => [len(1).as_slice(), &[0u8]].concat(),
+            Unchoke => [len(1).as_slice(), &[1u8]].concat(),
+            Interested => [len(1).as_slice(), &[2u8]].concat(),
+            Uninterested => [len(1).as_slice(), &[3u8]].concat(),
+            Have(x) => [len(5).as_slice(), &[4u8], &(*x as u32).to_be_bytes()].concat(),
+            BitField(v) => [len(v.len() as u32 + 1).as_slice(), &[5u8], v].concat(),
+            Request {
+                index,
+                begin,
+                length,
+            } => [
+                len(13).as_slice(),
+                &[6u8],
+                &(*index as u32).to_be_bytes(),
+                &(*begin as u32).to_be_bytes(),
+                &(*length as u32).to_be_bytes(),
+            ]
+            .concat(),
+            Piece {
+                index,
+                begin,
+                block,
+            } => [
+                len(9 + block.len() as u32).as_slice(),
+                &[7u8],
+                &(*index as u32).to_be_bytes(),
+                &(*begin as u32).to_be_bytes(),
+                block,
+            ]
+            .concat(),
+            Cancel {
+                index,
+                begin,
+                length,
+            } => [
+                len(13).as_slice(),
+                &[8u8],
+                &(*index as u32).to_be_bytes(),
+                &(*begin as u32).to_be_bytes(),
+                &(*length as u32).to_be_bytes(),
+            ]
+            .concat(),
+            Port(i) => [len(3).as_slice(), &[9u8], &i.to_be_bytes()].concat(),
+            SuggestPiece(x) => [len(5).as_slice(), &[13u8], &(*x as u32).to_be_bytes()].concat(),
+            HaveAll => [len(1).as_slice(), &[14u8]].concat(),
+            HaveNone => [len(1).as_slice(), &[15u8]].concat(),
+            RejectRequest {
+                index,
+                begin,
+                length,
+            } => [
+                len(13).as_slice(),
+                &[16u8],
+                &(*index as u32).to_be_bytes(),
+                &(*begin as u32).to_be_bytes(),
+                &(*length as u32).to_be_bytes(),
+            ]
+            .concat(),
+            AllowedFast(x) => [len(5).as_slice(), &[17u8], &(*x as u32).to_be_bytes()].concat(),
+            KeepAlive => len(0).to_vec(),
+            Extended { id, payload } => [
+                len(2 + payload.len() as u32).as_slice(),
+                &[20u8],
+                &[*id],
+                payload,
+            ]
+            .concat(),
+        }
+    }
+
+    fn from_request(v: &[u8]) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        Message::parse(Bytes::copy_from_slice(v)).ok()
+    }
+}
+
+impl Message {
+    // bytes the message takes up on the wire, without encoding it
+    pub fn wire_len(&self) -> usize {
+        use Message::*;
+
+        match self {
+            Handshake(h) => 49 + h.pstr.as_ref().map_or(19, |pstr| pstr.len()),
+            KeepAlive => 4,
+            Choke | Unchoke | Interested | Uninterested | HaveAll | HaveNone => 5,
+            Have(_) | SuggestPiece(_) | AllowedFast(_) => 9,
+            Port(_) => 7,
+            Request { .. } | Cancel { .. } | RejectRequest { .. } => 17,
+            BitField(v) => 5 + v.len(),
+            Piece { block, .. } => 13 + block.len(),
+            Extended { payload, .. } => 6 + payload.len(),
+        }
+    }
+}
+impl ParseCheck for Message {
+    fn frame_len(v: &[u8]) -> Option<usize> {
+        let n = u32::from_be_bytes(v.get(..4)?.try_into().ok()?);
+        Some(4 + n as usize)
+    }
+
+    fn check(v: &mut Cursor<&[u8]>) -> Result<(), ParseError> {
+        let len = Self::frame_len(v.chunk()).ok_or(ParseError::Incomplete)?;
+
+        // the id is known long before a large payload arrives, no need to wait for it. A keep-alive
+        // has none, whatever comes after its length belongs to the next frame
+        if let Some(&id) = v.chunk().get(4).filter(|_| len > 4) {
+            if !valid_length(id, len - 4) {
+                return Err(ParseError::InvalidLength(id, len - 4));
+            }
+        }
+
+        if v.remaining() < len {
+            return Err(ParseError::Incomplete);
+        }
+        v.advance(len);
+
+        Ok(())
+    }
+
+    // takes a single frame, payloads end up as slices of it instead of copies
+    fn parse(mut v: Bytes) -> Result<Self, ParseError>
+    where
+        Self: Sized,
+    {
+        use Message::*;
+
+        if v.remaining() < 4 {
+            return Err(ParseError::Incomplete);
+        }
+        let n = v.get_u32() as usize;
+        if v.remaining() < n {
+            return Err(ParseError::Incomplete);
+        }
+        let mut v = v.split_to(n);
+
+        if n == 0 {
+            return Ok(KeepAlive);
+        }
+
+        let id = v.get_u8();
+        if !valid_length(id, n) {
+            return Err(ParseError::InvalidLength(id, n));
+        }
+
+        let res = match id {
+            0 => Choke,
+            1 => Unchoke,
+            2 => Interested,
+            3 => Uninterested,
+            4 => Have(v.get_u32() as usize),
+            5 => BitField(v),
+            6 => Request {
+                index: v.get_u32() as usize,
+                begin: v.get_u32() as usize,
+                length: v.get_u32() as usize,
+            },
+            7 => Piece {
+                index: v.get_u32() as usize,
+                begin: v.get_u32() as usize,
+                block: v,
+            },
+            8 => Cancel {
+                index: v.get_u32() as usize,
+                begin: v.get_u32() as usize,
+                length: v.get_u32() as usize,
+            },
+            9 => Port(v.get_u16()),
+            13 => SuggestPiece(v.get_u32() as usize),
+            14 => HaveAll,
+            15 => HaveNone,
+            16 => RejectRequest {
+                index: v.get_u32() as usize,
+                begin: v.get_u32() as usize,
+                length: v.get_u32() as usize,
+            },
+            17 => AllowedFast(v.get_u32() as usize),
+            20 => Extended {
+                id: v.get_u8(),
+                payload: v,
+            },
+            id => return Err(ParseError::Malformed(id)),
+        };
+
+        Ok(res)
+    }
+}
+
+// the pieces a peer gets to request while choked, the same for every client that follows BEP 6 so
+// peers behind the same /24 can't collect more of them by reconnecting
+pub fn allowed_fast(ip: Ipv4Addr, hash: &[u8; 20], pieces: usize, k: usize) -> Vec<usize> {
+    let mut set = Vec::with_capacity(k);
+    if pieces == 0 {
+        return set;
+    }
+
+    let ip = u32::from(ip) & 0xffff_ff00;
+    let mut x = [ip.to_be_bytes().as_slice(), hash].concat();
+    while set.len() < k.min(pieces) {
+        x = sha1(&x).to_vec();
+        for y in x.chunks(4).take(5) {
+            let index = u32::from_be_bytes(y.try_into().unwrap()) as usize % pieces;
+            if set.len() < k && !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+
+    set
+}
+
+// fixed size messages have exactly one valid length, blocks are bounded by what anyone would
+// request
+fn valid_length(id: u8, n: usize) -> bool {
+    match id {
+        0..=3 | 14 | 15 => n == 1,
+        4 | 13 | 17 => n == 5,
+        6 | 8 | 16 => n == 13,
+        7 => n > 9 && n <= 9 + MAX_BLOCK,
+        9 => n == 3,
+        20 => n >= 2,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_limits() {
+        let check = |v: &[u8]| Message::check(&mut Cursor::new(v));
+
+        assert_eq!(
+            Message::frame_len(&[0xff, 0xff, 0xff, 0xff]),
+            Some(4 + u32::MAX as usize)
+        );
+        assert!(matches!(check(&[0, 0, 0, 1, 1]), Ok(())));
+        assert!(matches!(
+            check(&[0, 0, 0, 2, 1, 0]),
+            Err(ParseError::InvalidLength(1, 2))
+        ));
+        assert!(matches!(
+            check(&[0, 4, 0, 0, 7]),
+            Err(ParseError::InvalidLength(7, _))
+        ));
+        assert!(matches!(
+            check(&[0, 0, 0, 5, 4]),
+            Err(ParseError::Incomplete)
+        ));
+
+        // a keep-alive with the next frame already behind it
+        let buf = [0, 0, 0, 0, 0, 0, 0, 1, 2];
+        let mut v = Cursor::new(&buf[..]);
+        let mut frames = Vec::new();
+        while v.has_remaining() {
+            let start = v.position() as usize;
+            Message::check(&mut v).unwrap();
+            let frame = Bytes::copy_from_slice(&buf[start..v.position() as usize]);
+            frames.push(Message::parse(frame).unwrap());
+        }
+        assert!(matches!(
+            frames[..],
+            [Message::KeepAlive, Message::Interested]
+        ));
+    }
+
+    #[test]
+    fn test_parse_piece() {
+        let frame = Message::Piece {
+            index: 3,
+            begin: 16384,
+            block: Bytes::from_static(b"hello"),
+        }
+        .to_request();
+
+        match Message::parse(Bytes::from(frame)) {
+            Ok(Message::Piece {
+                index,
+                begin,
+                block,
+            }) => assert_eq!((index, begin, &block[..]), (3, 16384, b"hello".as_slice())),
+            msg => panic!("{msg:?}"),
+        }
+
+        assert!(matches!(
+            Message::parse(Bytes::from_static(&[0, 0, 0, 0])),
+            Ok(Message::KeepAlive)
+        ));
+        assert!(Message::parse(Bytes::from_static(&[0, 0, 0, 1, 42])).is_err());
+
+        for m in [
+            Message::Piece {
+                index: 3,
+                begin: 0,
+                block: Bytes::from_static(b"hello"),
+            },
+            Message::BitField(Bytes::from_static(&[0xff, 0x80])),
+            Message::Port(6881),
+            Message::Interested,
+            Message::Extended {
+                id: 1,
+                payload: Bytes::from_static(b"de"),
+            },
+        ] {
+            assert_eq!(m.wire_len(), m.to_request().len());
+        }
+    }
+
+    #[test]
+    fn test_fast_messages() {
+        let round_trip = |m: Message| {
+            let frame = m.to_request();
+            assert_eq!(m.wire_len(), frame.len());
+            Message::parse(Bytes::from(frame)).unwrap()
+        };
+
+        assert!(matches!(round_trip(Message::HaveAll), Message::HaveAll));
+        assert!(matches!(round_trip(Message::HaveNone), Message::HaveNone));
+        assert!(matches!(
+            round_trip(Message::SuggestPiece(7)),
+            Message::SuggestPiece(7)
+        ));
+        assert!(matches!(
+            round_trip(Message::AllowedFast(1 << 20)),
+            Message::AllowedFast(0x100000)
+        ));
+        assert!(matches!(
+            round_trip(Message::RejectRequest {
+                index: 1,
+                begin: 16384,
+                length: 16384
+            }),
+            Message::RejectRequest {
+                index: 1,
+                begin: 16384,
+                length: 16384
+            }
+        ));
+        assert!(Message::parse(Bytes::from_static(&[0, 0, 0, 2, 14, 0])).is_err());
+
+        let mut h = Handshake::new([0u8; 20], [0u8; 20]);
+        assert!(h.fast());
+        h.reserved[7] &= !0x04;
+        assert!(!h.fast());
+    }
+
+    #[test]
+    fn test_allowed_fast() {
+        // the example of BEP 6
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        let set = allowed_fast(ip, &[0xaa; 20], 1313, 9);
+        assert_eq!(set, [1059, 431, 808, 1217, 287, 376, 1188, 353, 508]);
+        assert_eq!(allowed_fast(ip, &[0xaa; 20], 1313, 7), set[..7]);
+        // the last octet doesn't count
+        assert_eq!(
+            allowed_fast(Ipv4Addr::new(80, 4, 4, 1), &[0xaa; 20], 1313, 9),
+            set
+        );
+
+        // fewer pieces than the set would have
+        let mut small = allowed_fast(ip, &[0xaa; 20], 3, ALLOWED_FAST);
+        small.sort();
+        assert_eq!(small, [0, 1, 2]);
+        assert!(allowed_fast(ip, &[0xaa; 20], 0, ALLOWED_FAST).is_empty());
+    }
+}
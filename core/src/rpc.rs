@@ -0,0 +1,772 @@
+use std::{
+    fmt,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use bendy::{
+    decoding::{self, FromBencode, Object},
+    encoding::{self, AsString, SingleItemEncoder, ToBencode},
+};
+use color_eyre::Report;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+    sync::Mutex,
+};
+use tracing::debug;
+
+use crate::{
+    config::Config,
+    data::{GeneralError, TorrentInfo},
+    fetch::Fetcher,
+    framing::MAX_FRAME,
+    manager::TorrentManager,
+    picker::Priority,
+    torrent::{AddOptions, State, Torrent},
+};
+
+pub const DEFAULT_SOCKET: &str = "./everlasting.sock";
+
+// what the client asks a running session to do, torrents are named by their info hash
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    // the contents of a .torrent file, the daemon doesn't need to see the client's files. A root
+    // is where this torrent alone gets downloaded to, the session's default otherwise
+    Add {
+        torrent: Vec<u8>,
+        root: Option<PathBuf>,
+    },
+    AddMagnet {
+        link: String,
+        root: Option<PathBuf>,
+    },
+    // fetched by the daemon, it's the one that has to reach the server
+    AddUrl {
+        url: String,
+        root: Option<PathBuf>,
+    },
+    List,
+    Pause([u8; 20]),
+    Resume([u8; 20]),
+    Recheck([u8; 20]),
+    // announce right away instead of waiting out the interval
+    Reannounce([u8; 20]),
+    Remove([u8; 20]),
+    // files are numbered in the order the torrent lists them
+    SetPriority {
+        hash: [u8; 20],
+        file: usize,
+        priority: Priority,
+    },
+    // the pieces of this byte range of a file get fetched ahead of everything else, for players
+    // seeking into a file that isn't complete yet
+    PrioritizeRange {
+        hash: [u8; 20],
+        file: usize,
+        range: Range<u64>,
+    },
+    Stats,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    Done,
+    Added([u8; 20]),
+    Torrents(Vec<TorrentStatus>),
+    Stats(SessionStatus),
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TorrentStatus {
+    pub hash: [u8; 20],
+    // empty as long as the metadata of a magnet link hasn't arrived
+    pub name: String,
+    pub state: String,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SessionStatus {
+    pub torrents: u64,
+    // bytes per second
+    pub up: u64,
+    pub down: u64,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+impl fmt::Display for TorrentStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:<20} {:>16} {:>16} {:>16} {}",
+            hex::encode(self.hash),
+            self.state,
+            self.uploaded,
+            self.downloaded,
+            self.left,
+            self.name
+        )
+    }
+}
+
+impl fmt::Display for SessionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "torrents   {}", self.torrents)?;
+        writeln!(f, "up         {} B/s", self.up)?;
+        writeln!(f, "down       {} B/s", self.down)?;
+        writeln!(f, "uploaded   {}", self.uploaded)?;
+        write!(f, "downloaded {}", self.downloaded)
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Response::Done => Ok(()),
+            Response::Added(hash) => write!(f, "{}", hex::encode(hash)),
+            Response::Torrents(torrents) => {
+                let lines: Vec<_> = torrents.iter().map(TorrentStatus::to_string).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Response::Stats(stats) => write!(f, "{stats}"),
+            Response::Error(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+pub fn parse_hash(s: &str) -> Result<[u8; 20], GeneralError> {
+    hex::decode(s)
+        .ok()
+        .and_then(|v| v.try_into().ok())
+        .ok_or_else(|| GeneralError::InvalidInfoHash(s.to_owned()))
+}
+
+pub(crate) fn state_name(torrent: &Torrent) -> String {
+    if let Some((checked, total)) = torrent.checking() {
+        return format!("CheckingFiles {}%", checked * 100 / total.max(1));
+    }
+
+    match torrent.state() {
+        State::Error(reason) => format!("Error: {reason}"),
+        state => format!("{state:?}"),
+    }
+}
+
+// every message is bencoded and preceded by its length, a 4 byte big endian integer
+async fn read_message<T: FromBencode>(stream: &mut UnixStream) -> Result<Option<T>, Report> {
+    let len = match stream.read_u32().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if len > MAX_FRAME {
+        return Err(GeneralError::MalformedPacket(format!("rpc message of {len} bytes")).into());
+    }
+
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf).await?;
+
+    T::from_bencode(&buf)
+        .map(Some)
+        .map_err(|_| GeneralError::MalformedPacket("rpc message".to_owned()).into())
+}
+
+async fn write_message<T: ToBencode>(stream: &mut UnixStream, message: &T) -> Result<(), Report> {
+    let buf = message
+        .to_bencode()
+        .map_err(|_| GeneralError::MalformedPacket("rpc message".to_owned()))?;
+
+    stream.write_u32(buf.len() as u32).await?;
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+// lets another process add, list and control the torrents of a running session
+pub struct RpcServer {
+    listener: UnixListener,
+    manager: Arc<Mutex<TorrentManager>>,
+    // torrents added over RPC get the options the session was started with
+    options: AddOptions,
+    fetcher: Arc<Fetcher>,
+}
+
+impl RpcServer {
+    pub fn bind(
+        path: impl AsRef<Path>,
+        manager: Arc<Mutex<TorrentManager>>,
+        options: AddOptions,
+    ) -> Result<Self, Report> {
+        // whatever an earlier session left behind would keep us from binding
+        if path.as_ref().exists() {
+            std::fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        debug!("accepting commands on {}", path.as_ref().display());
+
+        Ok(Self {
+            listener,
+            manager,
+            options,
+            fetcher: Arc::new(Fetcher::new(&Config::default())?),
+        })
+    }
+
+    // the session's proxy and torrents directory instead of the defaults
+    pub fn set_fetcher(&mut self, fetcher: Fetcher) {
+        self.fetcher = Arc::new(fetcher);
+    }
+
+    pub async fn run(self) -> Result<(), Report> {
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+            let (manager, options) = (self.manager.clone(), self.options.clone());
+            let fetcher = self.fetcher.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = serve(stream, manager, options, fetcher).await {
+                    debug!("rpc client went away: {e}");
+                }
+            });
+        }
+    }
+}
+
+async fn serve(
+    mut stream: UnixStream,
+    manager: Arc<Mutex<TorrentManager>>,
+    options: AddOptions,
+    fetcher: Arc<Fetcher>,
+) -> Result<(), Report> {
+    while let Some(request) = read_message::<Request>(&mut stream).await? {
+        let response = execute(request, &manager, &options, &fetcher)
+            .await
+            .unwrap_or_else(|e| Response::Error(e.to_string()));
+        write_message(&mut stream, &response).await?;
+    }
+
+    Ok(())
+}
+
+async fn execute(
+    request: Request,
+    manager: &Mutex<TorrentManager>,
+    options: &AddOptions,
+    fetcher: &Fetcher,
+) -> Result<Response, Report> {
+    match request {
+        Request::Add { torrent, root } => {
+            let info = TorrentInfo::from_bencode(&torrent)
+                .map_err(|_| GeneralError::MalformedPacket("torrent file".to_owned()))?;
+            let hash = info.hash;
+            manager
+                .lock()
+                .await
+                .add(info, with_root(options, root))
+                .await?;
+
+            Ok(Response::Added(hash))
+        }
+        Request::AddMagnet { link, root } => {
+            let info = TorrentInfo::from_magnet(&link)?;
+            let hash = info.hash;
+            manager
+                .lock()
+                .await
+                .add(info, with_root(options, root))
+                .await?;
+
+            Ok(Response::Added(hash))
+        }
+        Request::AddUrl { url, root } => {
+            let torrent = fetcher.add(manager, &url, with_root(options, root)).await?;
+            let hash = torrent.read().await.info().hash;
+
+            Ok(Response::Added(hash))
+        }
+        Request::List => {
+            let torrents = manager.lock().await.torrents();
+
+            let mut list = Vec::with_capacity(torrents.len());
+            for torrent in torrents {
+                let torrent = torrent.read().await;
+                let info = torrent.info();
+                let (uploaded, downloaded, left) = torrent.stats().up_down_left();
+
+                list.push(TorrentStatus {
+                    hash: info.hash,
+                    name: info
+                        .info
+                        .as_ref()
+                        .map(|info| info.mode.name())
+                        .unwrap_or_default(),
+                    state: state_name(&torrent),
+                    uploaded,
+                    downloaded,
+                    left,
+                });
+            }
+            list.sort_by_key(|status| status.hash);
+
+            Ok(Response::Torrents(list))
+        }
+        Request::Pause(hash) => {
+            manager.lock().await.pause(&hash).await?;
+            Ok(Response::Done)
+        }
+        Request::Resume(hash) => {
+            manager.lock().await.resume(&hash).await?;
+            Ok(Response::Done)
+        }
+        Request::Recheck(hash) => {
+            manager.lock().await.recheck(&hash).await?;
+            Ok(Response::Done)
+        }
+        Request::Reannounce(hash) => {
+            manager.lock().await.reannounce(&hash)?;
+            Ok(Response::Done)
+        }
+        Request::Remove(hash) => {
+            manager.lock().await.remove(&hash).await?;
+            Ok(Response::Done)
+        }
+        Request::SetPriority {
+            hash,
+            file,
+            priority,
+        } => {
+            manager
+                .lock()
+                .await
+                .set_priority(&hash, file, priority)
+                .await?;
+            Ok(Response::Done)
+        }
+        Request::PrioritizeRange { hash, file, range } => {
+            manager
+                .lock()
+                .await
+                .prioritize_range(&hash, file, range)
+                .await?;
+            Ok(Response::Done)
+        }
+        Request::Stats => {
+            let manager = manager.lock().await;
+            let session = manager.rates().session();
+
+            Ok(Response::Stats(SessionStatus {
+                torrents: manager.torrents().len() as u64,
+                up: session.up,
+                down: session.down,
+                uploaded: session.uploaded,
+                downloaded: session.downloaded,
+            }))
+        }
+    }
+}
+
+fn with_root(options: &AddOptions, root: Option<PathBuf>) -> AddOptions {
+    AddOptions {
+        root: root.or_else(|| options.root.clone()),
+        ..options.clone()
+    }
+}
+
+pub struct RpcClient {
+    stream: UnixStream,
+}
+
+impl RpcClient {
+    pub async fn connect(path: impl AsRef<Path>) -> Result<Self, Report> {
+        let stream = UnixStream::connect(path).await?;
+        Ok(Self { stream })
+    }
+
+    // the daemon's errors come back as errors
+    pub async fn call(&mut self, request: &Request) -> Result<Response, Report> {
+        write_message(&mut self.stream, request).await?;
+
+        match read_message(&mut self.stream).await? {
+            Some(Response::Error(reason)) => Err(GeneralError::RpcFailure(reason).into()),
+            Some(response) => Ok(response),
+            None => Err(GeneralError::BrokenPipe.into()),
+        }
+    }
+}
+
+impl ToBencode for Request {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), encoding::Error> {
+        let (q, hash) = match self {
+            Request::Add { .. } | Request::AddMagnet { .. } | Request::AddUrl { .. } => {
+                ("add", None)
+            }
+            Request::List => ("list", None),
+            Request::Pause(hash) => ("pause", Some(hash)),
+            Request::Resume(hash) => ("resume", Some(hash)),
+            Request::Recheck(hash) => ("recheck", Some(hash)),
+            Request::Reannounce(hash) => ("reannounce", Some(hash)),
+            Request::Remove(hash) => ("remove", Some(hash)),
+            Request::SetPriority { hash, .. } => ("set-priority", Some(hash)),
+            Request::PrioritizeRange { hash, .. } => ("prioritize-range", Some(hash)),
+            Request::Stats => ("stats", None),
+        };
+
+        let root = match self {
+            Request::Add { root, .. }
+            | Request::AddMagnet { root, .. }
+            | Request::AddUrl { root, .. } => root.as_ref(),
+            _ => None,
+        };
+
+        encoder.emit_dict(|mut e| {
+            if let Request::PrioritizeRange { range, .. } = self {
+                e.emit_pair(b"end", range.end)?;
+            }
+            if let Request::SetPriority { file, .. } | Request::PrioritizeRange { file, .. } = self
+            {
+                e.emit_pair(b"file", *file as u64)?;
+            }
+            if let Some(hash) = hash {
+                e.emit_pair(b"hash", AsString(hash.as_slice()))?;
+            }
+            if let Request::AddMagnet { link, .. } = self {
+                e.emit_pair(b"magnet", link)?;
+            }
+            if let Request::SetPriority { priority, .. } = self {
+                e.emit_pair(b"priority", priority.to_string())?;
+            }
+            e.emit_pair(b"q", q)?;
+            if let Some(root) = root {
+                e.emit_pair(b"root", root.to_string_lossy().into_owned())?;
+            }
+            if let Request::PrioritizeRange { range, .. } = self {
+                e.emit_pair(b"start", range.start)?;
+            }
+            if let Request::Add { torrent, .. } = self {
+                e.emit_pair(b"torrent", AsString(torrent.as_slice()))?;
+            }
+            if let Request::AddUrl { url, .. } = self {
+                e.emit_pair(b"url", url)?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl FromBencode for Request {
+    fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        let (mut q, mut hash, mut torrent, mut magnet, mut url) = (None, None, None, None, None);
+        let (mut file, mut priority, mut root) = (None, None, None);
+        let (mut start, mut end) = (None, None);
+
+        let mut dict = object.try_into_dictionary()?;
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"q", v) => q = Some(String::decode_bencode_object(v)?),
+                (b"hash", v) => {
+                    let AsString(v) = AsString::decode_bencode_object(v)?;
+                    hash = Some(<[u8; 20]>::try_from(v).map_err(|_| {
+                        decoding::Error::malformed_content("info hash is not 20 bytes")
+                    })?);
+                }
+                (b"torrent", v) => torrent = Some(AsString::decode_bencode_object(v)?.0),
+                (b"magnet", v) => magnet = Some(String::decode_bencode_object(v)?),
+                (b"url", v) => url = Some(String::decode_bencode_object(v)?),
+                (b"file", v) => file = Some(u64::decode_bencode_object(v)? as usize),
+                (b"root", v) => root = Some(PathBuf::from(String::decode_bencode_object(v)?)),
+                (b"start", v) => start = Some(u64::decode_bencode_object(v)?),
+                (b"end", v) => end = Some(u64::decode_bencode_object(v)?),
+                (b"priority", v) => {
+                    let v = String::decode_bencode_object(v)?;
+                    priority = Some(
+                        v.parse::<Priority>()
+                            .map_err(|e| decoding::Error::malformed_content(e.to_string()))?,
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        let hash = || hash.ok_or_else(|| decoding::Error::missing_field("hash"));
+        match q.as_deref() {
+            Some("add") => match (torrent, magnet, url) {
+                (Some(torrent), _, _) => Ok(Request::Add { torrent, root }),
+                (None, Some(link), _) => Ok(Request::AddMagnet { link, root }),
+                (None, None, Some(url)) => Ok(Request::AddUrl { url, root }),
+                (None, None, None) => Err(decoding::Error::missing_field("torrent")),
+            },
+            Some("list") => Ok(Request::List),
+            Some("pause") => Ok(Request::Pause(hash()?)),
+            Some("resume") => Ok(Request::Resume(hash()?)),
+            Some("recheck") => Ok(Request::Recheck(hash()?)),
+            Some("reannounce") => Ok(Request::Reannounce(hash()?)),
+            Some("set-priority") => Ok(Request::SetPriority {
+                hash: hash()?,
+                file: file.ok_or_else(|| decoding::Error::missing_field("file"))?,
+                priority: priority.ok_or_else(|| decoding::Error::missing_field("priority"))?,
+            }),
+            Some("prioritize-range") => Ok(Request::PrioritizeRange {
+                hash: hash()?,
+                file: file.ok_or_else(|| decoding::Error::missing_field("file"))?,
+                range: start.ok_or_else(|| decoding::Error::missing_field("start"))?
+                    ..end.ok_or_else(|| decoding::Error::missing_field("end"))?,
+            }),
+            Some("remove") => Ok(Request::Remove(hash()?)),
+            Some("stats") => Ok(Request::Stats),
+            Some(q) => Err(decoding::Error::unexpected_field(q)),
+            None => Err(decoding::Error::missing_field("q")),
+        }
+    }
+}
+
+impl ToBencode for TorrentStatus {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), encoding::Error> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"downloaded", self.downloaded)?;
+            e.emit_pair(b"hash", AsString(self.hash.as_slice()))?;
+            e.emit_pair(b"left", self.left)?;
+            e.emit_pair(b"name", &self.name)?;
+            e.emit_pair(b"state", &self.state)?;
+            e.emit_pair(b"uploaded", self.uploaded)
+        })
+    }
+}
+
+impl FromBencode for TorrentStatus {
+    fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        let mut status = TorrentStatus {
+            hash: [0; 20],
+            name: String::new(),
+            state: String::new(),
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+        };
+
+        let mut dict = object.try_into_dictionary()?;
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"hash", v) => {
+                    let AsString(v) = AsString::decode_bencode_object(v)?;
+                    status.hash = v.try_into().map_err(|_| {
+                        decoding::Error::malformed_content("info hash is not 20 bytes")
+                    })?;
+                }
+                (b"name", v) => status.name = String::decode_bencode_object(v)?,
+                (b"state", v) => status.state = String::decode_bencode_object(v)?,
+                (b"uploaded", v) => status.uploaded = u64::decode_bencode_object(v)?,
+                (b"downloaded", v) => status.downloaded = u64::decode_bencode_object(v)?,
+                (b"left", v) => status.left = u64::decode_bencode_object(v)?,
+                _ => {}
+            }
+        }
+
+        Ok(status)
+    }
+}
+
+impl ToBencode for Response {
+    const MAX_DEPTH: usize = 3;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), encoding::Error> {
+        encoder.emit_dict(|mut e| match self {
+            Response::Done => e.emit_pair(b"r", "done"),
+            Response::Added(hash) => {
+                e.emit_pair(b"hash", AsString(hash.as_slice()))?;
+                e.emit_pair(b"r", "added")
+            }
+            Response::Torrents(torrents) => {
+                e.emit_pair(b"r", "torrents")?;
+                e.emit_pair(b"torrents", torrents)
+            }
+            Response::Stats(stats) => {
+                e.emit_pair(b"count", stats.torrents)?;
+                e.emit_pair(b"down", stats.down)?;
+                e.emit_pair(b"downloaded", stats.downloaded)?;
+                e.emit_pair(b"r", "stats")?;
+                e.emit_pair(b"up", stats.up)?;
+                e.emit_pair(b"uploaded", stats.uploaded)
+            }
+            Response::Error(reason) => {
+                e.emit_pair(b"r", "error")?;
+                e.emit_pair(b"reason", reason)
+            }
+        })
+    }
+}
+
+impl FromBencode for Response {
+    fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        let (mut r, mut hash, mut torrents, mut reason) = (None, None, None, None);
+        let mut stats = SessionStatus::default();
+
+        let mut dict = object.try_into_dictionary()?;
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"r", v) => r = Some(String::decode_bencode_object(v)?),
+                (b"hash", v) => {
+                    let AsString(v) = AsString::decode_bencode_object(v)?;
+                    hash = Some(<[u8; 20]>::try_from(v).map_err(|_| {
+                        decoding::Error::malformed_content("info hash is not 20 bytes")
+                    })?);
+                }
+                (b"count", v) => stats.torrents = u64::decode_bencode_object(v)?,
+                (b"torrents", v) => torrents = Some(Vec::decode_bencode_object(v)?),
+                (b"up", v) => stats.up = u64::decode_bencode_object(v)?,
+                (b"down", v) => stats.down = u64::decode_bencode_object(v)?,
+                (b"uploaded", v) => stats.uploaded = u64::decode_bencode_object(v)?,
+                (b"downloaded", v) => stats.downloaded = u64::decode_bencode_object(v)?,
+                (b"reason", v) => reason = Some(String::decode_bencode_object(v)?),
+                _ => {}
+            }
+        }
+
+        match r.as_deref() {
+            Some("done") => Ok(Response::Done),
+            Some("added") => Ok(Response::Added(
+                hash.ok_or_else(|| decoding::Error::missing_field("hash"))?,
+            )),
+            Some("torrents") => Ok(Response::Torrents(torrents.unwrap_or_default())),
+            Some("stats") => Ok(Response::Stats(stats)),
+            Some("error") => Ok(Response::Error(reason.unwrap_or_default())),
+            Some(r) => Err(decoding::Error::unexpected_field(r)),
+            None => Err(decoding::Error::missing_field("r")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{Config, Context},
+        extensions::ExtensionRegistry,
+        sqlite::Database,
+        testing::TempDir,
+    };
+
+    #[tokio::test]
+    async fn test_rpc() -> Result<(), Report> {
+        let dir = TempDir::new();
+        let db = Database::open(dir.join("db"))?;
+        let socket = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await?);
+        let ctx = Context::new(Config::default(), 6881, ExtensionRegistry::default());
+        let (_datagram_tx, datagrams) = tokio::sync::mpsc::channel(1);
+        let mut manager = TorrentManager::new(ctx, db, socket, datagrams);
+        manager
+            .add(
+                TorrentInfo {
+                    hash: [1u8; 20],
+                    ..Default::default()
+                },
+                AddOptions::default(),
+            )
+            .await?;
+
+        let path = dir.join("rpc.sock");
+        let server = RpcServer::bind(&path, Arc::new(Mutex::new(manager)), AddOptions::default())?;
+        tokio::spawn(server.run());
+        let mut client = RpcClient::connect(&path).await?;
+
+        let Response::Torrents(list) = client.call(&Request::List).await? else {
+            panic!("expected the list of torrents");
+        };
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].hash, [1u8; 20]);
+        assert_eq!(list[0].state, "DownloadingMetadata");
+
+        assert_eq!(
+            client.call(&Request::Pause([1u8; 20])).await?,
+            Response::Done
+        );
+        let Response::Torrents(list) = client.call(&Request::List).await? else {
+            panic!("expected the list of torrents");
+        };
+        assert_eq!(list[0].state, "Paused");
+        assert_eq!(
+            client.call(&Request::Reannounce([1u8; 20])).await?,
+            Response::Done
+        );
+
+        // the daemon's errors don't end the connection
+        assert!(client.call(&Request::Resume([2u8; 20])).await.is_err());
+        let request = Request::Add {
+            torrent: b"i4e".to_vec(),
+            root: None,
+        };
+        assert!(client.call(&request).await.is_err());
+        let link = "magnet:?xt=urn:btih:0202020202020202020202020202020202020202".to_owned();
+        let request = Request::AddMagnet {
+            link,
+            root: Some(dir.join("two")),
+        };
+        assert_eq!(client.call(&request).await?, Response::Added([2u8; 20]));
+        // nothing listens there, the error makes it back to the client
+        let url = "http://127.0.0.1:1/a.torrent".to_owned();
+        assert!(client
+            .call(&Request::AddUrl { url, root: None })
+            .await
+            .is_err());
+
+        assert_eq!(
+            client.call(&Request::Remove([1u8; 20])).await?,
+            Response::Done
+        );
+        let Response::Stats(stats) = client.call(&Request::Stats).await? else {
+            panic!("expected the session stats");
+        };
+        assert_eq!(stats.torrents, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_round_trip() -> Result<(), Report> {
+        let requests = [
+            Request::Recheck([3u8; 20]),
+            Request::Reannounce([3u8; 20]),
+            Request::PrioritizeRange {
+                hash: [3u8; 20],
+                file: 1,
+                range: 100..200,
+            },
+            Request::Add {
+                torrent: b"d4:infode".to_vec(),
+                root: Some(PathBuf::from("/srv/films")),
+            },
+            Request::AddMagnet {
+                link: "magnet:?xt=urn:btih:0303030303030303030303030303030303030303".to_owned(),
+                root: None,
+            },
+            Request::AddUrl {
+                url: "http://127.0.0.1/a.torrent".to_owned(),
+                root: Some(PathBuf::from("films")),
+            },
+        ];
+        for request in requests {
+            let encoded = request.to_bencode().unwrap();
+            assert_eq!(Request::from_bencode(&encoded).unwrap(), request);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,352 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::Report;
+use serde::{Deserialize, Deserializer};
+use toml::{Table, Value};
+
+use crate::{
+    config::{Config, Tunables, BLOCK_SIZE},
+    data::GeneralError,
+    helpers::PortRange,
+    mse::Encryption,
+    proxy::Proxy,
+};
+
+// every variable starting with this overrides the key of the same name, EVERLASTING_MAX_PEERS=20
+// is max-peers = 20
+const ENV_PREFIX: &str = "EVERLASTING_";
+// the keys of Settings, other variables with the prefix are none of our business
+const KEYS: &[&str] = &[
+    "port",
+    "download-dir",
+    "torrents-dir",
+    "max-peers",
+    "block-size",
+    "upload-slots",
+    "upload-limit",
+    "download-limit",
+    "dht",
+    "lsd",
+    "port-mapping",
+    "utp",
+    "encryption",
+    "proxy",
+    "connect-timeout",
+    "tracker-timeout",
+    "tracker-retries",
+];
+// points at the settings file instead of an override
+const ENV_CONFIG: &str = "EVERLASTING_CONFIG";
+
+// what a settings file may contain, everything left out keeps its default. Rates are in KiB/s and
+// timeouts in seconds, like the command line flags of the same name
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Settings {
+    #[serde(deserialize_with = "port")]
+    pub port: Option<PortRange>,
+    pub download_dir: Option<PathBuf>,
+    pub torrents_dir: Option<PathBuf>,
+    pub max_peers: Option<usize>,
+    #[serde(deserialize_with = "block_size")]
+    pub block_size: Option<usize>,
+    pub upload_slots: Option<usize>,
+    pub upload_limit: Option<u64>,
+    pub download_limit: Option<u64>,
+    pub dht: Option<bool>,
+    pub lsd: Option<bool>,
+    pub port_mapping: Option<bool>,
+    pub utp: Option<bool>,
+    pub encryption: Option<Encryption>,
+    pub proxy: Option<Proxy>,
+    pub connect_timeout: Option<u64>,
+    pub tracker_timeout: Option<u64>,
+    pub tracker_retries: Option<u8>,
+}
+
+// a single port can be written as a number, a range has to be a string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Port {
+    Number(u16),
+    Range(String),
+}
+
+fn port<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<PortRange>, D::Error> {
+    let range = match Port::deserialize(deserializer)? {
+        Port::Number(port) => PortRange {
+            low: port,
+            high: port,
+        },
+        Port::Range(s) => s.parse().map_err(serde::de::Error::custom)?,
+    };
+
+    Ok(Some(range))
+}
+
+fn block_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<usize>, D::Error> {
+    check_block_size(usize::deserialize(deserializer)?)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+// peers can't be asked for empty blocks, and most of them hang up on anything over BLOCK_SIZE
+fn check_block_size(size: usize) -> Result<usize, GeneralError> {
+    match size {
+        1..=BLOCK_SIZE => Ok(size),
+        _ => Err(GeneralError::InvalidSettings(format!(
+            "block size {size} isn't between 1 and {BLOCK_SIZE}"
+        ))),
+    }
+}
+
+// for --block-size, which doesn't go through a settings file
+pub fn parse_block_size(s: &str) -> Result<usize, GeneralError> {
+    let size = s
+        .parse()
+        .map_err(|_| GeneralError::InvalidSettings(format!("block size {s} isn't a number")))?;
+
+    check_block_size(size)
+}
+
+impl Settings {
+    // the file that was asked for, or the one in the config directory if there is one, with the
+    // environment on top
+    pub fn load(path: Option<&Path>) -> Result<Self, Report> {
+        let path = path
+            .map(Path::to_path_buf)
+            .or_else(|| env::var_os(ENV_CONFIG).map(PathBuf::from));
+
+        let mut table = match path {
+            Some(path) => read(&path)?,
+            // unlike one that was asked for, the default is allowed to be missing
+            None => match default_path() {
+                Some(path) if path.exists() => read(&path)?,
+                _ => Table::new(),
+            },
+        };
+        table.extend(overrides(env::vars()));
+
+        Ok(Self::parse(table)?)
+    }
+
+    fn parse(table: Table) -> Result<Self, GeneralError> {
+        Value::Table(table)
+            .try_into()
+            .map_err(|e: toml::de::Error| GeneralError::InvalidSettings(e.message().to_owned()))
+    }
+
+    // fields set here win over the ones of `fallback`
+    pub fn or(self, fallback: Self) -> Self {
+        Self {
+            port: self.port.or(fallback.port),
+            download_dir: self.download_dir.or(fallback.download_dir),
+            torrents_dir: self.torrents_dir.or(fallback.torrents_dir),
+            max_peers: self.max_peers.or(fallback.max_peers),
+            block_size: self.block_size.or(fallback.block_size),
+            upload_slots: self.upload_slots.or(fallback.upload_slots),
+            upload_limit: self.upload_limit.or(fallback.upload_limit),
+            download_limit: self.download_limit.or(fallback.download_limit),
+            dht: self.dht.or(fallback.dht),
+            lsd: self.lsd.or(fallback.lsd),
+            port_mapping: self.port_mapping.or(fallback.port_mapping),
+            utp: self.utp.or(fallback.utp),
+            encryption: self.encryption.or(fallback.encryption),
+            proxy: self.proxy.or(fallback.proxy),
+            connect_timeout: self.connect_timeout.or(fallback.connect_timeout),
+            tracker_timeout: self.tracker_timeout.or(fallback.tracker_timeout),
+            tracker_retries: self.tracker_retries.or(fallback.tracker_retries),
+        }
+    }
+
+    // `config` with whatever was set here
+    pub fn apply(self, config: Config) -> Config {
+        let secs = |secs: Option<u64>, default| secs.map(Duration::from_secs).unwrap_or(default);
+        let tunables = Tunables {
+            peer_connect_timeout: secs(self.connect_timeout, config.tunables.peer_connect_timeout),
+            tracker_connect_timeout: secs(
+                self.tracker_timeout,
+                config.tunables.tracker_connect_timeout,
+            ),
+            tracker_retries: self
+                .tracker_retries
+                .unwrap_or(config.tunables.tracker_retries),
+            ..config.tunables
+        };
+
+        Config {
+            port: self.port.unwrap_or(config.port),
+            download_dir: self.download_dir.unwrap_or(config.download_dir),
+            torrents_dir: self.torrents_dir.unwrap_or(config.torrents_dir),
+            max_peers: self.max_peers.unwrap_or(config.max_peers),
+            block_size: self.block_size.unwrap_or(config.block_size),
+            upload_slots: self.upload_slots.unwrap_or(config.upload_slots),
+            upload_limit: self
+                .upload_limit
+                .map_or(config.upload_limit, |kib| kib << 10),
+            download_limit: self
+                .download_limit
+                .map_or(config.download_limit, |kib| kib << 10),
+            dht: self.dht.unwrap_or(config.dht),
+            lsd: self.lsd.unwrap_or(config.lsd),
+            port_mapping: self.port_mapping.unwrap_or(config.port_mapping),
+            utp: self.utp.unwrap_or(config.utp),
+            encryption: self.encryption.unwrap_or(config.encryption),
+            proxy: self.proxy.or(config.proxy),
+            tunables,
+            ..config
+        }
+    }
+}
+
+// $XDG_CONFIG_HOME/everlasting/config.toml, or the same under ~/.config
+fn default_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+
+    Some(dir.join("everlasting").join("config.toml"))
+}
+
+fn read(path: &Path) -> Result<Table, GeneralError> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        GeneralError::InvalidSettings(format!("{}: {e}", path.display()))
+    };
+    let text = fs::read_to_string(path).map_err(|e| invalid(&e))?;
+
+    toml::from_str(&text).map_err(|e| invalid(&e))
+}
+
+// EVERLASTING_DOWNLOAD_DIR becomes download-dir. Values are read like TOML when they can be, so
+// numbers and booleans keep their type, anything else is taken for a string
+fn overrides(vars: impl IntoIterator<Item = (String, String)>) -> Table {
+    vars.into_iter()
+        .filter(|(key, _)| key != ENV_CONFIG)
+        .filter_map(|(key, raw)| {
+            let key = key
+                .strip_prefix(ENV_PREFIX)?
+                .to_lowercase()
+                .replace('_', "-");
+            if !KEYS.contains(&key.as_str()) {
+                return None;
+            }
+            let value = toml::from_str::<Table>(&format!("value = {raw}"))
+                .ok()
+                .and_then(|mut table| table.remove("value"))
+                .unwrap_or(Value::String(raw));
+
+            Some((key, value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings() -> Result<(), Report> {
+        let table = toml::from_str(
+            r#"
+            port = "6881-6889"
+            download-dir = "/srv/torrents"
+            torrents-dir = "/srv/torrents/.torrents"
+            max-peers = 30
+            upload-limit = 100
+            dht = false
+            encryption = "required"
+            proxy = "socks5://127.0.0.1:9050"
+            "#,
+        )?;
+        let settings = Settings::parse(table)?;
+        assert_eq!(
+            settings.port,
+            Some(PortRange {
+                low: 6881,
+                high: 6889
+            })
+        );
+        assert_eq!(settings.encryption, Some(Encryption::Required));
+        assert_eq!(settings.proxy, Some("socks5://127.0.0.1:9050".parse()?));
+
+        let config = settings.apply(Config::default());
+        assert_eq!(config.download_dir, Path::new("/srv/torrents"));
+        assert_eq!(config.torrents_dir, Path::new("/srv/torrents/.torrents"));
+        assert_eq!(config.max_peers, 30);
+        assert_eq!(config.upload_limit, 100 << 10);
+        assert!(!config.dht);
+        // left alone
+        assert!(config.utp);
+        assert_eq!(config.upload_slots, Config::default().upload_slots);
+
+        // typos don't go unnoticed
+        let table = toml::from_str("max_peers = 30")?;
+        assert!(Settings::parse(table).is_err());
+
+        // neither do blocks peers can't be asked for
+        for size in ["block-size = 0", "block-size = 32768"] {
+            assert!(Settings::parse(toml::from_str(size)?).is_err());
+        }
+        assert_eq!(
+            Settings::parse(toml::from_str("block-size = 8192")?)?.block_size,
+            Some(8192)
+        );
+        assert!(parse_block_size("0").is_err());
+        assert_eq!(parse_block_size("16384")?, BLOCK_SIZE);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_overrides() -> Result<(), Report> {
+        let vars = [
+            ("EVERLASTING_PORT", "7000"),
+            ("EVERLASTING_DOWNLOAD_DIR", "./elsewhere"),
+            ("EVERLASTING_UTP", "false"),
+            ("EVERLASTING_CONFIG", "/etc/everlasting.toml"),
+            // some other program's, or left over from an older version
+            ("EVERLASTING_FOO", "bar"),
+            ("HOME", "/root"),
+        ]
+        .map(|(key, value)| (key.to_owned(), value.to_owned()));
+
+        let mut table: Table = toml::from_str("port = 6881\nmax-peers = 30")?;
+        table.extend(overrides(vars));
+        let settings = Settings::parse(table)?;
+        assert_eq!(
+            settings.port,
+            Some(PortRange {
+                low: 7000,
+                high: 7000
+            })
+        );
+        assert_eq!(settings.download_dir, Some(PathBuf::from("./elsewhere")));
+        assert_eq!(settings.utp, Some(false));
+        assert_eq!(settings.max_peers, Some(30));
+
+        // every key the environment may set is one a settings file may set
+        for key in KEYS {
+            let mut table = Table::new();
+            table.insert(key.to_string(), Value::Array(Vec::new()));
+            let Err(GeneralError::InvalidSettings(e)) = Settings::parse(table) else {
+                panic!("{key} took an array");
+            };
+            assert!(!e.contains("unknown field"), "{e}");
+        }
+
+        // the command line has the last word
+        let flags = Settings {
+            max_peers: Some(10),
+            ..Default::default()
+        };
+        let settings = flags.or(settings);
+        assert_eq!(settings.max_peers, Some(10));
+        assert_eq!(settings.utp, Some(false));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+use tokio::{
+    sync::{mpsc, watch},
+    time::timeout,
+};
+
+// tells every task that got a signal to wrap up, and waits until they're all done
+pub struct Shutdown {
+    tx: watch::Sender<bool>,
+    // cloned into every signal, the receiver closes once the last of them is dropped
+    guard: Option<mpsc::Sender<()>>,
+    done_rx: mpsc::Receiver<()>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (guard, done_rx) = mpsc::channel(1);
+
+        Self {
+            tx: watch::channel(false).0,
+            guard: Some(guard),
+            done_rx,
+        }
+    }
+
+    pub fn signal(&self) -> Signal {
+        Signal {
+            rx: Some(self.tx.subscribe()),
+            _guard: self.guard.clone(),
+        }
+    }
+
+    // false if some of them were still busy after `grace`
+    pub async fn trigger(&mut self, grace: Duration) -> bool {
+        self.tx.send_replace(true);
+        self.guard = None;
+
+        timeout(grace, self.done_rx.recv()).await.is_ok()
+    }
+}
+
+// held by a task for as long as it has something left to do once the shutdown started, the
+// default one never fires
+#[derive(Debug, Clone, Default)]
+pub struct Signal {
+    rx: Option<watch::Receiver<bool>>,
+    // never sent on, only dropped
+    _guard: Option<mpsc::Sender<()>>,
+}
+
+impl Signal {
+    pub async fn recv(&mut self) {
+        let started = match &mut self.rx {
+            Some(rx) => rx.wait_for(|&stop| stop).await.is_ok(),
+            None => false,
+        };
+        // the session went away without shutting down, nobody is waiting for us
+        if !started {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let mut shutdown = Shutdown::new();
+        let mut signal = shutdown.signal();
+        let task = tokio::spawn(async move {
+            signal.recv().await;
+            // still wrapping up
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        });
+
+        assert!(shutdown.trigger(Duration::from_secs(5)).await);
+        assert!(task.is_finished());
+
+        // whoever holds on to theirs gets cut off
+        let mut shutdown = Shutdown::new();
+        let _stuck = shutdown.signal();
+        assert!(!shutdown.trigger(Duration::from_millis(50)).await);
+
+        // a signal of nothing waits forever
+        let idle = timeout(Duration::from_millis(50), Signal::default().recv()).await;
+        assert!(idle.is_err());
+    }
+}
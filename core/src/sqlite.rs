@@ -0,0 +1,816 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Utc};
+use color_eyre::Report;
+use rand::Rng;
+use tokio::{
+    sync::{watch, RwLock},
+    task::AbortHandle,
+};
+use tracing::debug;
+
+use crate::{
+    data::GeneralError,
+    stats::Stats,
+    torrent::{State, Torrent},
+};
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Added = 0,
+    StateChanged,
+    Finished,
+    TrackerError,
+    Recheck,
+    Removed,
+}
+
+impl TryFrom<u8> for Kind {
+    type Error = GeneralError;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        use Kind::*;
+
+        [
+            Added,
+            StateChanged,
+            Finished,
+            TrackerError,
+            Recheck,
+            Removed,
+        ]
+        .into_iter()
+        .find(|&kind| kind as u8 == v)
+        .ok_or(GeneralError::CorruptRecord)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub time: DateTime<Utc>,
+    pub kind: Kind,
+    pub detail: String,
+}
+
+impl Entry {
+    // <8:unix millis><1:kind><detail>
+    fn to_bytes(&self) -> Vec<u8> {
+        [
+            self.time.timestamp_millis().to_be_bytes().as_slice(),
+            &[self.kind as u8],
+            self.detail.as_bytes(),
+        ]
+        .concat()
+    }
+
+    fn from_bytes(v: &[u8]) -> Result<Self, Report> {
+        if v.len() < 9 {
+            return Err(GeneralError::CorruptRecord.into());
+        }
+
+        let millis = i64::from_be_bytes(v[..8].try_into()?);
+        let time = Utc
+            .timestamp_millis_opt(millis)
+            .single()
+            .ok_or(GeneralError::CorruptRecord)?;
+
+        Ok(Self {
+            time,
+            kind: Kind::try_from(v[8])?,
+            detail: String::from_utf8(v[9..].to_vec())?,
+        })
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = format!("{:?}", self.kind);
+        write!(
+            f,
+            "{} {:<12} {}",
+            self.time.format("%Y-%m-%d %H:%M:%S"),
+            kind,
+            self.detail
+        )
+    }
+}
+
+// bytes moved for a single torrent on a single day (UTC)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transfer {
+    pub hash: [u8; 20],
+    pub day: NaiveDate,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+impl Transfer {
+    // key: <20:hash><4:days since CE>, value: <8:uploaded><8:downloaded>
+    fn from_bytes(k: &[u8], v: &[u8]) -> Result<Self, Report> {
+        if k.len() != 24 || v.len() != 16 {
+            return Err(GeneralError::CorruptRecord.into());
+        }
+
+        let days = i32::from_be_bytes(k[20..].try_into()?);
+        Ok(Self {
+            hash: k[..20].try_into()?,
+            day: NaiveDate::from_num_days_from_ce_opt(days).ok_or(GeneralError::CorruptRecord)?,
+            uploaded: u64::from_be_bytes(v[..8].try_into()?),
+            downloaded: u64::from_be_bytes(v[8..].try_into()?),
+        })
+    }
+}
+
+// how a peer treated us, kept per address across sessions since private trackers tend to hand out
+// the same peers over and over
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Reputation {
+    // verified bytes only, anything that failed its hash check counts against them instead
+    pub delivered: u64,
+    pub hash_failures: u32,
+    pub snubs: u32,
+    pub connects: u32,
+    pub failed_connects: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerEvent {
+    Connected,
+    ConnectFailed,
+    Delivered(u64),
+    HashFailure,
+    Snubbed,
+}
+
+impl Reputation {
+    // <8:delivered><4:hash failures><4:snubs><4:connects><4:failed connects>
+    fn to_bytes(self) -> Vec<u8> {
+        [
+            self.delivered.to_be_bytes().as_slice(),
+            &self.hash_failures.to_be_bytes(),
+            &self.snubs.to_be_bytes(),
+            &self.connects.to_be_bytes(),
+            &self.failed_connects.to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    fn from_bytes(v: &[u8]) -> Result<Self, Report> {
+        if v.len() != 24 {
+            return Err(GeneralError::CorruptRecord.into());
+        }
+        let u32_at = |i: usize| u32::from_be_bytes(v[i..i + 4].try_into().unwrap());
+
+        Ok(Self {
+            delivered: u64::from_be_bytes(v[..8].try_into()?),
+            hash_failures: u32_at(8),
+            snubs: u32_at(12),
+            connects: u32_at(16),
+            failed_connects: u32_at(20),
+        })
+    }
+
+    fn apply(&mut self, event: PeerEvent) {
+        match event {
+            PeerEvent::Connected => self.connects = self.connects.saturating_add(1),
+            PeerEvent::ConnectFailed => {
+                self.failed_connects = self.failed_connects.saturating_add(1)
+            }
+            PeerEvent::Delivered(n) => self.delivered = self.delivered.saturating_add(n),
+            PeerEvent::HashFailure => self.hash_failures = self.hash_failures.saturating_add(1),
+            PeerEvent::Snubbed => self.snubs = self.snubs.saturating_add(1),
+        }
+    }
+
+    // peers we know nothing about end up at 0.5, reliable peers that delivered a lot above that
+    // and peers that sent garbage well below
+    pub fn score(&self) -> f64 {
+        let attempts = self.connects as f64 + self.failed_connects as f64;
+        let reliability = (self.connects as f64 + 1.0) / (attempts + 2.0);
+        let throughput = (self.delivered as f64 / (1 << 20) as f64).ln_1p();
+
+        reliability * (1.0 + throughput) - 2.0 * self.hash_failures as f64 - 0.5 * self.snubs as f64
+    }
+}
+
+// what a torrent needs to pick up where it left off after a restart
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ResumeData {
+    // verified and flushed, these don't get hashed again
+    pub pieces: Vec<usize>,
+    // across every session, the trackers only ever hear about the current one
+    pub uploaded: u64,
+    pub downloaded: u64,
+    // the trackers were told we finished already, they only want to hear that once
+    pub completed: bool,
+}
+
+impl ResumeData {
+    // <8:uploaded><8:downloaded><1:completed><bitfield>, the first piece in the high bit of the
+    // first byte like in a Bitfield message
+    fn to_bytes(&self) -> Vec<u8> {
+        let len = self.pieces.iter().max().map_or(0, |&i| i / 8 + 1);
+        let mut bitfield = vec![0u8; len];
+        for &i in &self.pieces {
+            bitfield[i / 8] |= 0x80 >> (i % 8);
+        }
+
+        [
+            self.uploaded.to_be_bytes().as_slice(),
+            &self.downloaded.to_be_bytes(),
+            &[self.completed as u8],
+            &bitfield,
+        ]
+        .concat()
+    }
+
+    fn from_bytes(v: &[u8]) -> Result<Self, Report> {
+        if v.len() < 17 {
+            return Err(GeneralError::CorruptRecord.into());
+        }
+
+        let pieces = (0..(v.len() - 17) * 8)
+            .filter(|&i| v[17 + i / 8] & (0x80 >> (i % 8)) != 0)
+            .collect();
+
+        Ok(Self {
+            pieces,
+            uploaded: u64::from_be_bytes(v[..8].try_into()?),
+            downloaded: u64::from_be_bytes(v[8..16].try_into()?),
+            completed: v[16] != 0,
+        })
+    }
+}
+
+// a node of the DHT routing table, kept with the last time its bucket changed so the buckets that
+// went quiet in the meantime get refreshed right after a restart
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DhtNode {
+    pub id: [u8; 20],
+    pub addr: SocketAddr,
+    pub changed: DateTime<Utc>,
+}
+
+impl DhtNode {
+    // key: <4:"node"><20:id>, value: <8:unix millis><2:port><4 or 16:ip>
+    fn to_bytes(self) -> Vec<u8> {
+        let ip = match self.addr.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+
+        [
+            self.changed.timestamp_millis().to_be_bytes().as_slice(),
+            &self.addr.port().to_be_bytes(),
+            &ip,
+        ]
+        .concat()
+    }
+
+    fn from_bytes(k: &[u8], v: &[u8]) -> Result<Self, Report> {
+        let ip = match v.len() {
+            14 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&v[10..])?)),
+            26 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&v[10..])?)),
+            _ => return Err(GeneralError::CorruptRecord.into()),
+        };
+        let millis = i64::from_be_bytes(v[..8].try_into()?);
+
+        Ok(Self {
+            id: k
+                .strip_prefix(b"node")
+                .and_then(|id| id.try_into().ok())
+                .ok_or(GeneralError::CorruptRecord)?,
+            addr: SocketAddr::new(ip, u16::from_be_bytes(v[8..10].try_into()?)),
+            changed: Utc
+                .timestamp_millis_opt(millis)
+                .single()
+                .ok_or(GeneralError::CorruptRecord)?,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Database {
+    inner: sled::Db,
+}
+
+impl Database {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Report> {
+        Ok(Self {
+            inner: sled::open(path)?,
+        })
+    }
+
+    pub fn record<S: Into<String>>(
+        &self,
+        hash: &[u8; 20],
+        kind: Kind,
+        detail: S,
+    ) -> Result<(), Report> {
+        let history = self.inner.open_tree("history")?;
+
+        // ids are monotonic, so entries of a torrent come out in the order they went in
+        let id = self.inner.generate_id()?;
+        let key = [hash.as_slice(), &id.to_be_bytes()].concat();
+
+        let entry = Entry {
+            time: Utc::now(),
+            kind,
+            detail: detail.into(),
+        };
+        history.insert(key, entry.to_bytes())?;
+
+        Ok(())
+    }
+
+    // what a check of the files turned up, whatever is missing gets downloaded again
+    pub fn record_check(
+        &self,
+        hash: &[u8; 20],
+        verified: usize,
+        total: usize,
+    ) -> Result<(), Report> {
+        let missing = total - verified;
+        self.record(
+            hash,
+            Kind::Recheck,
+            format!("{verified} of {total} pieces verified, {missing} missing"),
+        )
+    }
+
+    pub fn history(&self, hash: &[u8; 20]) -> Result<Vec<Entry>, Report> {
+        let history = self.inner.open_tree("history")?;
+
+        history
+            .scan_prefix(hash)
+            .values()
+            .map(|v| Entry::from_bytes(&v?))
+            .collect()
+    }
+
+    // name the torrent was added under, if it was ever added at all
+    pub fn name(&self, hash: &[u8; 20]) -> Result<Option<String>, Report> {
+        let history = self.history(hash)?;
+        let added = history.into_iter().find(|entry| entry.kind == Kind::Added);
+
+        Ok(added.map(|entry| entry.detail))
+    }
+
+    pub fn add_transfer(
+        &self,
+        hash: &[u8; 20],
+        day: NaiveDate,
+        uploaded: u64,
+        downloaded: u64,
+    ) -> Result<(), Report> {
+        let transfer = self.inner.open_tree("transfer")?;
+        let key = [hash.as_slice(), &day.num_days_from_ce().to_be_bytes()].concat();
+
+        transfer.update_and_fetch(key, |v| {
+            let (up, down) = match v {
+                Some(v) if v.len() == 16 => (
+                    u64::from_be_bytes(v[..8].try_into().unwrap()),
+                    u64::from_be_bytes(v[8..].try_into().unwrap()),
+                ),
+                _ => (0, 0),
+            };
+            let up = (up + uploaded).to_be_bytes();
+            let down = (down + downloaded).to_be_bytes();
+
+            Some([up, down].concat())
+        })?;
+
+        Ok(())
+    }
+
+    // every torrent's transfer, day by day
+    pub fn transfers(&self) -> Result<Vec<Transfer>, Report> {
+        let transfer = self.inner.open_tree("transfer")?;
+
+        transfer
+            .iter()
+            .map(|kv| {
+                let (k, v) = kv?;
+                Transfer::from_bytes(&k, &v)
+            })
+            .collect()
+    }
+
+    // the key we announce with has to survive restarts, trackers use it to tell us apart from
+    // other peers behind the same address and to follow us across IP changes
+    pub fn announce_key(&self, hash: &[u8; 20]) -> Result<u32, Report> {
+        let keys = self.inner.open_tree("keys")?;
+
+        if let Some(v) = keys.get(hash)? {
+            let v = v
+                .as_ref()
+                .try_into()
+                .map_err(|_| GeneralError::CorruptRecord)?;
+            return Ok(u32::from_be_bytes(v));
+        }
+
+        let key = rand::thread_rng().gen::<u32>();
+        keys.insert(hash, &key.to_be_bytes())?;
+
+        Ok(key)
+    }
+
+    pub fn reputation(&self, ip: &IpAddr) -> Result<Reputation, Report> {
+        let peers = self.inner.open_tree("peers")?;
+
+        match peers.get(ip.to_string())? {
+            Some(v) => Reputation::from_bytes(&v),
+            None => Ok(Reputation::default()),
+        }
+    }
+
+    // ports change between sessions, addresses on a private tracker mostly don't
+    pub fn peer_event(&self, ip: &IpAddr, event: PeerEvent) -> Result<(), Report> {
+        let peers = self.inner.open_tree("peers")?;
+
+        // records that can't be read anymore start over instead of blocking every update
+        peers.update_and_fetch(ip.to_string(), |v| {
+            let mut reputation = v
+                .and_then(|v| Reputation::from_bytes(v).ok())
+                .unwrap_or_default();
+            reputation.apply(event);
+
+            Some(reputation.to_bytes())
+        })?;
+
+        Ok(())
+    }
+
+    // best peers first, whoever we know nothing about keeps their place among the unknowns
+    pub fn rank<T>(&self, peers: Vec<T>, addr: impl Fn(&T) -> IpAddr) -> Vec<T> {
+        let mut scored: Vec<_> = peers
+            .into_iter()
+            .map(|peer| {
+                let reputation = self.reputation(&addr(&peer)).unwrap_or_default();
+                (reputation.score(), peer)
+            })
+            .collect();
+        scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+
+        scored.into_iter().map(|(_, peer)| peer).collect()
+    }
+
+    pub fn resume_data(&self, hash: &[u8; 20]) -> Result<Option<ResumeData>, Report> {
+        let resume = self.inner.open_tree("resume")?;
+
+        resume
+            .get(hash)?
+            .map(|v| ResumeData::from_bytes(&v))
+            .transpose()
+    }
+
+    // the id our DHT node went by last time, nodes that know us keep routing to us under it
+    pub fn dht_id(&self) -> Result<Option<[u8; 20]>, Report> {
+        let dht = self.inner.open_tree("dht")?;
+
+        dht.get(b"id")?
+            .map(|v| {
+                v.as_ref()
+                    .try_into()
+                    .map_err(|_| GeneralError::CorruptRecord.into())
+            })
+            .transpose()
+    }
+
+    pub fn dht_nodes(&self) -> Result<Vec<DhtNode>, Report> {
+        let dht = self.inner.open_tree("dht")?;
+
+        dht.scan_prefix(b"node")
+            .map(|kv| {
+                let (k, v) = kv?;
+                DhtNode::from_bytes(&k, &v)
+            })
+            .collect()
+    }
+
+    // replaces the table of the last session, nodes that were dropped since don't come back
+    pub fn save_dht(&self, id: &[u8; 20], nodes: &[DhtNode]) -> Result<(), Report> {
+        let dht = self.inner.open_tree("dht")?;
+
+        let mut batch = sled::Batch::default();
+        for key in dht.iter().keys() {
+            batch.remove(key?);
+        }
+        batch.insert(b"id".as_slice(), id.as_slice());
+        for node in nodes {
+            batch.insert([b"node".as_slice(), &node.id].concat(), node.to_bytes());
+        }
+        dht.apply_batch(batch)?;
+
+        Ok(())
+    }
+
+    // sled writes back in the background, this waits for everything to be on disk
+    pub fn flush(&self) -> Result<(), Report> {
+        self.inner.flush()?;
+
+        Ok(())
+    }
+
+    pub fn save_resume_data(&self, hash: &[u8; 20], data: &ResumeData) -> Result<(), Report> {
+        let resume = self.inner.open_tree("resume")?;
+        resume.insert(hash, data.to_bytes())?;
+
+        Ok(())
+    }
+
+    // keeps the resume data of a torrent current, a crash costs at most the last minute
+    pub fn follow_resume(&self, hash: [u8; 20], torrent: Arc<RwLock<Torrent>>) -> AbortHandle {
+        let db = self.clone();
+
+        let task = tokio::spawn(async move {
+            let mut timer = tokio::time::interval(Duration::from_secs(60));
+            let mut last = None;
+
+            loop {
+                timer.tick().await;
+                // nothing worth saving until the metadata is there
+                let Some(data) = torrent.read().await.resume_data() else {
+                    continue;
+                };
+                if last.as_ref() == Some(&data) {
+                    continue;
+                }
+
+                if let Err(e) = db.save_resume_data(&hash, &data) {
+                    debug!("failed to save resume data: {e}");
+                }
+                last = Some(data);
+            }
+        });
+
+        task.abort_handle()
+    }
+
+    // adds whatever a torrent moved to today's totals every once in a while
+    pub fn follow_transfer(&self, hash: [u8; 20], stats: Arc<Stats>) -> AbortHandle {
+        let db = self.clone();
+
+        let task = tokio::spawn(async move {
+            let mut timer = tokio::time::interval(Duration::from_secs(60));
+            let (mut up, mut down, _) = stats.up_down_left();
+
+            loop {
+                timer.tick().await;
+                let (uploaded, downloaded, _) = stats.up_down_left();
+                if (uploaded, downloaded) == (up, down) {
+                    continue;
+                }
+
+                let today = Utc::now().date_naive();
+                if let Err(e) = db.add_transfer(&hash, today, uploaded - up, downloaded - down) {
+                    debug!("failed to record transfer: {e}");
+                }
+
+                (up, down) = (uploaded, downloaded);
+            }
+        });
+
+        task.abort_handle()
+    }
+
+    // records every state change of a torrent for as long as the torrent is around
+    pub fn follow(&self, hash: [u8; 20], mut rx: watch::Receiver<State>) {
+        let db = self.clone();
+
+        tokio::spawn(async move {
+            let mut from = rx.borrow_and_update().clone();
+
+            while rx.changed().await.is_ok() {
+                let to = rx.borrow_and_update().clone();
+
+                let mut result =
+                    db.record(&hash, Kind::StateChanged, format!("{from:?} -> {to:?}"));
+                if from == State::Downloading && to == State::Seeding {
+                    result = result.and(db.record(&hash, Kind::Finished, "all pieces verified"));
+                }
+
+                if let Err(e) = result {
+                    debug!("failed to record history: {e}");
+                }
+
+                from = to;
+            }
+        });
+    }
+}
+// use std::sync::mpsc::{self, Sender};
+
+// use color_eyre::Report;
+// use rusqlite::Connection;
+// use tokio::runtime::Runtime;
+
+// type Job<T> = Box<dyn FnOnce(&mut Option<T>) + Send>;
+
+// pub struct Executor<T> {
+//     inner: Sender<Job<T>>,
+// }
+
+// impl<T> Executor<T> {
+//     fn new(inner: Sender<Job<T>>) -> Self {
+//         Self { inner }
+//     }
+// }
+
+// struct DHT {
+//     inner: Executor<Connection>,
+// }
+
+// impl DHT {
+//     fn new() -> Self {
+//         let (rx, tx) = mpsc::channel();
+//         let exec = Executor::new(rx);
+
+//         Ok(Self {
+//             inner: Connection::open_in_memory()?,
+//         })
+//     }
+
+//     fn spawn<F>(&self, job: F)
+//     where
+//         F: FnOnce(&mut Option<T>) + Send + 'static,
+//     {
+//     }
+
+//     fn run() {}
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::Table;
+
+    #[test]
+    fn test_history() -> Result<(), Report> {
+        let db = Database {
+            inner: sled::Config::new().temporary(true).open()?,
+        };
+        let (a, b) = ([1u8; 20], [2u8; 20]);
+
+        db.record(&a, Kind::Added, "ubuntu.iso")?;
+        db.record(&b, Kind::Added, "debian.iso")?;
+        db.record(&a, Kind::TrackerError, "unregistered torrent")?;
+        db.record_check(&a, 7, 10)?;
+
+        let history = db.history(&a)?;
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0].kind, Kind::Added);
+        assert_eq!(history[1].detail, "unregistered torrent");
+        assert_eq!(history[2].kind, Kind::Recheck);
+        assert_eq!(history[2].detail, "7 of 10 pieces verified, 3 missing");
+
+        let entry = &history[1];
+        assert_eq!(&Entry::from_bytes(&entry.to_bytes())?, entry);
+        assert!(Entry::from_bytes(&[0u8; 4]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_announce_key() -> Result<(), Report> {
+        let db = Database {
+            inner: sled::Config::new().temporary(true).open()?,
+        };
+        let (a, b) = ([1u8; 20], [2u8; 20]);
+
+        let key = db.announce_key(&a)?;
+        assert_eq!(db.announce_key(&a)?, key);
+        assert_ne!(db.announce_key(&b)?, key);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transfer() -> Result<(), Report> {
+        let db = Database {
+            inner: sled::Config::new().temporary(true).open()?,
+        };
+        let (a, b) = ([1u8; 20], [2u8; 20]);
+        let day = |d| NaiveDate::from_ymd_opt(2023, 6, d).unwrap();
+
+        db.record(&a, Kind::Added, "ubuntu.iso")?;
+        db.add_transfer(&a, day(1), 10, 100)?;
+        db.add_transfer(&a, day(1), 5, 0)?;
+        db.add_transfer(&a, day(2), 1, 2)?;
+        db.add_transfer(&b, day(1), 0, 7)?;
+
+        let transfers = db.transfers()?;
+        assert_eq!(transfers.len(), 3);
+        assert_eq!(
+            transfers[0],
+            Transfer {
+                hash: a,
+                day: day(1),
+                uploaded: 15,
+                downloaded: 100
+            }
+        );
+        assert_eq!(transfers[1].day, day(2));
+
+        assert_eq!(db.name(&a)?.as_deref(), Some("ubuntu.iso"));
+        assert_eq!(db.name(&b)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dht_nodes() -> Result<(), Report> {
+        let db = Database {
+            inner: sled::Config::new().temporary(true).open()?,
+        };
+        assert_eq!(db.dht_id()?, None);
+
+        let changed = Utc.timestamp_millis_opt(1_700_000_000_000).unwrap();
+        let nodes = [
+            DhtNode {
+                id: [0xff; 20],
+                addr: "192.0.2.1:6881".parse()?,
+                changed,
+            },
+            DhtNode {
+                id: [0x0f; 20],
+                addr: "[2001:db8::1]:51413".parse()?,
+                changed,
+            },
+        ];
+        db.save_dht(&[0u8; 20], &nodes)?;
+
+        // the next session starts where this one stopped, buckets that went quiet included
+        let table = Table::load(&db)?;
+        let mut saved = table.saved();
+        saved.sort_by_key(|node| node.id);
+        assert_eq!(saved, [nodes[1], nodes[0]]);
+        assert_eq!(db.dht_id()?, Some([0u8; 20]));
+
+        // nodes that got dropped in the meantime don't come back
+        db.save_dht(&[0u8; 20], &nodes[..1])?;
+        assert_eq!(db.dht_nodes()?, nodes[..1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_data() -> Result<(), Report> {
+        let db = Database {
+            inner: sled::Config::new().temporary(true).open()?,
+        };
+        let (a, b) = ([1u8; 20], [2u8; 20]);
+
+        let data = ResumeData {
+            pieces: vec![0, 7, 8, 21],
+            uploaded: 1 << 20,
+            downloaded: 3 << 20,
+            completed: true,
+        };
+        db.save_resume_data(&a, &data)?;
+        assert_eq!(db.resume_data(&a)?, Some(data.clone()));
+        assert_eq!(db.resume_data(&b)?, None);
+        assert_eq!(data.to_bytes()[17..], [0x81, 0x80, 0x04]);
+
+        // nothing verified yet is fine, less than the counters isn't
+        let empty = ResumeData::default();
+        assert_eq!(ResumeData::from_bytes(&empty.to_bytes())?, empty);
+        assert!(ResumeData::from_bytes(&[0u8; 16]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reputation() -> Result<(), Report> {
+        let db = Database {
+            inner: sled::Config::new().temporary(true).open()?,
+        };
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let (good, bad, flaky) = (ip("10.0.0.1"), ip("10.0.0.2"), ip("10.0.0.3"));
+
+        db.peer_event(&good, PeerEvent::Connected)?;
+        db.peer_event(&good, PeerEvent::Delivered(64 << 20))?;
+        db.peer_event(&bad, PeerEvent::Connected)?;
+        db.peer_event(&bad, PeerEvent::HashFailure)?;
+        db.peer_event(&flaky, PeerEvent::ConnectFailed)?;
+        db.peer_event(&flaky, PeerEvent::ConnectFailed)?;
+
+        let reputation = db.reputation(&good)?;
+        assert_eq!(reputation.delivered, 64 << 20);
+        assert_eq!(Reputation::from_bytes(&reputation.to_bytes())?, reputation);
+        assert_eq!(db.reputation(&ip("10.0.0.4"))?.score(), 0.5);
+
+        let unknown = ip("10.0.0.4");
+        let ranked = db.rank(vec![bad, flaky, unknown, good], |ip| *ip);
+        assert_eq!(ranked, vec![good, unknown, flaky, bad]);
+
+        Ok(())
+    }
+}
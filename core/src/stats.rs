@@ -0,0 +1,645 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use chrono::NaiveDate;
+
+use crate::sqlite::Transfer;
+
+// transfer counters of a single torrent, bumped by whoever moves the bytes and read by the
+// trackers at announce time
+#[derive(Debug, Default)]
+pub struct Stats {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
+    // bytes thrown away because the piece they were part of failed its hash check
+    corrupt: AtomicU64,
+    // blocks we already had, from endgame or peers that ignore our cancels
+    redundant: AtomicU64,
+    // connections that came up and haven't gone away yet, with what they last reported
+    peers: Mutex<HashMap<SocketAddr, PeerStatus>>,
+}
+
+impl Stats {
+    pub fn uploaded(&self, n: u64) {
+        self.uploaded.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn downloaded(&self, n: u64) {
+        self.downloaded.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn corrupt(&self, n: u64) {
+        self.corrupt.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn redundant(&self, n: u64) {
+        self.redundant.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn set_left(&self, n: u64) {
+        self.left.store(n, Ordering::Relaxed);
+    }
+
+    pub fn up_down_left(&self) -> (u64, u64, u64) {
+        (
+            self.uploaded.load(Ordering::Relaxed),
+            self.downloaded.load(Ordering::Relaxed),
+            self.left.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn corrupt_redundant(&self) -> (u64, u64) {
+        (
+            self.corrupt.load(Ordering::Relaxed),
+            self.redundant.load(Ordering::Relaxed),
+        )
+    }
+
+    // the peer counts until the guard is dropped, however its connection ends
+    pub fn connected(self: &Arc<Self>, peer: SocketAddr) -> Connected {
+        let status = PeerStatus::default();
+        self.peers.lock().unwrap().insert(peer, status);
+
+        Connected {
+            stats: self.clone(),
+            peer,
+        }
+    }
+
+    pub fn peers(&self) -> Vec<(SocketAddr, PeerStatus)> {
+        let peers = self.peers.lock().unwrap();
+        peers
+            .iter()
+            .map(|(peer, status)| (*peer, status.clone()))
+            .collect()
+    }
+}
+
+// how a connected peer and we treat each other, as far as the dashboard is concerned
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStatus {
+    // the `v` of its extension handshake
+    pub client: Option<String>,
+    // choked and interested are about us, the peer_ ones about how we treat the peer
+    pub choked: bool,
+    pub interested: bool,
+    pub peer_choked: bool,
+    pub peer_interested: bool,
+    // pieces the peer told us it has
+    pub pieces: usize,
+    pub seed: bool,
+}
+
+impl Default for PeerStatus {
+    fn default() -> Self {
+        Self {
+            client: None,
+            choked: true,
+            interested: false,
+            peer_choked: true,
+            peer_interested: false,
+            pieces: 0,
+            seed: false,
+        }
+    }
+}
+
+impl PeerStatus {
+    // between 0 and 1, of a torrent with this many pieces
+    pub fn completed(&self, pieces: usize) -> f64 {
+        match (self.seed, pieces) {
+            (true, _) => 1.0,
+            (false, 0) => 0.0,
+            (false, n) => self.pieces.min(n) as f64 / n as f64,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Connected {
+    stats: Arc<Stats>,
+    peer: SocketAddr,
+}
+
+impl Connected {
+    pub fn report(&self, status: PeerStatus) {
+        self.stats.peers.lock().unwrap().insert(self.peer, status);
+    }
+}
+
+impl Drop for Connected {
+    fn drop(&mut self) {
+        self.stats.peers.lock().unwrap().remove(&self.peer);
+    }
+}
+
+// seconds the moving averages are taken over
+pub const RATE_WINDOW: usize = 10;
+
+// bytes per second over the last RATE_WINDOW seconds
+#[derive(Debug, Clone)]
+pub struct Rate {
+    start: Instant,
+    // bytes moved in each of the last seconds, by second modulo the window
+    buckets: [u64; RATE_WINDOW],
+    // the second the newest bucket belongs to
+    last: u64,
+    total: u64,
+}
+
+impl Rate {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            start: now,
+            buckets: [0; RATE_WINDOW],
+            last: 0,
+            total: 0,
+        }
+    }
+
+    // empties the buckets of the seconds nothing happened in
+    fn advance(&mut self, now: Instant) {
+        let second = now.saturating_duration_since(self.start).as_secs();
+
+        for s in (self.last + 1..=second).take(RATE_WINDOW) {
+            self.buckets[s as usize % RATE_WINDOW] = 0;
+        }
+        self.last = self.last.max(second);
+    }
+
+    pub fn add(&mut self, n: u64, now: Instant) {
+        self.advance(now);
+        self.buckets[self.last as usize % RATE_WINDOW] += n;
+        self.total += n;
+    }
+
+    pub fn per_second(&mut self, now: Instant) -> u64 {
+        self.advance(now);
+        self.buckets.iter().sum::<u64>() / RATE_WINDOW as u64
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+impl Default for Rate {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+// what a peer and we exchanged, in bytes per second and in total
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PeerRates {
+    pub up: u64,
+    pub down: u64,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub interested: bool,
+    pub snubbed: bool,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    up: Rate,
+    down: Rate,
+    interested: bool,
+    snubbed: bool,
+}
+
+impl Counters {
+    fn snapshot(&mut self, now: Instant) -> PeerRates {
+        PeerRates {
+            up: self.up.per_second(now),
+            down: self.down.per_second(now),
+            uploaded: self.up.total(),
+            downloaded: self.down.total(),
+            interested: self.interested,
+            snubbed: self.snubbed,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RatesInner {
+    session: Counters,
+    peers: HashMap<SocketAddr, Counters>,
+}
+
+// transfer rates of the whole session and of every connected peer, the connections count every
+// frame they send and receive, the choker and the dashboard read them
+#[derive(Debug, Default)]
+pub struct Rates {
+    inner: Mutex<RatesInner>,
+}
+
+impl Rates {
+    pub fn uploaded(&self, peer: SocketAddr, n: usize) {
+        self.add(peer, true, n as u64, Instant::now());
+    }
+
+    pub fn downloaded(&self, peer: SocketAddr, n: usize) {
+        self.add(peer, false, n as u64, Instant::now());
+    }
+
+    fn add(&self, peer: SocketAddr, up: bool, n: u64, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let peer = inner.peers.entry(peer).or_default();
+
+        for counters in [&mut inner.session, peer] {
+            match up {
+                true => counters.up.add(n, now),
+                false => counters.down.add(n, now),
+            }
+        }
+    }
+
+    pub fn set_interested(&self, peer: SocketAddr, interested: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.peers.entry(peer).or_default().interested = interested;
+    }
+
+    pub fn set_snubbed(&self, peer: SocketAddr, snubbed: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.peers.entry(peer).or_default().snubbed = snubbed;
+    }
+
+    pub fn peer_gone(&self, peer: SocketAddr) {
+        self.inner.lock().unwrap().peers.remove(&peer);
+    }
+
+    pub fn session(&self) -> PeerRates {
+        self.session_at(Instant::now())
+    }
+
+    fn session_at(&self, now: Instant) -> PeerRates {
+        self.inner.lock().unwrap().session.snapshot(now)
+    }
+
+    pub fn peers(&self) -> Vec<(SocketAddr, PeerRates)> {
+        self.peers_at(Instant::now())
+    }
+
+    fn peers_at(&self, now: Instant) -> Vec<(SocketAddr, PeerRates)> {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner
+            .peers
+            .iter_mut()
+            .map(|(peer, counters)| (*peer, counters.snapshot(now)))
+            .collect()
+    }
+
+    // where an interested peer stands among the others, the ones that give us the most first and
+    // the ones taking the most from us once nobody gives us anything, None if it isn't interested.
+    // Peers snubbing us come last, they only get the slots nobody else wants
+    pub fn rank(&self, peer: SocketAddr) -> Option<usize> {
+        self.rank_at(peer, Instant::now())
+    }
+
+    fn rank_at(&self, peer: SocketAddr, now: Instant) -> Option<usize> {
+        let mut peers: Vec<_> = self
+            .peers_at(now)
+            .into_iter()
+            .filter(|(_, rates)| rates.interested)
+            .collect();
+        // ties go to the lower address, so every connection sees the same order
+        peers.sort_by_key(|(addr, rates)| {
+            let rate = std::cmp::Reverse((rates.down, rates.up));
+            (rates.snubbed, rate, *addr)
+        });
+
+        peers.iter().position(|(addr, _)| *addr == peer)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum Format {
+    #[default]
+    Text,
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Totals {
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+impl Totals {
+    fn add(&mut self, transfer: &Transfer) {
+        self.uploaded += transfer.uploaded;
+        self.downloaded += transfer.downloaded;
+    }
+
+    // nothing downloaded means there is no ratio to speak of
+    pub fn ratio(&self) -> Option<f64> {
+        (self.downloaded != 0).then(|| self.uploaded as f64 / self.downloaded as f64)
+    }
+}
+
+// everything the history knows about what we moved, for people keeping an eye on their ratio
+// across trackers
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub total: Totals,
+    // ordered by info hash, with the name the torrent was added under
+    pub torrents: Vec<([u8; 20], Option<String>, Totals)>,
+    pub days: Vec<(NaiveDate, Totals)>,
+}
+
+impl Summary {
+    pub fn new(transfers: &[Transfer], name: impl Fn(&[u8; 20]) -> Option<String>) -> Self {
+        let mut total = Totals::default();
+        let mut torrents: BTreeMap<[u8; 20], Totals> = BTreeMap::new();
+        let mut days: BTreeMap<NaiveDate, Totals> = BTreeMap::new();
+
+        for transfer in transfers {
+            total.add(transfer);
+            torrents.entry(transfer.hash).or_default().add(transfer);
+            days.entry(transfer.day).or_default().add(transfer);
+        }
+
+        Self {
+            total,
+            torrents: torrents
+                .into_iter()
+                .map(|(hash, totals)| (hash, name(&hash), totals))
+                .collect(),
+            days: days.into_iter().collect(),
+        }
+    }
+
+    pub fn export(&self, format: Format) -> String {
+        match format {
+            Format::Text => self.text(),
+            Format::Csv => self.csv(),
+            Format::Json => self.json(),
+        }
+    }
+
+    fn text(&self) -> String {
+        let ratio = |t: &Totals| t.ratio().map_or("-".to_owned(), |r| format!("{r:.3}"));
+        let row = |key: &str, t: &Totals| {
+            format!(
+                "{key:<40} {:>16} {:>16} {:>8}\n",
+                t.uploaded,
+                t.downloaded,
+                ratio(t)
+            )
+        };
+
+        let mut out = format!(
+            "{:<40} {:>16} {:>16} {:>8}\n",
+            "", "uploaded", "downloaded", "ratio"
+        );
+        out += &row("total", &self.total);
+
+        out += "\n";
+        for (hash, name, totals) in &self.torrents {
+            let hash = hex::encode(hash);
+            out += &row(name.as_deref().unwrap_or(&hash), totals);
+        }
+
+        out += "\n";
+        for (day, totals) in &self.days {
+            out += &row(&day.to_string(), totals);
+        }
+
+        out
+    }
+
+    // a single table, the first column tells the rows apart
+    fn csv(&self) -> String {
+        let ratio = |t: &Totals| t.ratio().map_or(String::new(), |r| r.to_string());
+        let mut out = "kind,key,name,uploaded,downloaded,ratio\n".to_owned();
+
+        let mut row = |kind: &str, key: &str, name: &str, t: &Totals| {
+            let name = match name.contains([',', '"', '\n']) {
+                true => format!("\"{}\"", name.replace('"', "\"\"")),
+                false => name.to_owned(),
+            };
+            let _ = writeln!(
+                out,
+                "{kind},{key},{name},{},{},{}",
+                t.uploaded,
+                t.downloaded,
+                ratio(t)
+            );
+        };
+
+        row("total", "", "", &self.total);
+        for (hash, name, totals) in &self.torrents {
+            row(
+                "torrent",
+                &hex::encode(hash),
+                name.as_deref().unwrap_or_default(),
+                totals,
+            );
+        }
+        for (day, totals) in &self.days {
+            row("day", &day.to_string(), "", totals);
+        }
+
+        out
+    }
+
+    fn json(&self) -> String {
+        let totals = |t: &Totals| {
+            let ratio = t.ratio().map_or("null".to_owned(), |r| r.to_string());
+            format!(
+                "\"uploaded\":{},\"downloaded\":{},\"ratio\":{ratio}",
+                t.uploaded, t.downloaded
+            )
+        };
+
+        let torrents: Vec<_> = self
+            .torrents
+            .iter()
+            .map(|(hash, name, t)| {
+                let name = name.as_deref().map_or("null".to_owned(), json_string);
+                format!(
+                    "{{\"hash\":\"{}\",\"name\":{name},{}}}",
+                    hex::encode(hash),
+                    totals(t)
+                )
+            })
+            .collect();
+        let days: Vec<_> = self
+            .days
+            .iter()
+            .map(|(day, t)| format!("{{\"day\":\"{day}\",{}}}", totals(t)))
+            .collect();
+
+        format!(
+            "{{\"total\":{{{}}},\"torrents\":[{}],\"days\":[{}]}}\n",
+            totals(&self.total),
+            torrents.join(","),
+            days.join(",")
+        )
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rates() {
+        let now = Instant::now();
+        let secs = |n| now + Duration::from_secs(n);
+
+        let mut rate = Rate::new(now);
+        rate.add(1000, now);
+        rate.add(1000, secs(9));
+        assert_eq!(rate.per_second(secs(9)), 200);
+        // the first second dropped out of the window
+        assert_eq!(rate.per_second(secs(10)), 100);
+        assert_eq!(rate.per_second(secs(60)), 0);
+        assert_eq!(rate.total(), 2000);
+
+        let rates = Rates::default();
+        let (a, b, c): (SocketAddr, SocketAddr, SocketAddr) = (
+            "10.0.0.1:6881".parse().unwrap(),
+            "10.0.0.2:6881".parse().unwrap(),
+            "10.0.0.3:6881".parse().unwrap(),
+        );
+        rates.add(a, false, 500, now);
+        rates.add(b, false, 5000, now);
+        rates.add(c, true, 100, now);
+        rates.add(a, true, 50, now);
+        assert_eq!(rates.session_at(now).down, 550);
+        assert_eq!(rates.session_at(now).uploaded, 150);
+
+        // only interested peers get ranked, the ones giving us the most first
+        assert_eq!(rates.rank_at(a, now), None);
+        for peer in [a, b, c] {
+            rates.set_interested(peer, true);
+        }
+        assert_eq!(rates.rank_at(b, now), Some(0));
+        assert_eq!(rates.rank_at(a, now), Some(1));
+        assert_eq!(rates.rank_at(c, now), Some(2));
+
+        // peers snubbing us go to the back, whatever they gave us before
+        rates.set_snubbed(b, true);
+        assert_eq!(rates.rank_at(a, now), Some(0));
+        assert_eq!(rates.rank_at(b, now), Some(2));
+        rates.set_snubbed(b, false);
+
+        rates.peer_gone(b);
+        assert_eq!(rates.rank_at(a, now), Some(0));
+        assert_eq!(rates.peers_at(now).len(), 2);
+        assert_eq!(rates.session_at(now).downloaded, 5500);
+    }
+
+    #[test]
+    fn test_peer_status() {
+        let stats = Arc::new(Stats::default());
+        let (a, b): (SocketAddr, SocketAddr) = (
+            "10.0.0.1:6881".parse().unwrap(),
+            "10.0.0.2:6881".parse().unwrap(),
+        );
+
+        let connected = stats.connected(a);
+        let other = stats.connected(b);
+        assert_eq!(stats.peers().len(), 2);
+
+        connected.report(PeerStatus {
+            client: Some("everlasting 0.1".to_owned()),
+            interested: true,
+            pieces: 3,
+            ..Default::default()
+        });
+        let peers = stats.peers();
+        let (_, status) = peers.iter().find(|(peer, _)| *peer == a).unwrap();
+        assert_eq!(status.client.as_deref(), Some("everlasting 0.1"));
+        assert_eq!(status.completed(4), 0.75);
+        // nothing is known about the torrent's pieces before the metadata came in
+        assert_eq!(status.completed(0), 0.0);
+
+        let seed = PeerStatus {
+            seed: true,
+            ..Default::default()
+        };
+        assert_eq!(seed.completed(0), 1.0);
+
+        // gone as soon as their connections are
+        drop(other);
+        assert_eq!(stats.peers().len(), 1);
+        drop(connected);
+        assert!(stats.peers().is_empty());
+    }
+
+    #[test]
+    fn test_summary() {
+        let day = |d| NaiveDate::from_ymd_opt(2023, 6, d).unwrap();
+        let transfer = |hash, day, uploaded, downloaded| Transfer {
+            hash,
+            day,
+            uploaded,
+            downloaded,
+        };
+        let (a, b) = ([1u8; 20], [2u8; 20]);
+
+        let summary = Summary::new(
+            &[
+                transfer(a, day(1), 300, 100),
+                transfer(a, day(2), 100, 0),
+                transfer(b, day(2), 0, 0),
+            ],
+            |hash| (hash == &a).then(|| "say \"hi\", ok".to_owned()),
+        );
+        assert_eq!(summary.total.ratio(), Some(4.0));
+        assert_eq!(summary.torrents[1].2.ratio(), None);
+        assert_eq!(summary.days.len(), 2);
+
+        let csv = summary.export(Format::Csv);
+        let mut lines = csv.lines().skip(1);
+        assert_eq!(lines.next(), Some("total,,,400,100,4"));
+        assert_eq!(
+            lines.next().unwrap(),
+            format!(
+                "torrent,{},\"say \"\"hi\"\", ok\",400,100,4",
+                hex::encode(a)
+            )
+        );
+
+        let json = summary.export(Format::Json);
+        assert!(json.starts_with(r#"{"total":{"uploaded":400,"downloaded":100,"ratio":4},"#));
+        assert!(json.contains(r#""name":"say \"hi\", ok""#));
+        assert!(json.contains(r#""name":null,"uploaded":0,"downloaded":0,"ratio":null"#));
+    }
+}
@@ -0,0 +1,543 @@
+use std::collections::{hash_map::Entry, BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use color_eyre::Report;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::data::{GeneralError, Info, Mode, DOWNLOAD_DIR};
+
+// where verified pieces end up, the piece manager assembles and hashes them in memory and only
+// hands over what it's going to keep, so a backend never sees a block that failed its hash check
+pub trait Storage: Send + Sync {
+    // part of a piece that was written before, pieces may span several files and that's up to
+    // the backend to sort out
+    fn read_block(&self, index: usize, range: Range<usize>) -> Result<Vec<u8>, Report>;
+
+    fn write_block(&mut self, index: usize, begin: usize, block: &[u8]) -> Result<(), Report>;
+
+    // several blocks in one go, in order
+    fn write_batch(&mut self, blocks: &[(usize, usize, Bytes)]) -> Result<(), Report> {
+        blocks
+            .iter()
+            .try_for_each(|(index, begin, block)| self.write_block(*index, *begin, block))
+    }
+
+    // whatever was written so far has to survive a power loss once this returns
+    fn flush(&mut self) -> Result<(), Report>;
+
+    // whatever was written so far is in the files once this returns, even if it isn't synced.
+    // Backends that don't hold on to anything have nothing to do
+    fn drain(&mut self) -> Result<(), Report> {
+        Ok(())
+    }
+
+    // pieces passed their hash check, or lost it again when `valid` is false
+    fn verified(&mut self, indices: &[usize], valid: bool) -> Result<(), Report>;
+
+    // whether all `len` bytes of a piece are still there to be read back
+    fn contains(&self, index: usize, len: usize) -> bool;
+
+    // sizes the files before anything gets written to them, backends without files have nothing
+    // to do
+    fn allocate(&mut self) -> Result<(), Report> {
+        Ok(())
+    }
+
+    // data that was downloaded elsewhere may already be where finished data goes, it's taken for
+    // unverified until a recheck read it back from there
+    fn claim_files(&mut self) -> Result<(), Report> {
+        Ok(())
+    }
+}
+
+// how files get sized up front
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum Allocation {
+    // only the length is set, the filesystem finds room for the blocks as they're written
+    #[default]
+    Sparse,
+    // every byte is written once, so the space is ours and the file ends up less fragmented
+    Full,
+}
+
+// one file per torrent file below a root directory, unfinished ones carry a .part suffix so other
+// tools never pick up half-written data
+pub struct FsStorage {
+    piece_len: u64,
+    root: PathBuf,
+    mode: Mode,
+    verified: Vec<bool>,
+    // files written to since the last flush, by index
+    dirty: BTreeSet<usize>,
+    allocation: Allocation,
+}
+
+impl FsStorage {
+    pub fn new(info: &Info) -> Self {
+        Self {
+            piece_len: info.piece_length,
+            root: PathBuf::from(DOWNLOAD_DIR),
+            mode: info.mode.clone(),
+            verified: vec![false; info.pieces.len()],
+            dirty: BTreeSet::new(),
+            allocation: Allocation::default(),
+        }
+    }
+
+    pub fn set_allocation(&mut self, allocation: Allocation) {
+        self.allocation = allocation;
+    }
+
+    // where the files are expected to be, use move_storage to take them along
+    pub fn set_root(&mut self, root: &Path) {
+        self.root = root.to_path_buf();
+    }
+
+    // on-disk location and length of every file
+    pub fn paths(&self) -> Vec<(PathBuf, u64)> {
+        let mut offset = 0;
+
+        self.mode
+            .files(&self.root)
+            .into_iter()
+            .map(|(path, length)| {
+                let pieces = self.file_pieces(offset, length);
+                offset += length;
+
+                let verified = |i: usize| self.verified.get(i).copied().unwrap_or(false);
+                match pieces.into_iter().all(verified) {
+                    true => (path, length),
+                    false => {
+                        let mut name = path.file_name().unwrap_or_default().to_owned();
+                        name.push(".part");
+                        (path.with_file_name(name), length)
+                    }
+                }
+            })
+            .collect()
+    }
+
+    // pieces covering a file at the given offset within the torrent
+    fn file_pieces(&self, offset: u64, length: u64) -> Range<usize> {
+        if length == 0 {
+            return 0..0;
+        }
+
+        let piece = |offset: u64| (offset / self.piece_len) as usize;
+        piece(offset)..piece(offset + length - 1) + 1
+    }
+
+    // files overlapping a range of a piece: file index, path, offset within the file and the part
+    // of the range that lives there
+    fn segments(
+        &self,
+        index: usize,
+        range: Range<usize>,
+    ) -> Vec<(usize, PathBuf, u64, Range<usize>)> {
+        let start = self.piece_len * index as u64 + range.start as u64;
+        let end = start + range.len() as u64;
+        let mut segments = Vec::new();
+        let mut offset = 0;
+
+        for (file, (path, length)) in self.paths().into_iter().enumerate() {
+            let overlap = start.max(offset)..end.min(offset + length);
+
+            if !overlap.is_empty() {
+                let part = (overlap.start - start) as usize..(overlap.end - start) as usize;
+                segments.push((file, path, overlap.start - offset, part));
+            }
+
+            offset += length;
+        }
+
+        segments
+    }
+
+    // writes a block through whichever of `files` are open already, opening the others
+    fn write_into(
+        &mut self,
+        files: &mut HashMap<PathBuf, fs::File>,
+        index: usize,
+        begin: usize,
+        block: &[u8],
+    ) -> Result<(), Report> {
+        for (file, path, offset, part) in self.segments(index, begin..begin + block.len()) {
+            let f = match files.entry(path) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    if let Some(parent) = entry.key().parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+
+                    let f = fs::OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(false)
+                        .open(entry.key())?;
+                    entry.insert(f)
+                }
+            };
+            f.seek(SeekFrom::Start(offset))?;
+            f.write_all(&block[part])?;
+
+            self.dirty.insert(file);
+        }
+
+        Ok(())
+    }
+
+    // moves every file below a new root and keeps serving them from there, a rename when both
+    // live on the same filesystem, a copy that has to pass `check` before the originals go
+    // otherwise
+    pub fn move_storage(
+        &mut self,
+        to: &Path,
+        check: impl Fn(&Self) -> Result<(), Report>,
+    ) -> Result<(), Report> {
+        let from = std::mem::replace(&mut self.root, to.to_path_buf());
+        let moves: Vec<_> = self
+            .paths()
+            .into_iter()
+            .filter_map(|(dst, _)| Some((from.join(dst.strip_prefix(to).ok()?), dst)))
+            .collect();
+
+        // whatever got moved so far and whether it was renamed, so a failure can put it all back
+        let mut moved = Vec::new();
+        let mut result = (|| {
+            for (src, dst) in &moves {
+                if let Some(parent) = dst.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                if fs::rename(src, dst).is_ok() {
+                    moved.push((src, dst, true));
+                } else if src.exists() {
+                    moved.push((src, dst, false));
+                    fs::copy(src, dst)?;
+                    // copies get the current time, archives don't want that
+                    let mtime = fs::metadata(src)?.modified()?;
+                    fs::File::options()
+                        .write(true)
+                        .open(dst)?
+                        .set_modified(mtime)?;
+                }
+            }
+
+            Ok::<_, Report>(())
+        })();
+
+        if result.is_ok() && moved.iter().any(|(_, _, renamed)| !renamed) {
+            result = check(self);
+        }
+
+        if let Err(e) = result {
+            // the renamed files are the only copy there is
+            for (src, dst, renamed) in moved.into_iter().rev() {
+                let undone = if renamed {
+                    fs::rename(dst, src)
+                } else {
+                    fs::remove_file(dst)
+                };
+                match undone {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        debug!("failed to move {} back: {e}", dst.display())
+                    }
+                    _ => (),
+                }
+            }
+            self.root = from;
+
+            return Err(e);
+        }
+
+        for (src, _) in &moves {
+            let _ = fs::remove_file(src);
+
+            // clean up whatever directories are empty now, remove_dir refuses non-empty ones
+            for dir in src.ancestors().skip(1).take_while(|dir| *dir != from) {
+                if fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Storage for FsStorage {
+    fn read_block(&self, index: usize, range: Range<usize>) -> Result<Vec<u8>, Report> {
+        let mut buf = vec![0u8; range.len()];
+
+        for (_, path, offset, part) in self.segments(index, range) {
+            let mut file = fs::File::open(path).map_err(|_| GeneralError::NonExistentFile)?;
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut buf[part])?;
+        }
+
+        Ok(buf)
+    }
+
+    fn write_block(&mut self, index: usize, begin: usize, block: &[u8]) -> Result<(), Report> {
+        self.write_into(&mut HashMap::new(), index, begin, block)
+    }
+
+    // consecutive pieces mostly land in the same file, it only gets opened once for all of them
+    fn write_batch(&mut self, blocks: &[(usize, usize, Bytes)]) -> Result<(), Report> {
+        let mut files = HashMap::new();
+
+        for (index, begin, block) in blocks {
+            self.write_into(&mut files, *index, *begin, block)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Report> {
+        let paths = self.paths();
+
+        // syncing a handle that was opened read-only fails on some platforms
+        for file in std::mem::take(&mut self.dirty) {
+            fs::OpenOptions::new()
+                .write(true)
+                .open(&paths[file].0)?
+                .sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    fn verified(&mut self, indices: &[usize], valid: bool) -> Result<(), Report> {
+        let before = self.paths();
+
+        for &index in indices {
+            let verified = self
+                .verified
+                .get_mut(index)
+                .ok_or(GeneralError::InvalidPieceIdx)?;
+            *verified = valid;
+        }
+
+        // files that got their last piece are moved into place in one go, files that lost one
+        // are unfinished again and get their suffix back
+        for ((from, _), (to, _)) in before.iter().zip(self.paths()) {
+            if *from != to && from.exists() {
+                fs::rename(from, to)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // a file that got deleted or truncated while we weren't looking takes its pieces along
+    fn contains(&self, index: usize, len: usize) -> bool {
+        self.segments(index, 0..len)
+            .into_iter()
+            .all(|(_, path, offset, part)| {
+                let size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+                size >= offset + part.len() as u64
+            })
+    }
+
+    // complete files of pieces we haven't verified get their suffix, a file we have both of is
+    // left alone, the unfinished one is what we wrote
+    fn claim_files(&mut self) -> Result<(), Report> {
+        let finished = self.mode.files(&self.root);
+
+        for ((from, _), (to, _)) in finished.iter().zip(self.paths()) {
+            if *from != to && from.exists() && !to.exists() {
+                fs::rename(from, to)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // files that already have their length are left alone, so are the bytes in the ones that
+    // don't, empty files get created as well
+    fn allocate(&mut self) -> Result<(), Report> {
+        for (path, length) in self.paths() {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            let size = file.metadata()?.len();
+            if size >= length {
+                continue;
+            }
+
+            match self.allocation {
+                Allocation::Sparse => file.set_len(length)?,
+                Allocation::Full => {
+                    file.seek(SeekFrom::Start(size))?;
+                    io::copy(&mut io::repeat(0).take(length - size), &mut file)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// pieces kept in memory, for tests and for torrents that never need to touch the disk
+#[derive(Default)]
+pub struct MemoryStorage {
+    pieces: HashMap<usize, Vec<u8>>,
+}
+
+impl Storage for MemoryStorage {
+    fn read_block(&self, index: usize, range: Range<usize>) -> Result<Vec<u8>, Report> {
+        let piece = self
+            .pieces
+            .get(&index)
+            .ok_or(GeneralError::InvalidPieceIdx)?;
+
+        piece
+            .get(range)
+            .map(<[u8]>::to_vec)
+            .ok_or(GeneralError::InvalidRange.into())
+    }
+
+    fn write_block(&mut self, index: usize, begin: usize, block: &[u8]) -> Result<(), Report> {
+        let piece = self.pieces.entry(index).or_default();
+        if piece.len() < begin + block.len() {
+            piece.resize(begin + block.len(), 0);
+        }
+        piece[begin..begin + block.len()].copy_from_slice(block);
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Report> {
+        Ok(())
+    }
+
+    fn verified(&mut self, indices: &[usize], valid: bool) -> Result<(), Report> {
+        if !valid {
+            indices.iter().for_each(|index| {
+                self.pieces.remove(index);
+            });
+        }
+
+        Ok(())
+    }
+
+    fn contains(&self, index: usize, len: usize) -> bool {
+        self.pieces
+            .get(&index)
+            .map(|piece| piece.len() >= len)
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{multi_info, TempDir};
+
+    #[test]
+    fn test_fs_storage() -> Result<(), Report> {
+        let tmp = TempDir::new();
+        let info = multi_info("album", &[("a", 3), ("b", 5)], 4, vec![[0u8; 20]; 2]);
+        let mut storage = FsStorage::new(&info);
+        storage.set_root(&tmp);
+
+        // the first piece spans both files
+        storage.write_block(0, 0, b"abcd")?;
+        storage.flush()?;
+        assert_eq!(std::fs::read(tmp.join("album/a.part"))?, b"abc");
+        assert_eq!(storage.read_block(0, 2..4)?, b"cd");
+        assert!(storage.contains(0, 4) && !storage.contains(1, 4));
+
+        storage.verified(&[0], true)?;
+        assert!(tmp.join("album/a").exists() && tmp.join("album/b.part").exists());
+
+        storage.write_block(1, 0, b"efgh")?;
+        storage.verified(&[1], true)?;
+        assert_eq!(std::fs::read(tmp.join("album/b"))?, b"defgh");
+
+        storage.verified(&[1], false)?;
+        assert!(tmp.join("album/b.part").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_rollback() -> Result<(), Report> {
+        let tmp = TempDir::new();
+        let (from, to) = (tmp.join("from"), tmp.join("to"));
+        let info = multi_info("album", &[("a", 4), ("sub/b", 4)], 4, vec![[0u8; 20]; 2]);
+        let mut storage = FsStorage::new(&info);
+        storage.set_root(&from);
+        storage.write_block(0, 0, b"abcd")?;
+        storage.write_block(1, 0, b"efgh")?;
+        storage.flush()?;
+
+        // the second file can't go where it's supposed to, after the first one already went
+        fs::create_dir_all(to.join("album"))?;
+        fs::write(to.join("album/sub"), b"")?;
+        assert!(storage.move_storage(&to, |_| Ok(())).is_err());
+
+        assert_eq!(fs::read(from.join("album/a.part"))?, b"abcd");
+        assert!(!to.join("album/a.part").exists());
+        assert!(storage.paths()[0].0.starts_with(&from));
+        assert_eq!(storage.read_block(1, 0..4)?, b"efgh");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_allocate() -> Result<(), Report> {
+        let files = [("a", 6), ("empty", 0), ("sub/b", 2)];
+        let info = multi_info("album", &files, 4, vec![[0u8; 20]; 2]);
+
+        for allocation in [Allocation::Sparse, Allocation::Full] {
+            let tmp = TempDir::new();
+            let mut storage = FsStorage::new(&info);
+            storage.set_root(&tmp);
+            storage.set_allocation(allocation);
+
+            // what's there already survives
+            storage.write_block(0, 0, b"ab")?;
+            storage.allocate()?;
+            assert_eq!(std::fs::read(tmp.join("album/a.part"))?, b"ab\0\0\0\0");
+            assert_eq!(std::fs::metadata(tmp.join("album/empty"))?.len(), 0);
+            assert_eq!(std::fs::metadata(tmp.join("album/sub/b.part"))?.len(), 2);
+
+            // writes land in the sized files like they would in growing ones
+            storage.write_block(1, 0, b"efgh")?;
+            storage.flush()?;
+            assert_eq!(storage.read_block(1, 0..4)?, b"efgh");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_storage() -> Result<(), Report> {
+        let mut storage = MemoryStorage::default();
+
+        storage.write_block(3, 4, b"efgh")?;
+        storage.write_block(3, 0, b"abcd")?;
+        assert_eq!(storage.read_block(3, 2..6)?, b"cdef");
+        assert!(storage.read_block(3, 6..10).is_err());
+        assert!(storage.contains(3, 8) && !storage.contains(3, 9));
+
+        storage.verified(&[3], false)?;
+        assert!(!storage.contains(3, 8));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+use std::{net::SocketAddr, ops::Range, sync::Arc, time::Duration};
+
+use color_eyre::Report;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::RwLock,
+    time::sleep,
+};
+use tracing::debug;
+
+use crate::{data::GeneralError, torrent::Torrent};
+
+const MAX_HEAD: usize = 8192;
+const CHUNK_SIZE: u64 = 1 << 18;
+
+// serves the files of a torrent over plain HTTP so players like VLC or mpv can start playing
+// while the download is still in progress: GET /<file index>[/<anything>]
+pub struct StreamServer {
+    listener: TcpListener,
+    torrent: Arc<RwLock<Torrent>>,
+}
+
+impl StreamServer {
+    pub async fn bind(addr: SocketAddr, torrent: Arc<RwLock<Torrent>>) -> Result<Self, Report> {
+        let listener = TcpListener::bind(addr).await?;
+        debug!("streaming files on http://{}", listener.local_addr()?);
+
+        Ok(Self { listener, torrent })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Report> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub async fn run(self) -> Result<(), Report> {
+        loop {
+            let (stream, peer) = self.listener.accept().await?;
+            let torrent = self.torrent.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = serve(stream, torrent).await {
+                    debug!("stream to [{peer}] ended: {e}");
+                }
+            });
+        }
+    }
+}
+
+async fn serve(mut stream: TcpStream, torrent: Arc<RwLock<Torrent>>) -> Result<(), Report> {
+    let head = read_head(&mut stream).await?;
+    let mut lines = head.lines();
+
+    let mut request = lines.next().unwrap_or_default().split(' ');
+    let (method, target) = (request.next(), request.next());
+    if !matches!(method, Some("GET") | Some("HEAD")) {
+        return respond(&mut stream, "405 Method Not Allowed", &[]).await;
+    }
+
+    let range = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("range"))
+        .map(|(_, v)| v.trim().to_owned());
+
+    let index = target
+        .and_then(|target| target.trim_start_matches('/').split('/').next())
+        .and_then(|index| index.parse::<usize>().ok());
+    let files = torrent.read().await.files()?;
+
+    let file = index.and_then(|i| files.get(i).cloned().map(|file| (i, file)));
+    let Some((index, (_, length))) = file else {
+        return respond(&mut stream, "404 Not Found", &[]).await;
+    };
+
+    let range = range.and_then(|range| parse_range(&range, length));
+    let (status, range, content_range) = match range {
+        Some(range) if range.is_empty() => {
+            let unsatisfied = format!("bytes */{length}");
+            return respond(
+                &mut stream,
+                "416 Range Not Satisfiable",
+                &[("Content-Range", &unsatisfied)],
+            )
+            .await;
+        }
+        Some(range) => {
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, length);
+            ("206 Partial Content", range, Some(content_range))
+        }
+        // players probing with several ranges at once are fine with the whole file
+        None => ("200 OK", 0..length, None),
+    };
+
+    let content_length = (range.end - range.start).to_string();
+    let mut headers = vec![
+        ("Accept-Ranges", "bytes"),
+        ("Content-Type", "application/octet-stream"),
+        ("Content-Length", content_length.as_str()),
+    ];
+    if let Some(content_range) = &content_range {
+        headers.push(("Content-Range", content_range));
+    }
+    respond(&mut stream, status, &headers).await?;
+
+    if method == Some("HEAD") || range.is_empty() {
+        return Ok(());
+    }
+
+    let mut offset = range.start;
+
+    while offset < range.end {
+        let chunk = offset..(offset + CHUNK_SIZE).min(range.end);
+        wait_for(&torrent, index, chunk.clone(), range.end).await?;
+
+        // verified pieces may not have been written yet, so not straight from the file
+        let buf = torrent.read().await.read_range(index, chunk.clone())?;
+        stream.write_all(&buf).await?;
+
+        offset = chunk.end;
+    }
+
+    Ok(())
+}
+
+// blocks until the chunk is on disk, boosting everything from the read position onwards so the
+// player doesn't stall again right after
+async fn wait_for(
+    torrent: &RwLock<Torrent>,
+    file: usize,
+    chunk: Range<u64>,
+    end: u64,
+) -> Result<(), Report> {
+    let mut boosted = false;
+
+    loop {
+        if torrent.read().await.has_range(file, chunk.clone())? {
+            return Ok(());
+        }
+
+        if !boosted {
+            debug!("waiting for bytes {chunk:?} of file {file}");
+            torrent
+                .write()
+                .await
+                .prioritize_range(file, chunk.start..end)?;
+            boosted = true;
+        }
+
+        sleep(Duration::from_millis(500)).await;
+    }
+}
+
+async fn read_head(stream: &mut TcpStream) -> Result<String, Report> {
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_HEAD {
+            return Err(
+                GeneralError::UnexpectedResponse("request head too large".to_owned()).into(),
+            );
+        }
+
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(GeneralError::BrokenPipe.into());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    headers: &[(&str, &str)],
+) -> Result<(), Report> {
+    let mut head = format!("HTTP/1.1 {status}\r\nConnection: close\r\n");
+    for (k, v) in headers {
+        head += &format!("{k}: {v}\r\n");
+    }
+    if !headers.iter().any(|(k, _)| *k == "Content-Length") {
+        head += "Content-Length: 0\r\n";
+    }
+    head += "\r\n";
+
+    stream.write_all(head.as_bytes()).await.map_err(Into::into)
+}
+
+// single ranges only: "bytes=a-b", "bytes=a-" and "bytes=-n". Anything else, several ranges
+// included, is None and the header gets ignored. A range with nothing of the file in it comes back
+// empty
+fn parse_range(value: &str, length: u64) -> Option<Range<u64>> {
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => length.saturating_sub(suffix.parse().ok()?)..length,
+        (start, "") => start.parse().ok()?..length,
+        (start, end) => {
+            let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+            if end < start {
+                return None;
+            }
+            start..(end + 1).min(length)
+        }
+    };
+
+    Some(range.start.min(range.end)..range.end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::{Info, Mode, TorrentInfo},
+        torrent::AddOptions,
+    };
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(0..100));
+        assert_eq!(parse_range("bytes=500-", 1000), Some(500..1000));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(900..1000));
+        assert_eq!(parse_range("bytes=900-5000", 1000), Some(900..1000));
+
+        // past the end is unsatisfiable, anything we don't understand gets the whole file
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(1000..1000));
+        assert_eq!(parse_range("bytes=2000-3000", 1000), Some(1000..1000));
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("bytes=6-5", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+    }
+
+    #[tokio::test]
+    async fn test_empty_file() -> Result<(), Report> {
+        let info = TorrentInfo {
+            info: Some(Info {
+                mode: Mode::Single {
+                    name: "empty".to_owned(),
+                    length: 0,
+                    md5sum: None,
+                },
+                piece_length: 16,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let torrent = Torrent::new(info, AddOptions::default())?;
+        let server =
+            StreamServer::bind("127.0.0.1:0".parse()?, Arc::new(RwLock::new(torrent))).await?;
+        let addr = server.local_addr()?;
+        tokio::spawn(server.run());
+
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(b"GET /0 HTTP/1.1\r\n\r\n").await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Length: 0\r\n"));
+        assert!(!response.contains("Content-Range"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+use crate::data::{File, Info, Mode};
+
+// a directory of its own under the system's temp dir, removed with everything in it on drop
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("everlasting-{}", rand::random::<u32>()));
+        fs::create_dir_all(&path).expect("temp dir can be created");
+
+        Self(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// a torrent of several files under `dir_name`, paths with a '/' end up in subdirectories
+pub fn multi(dir_name: &str, files: &[(&str, u64)]) -> Mode {
+    let file = |&(path, length): &(&str, u64)| File {
+        length,
+        md5sum: None,
+        path: path.split('/').map(str::to_owned).collect(),
+        ..Default::default()
+    };
+
+    Mode::Multi {
+        dir_name: dir_name.to_owned(),
+        files: files.iter().map(file).collect(),
+        md5sum: None,
+    }
+}
+
+pub fn multi_info(
+    dir_name: &str,
+    files: &[(&str, u64)],
+    piece_length: u64,
+    pieces: Vec<[u8; 20]>,
+) -> Info {
+    Info {
+        mode: multi(dir_name, files),
+        piece_length,
+        pieces: pieces.into_boxed_slice(),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,796 @@
+use std::{
+    fs,
+    net::SocketAddr,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use color_eyre::Report;
+use tokio::sync::watch;
+use tracing::debug;
+
+use crate::{
+    config::BLOCK_SIZE,
+    data::{Event, GeneralError, Info, Peer, Status, TorrentInfo, DOWNLOAD_DIR},
+    picker::{PickerFactory, Priority, Strategy},
+    piece_manager::{BitField, DataManager, SyncPolicy},
+    pwp::Block,
+    sqlite::ResumeData,
+    stats::Stats,
+    storage::Allocation,
+};
+
+#[derive(Debug, Default, Clone)]
+pub struct AddOptions {
+    // skip the hash check and treat the existing files as complete, meant for migrating large
+    // seeding libraries where a full recheck would take days
+    pub seed_mode: bool,
+    // hash pieces read back from disk before uploading them
+    pub verify_uploads: bool,
+    pub sync: SyncPolicy,
+    // how files get sized before the download starts
+    pub allocation: Allocation,
+    // bytes of recently uploaded pieces kept in memory
+    pub read_cache: usize,
+    // bytes of verified pieces that may wait in memory before they're written, 0 writes through
+    pub write_cache: usize,
+    // stamp finished files with the torrent's creation date, for archives that care about them
+    pub set_mtimes: bool,
+    // finished torrents get moved here and keep seeding from their new location
+    pub completed_dir: Option<PathBuf>,
+    // where the files get downloaded to, instead of the default directory
+    pub root: Option<PathBuf>,
+    // comes from the session's config, the default block size otherwise
+    pub block_size: Option<usize>,
+    // sequential unless something else is asked for
+    pub strategy: Strategy,
+    // a picker of its own, instead of one of the built-in strategies
+    pub picker: Option<PickerFactory>,
+    // bytes per second for this torrent alone, 0 for no limit. The session's limits still apply
+    pub upload_limit: u64,
+    pub download_limit: u64,
+}
+
+impl AddOptions {
+    pub fn root(&self) -> &Path {
+        self.root.as_deref().unwrap_or(Path::new(DOWNLOAD_DIR))
+    }
+
+    fn manager(&self, info: Info, stats: Arc<Stats>) -> DataManager {
+        let picker = self.picker.as_ref().map(|picker| picker.build(&info));
+        let mut manager = DataManager::new(info);
+        match picker {
+            Some(picker) => manager.set_picker(picker),
+            None => manager.set_strategy(self.strategy),
+        }
+        manager.set_stats(stats);
+        manager.set_root(self.root());
+        manager.set_block_size(self.block_size.unwrap_or(BLOCK_SIZE));
+        manager.set_verify_uploads(self.verify_uploads);
+        manager.set_sync_policy(self.sync);
+        manager.set_allocation(self.allocation);
+        manager.set_read_cache(self.read_cache);
+        manager.set_write_cache(self.write_cache);
+
+        manager
+    }
+}
+
+// CheckingFiles -> DownloadingMetadata -> Downloading -> Seeding, every state can be paused or
+// fail, resuming goes back to whatever the torrent's data says it should be doing
+#[derive(Debug, Clone, PartialEq)]
+pub enum State {
+    CheckingFiles,
+    DownloadingMetadata,
+    Downloading,
+    Seeding,
+    Paused,
+    Error(String),
+}
+
+impl State {
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self,
+            State::DownloadingMetadata | State::Downloading | State::Seeding
+        )
+    }
+}
+
+pub struct Torrent {
+    inner: TorrentInfo,
+    // file_structure: todo!(),
+    manager: Option<DataManager>,
+    options: AddOptions,
+    state: watch::Sender<State>,
+    // last scrape result, None until a tracker answered
+    swarm: Option<Status>,
+    stats: Arc<Stats>,
+    // uploaded and downloaded in earlier sessions
+    carried: (u64, u64),
+    // the trackers know we finished, from this session or an earlier one
+    completed: bool,
+    // pieces hashed so far while checking the files
+    checked: usize,
+    status: Event,
+    peers: Vec<Peer>,
+}
+
+impl Torrent {
+    pub fn new(inner: TorrentInfo, options: AddOptions) -> Result<Self, Report> {
+        let stats = Arc::new(Stats::default());
+
+        // magnet links don't come with the info dictionary, the manager gets created once the
+        // metadata has been fetched
+        let manager = match inner.info.clone() {
+            Some(info) => {
+                let mut manager = options.manager(info.clone(), stats.clone());
+
+                if options.seed_mode {
+                    manager.assume_complete()?;
+                    let missing = manager.invalidate_missing()?;
+
+                    debug!(
+                        "added [{}] as seed, skipping hash check, {} pieces missing on disk",
+                        info.mode.name(),
+                        missing.len()
+                    );
+                }
+
+                Some(manager)
+            }
+            None if options.seed_mode => return Err(GeneralError::MissingInfo.into()),
+            None => None,
+        };
+
+        let state = match manager {
+            None => State::DownloadingMetadata,
+            Some(ref manager) if options.seed_mode && manager.complete() => State::Seeding,
+            Some(_) if options.seed_mode => State::Downloading,
+            Some(_) => State::CheckingFiles,
+        };
+        let (state, _) = watch::channel(state);
+
+        let left = match &manager {
+            Some(manager) => manager.left(),
+            None => inner.length() as u64,
+        };
+        stats.set_left(left);
+
+        Ok(Self {
+            inner,
+            manager,
+            options,
+            state,
+            swarm: None,
+            stats,
+            carried: (0, 0),
+            completed: false,
+            checked: 0,
+            status: Event::None,
+            peers: Vec::new(),
+        })
+    }
+
+    pub fn state(&self) -> State {
+        self.state.borrow().clone()
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    // lets the engine, RPC and TUI follow state changes without polling
+    pub fn subscribe(&self) -> watch::Receiver<State> {
+        self.state.subscribe()
+    }
+
+    fn transition(&mut self, to: State) -> Result<(), Report> {
+        use State::*;
+
+        let from = self.state();
+        let allowed = match (&from, &to) {
+            (_, Error(_)) => true,
+            (Paused, Paused) => false,
+            (_, Paused) => true,
+            (CheckingFiles, Downloading | Seeding) => true,
+            (DownloadingMetadata, CheckingFiles) => true,
+            (Downloading, Seeding | CheckingFiles) => true,
+            // pieces can go missing again after a recheck or when corruption is found
+            (Seeding, Downloading | CheckingFiles) => true,
+            (Paused, _) => true,
+            (Error(_), CheckingFiles | DownloadingMetadata) => true,
+            _ => false,
+        };
+
+        // the info dictionary is all that separates fetching metadata from everything else
+        let consistent = match &to {
+            DownloadingMetadata => self.manager.is_none(),
+            CheckingFiles | Downloading => self.manager.is_some(),
+            Seeding => self.manager.as_ref().map(|m| m.complete()).unwrap_or(false),
+            Paused | Error(_) => true,
+        };
+
+        if !allowed || !consistent {
+            return Err(GeneralError::InvalidTransition(from, to).into());
+        }
+
+        // a check always starts over, whatever an earlier one got to may have changed since
+        if to == CheckingFiles {
+            self.checked = 0;
+        }
+
+        // only now, files that went missing while checking would look like they're still there
+        if to == Downloading {
+            if let Some(manager) = &mut self.manager {
+                manager.allocate()?;
+            }
+        }
+
+        debug!("[{}] {from:?} -> {to:?}", hex::encode(self.inner.hash));
+        self.state.send_replace(to);
+
+        Ok(())
+    }
+
+    // where a torrent belongs once nothing is holding it back
+    fn next_state(&self) -> State {
+        match &self.manager {
+            None => State::DownloadingMetadata,
+            Some(manager) if manager.complete() => State::Seeding,
+            Some(_) => State::Downloading,
+        }
+    }
+
+    pub fn metadata_received(&mut self, info: Info) -> Result<(), Report> {
+        if self.manager.is_some() {
+            return Err(GeneralError::InvalidTransition(self.state(), State::CheckingFiles).into());
+        }
+
+        let manager = self.options.manager(info.clone(), self.stats.clone());
+        self.stats.set_left(manager.left());
+        self.manager = Some(manager);
+        self.inner.info = Some(info);
+
+        self.transition(State::CheckingFiles)
+    }
+
+    // forgets which pieces we have, check_files finds them on disk again
+    pub fn recheck(&mut self) -> Result<(), Report> {
+        self.transition(State::CheckingFiles)
+    }
+
+    // hashes the next `count` pieces on disk, and moves on once there are none left to hash, which
+    // is when this returns true. Done a few at a time, so the torrent can be looked at in between
+    pub fn check_files(&mut self, count: usize) -> Result<bool, Report> {
+        if self.state() != State::CheckingFiles {
+            return Err(GeneralError::InvalidTransition(self.state(), State::CheckingFiles).into());
+        }
+        let total = self.inner.info.as_ref().map_or(0, |info| info.pieces.len());
+        let manager = self.manager.as_mut().ok_or(GeneralError::MissingInfo)?;
+
+        if self.checked == 0 {
+            manager.forget()?;
+        }
+        let end = total.min(self.checked + count);
+        manager.check(self.checked..end)?;
+        self.checked = end;
+        self.stats.set_left(manager.left());
+
+        if end < total {
+            return Ok(false);
+        }
+
+        debug!(
+            "[{}] checked, {} bytes missing",
+            hex::encode(self.inner.hash),
+            manager.left()
+        );
+        self.files_checked()?;
+
+        Ok(true)
+    }
+
+    // pieces hashed so far and how many there are, while the files are being checked
+    pub fn checking(&self) -> Option<(usize, usize)> {
+        let total = self.inner.info.as_ref()?.pieces.len();
+        (self.state() == State::CheckingFiles).then_some((self.checked, total))
+    }
+
+    // pieces we have on disk and how many there are, None until there's an info dictionary
+    pub fn verified(&self) -> Option<(usize, usize)> {
+        let manager = self.manager.as_ref()?;
+        let total = self.inner.info.as_ref()?.pieces.len();
+        Some((manager.flushed_pieces().len(), total))
+    }
+
+    pub fn files_checked(&mut self) -> Result<(), Report> {
+        self.transition(self.next_state())
+    }
+
+    // called whenever a piece got verified, flips over to seeding after the last one
+    pub fn piece_completed(&mut self) -> Result<(), Report> {
+        if let Some(manager) = &self.manager {
+            self.stats.set_left(manager.left());
+        }
+
+        match self.state() {
+            State::Downloading if self.next_state() == State::Seeding => {
+                self.transition(State::Seeding)?;
+                self.completed = true;
+
+                if let Some(dir) = self.options.completed_dir.clone() {
+                    let manager = self.manager.as_mut().ok_or(GeneralError::MissingInfo)?;
+                    manager.move_storage(&dir)?;
+                    debug!(
+                        "moved [{}] to {}",
+                        hex::encode(self.inner.hash),
+                        dir.display()
+                    );
+                }
+
+                if self.options.set_mtimes {
+                    self.set_mtimes()?;
+                }
+
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn set_mtimes(&self) -> Result<(), Report> {
+        let Some(created) = self.inner.created else {
+            debug!("torrent has no creation date, leaving mtimes alone");
+            return Ok(());
+        };
+        let mtime = UNIX_EPOCH + Duration::from_secs(created);
+
+        for (path, _) in self.files()? {
+            fs::File::options()
+                .write(true)
+                .open(path)?
+                .set_modified(mtime)?;
+        }
+
+        Ok(())
+    }
+
+    // picks up where an earlier session left off instead of checking the files, pieces it verified
+    // are trusted as long as their files are still there
+    pub fn restore(&mut self, resume: &ResumeData) -> Result<(), Report> {
+        if self.state() != State::CheckingFiles {
+            return Err(GeneralError::InvalidTransition(self.state(), State::CheckingFiles).into());
+        }
+        let manager = self.manager.as_mut().ok_or(GeneralError::MissingInfo)?;
+
+        manager.assume_flushed(&resume.pieces)?;
+        self.invalidate_missing()?;
+        if let Some(manager) = &self.manager {
+            self.stats.set_left(manager.left());
+        }
+
+        self.carried = (resume.uploaded, resume.downloaded);
+        self.completed = resume.completed;
+        debug!(
+            "[{}] resumed with {} verified pieces",
+            hex::encode(self.inner.hash),
+            resume.pieces.len()
+        );
+
+        self.files_checked()
+    }
+
+    // what peers hear we have right after the handshake, None until there's an info dictionary
+    pub fn bitfield(&self) -> Option<Vec<u8>> {
+        Some(self.manager.as_ref()?.bitfield())
+    }
+
+    // None until there's an info dictionary to say which pieces we have
+    pub fn resume_data(&self) -> Option<ResumeData> {
+        let manager = self.manager.as_ref()?;
+        let (uploaded, downloaded, _) = self.stats.up_down_left();
+
+        Some(ResumeData {
+            pieces: manager.flushed_pieces(),
+            uploaded: self.carried.0 + uploaded,
+            downloaded: self.carried.1 + downloaded,
+            completed: self.completed,
+        })
+    }
+
+    pub fn sync(&mut self) -> Result<(), Report> {
+        match &mut self.manager {
+            Some(manager) => manager.sync(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn pause(&mut self) -> Result<(), Report> {
+        self.transition(State::Paused)
+    }
+
+    pub fn resume(&mut self) -> Result<(), Report> {
+        match self.state() {
+            // files might have changed while we weren't looking, without metadata there are none
+            State::Error(_) if self.manager.is_some() => self.transition(State::CheckingFiles),
+            State::Error(_) => self.transition(self.next_state()),
+            State::Paused => {
+                self.invalidate_missing()?;
+                self.transition(self.next_state())
+            }
+            from => Err(GeneralError::InvalidTransition(from.clone(), from).into()),
+        }
+    }
+
+    // files that were deleted or truncated while the torrent wasn't running only cost the pieces
+    // covering them, instead of an error or a full recheck
+    fn invalidate_missing(&mut self) -> Result<(), Report> {
+        let Some(manager) = self.manager.as_mut() else {
+            return Ok(());
+        };
+
+        let missing = manager.invalidate_missing()?;
+        if !missing.is_empty() {
+            debug!(
+                "[{}] {} pieces went missing on disk",
+                hex::encode(self.inner.hash),
+                missing.len()
+            );
+            self.stats.set_left(manager.left());
+        }
+
+        Ok(())
+    }
+
+    pub fn fail(&mut self, reason: String) -> Result<(), Report> {
+        self.transition(State::Error(reason))
+    }
+
+    pub fn swarm(&self) -> Option<&Status> {
+        self.swarm.as_ref()
+    }
+
+    pub fn scraped(&mut self, status: Status) {
+        self.swarm = Some(status);
+    }
+
+    pub fn set_file_priority(&mut self, file: usize, priority: Priority) -> Result<(), Report> {
+        let manager = self.manager.as_mut().ok_or(GeneralError::MissingInfo)?;
+        manager.set_file_priority(file, priority)
+    }
+
+    pub fn prioritize_range(&mut self, file: usize, range: Range<u64>) -> Result<(), Report> {
+        let manager = self.manager.as_mut().ok_or(GeneralError::MissingInfo)?;
+        manager.prioritize_range(file, range)
+    }
+
+    // next piece to download from a peer that has the pieces `has` says it does, along with the
+    // blocks of it we still need
+    pub fn claim_piece(&mut self, has: &dyn Fn(usize) -> bool) -> Option<(usize, Vec<Block>)> {
+        if self.state() != State::Downloading {
+            return None;
+        }
+        let manager = self.manager.as_mut()?;
+        let index = manager.claim_piece(has)?;
+
+        Some((index, manager.missing_blocks(index)))
+    }
+
+    // nothing before the metadata is in, there's no telling which pieces those are
+    pub fn wants(&self, has: &dyn Fn(usize) -> bool) -> bool {
+        self.manager.as_ref().is_some_and(|m| m.wants(has))
+    }
+
+    pub fn requesters(&self, index: usize) -> usize {
+        self.manager
+            .as_ref()
+            .map(|m| m.requesters(index))
+            .unwrap_or(0)
+    }
+
+    pub fn release_piece(&mut self, index: usize) {
+        if let Some(manager) = self.manager.as_mut() {
+            manager.release_piece(index);
+        }
+    }
+
+    // what the swarm has, magnet links only start counting once the metadata is in
+    pub fn peer_bitfield(&mut self, peer: SocketAddr, bitfield: BitField) {
+        if let Some(manager) = self.manager.as_mut() {
+            manager.peer_bitfield(peer, bitfield);
+        }
+    }
+
+    pub fn peer_have_all(&mut self, peer: SocketAddr) {
+        if let Some(manager) = self.manager.as_mut() {
+            manager.peer_have_all(peer);
+        }
+    }
+
+    pub fn peer_have(&mut self, peer: SocketAddr, index: usize) {
+        if let Some(manager) = self.manager.as_mut() {
+            manager.peer_have(peer, index);
+        }
+    }
+
+    pub fn peer_gone(&mut self, peer: SocketAddr) {
+        if let Some(manager) = self.manager.as_mut() {
+            manager.peer_gone(peer);
+        }
+    }
+
+    // true if the block completed a piece, which is on disk by now
+    pub async fn write_block(
+        &mut self,
+        index: usize,
+        begin: usize,
+        block: &[u8],
+    ) -> Result<bool, Report> {
+        let manager = self.manager.as_mut().ok_or(GeneralError::MissingInfo)?;
+        self.stats.downloaded(block.len() as u64);
+
+        let completed = manager.write_block(index, begin, block).await?;
+        if completed {
+            self.piece_completed()?;
+        }
+
+        Ok(completed)
+    }
+
+    pub fn has_range(&self, file: usize, range: Range<u64>) -> Result<bool, Report> {
+        let manager = self.manager.as_ref().ok_or(GeneralError::MissingInfo)?;
+        manager.has_range(file, range)
+    }
+
+    pub fn read_block(
+        &mut self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> Result<Bytes, Report> {
+        let manager = self.manager.as_mut().ok_or(GeneralError::MissingInfo)?;
+        let block = manager.read_block(index, begin, length);
+
+        // verifying on read may have found a corrupt piece and thrown it away
+        if block.is_err() && !manager.complete() {
+            self.repair()?;
+        }
+
+        block
+    }
+
+    // bytes of a single file for readers outside the swarm, they see whatever was verified even
+    // before it got written
+    pub fn read_range(&self, file: usize, range: Range<u64>) -> Result<Vec<u8>, Report> {
+        let manager = self.manager.as_ref().ok_or(GeneralError::MissingInfo)?;
+        manager.read_range(file, range)
+    }
+
+    // a peer reported that a piece we uploaded doesn't match its hash
+    pub fn piece_corrupt(&mut self, index: usize) -> Result<(), Report> {
+        let manager = self.manager.as_mut().ok_or(GeneralError::MissingInfo)?;
+        manager.invalidate(index)?;
+
+        self.repair()
+    }
+
+    // seeds that lost a piece go back to downloading it, and stop claiming to have it
+    fn repair(&mut self) -> Result<(), Report> {
+        let Some(manager) = &self.manager else {
+            return Ok(());
+        };
+        self.stats.set_left(manager.left());
+
+        match self.state() {
+            State::Seeding => self.transition(State::Downloading),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn info(&self) -> &TorrentInfo {
+        &self.inner
+    }
+
+    // where the files currently live on disk, unfinished ones still have their .part suffix
+    pub fn files(&self) -> Result<Vec<(PathBuf, u64)>, Report> {
+        let manager = self.manager.as_ref().ok_or(GeneralError::MissingInfo)?;
+        Ok(manager.paths())
+    }
+
+    pub fn clear_window(&mut self, file: usize) {
+        if let Some(manager) = self.manager.as_mut() {
+            manager.clear_window(file);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{multi_info, TempDir};
+
+    #[test]
+    fn test_state_transitions() -> Result<(), Report> {
+        let mut torrent = Torrent::new(TorrentInfo::default(), AddOptions::default())?;
+        let mut rx = torrent.subscribe();
+        assert_eq!(torrent.state(), State::DownloadingMetadata);
+
+        // no metadata means there is nothing to check or seed
+        assert!(torrent.files_checked().is_err());
+        assert!(torrent.recheck().is_err());
+        assert_eq!(torrent.state(), State::DownloadingMetadata);
+
+        torrent.pause()?;
+        assert!(torrent.pause().is_err());
+        torrent.resume()?;
+        assert_eq!(torrent.state(), State::DownloadingMetadata);
+
+        torrent.metadata_received(Info::default())?;
+        assert_eq!(torrent.state(), State::CheckingFiles);
+        assert!(rx.has_changed()?);
+        assert_eq!(*rx.borrow_and_update(), State::CheckingFiles);
+
+        // a torrent without pieces is complete as soon as it's been checked
+        torrent.files_checked()?;
+        assert_eq!(torrent.state(), State::Seeding);
+        assert!(torrent.metadata_received(Info::default()).is_err());
+
+        torrent.fail("disk full".to_owned())?;
+        assert!(!torrent.state().is_active());
+        torrent.resume()?;
+        assert_eq!(torrent.state(), State::CheckingFiles);
+
+        // a magnet link that failed before the metadata came in goes back to fetching it
+        let mut magnet = Torrent::new(TorrentInfo::default(), AddOptions::default())?;
+        magnet.fail("tracker unreachable".to_owned())?;
+        magnet.resume()?;
+        assert_eq!(magnet.state(), State::DownloadingMetadata);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore() -> Result<(), Report> {
+        let root = TempDir::new();
+        let data: Vec<u8> = (0..12).collect();
+
+        let pieces = data.chunks(4).map(crate::piece_manager::sha1).collect();
+        let info = multi_info("dir", &[("a", 8), ("b", 4)], 4, pieces);
+        let options = AddOptions {
+            root: Some(root.to_path_buf()),
+            ..Default::default()
+        };
+        let torrent = |info: &Info| {
+            Torrent::new(
+                TorrentInfo {
+                    info: Some(info.clone()),
+                    ..Default::default()
+                },
+                options.clone(),
+            )
+        };
+
+        // the first file made it to disk, the second one went missing since
+        fs::create_dir_all(root.join("dir"))?;
+        fs::write(root.join("dir/a"), &data[..8])?;
+        let resume = ResumeData {
+            pieces: vec![0, 1, 2],
+            uploaded: 100,
+            downloaded: 12,
+            completed: true,
+        };
+
+        let mut restored = torrent(&info)?;
+        restored.restore(&resume)?;
+        assert_eq!(restored.state(), State::Downloading);
+        assert_eq!(restored.stats().up_down_left().2, 4);
+        assert_eq!(&restored.read_block(1, 0, 4)?[..], &data[4..8]);
+        assert!(restored.restore(&resume).is_err());
+
+        restored.stats().uploaded(4);
+        let saved = restored.resume_data().unwrap();
+        assert_eq!(saved.pieces, vec![0, 1]);
+        assert_eq!((saved.uploaded, saved.downloaded), (104, 12));
+        assert!(saved.completed);
+
+        // resume data of some other torrent doesn't fit
+        let mut other = torrent(&info)?;
+        let resume = ResumeData {
+            pieces: vec![7],
+            ..Default::default()
+        };
+        assert!(other.restore(&resume).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_files() -> Result<(), Report> {
+        let root = TempDir::new();
+        let data: Vec<u8> = (0..12).collect();
+
+        let pieces = data.chunks(4).map(crate::piece_manager::sha1).collect();
+        let info = multi_info("dir", &[("a", 8), ("b", 4)], 4, pieces);
+        let options = AddOptions {
+            root: Some(root.to_path_buf()),
+            ..Default::default()
+        };
+        let mut torrent = Torrent::new(
+            TorrentInfo {
+                info: Some(info),
+                ..Default::default()
+            },
+            options,
+        )?;
+
+        // the first file was downloaded elsewhere, the second one is broken
+        fs::create_dir_all(root.join("dir"))?;
+        fs::write(root.join("dir/a"), &data[..8])?;
+        fs::write(root.join("dir/b.part"), b"oops")?;
+
+        assert!(!torrent.check_files(2)?);
+        assert_eq!(torrent.checking(), Some((2, 3)));
+        assert!(torrent.check_files(2)?);
+        assert_eq!(torrent.checking(), None);
+        assert_eq!(torrent.state(), State::Downloading);
+        assert_eq!(torrent.stats().up_down_left().2, 4);
+        assert_eq!(torrent.resume_data().unwrap().pieces, vec![0, 1]);
+        assert!(root.join("dir/a").exists());
+
+        // fixed behind our back, only a recheck notices
+        fs::write(root.join("dir/b.part"), &data[8..])?;
+        assert!(torrent.check_files(3).is_err());
+        torrent.recheck()?;
+        assert!(torrent.check_files(3)?);
+        assert_eq!(torrent.state(), State::Seeding);
+        assert!(root.join("dir/b").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_while_seeding() -> Result<(), Report> {
+        let root = TempDir::new();
+        fs::write(root.join("data"), [0u8; 8])?;
+
+        let info = Info {
+            mode: crate::data::Mode::Single {
+                name: "data".to_owned(),
+                length: 8,
+                md5sum: None,
+            },
+            piece_length: 4,
+            // neither piece matches what's on disk
+            pieces: vec![[1u8; 20]; 2].into_boxed_slice(),
+            ..Default::default()
+        };
+        let options = AddOptions {
+            seed_mode: true,
+            verify_uploads: true,
+            root: Some(root.to_path_buf()),
+            ..Default::default()
+        };
+        let mut torrent = Torrent::new(
+            TorrentInfo {
+                info: Some(info),
+                ..Default::default()
+            },
+            options,
+        )?;
+        assert_eq!(torrent.state(), State::Seeding);
+
+        assert!(torrent.read_block(1, 0, 4).is_err());
+        assert_eq!(torrent.state(), State::Downloading);
+        assert_eq!(torrent.stats().up_down_left().2, 4);
+
+        // a peer complaining about the other one takes it out as well
+        torrent.piece_corrupt(0)?;
+        assert_eq!(torrent.stats().up_down_left().2, 8);
+
+        Ok(())
+    }
+}
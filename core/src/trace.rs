@@ -0,0 +1,140 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{LineWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use color_eyre::Report;
+
+use crate::pwp::Message;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+// a message that went over a connection, stamped when it was read or right before it was written
+#[derive(Debug, Clone)]
+pub struct Traced<'a> {
+    pub time: DateTime<Utc>,
+    pub peer: SocketAddr,
+    pub direction: Direction,
+    pub message: &'a Message,
+}
+
+impl<'a> Traced<'a> {
+    pub fn new(peer: SocketAddr, direction: Direction, message: &'a Message) -> Self {
+        Self {
+            time: Utc::now(),
+            peer,
+            direction,
+            message,
+        }
+    }
+}
+
+// blocks and extension payloads are left out, the sizes are what matters when two clients don't
+// understand each other
+impl fmt::Display for Traced<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let arrow = match self.direction {
+            Direction::Inbound => "<-",
+            Direction::Outbound => "->",
+        };
+        write!(
+            f,
+            "{} [{}] {arrow} ",
+            self.time.format("%Y-%m-%d %H:%M:%S%.3f"),
+            self.peer
+        )?;
+
+        match self.message {
+            Message::BitField(bytes) => write!(f, "BitField({} bytes)", bytes.len()),
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => write!(
+                f,
+                "Piece {{ index: {index}, begin: {begin}, length: {} }}",
+                block.len()
+            ),
+            Message::Extended { id, payload } => {
+                write!(f, "Extended {{ id: {id}, length: {} }}", payload.len())
+            }
+            message => write!(f, "{message:?}"),
+        }
+    }
+}
+
+// gets to see every message of every connection, meant for debugging interoperability problems
+#[derive(Clone)]
+pub struct Inspector(Arc<dyn Fn(&Traced) + Send + Sync>);
+
+impl Inspector {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&Traced) + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    // one line per message, only the peer's if one is given
+    pub fn to_file<P: AsRef<Path>>(path: P, peer: Option<IpAddr>) -> Result<Self, Report> {
+        let file = Mutex::new(LineWriter::new(File::create(path)?));
+
+        Ok(Self::new(move |traced| {
+            if peer.map_or(true, |ip| ip == traced.peer.ip()) {
+                let _ = writeln!(file.lock().unwrap(), "{traced}");
+            }
+        }))
+    }
+
+    pub fn inspect(&self, peer: SocketAddr, direction: Direction, message: &Message) {
+        (self.0)(&Traced::new(peer, direction, message));
+    }
+}
+
+impl fmt::Debug for Inspector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Inspector")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_inspector() -> Result<(), Report> {
+        let dir = TempDir::new();
+        let path = dir.join("trace");
+        let (a, b): (SocketAddr, SocketAddr) = ("10.0.0.1:6881".parse()?, "10.0.0.2:6881".parse()?);
+
+        let inspector = Inspector::to_file(&path, Some(a.ip()))?;
+        inspector.inspect(a, Direction::Outbound, &Message::Interested);
+        inspector.inspect(b, Direction::Inbound, &Message::Choke);
+        let piece = Message::Piece {
+            index: 1,
+            begin: 0,
+            block: Bytes::from_static(&[0u8; 64]),
+        };
+        inspector.inspect(a, Direction::Inbound, &piece);
+
+        let trace = std::fs::read_to_string(&path)?;
+        let lines: Vec<_> = trace.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("[10.0.0.1:6881] -> Interested"));
+        assert!(lines[1].ends_with("<- Piece { index: 1, begin: 0, length: 64 }"));
+
+        Ok(())
+    }
+}
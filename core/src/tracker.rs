@@ -0,0 +1,574 @@
+use bendy::decoding::FromBencode;
+use color_eyre::Report;
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::{mpsc, watch, Notify, RwLock};
+use tokio::time::{sleep, sleep_until, Instant};
+use tracing::debug;
+use url::Url;
+
+use crate::config::Context;
+use crate::data::{Event, GeneralError, Peers, ScrapeResponse, Status, TorrentInfo, Tracker};
+use crate::demux::Datagram;
+use crate::helpers::Query;
+use crate::proxy;
+use crate::shutdown::Signal;
+use crate::torrent::{self, Torrent};
+use crate::tracker_session::{
+    news, Action, Announced, HttpSession, Lifecycle, Parameters, Schedule, Session, Tiers,
+    Transactions, UdpSession,
+};
+use crate::udp::Response;
+
+pub type Message = (SocketAddr, Response);
+
+// how often the swarm of a torrent gets scraped, trackers don't like being asked more often
+pub const SCRAPE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+pub struct UdpTracker {
+    pub socket: Arc<UdpSocket>,
+    pub session_map: HashMap<SocketAddr, Arc<UdpSession>>,
+    pub hash: [u8; 20],
+    pub length: usize,
+    swarm_tx: watch::Sender<Option<Status>>,
+}
+
+impl UdpTracker {
+    pub fn new(
+        ctx: &Context,
+        info: &TorrentInfo,
+        socket: Arc<UdpSocket>,
+        transactions: Arc<Transactions>,
+        peer_id: [u8; 20],
+        key: u32,
+        torrent: &Torrent,
+    ) -> Result<Self, Report> {
+        let trackers = info.announce.udp();
+        let parameters = Parameters::new(info, peer_id, key, socket.local_addr()?.port());
+
+        let session_map = trackers
+            .into_iter()
+            .map(|addr| {
+                debug!("adding UDP tracker session for [{addr}]");
+                let session = UdpSession::new(
+                    socket.clone(),
+                    addr,
+                    &parameters,
+                    transactions.clone(),
+                    torrent.stats(),
+                    ctx.config.tunables.clone(),
+                );
+                (addr, Arc::new(session))
+            })
+            .collect();
+
+        Ok(Self {
+            session_map,
+            socket,
+            hash: info.hash,
+            length: info.length(),
+            swarm_tx: watch::channel(None).0,
+        })
+    }
+
+    // sees every scrape run() makes, ends up in Torrent::swarm
+    pub fn swarm(&self) -> watch::Receiver<Option<Status>> {
+        self.swarm_tx.subscribe()
+    }
+
+    // announces go through the tiers of `Trackers`, these sessions only scrape
+    pub async fn run(self) {
+        let sessions: Vec<_> = self.session_map.into_values().collect();
+
+        if !sessions.is_empty() {
+            Self::scrape_every(sessions, self.hash, SCRAPE_INTERVAL, self.swarm_tx).await;
+        }
+    }
+
+    async fn scrape_every(
+        sessions: Vec<Arc<UdpSession>>,
+        hash: [u8; 20],
+        interval: Duration,
+        swarm_tx: watch::Sender<Option<Status>>,
+    ) {
+        loop {
+            if let Some(status) = Self::scrape(&sessions, hash).await {
+                debug!("scraped [{}]: {status:?}", hex::encode(hash));
+                swarm_tx.send_replace(Some(status));
+            }
+
+            sleep(interval).await;
+        }
+    }
+
+    // the trackers all describe the same swarm, so the first one that knows the torrent is enough
+    async fn scrape(sessions: &[Arc<UdpSession>], hash: [u8; 20]) -> Option<Status> {
+        for session in sessions {
+            let status = match session.connect().await {
+                Ok(cid) => session.scrape(cid, &[hash]).await,
+                Err(e) => Err(e),
+            };
+
+            match status {
+                Ok(mut status) if !status.is_empty() => return Some(status.swap_remove(0)),
+                Ok(_) => debug!("[{}] doesn't know the torrent", session.dst()),
+                Err(e) => debug!("failed to scrape [{}]: {e}", session.dst()),
+            }
+        }
+
+        None
+    }
+
+    // the demultiplexer hands us whatever looks like a BEP 15 response, the trackers of every
+    // torrent share the one socket and get their answers by transaction id
+    pub async fn listen(mut datagrams: Receiver<Datagram>, transactions: Arc<Transactions>) {
+        while let Some((datagram, peer)) = datagrams.recv().await {
+            let resp = match Response::to_response(&datagram, peer.is_ipv6()) {
+                Ok(resp) => resp,
+                Err(e) => {
+                    debug!("dropping datagram from [{peer}]: {e}");
+                    continue;
+                }
+            };
+
+            if let Err(resp) = transactions.resolve(peer, resp) {
+                debug!("dropping unexpected response from [{peer}]: {resp:?}");
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum State {
+    Disconnected,
+    Connected { cid: i64 },
+    Announced { peers: Peers, cid: i64 },
+    Scraped,
+}
+
+// announces a torrent to its trackers, HTTP and UDP ones alike, one tracker at a time
+pub struct Trackers {
+    parameters: Parameters,
+    sessions: Vec<(Session, Schedule)>,
+    tiers: Tiers,
+    state_rx: watch::Receiver<torrent::State>,
+    peer_tx: mpsc::Sender<Peers>,
+    reannounce: Arc<Notify>,
+    // trackers hear that we stopped before the session goes away
+    shutdown: Signal,
+}
+
+impl Parameters {
+    fn tracker_id(&mut self, tracker_id: String) {
+        self.tracker_id = Some(tracker_id);
+    }
+}
+
+impl Trackers {
+    pub fn new(
+        ctx: &Context,
+        info: &TorrentInfo,
+        socket: Arc<UdpSocket>,
+        transactions: Arc<Transactions>,
+        peer_id: [u8; 20],
+        key: u32,
+        torrent: &Torrent,
+    ) -> Result<(Self, mpsc::Receiver<Peers>), Report> {
+        let parameters = Parameters::new(info, peer_id, key, ctx.port);
+        let tunables = &ctx.config.tunables;
+        let (peer_tx, peer_rx) = mpsc::channel(tunables.channel_capacity);
+
+        debug!("trackers: {:?}", info.announce.tiers);
+        let mut sessions = Vec::new();
+        let mut sizes = Vec::with_capacity(info.announce.tiers.len());
+        for tier in &info.announce.tiers {
+            let before = sessions.len();
+
+            for tracker in tier {
+                let session = match tracker {
+                    Tracker::Http(url) => HttpSession::connect(
+                        url.clone(),
+                        torrent.stats(),
+                        ctx.external_ip.clone(),
+                        tunables,
+                        ctx.config.announce_bounds(url),
+                        ctx.config.proxy.as_ref(),
+                    )
+                    .map(Session::Http),
+                    Tracker::Udp(addr) => Ok(Session::Udp(UdpSession::new(
+                        socket.clone(),
+                        *addr,
+                        &parameters,
+                        transactions.clone(),
+                        torrent.stats(),
+                        tunables.clone(),
+                    ))),
+                };
+
+                match session {
+                    Ok(session) => sessions.push((session, Schedule::default())),
+                    Err(e) => debug!("skipping tracker {tracker:?}: {e}"),
+                }
+            }
+            sizes.push(sessions.len() - before);
+        }
+
+        Ok((
+            Self {
+                parameters,
+                sessions,
+                tiers: Tiers::new(&sizes),
+                state_rx: torrent.subscribe(),
+                peer_tx,
+                reannounce: Arc::new(Notify::new()),
+                shutdown: Signal::default(),
+            },
+            peer_rx,
+        ))
+    }
+
+    // wakes the torrent up for an announce right away
+    pub fn reannouncer(&self) -> Arc<Notify> {
+        self.reannounce.clone()
+    }
+
+    pub fn set_shutdown(&mut self, shutdown: Signal) {
+        self.shutdown = shutdown;
+    }
+
+    // the HTTP trackers in tier order, the first answer wins like in UdpTracker::scrape
+    pub async fn scrape(&self) -> Option<Status> {
+        let hash = self.parameters.info_hash;
+
+        for i in self.tiers.order() {
+            let Session::Http(session) = &self.sessions[i].0 else {
+                continue;
+            };
+
+            match session.scrape(&[hash]).await {
+                Ok(resp) => match resp.get(&hash) {
+                    Some(status) => return Some(status.clone()),
+                    None => debug!("[{}] doesn't know the torrent", session.dst()),
+                },
+                Err(e) => debug!("failed to scrape [{}]: {e}", session.dst()),
+            }
+        }
+
+        None
+    }
+
+    // the first tracker that answers, the ones that turned us down for good are forgotten
+    async fn announce(&mut self, parameters: &Parameters) -> Result<Announced, Report> {
+        let mut last = None;
+
+        for i in self.tiers.order() {
+            let (session, schedule) = &mut self.sessions[i];
+            // rate limited, it told us when to come back
+            if schedule.earliest() > Instant::now() {
+                continue;
+            }
+
+            match session.announce_event(parameters).await {
+                Ok(announced) => {
+                    schedule.succeeded(announced.wait, Duration::ZERO);
+                    self.tiers.promote(i);
+                    return Ok(announced);
+                }
+                Err(e) => {
+                    let action = Action::of(&e);
+                    debug!("[{}] {e}, {action:?}", session.dst());
+
+                    if schedule.failed(action).is_none() {
+                        self.tiers.remove(i);
+                    }
+                    last = Some(e);
+                }
+            }
+        }
+
+        Err(last.unwrap_or_else(|| GeneralError::DeadUdpTrackers.into()))
+    }
+
+    pub async fn run(mut self) -> Result<(), Report> {
+        let mut lifecycle = Lifecycle::default();
+        let mut schedule = Schedule::default();
+
+        while !self.tiers.is_empty() {
+            let state = self.state_rx.borrow_and_update().clone();
+            let Some(event) = lifecycle.event(&state) else {
+                // paused, nothing to say until the torrent is resumed
+                let changed = tokio::select! {
+                    changed = self.state_rx.changed() => changed.is_ok(),
+                    _ = self.shutdown.recv() => false,
+                };
+                if !changed {
+                    return Ok(());
+                }
+                continue;
+            };
+            let parameters = Parameters {
+                event: event.clone(),
+                ..self.parameters.clone()
+            };
+
+            // a tracker failing doesn't stop the others, only all of them failing gets us to back
+            // off
+            let wait = match self.announce(&parameters).await {
+                Ok(announced) => {
+                    if self.peer_tx.send(announced.peers).await.is_err() {
+                        return Ok(());
+                    }
+                    schedule.succeeded(announced.wait, announced.min)
+                }
+                Err(e) if self.tiers.is_empty() => return Err(e),
+                Err(e) => {
+                    debug!(
+                        "[{}] no tracker answered: {e}",
+                        hex::encode(parameters.info_hash)
+                    );
+                    schedule.failed(Action::Retry).unwrap_or(SCRAPE_INTERVAL)
+                }
+            };
+            if event == Event::Stopped {
+                continue;
+            }
+            debug!("next announce in {wait:?}");
+
+            let alive = tokio::select! {
+                _ = sleep(wait) => true,
+                _ = self.reannounce.notified() => {
+                    debug!("re-announce requested");
+                    sleep_until(schedule.earliest()).await;
+                    true
+                }
+                alive = news(&mut self.state_rx, &lifecycle) => alive,
+                _ = self.shutdown.recv() => false,
+            };
+
+            if !alive {
+                if let Some(event) = lifecycle.stop() {
+                    let _ = self
+                        .announce(&Parameters {
+                            event,
+                            ..parameters
+                        })
+                        .await;
+                }
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// scrapes the trackers of every torrent once in a while, so queueing and the UI know how healthy
+// a swarm is without having to announce to it
+pub struct Scraper {
+    client: reqwest::Client,
+    torrents: Vec<Arc<RwLock<Torrent>>>,
+    interval: Duration,
+}
+
+impl Scraper {
+    pub fn new(
+        ctx: &Context,
+        torrents: Vec<Arc<RwLock<Torrent>>>,
+        interval: Duration,
+    ) -> Result<Self, Report> {
+        let client = proxy::http_client(ctx.config.proxy.as_ref())?
+            .connect_timeout(ctx.config.tunables.tracker_connect_timeout)
+            .build()?;
+
+        Ok(Self {
+            client,
+            torrents,
+            interval,
+        })
+    }
+
+    pub async fn run(self) -> Result<(), Report> {
+        loop {
+            let mut torrents = Vec::with_capacity(self.torrents.len());
+            for torrent in &self.torrents {
+                let info = torrent.read().await.info().clone();
+                torrents.push((info.hash, info.announce.http(), torrent));
+            }
+
+            // TODO: UDP trackers, they need their own connection id first
+            let mut trackers: Vec<String> = Vec::new();
+            for tracker in torrents.iter().flat_map(|t| &t.1) {
+                if !trackers.contains(tracker) {
+                    trackers.push(tracker.clone());
+                }
+            }
+            let mut done = HashSet::new();
+
+            // every tracker describes the same swarm, one answer per torrent is enough
+            for tracker in trackers {
+                let hashes: Vec<_> = torrents
+                    .iter()
+                    .filter(|(hash, trackers, _)| {
+                        !done.contains(hash) && trackers.contains(&tracker)
+                    })
+                    .map(|(hash, _, _)| *hash)
+                    .collect();
+                if hashes.is_empty() {
+                    continue;
+                }
+
+                let resp = match self.scrape(&tracker, &hashes).await {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        debug!("failed to scrape [{tracker}]: {e}");
+                        continue;
+                    }
+                };
+
+                let pending = torrents
+                    .iter()
+                    .filter(|(hash, _, _)| hashes.contains(hash))
+                    .map(|(hash, _, torrent)| (*hash, (*hash, Arc::clone(torrent))));
+                let matched: Vec<_> = resp
+                    .matching(pending)
+                    .map(|(torrent, status)| (torrent, status.clone()))
+                    .collect();
+
+                for ((hash, torrent), status) in matched {
+                    debug!(
+                        "scraped [{}] from [{tracker}]: {status:?}",
+                        hex::encode(hash)
+                    );
+                    torrent.write().await.scraped(status);
+                    done.insert(hash);
+                }
+            }
+
+            sleep(self.interval).await;
+        }
+    }
+
+    async fn scrape(&self, tracker: &str, hashes: &[[u8; 20]]) -> Result<ScrapeResponse, Report> {
+        scrape(&self.client, tracker, hashes).await
+    }
+}
+
+pub(crate) async fn scrape(
+    client: &reqwest::Client,
+    tracker: &str,
+    hashes: &[[u8; 20]],
+) -> Result<ScrapeResponse, Report> {
+    let url = scrape_url(tracker, hashes)?;
+    let bytes = client
+        .get(url.clone())
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+
+    let resp = ScrapeResponse::from_bencode(&bytes)
+        .map_err(|_| GeneralError::UnexpectedResponse(url.to_string()))?;
+    if let Some(reason) = resp.failure_reason {
+        return Err(GeneralError::ScrapeFailure(reason).into());
+    }
+
+    Ok(resp)
+}
+
+// only trackers whose last path segment is exactly "announce" support scraping (BEP 48):
+// http://example.com/x/announce?passkey=y -> http://example.com/x/scrape?passkey=y
+fn scrape_url(announce: &str, hashes: &[[u8; 20]]) -> Result<Url, Report> {
+    let mut url = Url::parse(announce)?;
+
+    let dir = match url.path().rsplit_once('/') {
+        Some((dir, "announce")) => dir.to_owned(),
+        _ => return Err(GeneralError::NotScrapable(announce.to_owned()).into()),
+    };
+    url.set_path(&format!("{dir}/scrape"));
+
+    let query = hashes.iter().fold(Query::from_url(&url), |query, hash| {
+        query.bytes("info_hash", hash)
+    });
+    url.set_query(Some(&query.build()));
+
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrape_matching() -> Result<(), Report> {
+        let url = scrape_url(
+            "http://t.example/x/announce?pk=1",
+            &[[0xaa; 20], [0xbb; 20]],
+        )?;
+        assert_eq!(
+            url.as_str(),
+            format!(
+                "http://t.example/x/scrape?pk=1&info_hash={}&info_hash={}",
+                "%AA".repeat(20),
+                "%BB".repeat(20)
+            )
+        );
+
+        let mut resp = ScrapeResponse::new();
+        let status = Status {
+            seeders: 3,
+            finished: 10,
+            leechers: 1,
+            name: Some("debian.iso".to_owned()),
+        };
+        resp.files.insert([0xaa; 20], status.clone());
+
+        assert_eq!(resp.get(&[0xaa; 20]), Some(&status));
+        let matched: Vec<_> = resp
+            .matching([([0xaa; 20], "a"), ([0xbb; 20], "b")])
+            .collect();
+        assert_eq!(matched, vec![("a", &status)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scrape_failure() {
+        let resp = ScrapeResponse::from_bencode(b"d14:failure reason11:not allowede").unwrap();
+        assert_eq!(resp.failure_reason.as_deref(), Some("not allowed"));
+        assert!(resp.files.is_empty());
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        let hash = [[0x01; 20]];
+        let scrape = |announce| scrape_url(announce, &hash).map(|url| url.path().to_owned());
+
+        assert_eq!(scrape("http://t.example/announce").unwrap(), "/scrape");
+        assert_eq!(scrape("http://t.example/a/announce").unwrap(), "/a/scrape");
+        let url = scrape_url("http://t.example/announce?pk=abc", &hash).unwrap();
+        assert!(url.query().unwrap().starts_with("pk=abc&info_hash="));
+
+        for announce in [
+            "http://t.example/announce.php",
+            "http://t.example/announce/",
+            "http://t.example/x/announce/y",
+            "http://t.example/a",
+            "http://t.example/announce_x",
+        ] {
+            let e = scrape(announce).unwrap_err();
+            assert!(matches!(
+                e.downcast_ref::<GeneralError>(),
+                Some(GeneralError::NotScrapable(_))
+            ));
+        }
+    }
+}
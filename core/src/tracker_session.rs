@@ -0,0 +1,956 @@
+use bendy::decoding::FromBencode;
+use color_eyre::Report;
+
+use futures_util::TryFutureExt;
+use rand::{seq::SliceRandom, Rng};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{
+    net::UdpSocket,
+    sync::{oneshot, watch},
+    time::{timeout, Instant},
+};
+use tracing::debug;
+use url::Url;
+
+use crate::{
+    config::Tunables,
+    data::{
+        Event, GeneralError, HttpResponse, Peers, ScrapeResponse, Status, TorrentInfo, PROTOCOL_ID,
+    },
+    external_ip::ExternalIp,
+    helpers::{self, Query},
+    proxy::{self, Proxy},
+    stats::Stats,
+    torrent::State,
+    tracker,
+    udp::{Request, Response},
+};
+
+// how long to wait before trying a tracker again that failed for no reason we recognize
+const RETRY: Duration = Duration::from_secs(60);
+// rate limited without being told for how long
+const BACK_OFF: Duration = Duration::from_secs(5 * 60);
+// failures in a row double the wait, up to this
+const MAX_RETRY: Duration = Duration::from_secs(30 * 60);
+
+// failure reasons are free-form text, these are the ones that change what we do next
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum TrackerError {
+    #[error("torrent is not registered with the tracker")]
+    Unregistered,
+    #[error("passkey was rejected")]
+    InvalidPasskey,
+    #[error("client is not allowed on this tracker")]
+    BannedClient,
+    #[error("rate limited, retry after {0:?}")]
+    RateLimited(Option<Duration>),
+    #[error("tracker failure: {0}")]
+    Other(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Retry,
+    BackOff(Duration),
+    Disable,
+}
+
+impl From<&str> for TrackerError {
+    fn from(reason: &str) -> Self {
+        let lower = reason.to_lowercase();
+        let mentions = |words: &[&str]| words.iter().any(|word| lower.contains(word));
+
+        if mentions(&[
+            "unregistered",
+            "not registered",
+            "torrent not found",
+            "unknown torrent",
+        ]) {
+            TrackerError::Unregistered
+        } else if mentions(&["passkey", "authkey", "unauthorized", "not authorized"]) {
+            TrackerError::InvalidPasskey
+        } else if mentions(&["banned", "not whitelisted", "client is not", "not allowed"]) {
+            TrackerError::BannedClient
+        } else if mentions(&["rate limit", "too many", "slow down", "too soon"]) {
+            TrackerError::RateLimited(retry_after(&lower))
+        } else {
+            TrackerError::Other(reason.to_owned())
+        }
+    }
+}
+
+impl TrackerError {
+    pub fn action(&self) -> Action {
+        match self {
+            TrackerError::Unregistered
+            | TrackerError::InvalidPasskey
+            | TrackerError::BannedClient => Action::Disable,
+            TrackerError::RateLimited(after) => Action::BackOff(after.unwrap_or(BACK_OFF)),
+            TrackerError::Other(_) => Action::Retry,
+        }
+    }
+}
+
+impl Action {
+    // anything but a failure reason we recognize is worth another try
+    pub(crate) fn of(e: &Report) -> Self {
+        match e.downcast_ref::<TrackerError>() {
+            Some(e) => e.action(),
+            None => Action::Retry,
+        }
+    }
+}
+
+// when the next announce is due: whatever interval the tracker asked for after a success, longer
+// and longer waits after failures in a row, and never a forced one before the min interval is up
+#[derive(Debug)]
+pub struct Schedule {
+    failures: u32,
+    earliest: Instant,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self {
+            failures: 0,
+            earliest: Instant::now(),
+        }
+    }
+}
+
+impl Schedule {
+    pub fn succeeded(&mut self, wait: Duration, min: Duration) -> Duration {
+        self.failures = 0;
+        self.earliest = Instant::now() + min;
+
+        wait
+    }
+
+    // None once the tracker isn't worth bothering anymore
+    pub fn failed(&mut self, action: Action) -> Option<Duration> {
+        let wait = match action {
+            Action::Retry => RETRY
+                .saturating_mul(1 << self.failures.min(5))
+                .min(MAX_RETRY),
+            Action::BackOff(after) => {
+                self.earliest = Instant::now() + after;
+                after
+            }
+            Action::Disable => return None,
+        };
+        self.failures += 1;
+
+        Some(wait)
+    }
+
+    // a manual re-announce still has to respect whatever the tracker asked us to wait
+    pub fn earliest(&self) -> Instant {
+        self.earliest
+    }
+}
+
+// "retry in 30 seconds", "wait 5 min", a bare number counts as seconds
+fn retry_after(reason: &str) -> Option<Duration> {
+    let mut words = reason.split(|c: char| !c.is_ascii_alphanumeric());
+    let n = words.find_map(|word| word.parse::<u64>().ok())?;
+
+    let unit = match words.find(|word| !word.is_empty()) {
+        Some(unit) if unit.starts_with('h') => 60 * 60,
+        Some(unit) if unit.starts_with('m') => 60,
+        _ => 1,
+    };
+
+    Some(Duration::from_secs(n * unit))
+}
+
+// what a tracker turned out not to accept, learned from its failure reasons so the next announce
+// doesn't fail the same way
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Quirks {
+    pub compact: Option<bool>,
+    pub no_peer_id: Option<bool>,
+    pub numwant: Option<u8>,
+}
+
+impl Quirks {
+    pub fn apply(&self, p: &mut Parameters) {
+        p.compact = self.compact.unwrap_or(p.compact);
+        p.no_peer_id = self.no_peer_id.unwrap_or(p.no_peer_id);
+        p.numwant = self.numwant.unwrap_or(p.numwant);
+    }
+
+    // there's no standard for failure reasons, so this only goes by the parameter they mention,
+    // returns whether another attempt would be any different
+    pub fn learn(&mut self, reason: &str, sent: &Parameters) -> bool {
+        let reason = reason.to_lowercase();
+
+        let required = ["only", "require", "must"]
+            .iter()
+            .any(|word| reason.contains(word));
+        let unsupported = ["not supported", "unsupported", "not allowed"]
+            .iter()
+            .any(|word| reason.contains(word));
+        let flip = |sent: bool| match (required, unsupported) {
+            (true, false) => true,
+            (false, true) => false,
+            _ => !sent,
+        };
+
+        if reason.contains("compact") {
+            self.compact = Some(flip(sent.compact));
+        } else if reason.contains("no_peer_id") || reason.contains("peer id") {
+            self.no_peer_id = Some(flip(sent.no_peer_id));
+        } else if reason.contains("numwant") {
+            self.numwant = Some(sent.numwant / 2);
+        }
+
+        let mut next = sent.clone();
+        self.apply(&mut next);
+
+        next != *sent
+    }
+}
+
+// our own limits on how often a tracker hears from us, the tracker's min interval always wins
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct IntervalBounds {
+    pub min: Option<Duration>,
+    pub max: Option<Duration>,
+}
+
+impl IntervalBounds {
+    pub fn apply(&self, interval: u64, tracker_min: Option<u64>) -> Duration {
+        let interval = Duration::from_secs(interval);
+        let interval = self.max.map_or(interval, |max| interval.min(max));
+        let interval = self.min.map_or(interval, |min| interval.max(min));
+
+        interval.max(Duration::from_secs(tracker_min.unwrap_or(0)))
+    }
+}
+
+// "tracker.example.org=60:1800" in seconds, either side of the colon may be left out
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnounceInterval {
+    pub host: String,
+    pub bounds: IntervalBounds,
+}
+
+impl FromStr for AnnounceInterval {
+    type Err = GeneralError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let e = || GeneralError::InvalidInterval(s.to_owned());
+        let parse = |s: &str| match s.trim() {
+            "" => Ok(None),
+            secs => secs
+                .parse::<u64>()
+                .map(|secs| Some(Duration::from_secs(secs)))
+                .map_err(|_| e()),
+        };
+
+        let (host, bounds) = s.split_once('=').ok_or_else(e)?;
+        let (min, max) = bounds.split_once(':').ok_or_else(e)?;
+        let bounds = IntervalBounds {
+            min: parse(min)?,
+            max: parse(max)?,
+        };
+
+        if host.trim().is_empty()
+            || matches!((bounds.min, bounds.max), (Some(min), Some(max)) if min > max)
+        {
+            return Err(e());
+        }
+
+        Ok(Self {
+            host: host.trim().to_lowercase(),
+            bounds,
+        })
+    }
+}
+
+// what a tracker gets told along with an announce: started first, completed once a download
+// finishes while we're watching and stopped when the torrent is paused or goes away, BEP 3
+#[derive(Debug, Default)]
+pub struct Lifecycle {
+    started: bool,
+    downloading: bool,
+}
+
+impl Lifecycle {
+    // None while there's nothing to announce at all
+    pub fn event(&mut self, state: &State) -> Option<Event> {
+        match state {
+            State::Paused | State::Error(_) => {
+                self.downloading = false;
+                std::mem::take(&mut self.started).then_some(Event::Stopped)
+            }
+            _ => {
+                self.downloading |= *state == State::Downloading;
+
+                if !std::mem::replace(&mut self.started, true) {
+                    Some(Event::Started)
+                } else if *state == State::Seeding && self.downloading {
+                    self.downloading = false;
+                    Some(Event::Completed)
+                } else {
+                    Some(Event::None)
+                }
+            }
+        }
+    }
+
+    // whether the state is news the tracker should hear about before the interval is up
+    pub fn urgent(&self, state: &State) -> bool {
+        match state {
+            State::Paused | State::Error(_) => self.started,
+            State::Seeding => self.downloading,
+            _ => !self.started,
+        }
+    }
+
+    // the torrent went away without being paused first
+    pub fn stop(&mut self) -> Option<Event> {
+        std::mem::take(&mut self.started).then_some(Event::Stopped)
+    }
+}
+
+// sleeps until the torrent changes in a way the tracker should hear about right away, false
+// once the torrent is gone
+pub(crate) async fn news(state_rx: &mut watch::Receiver<State>, lifecycle: &Lifecycle) -> bool {
+    loop {
+        if state_rx.changed().await.is_err() {
+            return false;
+        }
+        if lifecycle.urgent(&state_rx.borrow_and_update()) {
+            return true;
+        }
+    }
+}
+
+// what a tracker answered to an announce, and when it wants to hear from us again
+#[derive(Debug, Clone, PartialEq)]
+pub struct Announced {
+    pub peers: Peers,
+    pub wait: Duration,
+    // forced announces have to wait this long
+    pub min: Duration,
+}
+
+pub struct HttpSession {
+    quirks: Quirks,
+    stats: Arc<Stats>,
+    external_ip: Arc<ExternalIp>,
+    socket: reqwest::Client,
+    dst: String,
+    retries: u8,
+    bounds: IntervalBounds,
+}
+
+impl HttpSession {
+    pub fn connect(
+        dst: String,
+        stats: Arc<Stats>,
+        external_ip: Arc<ExternalIp>,
+        tunables: &Tunables,
+        bounds: IntervalBounds,
+        proxy: Option<&Proxy>,
+    ) -> Result<Self, Report> {
+        let socket = proxy::http_client(proxy)?
+            .connect_timeout(tunables.tracker_connect_timeout)
+            .build()?;
+
+        Ok(Self {
+            quirks: Quirks::default(),
+            socket,
+            dst,
+            retries: tunables.tracker_retries,
+            bounds,
+            stats,
+            external_ip,
+        })
+    }
+
+    pub fn dst(&self) -> &str {
+        &self.dst
+    }
+
+    pub async fn scrape(&self, hashes: &[[u8; 20]]) -> Result<ScrapeResponse, Report> {
+        tracker::scrape(&self.socket, &self.dst, hashes).await
+    }
+
+    async fn build_request(&self, p: &Parameters) -> Result<Url, Report> {
+        let event = match p.event {
+            Event::Started => Some("started"),
+            Event::Stopped => Some("stopped"),
+            Event::Completed => Some("completed"),
+            Event::None => None,
+        };
+
+        let mut url = Url::parse(&self.dst)?;
+        let (uploaded, downloaded, left) = p.up_down_left;
+        let (corrupt, redundant) = p.corrupt_redundant;
+
+        let mut query = Query::from_url(&url)
+            .bytes("info_hash", &p.info_hash)
+            .bytes("peer_id", &p.peer_id)
+            .pair("port", p.port)
+            .pair("uploaded", uploaded)
+            .pair("downloaded", downloaded)
+            .pair("left", left)
+            .pair("corrupt", corrupt)
+            .pair("redundant", redundant)
+            .pair("compact", p.compact as u8)
+            .pair("no_peer_id", p.no_peer_id as u8)
+            .pair("numwant", p.numwant)
+            .pair("key", format!("{:08X}", p.key));
+
+        if let Some(event) = event {
+            query = query.pair("event", event);
+        }
+
+        url.set_query(Some(&query.build()));
+        Ok(url)
+    }
+
+    async fn get(&mut self, parameters: &Parameters) -> Result<HttpResponse, Report> {
+        // progress has to be current, private trackers keep ratios based on these numbers
+        let mut parameters = Parameters {
+            up_down_left: self.stats.up_down_left(),
+            corrupt_redundant: self.stats.corrupt_redundant(),
+            ..parameters.clone()
+        };
+        self.quirks.apply(&mut parameters);
+
+        let resp = self.request(&parameters).await?;
+
+        match &resp.failure_reason {
+            Some(reason) if self.quirks.learn(reason, &parameters) => {
+                debug!(
+                    "[{}] adapting to {:?} after: {reason}",
+                    self.dst, self.quirks
+                );
+                self.quirks.apply(&mut parameters);
+
+                self.request(&parameters).await
+            }
+            _ => Ok(resp),
+        }
+        .and_then(|resp| match resp.failure_reason {
+            Some(reason) => Err(TrackerError::from(reason.as_str()).into()),
+            None => Ok(resp),
+        })
+    }
+
+    async fn request(&self, parameters: &Parameters) -> Result<HttpResponse, Report> {
+        let url = self.build_request(parameters).await?;
+        let f = || self.socket.get(url.clone()).send().map_err(Report::from);
+
+        let resp: reqwest::Response = helpers::attempt(f, self.retries, 1).await?;
+        let bytes = resp.bytes().await?;
+
+        let resp = HttpResponse::from_bencode(&bytes)
+            .map_err(|_| GeneralError::UnexpectedResponse(url.to_string()))?;
+
+        if let Some(warning) = &resp.warning {
+            let kind = TrackerError::from(warning.as_str());
+            debug!("[{}] warning: {warning} ({:?})", self.dst, kind.action());
+        }
+        if let Some(ip) = resp.external_ip {
+            self.external_ip.vote(&self.dst, ip);
+        }
+
+        Ok(resp)
+    }
+
+    pub async fn announce_event(&mut self, parameters: &Parameters) -> Result<Announced, Report> {
+        let resp = self.get(parameters).await?;
+
+        Ok(Announced {
+            peers: resp.peers,
+            wait: self.bounds.apply(resp.interval, resp.min_interval),
+            min: Duration::from_secs(resp.min_interval.unwrap_or(0)),
+        })
+    }
+}
+
+// every tracker shares one socket, so responses find their way back by transaction id and
+// announces and scrapes don't steal each other's answers
+#[derive(Default)]
+pub struct Transactions {
+    pending: Mutex<HashMap<(SocketAddr, i32), oneshot::Sender<Response>>>,
+}
+
+impl Transactions {
+    fn register(&self, dst: SocketAddr, tid: i32) -> oneshot::Receiver<Response> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert((dst, tid), tx);
+        rx
+    }
+
+    fn cancel(&self, dst: SocketAddr, tid: i32) {
+        self.pending.lock().unwrap().remove(&(dst, tid));
+    }
+
+    // hands the response back if nobody is waiting for it
+    pub fn resolve(&self, src: SocketAddr, resp: Response) -> Result<(), Response> {
+        let tx = self.pending.lock().unwrap().remove(&(src, resp.tid()));
+        match tx {
+            Some(tx) => tx.send(resp),
+            None => Err(resp),
+        }
+    }
+}
+
+pub struct UdpSession {
+    peer_id: [u8; 20],
+    key: u32,
+    port: u16,
+    transactions: Arc<Transactions>,
+    stats: Arc<Stats>,
+    socket: Arc<UdpSocket>,
+    dst: SocketAddr,
+    tunables: Tunables,
+}
+
+impl UdpSession {
+    // only the peer id, key and port of the parameters are used, the rest comes with each announce
+    pub fn new(
+        socket: Arc<UdpSocket>,
+        dst: SocketAddr,
+        parameters: &Parameters,
+        transactions: Arc<Transactions>,
+        stats: Arc<Stats>,
+        tunables: Tunables,
+    ) -> Self {
+        debug!(?dst);
+        Self {
+            tunables,
+            peer_id: parameters.peer_id,
+            key: parameters.key,
+            port: parameters.port,
+            transactions,
+            stats,
+            socket,
+            dst,
+        }
+    }
+
+    pub fn dst(&self) -> SocketAddr {
+        self.dst
+    }
+
+    pub async fn connect(&self) -> Result<i64, Report> {
+        let packet = Request::Connect {
+            cid: PROTOCOL_ID,
+            action: 0_i32,
+            tid: rand::thread_rng().gen(),
+        };
+
+        match self.dispatch(packet).await? {
+            Response::Connect { cid, .. } => Ok(cid),
+            Response::Error { error, .. } => Err(TrackerError::from(error.as_str()).into()),
+            resp => Err(GeneralError::UnexpectedResponse(format!("{resp:?}")).into()),
+        }
+    }
+
+    pub async fn dispatch(&self, packet: Request) -> Result<Response, Report> {
+        let tid = packet.tid();
+        let result = self.retransmit(&packet).await;
+        self.transactions.cancel(self.dst, tid);
+
+        result
+    }
+
+    async fn retransmit(&self, packet: &Request) -> Result<Response, Report> {
+        for _ in 0..self.tunables.tracker_retries {
+            let resp_rx = self.transactions.register(self.dst, packet.tid());
+
+            let dst = helpers::mapped(&self.socket.local_addr()?, self.dst);
+            match self.socket.send_to(&packet.to_request(), dst).await {
+                Ok(_) => {
+                    debug!("socket [{}] sent request: {:?}", self.dst, packet);
+                    // a late answer to the previous attempt carries the same tid and is just as good
+                    let Ok(Ok(resp)) = timeout(self.tunables.udp_timeout, resp_rx).await else {
+                        continue;
+                    };
+                    debug!("socket [{}] received response: {:?}", self.dst.ip(), resp);
+
+                    return Ok(resp);
+                }
+                Err(e) => match e.kind() {
+                    ErrorKind::WouldBlock => continue,
+                    _ => return Err(e.into()),
+                },
+            }
+        }
+
+        Err(GeneralError::Timeout(Some(self.dst)).into())
+    }
+
+    pub async fn announce(
+        &self,
+        cid: i64,
+        info_hash: [u8; 20],
+        event: Event,
+    ) -> Result<Response, Report> {
+        let packet = Request::Announce {
+            cid,
+            action: 1i32,
+            tid: rand::thread_rng().gen::<i32>(),
+            info_hash,
+            peer_id: self.peer_id,
+            up_down_left: self.stats.up_down_left(),
+            event,
+            ip: None,
+            key: self.key,
+            num_want: -1i32,
+            port: self.port,
+        };
+
+        self.dispatch(packet).await
+    }
+
+    // the counts come back in the order of the hashes, trackers cap a scrape at about 74 of them
+    pub async fn scrape(&self, cid: i64, hashes: &[[u8; 20]]) -> Result<Vec<Status>, Report> {
+        let packet = Request::Scrape {
+            cid,
+            action: 2i32,
+            tid: rand::thread_rng().gen::<i32>(),
+            hashes: hashes.to_vec(),
+        };
+
+        match self.dispatch(packet).await? {
+            Response::Scrape { hashes, .. } => Ok(hashes.into_iter().map(Into::into).collect()),
+            Response::Error { error, .. } => Err(TrackerError::from(error.as_str()).into()),
+            resp => Err(GeneralError::UnexpectedResponse(format!("{resp:?}")).into()),
+        }
+    }
+
+    // connection ids only last a minute, every announce gets a fresh one
+    pub async fn announce_event(
+        &self,
+        info_hash: [u8; 20],
+        event: Event,
+    ) -> Result<Announced, Report> {
+        let cid = self.connect().await?;
+
+        match self.announce(cid, info_hash, event).await? {
+            Response::Announce {
+                peers, interval, ..
+            } => Ok(Announced {
+                peers: peers.iter().map(Into::into).collect(),
+                // a tracker asking for announces back to back gets them once a minute, BEP 15
+                // has no min interval
+                wait: Duration::from_secs(interval.max(0) as u64).max(RETRY),
+                min: Duration::ZERO,
+            }),
+            Response::Error { error, .. } => Err(TrackerError::from(error.as_str()).into()),
+            resp => Err(GeneralError::UnexpectedResponse(format!("{resp:?}")).into()),
+        }
+    }
+}
+
+// a tracker of either kind, tiers mix them freely
+pub enum Session {
+    Http(HttpSession),
+    Udp(UdpSession),
+}
+
+impl Session {
+    pub fn dst(&self) -> String {
+        match self {
+            Session::Http(session) => session.dst().to_owned(),
+            Session::Udp(session) => session.dst().to_string(),
+        }
+    }
+
+    pub async fn announce_event(&mut self, parameters: &Parameters) -> Result<Announced, Report> {
+        match self {
+            Session::Http(session) => session.announce_event(parameters).await,
+            Session::Udp(session) => {
+                let event = parameters.event.clone();
+                session.announce_event(parameters.info_hash, event).await
+            }
+        }
+    }
+}
+
+// BEP 12: trackers get asked one at a time, a tier only once every tracker of the ones before it
+// failed. Within a tier the order is random at first, and whoever answers moves to the front
+#[derive(Debug, Default)]
+pub struct Tiers {
+    // indices of the trackers, numbered across tiers in the order they were listed
+    tiers: Vec<Vec<usize>>,
+}
+
+impl Tiers {
+    pub fn new(sizes: &[usize]) -> Self {
+        let mut next = 0;
+        let tiers = sizes
+            .iter()
+            .filter(|n| **n > 0)
+            .map(|n| {
+                let mut tier: Vec<_> = (next..next + n).collect();
+                tier.shuffle(&mut rand::thread_rng());
+                next += n;
+                tier
+            })
+            .collect();
+
+        Self { tiers }
+    }
+
+    // every tracker, in the order they should be asked
+    pub fn order(&self) -> Vec<usize> {
+        self.tiers.concat()
+    }
+
+    pub fn promote(&mut self, tracker: usize) {
+        for tier in &mut self.tiers {
+            if let Some(i) = tier.iter().position(|t| *t == tracker) {
+                tier[..=i].rotate_right(1);
+            }
+        }
+    }
+
+    // the tracker turned us down for good
+    pub fn remove(&mut self, tracker: usize) {
+        for tier in &mut self.tiers {
+            tier.retain(|t| *t != tracker);
+        }
+        self.tiers.retain(|tier| !tier.is_empty());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Parameters {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub up_down_left: (u64, u64, u64),
+    // only HTTP announces have room for these, the UDP protocol doesn't know about them
+    pub corrupt_redundant: (u64, u64),
+    // some trackers only support compact responses
+    pub compact: bool,
+    pub no_peer_id: bool,
+    pub event: Event,
+    pub ip: Option<SocketAddr>,
+    pub numwant: u8,
+    pub key: u32,
+    pub tracker_id: Option<String>,
+    pub extensions: Option<()>,
+}
+
+impl Parameters {
+    pub fn new(info: &TorrentInfo, peer_id: [u8; 20], key: u32, port: u16) -> Self {
+        Parameters {
+            info_hash: info.hash,
+            peer_id,
+            port,
+            up_down_left: (0, 0, info.length() as u64),
+            corrupt_redundant: (0, 0),
+            compact: false,
+            no_peer_id: false,
+            event: Event::None,
+            ip: None,
+            numwant: 50,
+            key,
+            tracker_id: None,
+            extensions: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracker_error() {
+        let e = |s: &str| TrackerError::from(s);
+
+        assert_eq!(e("Unregistered torrent"), TrackerError::Unregistered);
+        assert_eq!(e("Invalid passkey"), TrackerError::InvalidPasskey);
+        assert_eq!(
+            e("Your client is not whitelisted"),
+            TrackerError::BannedClient
+        );
+        assert_eq!(
+            e("Rate limited, retry in 2 minutes"),
+            TrackerError::RateLimited(Some(Duration::from_secs(120)))
+        );
+        assert_eq!(e("too many requests").action(), Action::BackOff(BACK_OFF));
+        assert_eq!(e("Unregistered torrent").action(), Action::Disable);
+        assert_eq!(e("tracker is down for maintenance").action(), Action::Retry);
+    }
+
+    #[test]
+    fn test_transactions() {
+        let transactions = Transactions::default();
+        let dst = SocketAddr::from(([10, 0, 0, 1], 6969));
+        let connect = |tid| Response::Connect {
+            action: 0,
+            tid,
+            cid: 42,
+        };
+
+        let mut rx = transactions.register(dst, 7);
+        assert!(transactions.resolve(dst, connect(8)).is_err());
+        assert!(transactions
+            .resolve(SocketAddr::from(([10, 0, 0, 2], 6969)), connect(7))
+            .is_err());
+        assert!(rx.try_recv().is_err());
+
+        assert!(transactions.resolve(dst, connect(7)).is_ok());
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Response::Connect { tid: 7, .. })
+        ));
+        // answered once, a duplicate has nobody waiting for it
+        assert!(transactions.resolve(dst, connect(7)).is_err());
+
+        let _rx = transactions.register(dst, 9);
+        transactions.cancel(dst, 9);
+        assert!(transactions.resolve(dst, connect(9)).is_err());
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let mut lifecycle = Lifecycle::default();
+
+        // nothing to stop before anything was started
+        assert_eq!(lifecycle.event(&State::Paused), None);
+        assert!(lifecycle.urgent(&State::CheckingFiles));
+        assert_eq!(lifecycle.event(&State::CheckingFiles), Some(Event::Started));
+        assert_eq!(lifecycle.event(&State::Downloading), Some(Event::None));
+        assert!(!lifecycle.urgent(&State::Downloading));
+
+        assert!(lifecycle.urgent(&State::Seeding));
+        assert_eq!(lifecycle.event(&State::Seeding), Some(Event::Completed));
+        assert_eq!(lifecycle.event(&State::Seeding), Some(Event::None));
+
+        assert!(lifecycle.urgent(&State::Paused));
+        assert_eq!(lifecycle.event(&State::Paused), Some(Event::Stopped));
+        assert_eq!(lifecycle.event(&State::Paused), None);
+        assert!(!lifecycle.urgent(&State::Paused));
+
+        // seeding from the start was never a download we finished
+        assert_eq!(lifecycle.event(&State::Seeding), Some(Event::Started));
+        assert_eq!(lifecycle.event(&State::Seeding), Some(Event::None));
+        assert_eq!(lifecycle.stop(), Some(Event::Stopped));
+        assert_eq!(lifecycle.stop(), None);
+    }
+
+    #[test]
+    fn test_schedule() {
+        let mut schedule = Schedule::default();
+        let secs = Duration::from_secs;
+
+        let waits: Vec<_> = (0..7)
+            .map(|_| schedule.failed(Action::Retry).unwrap())
+            .collect();
+        assert_eq!(
+            waits,
+            [60, 120, 240, 480, 960, 1800, 1800].map(secs).to_vec()
+        );
+        assert!(schedule.earliest() <= Instant::now());
+
+        // a success starts counting from scratch, but manual announces wait for the min interval
+        assert_eq!(schedule.succeeded(secs(1800), secs(900)), secs(1800));
+        assert!(schedule.earliest() > Instant::now() + secs(800));
+        assert_eq!(schedule.failed(Action::Retry), Some(RETRY));
+
+        assert_eq!(schedule.failed(Action::BackOff(secs(600))), Some(secs(600)));
+        assert!(schedule.earliest() > Instant::now() + secs(500));
+        assert_eq!(schedule.failed(Action::Disable), None);
+    }
+
+    #[test]
+    fn test_tiers() {
+        let mut tiers = Tiers::new(&[2, 0, 3, 1]);
+        let order = tiers.order();
+        assert_eq!(order.len(), 6);
+
+        // the order within a tier is random, the tiers themselves stay where they are
+        let mut sorted = [order[..2].to_vec(), order[2..5].to_vec()];
+        sorted.iter_mut().for_each(|tier| tier.sort());
+        assert_eq!(sorted, [vec![0, 1], vec![2, 3, 4]]);
+        assert_eq!(order[5], 5);
+
+        // whoever answers gets asked first from now on, the rest keep their order
+        let last = order[4];
+        tiers.promote(last);
+        assert_eq!(
+            tiers.order()[..5],
+            [&order[..2], &[last], &order[2..4]].concat()
+        );
+
+        tiers.remove(5);
+        tiers.remove(order[0]);
+        assert_eq!(tiers.order().len(), 4);
+        assert!(!tiers.order().contains(&5));
+
+        for tracker in tiers.order() {
+            tiers.remove(tracker);
+        }
+        assert!(tiers.is_empty());
+    }
+
+    #[test]
+    fn test_interval_bounds() {
+        let bounds = |s: &str| s.parse::<AnnounceInterval>().unwrap().bounds;
+        let secs = Duration::from_secs;
+
+        let interval = "Tracker.example.org=60:1800"
+            .parse::<AnnounceInterval>()
+            .unwrap();
+        assert_eq!(interval.host, "tracker.example.org");
+        assert_eq!(interval.bounds.apply(3600, None), secs(1800));
+        assert_eq!(interval.bounds.apply(30, None), secs(60));
+        assert_eq!(interval.bounds.apply(900, Some(300)), secs(900));
+
+        // never more often than the tracker allows
+        assert_eq!(bounds("a=:600").apply(1800, Some(900)), secs(900));
+        assert_eq!(bounds("a=10:").apply(5, Some(30)), secs(30));
+        assert_eq!(IntervalBounds::default().apply(1800, Some(60)), secs(1800));
+
+        for s in ["a", "a=60", "=60:120", "a=x:1", "a=120:60"] {
+            assert!(s.parse::<AnnounceInterval>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn test_quirks() {
+        let mut quirks = Quirks::default();
+        let mut sent = Parameters::new(&TorrentInfo::default(), [0u8; 20], 0, 6881);
+
+        assert!(quirks.learn("This tracker only supports compact responses", &sent));
+        quirks.apply(&mut sent);
+        assert!(sent.compact);
+
+        // nothing new to learn, retrying would fail the same way
+        assert!(!quirks.learn("Compact responses are required", &sent));
+        assert!(!quirks.learn("unregistered torrent", &sent));
+
+        assert!(!quirks.learn("no_peer_id is not supported", &sent));
+        assert!(quirks.learn("no_peer_id is required", &sent));
+        quirks.apply(&mut sent);
+        assert!(sent.no_peer_id);
+
+        assert!(quirks.learn("numwant too large", &sent));
+        quirks.apply(&mut sent);
+        assert_eq!(sent.numwant, 25);
+    }
+}
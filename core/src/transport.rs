@@ -0,0 +1,171 @@
+use std::{
+    fmt,
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use color_eyre::Report;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    time::timeout,
+};
+use tracing::debug;
+
+use crate::{helpers, proxy::Proxy, utp::UtpSocket};
+
+// a peer connection the way the wire protocol sees it, whatever carries the bytes underneath
+pub struct Stream {
+    pub reader: ReadHalf,
+    pub writer: WriteHalf,
+}
+
+impl Stream {
+    pub fn new<R, W>(reader: R, writer: W, peer: SocketAddr) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let peer = helpers::canonical(peer);
+
+        Self {
+            reader: ReadHalf {
+                inner: Box::new(reader),
+                peer,
+            },
+            writer: WriteHalf {
+                inner: Box::new(writer),
+                peer,
+            },
+        }
+    }
+
+    pub fn tcp(stream: TcpStream) -> io::Result<Self> {
+        let peer = stream.peer_addr()?;
+        let (r, w) = stream.into_split();
+
+        Ok(Self::new(r, w, peer))
+    }
+}
+
+pub struct ReadHalf {
+    inner: Box<dyn AsyncRead + Send + Unpin>,
+    peer: SocketAddr,
+}
+
+impl ReadHalf {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+}
+
+impl AsyncRead for ReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+pub struct WriteHalf {
+    inner: Box<dyn AsyncWrite + Send + Unpin>,
+    peer: SocketAddr,
+}
+
+impl WriteHalf {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+}
+
+impl fmt::Debug for WriteHalf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteHalf")
+            .field("peer", &self.peer)
+            .finish()
+    }
+}
+
+impl AsyncWrite for WriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+// a way of reaching peers, each attempt gets `limit` to connect
+pub trait Transport {
+    fn connect(
+        &self,
+        addr: SocketAddr,
+        limit: Duration,
+    ) -> impl Future<Output = Result<Stream, Report>> + Send;
+}
+
+pub struct Tcp;
+
+impl Transport for Tcp {
+    async fn connect(&self, addr: SocketAddr, limit: Duration) -> Result<Stream, Report> {
+        let stream = timeout(limit, TcpStream::connect(addr)).await??;
+
+        Ok(Stream::tcp(stream)?)
+    }
+}
+
+// uTP where we have it, it backs off before the peer's TCP traffic and ours fill up a home
+// router's queue. Peers that don't speak it get dialed over TCP instead
+#[derive(Clone, Default)]
+pub struct Dialer {
+    utp: Option<Arc<UtpSocket>>,
+    // every peer goes through it over TCP, uTP would go around it
+    proxy: Option<Proxy>,
+}
+
+impl Dialer {
+    pub fn set_utp(&mut self, utp: Arc<UtpSocket>) {
+        self.utp = Some(utp);
+    }
+
+    pub fn set_proxy(&mut self, proxy: Proxy) {
+        self.proxy = Some(proxy);
+    }
+}
+
+impl Transport for Dialer {
+    async fn connect(&self, addr: SocketAddr, limit: Duration) -> Result<Stream, Report> {
+        // the stream's address would be the proxy's, the peer is who we asked for
+        if let Some(proxy) = &self.proxy {
+            let stream = timeout(limit, proxy.connect(addr)).await??;
+            let (r, w) = stream.into_split();
+
+            return Ok(Stream::new(r, w, addr));
+        }
+
+        if let Some(utp) = &self.utp {
+            match utp.connect(addr, limit).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => debug!("[{addr}] no uTP, falling back to TCP: {e}"),
+            }
+        }
+
+        Tcp.connect(addr, limit).await
+    }
+}
@@ -0,0 +1,317 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use color_eyre::Report;
+
+use crate::data::{self, Event, GeneralError};
+use crate::helpers;
+
+#[derive(Clone, Debug)]
+pub enum Request {
+    Connect {
+        cid: i64,
+        action: i32,
+        tid: i32,
+    },
+    Announce {
+        cid: i64,
+        action: i32,
+        tid: i32,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        up_down_left: (u64, u64, u64),
+        event: Event,
+        // 0 lets the tracker use the address the packet came from
+        ip: Option<Ipv4Addr>,
+        key: u32,
+        num_want: i32,
+        port: u16,
+    },
+    Scrape {
+        cid: i64,
+        action: i32,
+        tid: i32,
+        hashes: Vec<[u8; 20]>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum Response {
+    Connect {
+        action: i32,
+        tid: i32,
+        cid: i64,
+    },
+    Announce {
+        action: i32,
+        tid: i32,
+        interval: i32,
+        leechers: i32,
+        seeders: i32,
+        peers: Vec<SocketAddr>,
+    },
+    Scrape {
+        action: i32,
+        tid: i32,
+        hashes: Vec<Status>,
+    },
+    Error {
+        action: i32,
+        tid: i32,
+        error: String,
+    },
+}
+
+impl Response {
+    // what a response gets matched to its request by
+    pub fn tid(&self) -> i32 {
+        match self {
+            Response::Connect { tid, .. }
+            | Response::Announce { tid, .. }
+            | Response::Scrape { tid, .. }
+            | Response::Error { tid, .. } => *tid,
+        }
+    }
+
+    // trackers reached over IPv6 answer with 18-byte peers instead of 6-byte ones, BEP 15
+    pub fn to_response(v: &[u8], ipv6: bool) -> Result<Self, Report> {
+        let malformed = |reason: &str| GeneralError::MalformedPacket(reason.to_owned());
+
+        if v.len() < 8 {
+            return Err(malformed("shorter than a header").into());
+        }
+
+        let action = i32::from_be_bytes(v[0..4].try_into()?);
+        let tid = i32::from_be_bytes(v[4..8].try_into()?);
+
+        match action {
+            0 if v.len() < 16 => Err(malformed("truncated connect").into()),
+            0 => Ok(Response::Connect {
+                action,
+                tid,
+                cid: i64::from_be_bytes(v[8..16].try_into()?),
+            }),
+            1 if v.len() < 20 => Err(malformed("truncated announce").into()),
+            1 => {
+                // a trailing partial peer is ignored rather than rejecting the whole list
+                let peers = helpers::compact_peers(&v[20..], ipv6);
+
+                Ok(Response::Announce {
+                    action,
+                    tid,
+                    interval: i32::from_be_bytes(v[8..12].try_into()?),
+                    leechers: i32::from_be_bytes(v[12..16].try_into()?),
+                    seeders: i32::from_be_bytes(v[16..20].try_into()?),
+                    peers,
+                })
+            }
+            2 if v[8..].len() % 12 != 0 => Err(malformed("truncated scrape").into()),
+            2 => Ok(Response::Scrape {
+                action,
+                tid,
+                hashes: v[8..]
+                    .chunks_exact(12)
+                    .map(|x| Status {
+                        complete: i32::from_be_bytes([x[0], x[1], x[2], x[3]]),
+                        downloaded: i32::from_be_bytes([x[4], x[5], x[6], x[7]]),
+                        incomplete: i32::from_be_bytes([x[8], x[9], x[10], x[11]]),
+                    })
+                    .collect(),
+            }),
+            3 => Ok(Response::Error {
+                action,
+                tid,
+                error: String::from_utf8_lossy(&v[8..]).into_owned(),
+            }),
+            _ => Err(malformed("unknown action").into()),
+        }
+    }
+}
+
+impl Request {
+    pub fn tid(&self) -> i32 {
+        match self {
+            Request::Connect { tid, .. }
+            | Request::Announce { tid, .. }
+            | Request::Scrape { tid, .. } => *tid,
+        }
+    }
+
+    pub fn to_request(&self) -> Vec<u8> {
+        match self {
+            Request::Connect { cid, action, tid } => [
+                cid.to_be_bytes().to_vec(),
+                [action.to_be_bytes(), tid.to_be_bytes()].concat(),
+            ]
+            .concat(),
+            // <8:cid><4:action><4:tid><20:info_hash><20:peer_id><8:downloaded><8:left>
+            // <8:uploaded><4:event><4:ip><4:key><4:num_want><2:port>
+            Request::Announce {
+                cid,
+                action,
+                tid,
+                info_hash,
+                peer_id,
+                up_down_left: (uploaded, downloaded, left),
+                event,
+                ip,
+                key,
+                num_want,
+                port,
+            } => [
+                cid.to_be_bytes().as_slice(),
+                &action.to_be_bytes(),
+                &tid.to_be_bytes(),
+                info_hash,
+                peer_id,
+                &downloaded.to_be_bytes(),
+                &left.to_be_bytes(),
+                &uploaded.to_be_bytes(),
+                &(event.clone() as i32).to_be_bytes(),
+                &ip.map(u32::from).unwrap_or(0).to_be_bytes(),
+                &key.to_be_bytes(),
+                &num_want.to_be_bytes(),
+                &port.to_be_bytes(),
+            ]
+            .concat(),
+            // <8:cid><4:action><4:tid> followed by a 20 byte info hash per torrent
+            Request::Scrape {
+                cid,
+                action,
+                tid,
+                hashes,
+            } => [
+                cid.to_be_bytes().as_slice(),
+                action.to_be_bytes().as_slice(),
+                tid.to_be_bytes().as_slice(),
+                &hashes.iter().flat_map(|v| v.to_vec()).collect::<Vec<_>>(),
+            ]
+            .concat(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Status {
+    complete: i32,
+    downloaded: i32,
+    incomplete: i32,
+}
+
+// the counts come in the same order as the hashes of the request, the caller knows which is which
+impl From<Status> for data::Status {
+    fn from(status: Status) -> Self {
+        data::Status {
+            seeders: status.complete.max(0) as u32,
+            finished: status.downloaded.max(0) as u32,
+            leechers: status.incomplete.max(0) as u32,
+            name: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_malformed_response() {
+        assert!(Response::to_response(&[0, 0, 0], false).is_err());
+        // action 256 used to be read as a connect because only the last byte was looked at
+        assert!(Response::to_response(&[0, 0, 1, 0, 0, 0, 0, 0], false).is_err());
+        assert!(Response::to_response(&[0, 0, 0, 0, 0, 0, 0, 7, 1, 2], false).is_err());
+        assert!(Response::to_response(&[0, 0, 0, 2, 0, 0, 0, 7, 1], false).is_err());
+
+        let announce = [
+            [0, 0, 0, 1].as_slice(),
+            &[0u8; 16],
+            &[127, 0, 0, 1, 0, 80, 9],
+        ]
+        .concat();
+        match Response::to_response(&announce, false) {
+            Ok(Response::Announce { peers, .. }) => {
+                assert_eq!(peers, vec![SocketAddr::from(([127, 0, 0, 1], 80))])
+            }
+            resp => panic!("{resp:?}"),
+        }
+
+        let peer = [
+            [0x20, 0x01, 0x0d, 0xb8].as_slice(),
+            &[0; 11],
+            &[1, 0x1a, 0xe1],
+        ]
+        .concat();
+        match Response::to_response(&[&announce[..20], &peer].concat(), true) {
+            Ok(Response::Announce { peers, .. }) => {
+                assert_eq!(peers, vec!["[2001:db8::1]:6881".parse().unwrap()])
+            }
+            resp => panic!("{resp:?}"),
+        }
+    }
+
+    #[test]
+    fn test_announce_layout() {
+        let packet = Request::Announce {
+            cid: 0x41727101980,
+            action: 1,
+            tid: 7,
+            info_hash: [1u8; 20],
+            peer_id: [2u8; 20],
+            up_down_left: (3, 4, 5),
+            event: Event::Started,
+            ip: None,
+            key: 6,
+            num_want: -1,
+            port: 6881,
+        }
+        .to_request();
+
+        assert_eq!(packet.len(), 98);
+        assert_eq!(&packet[56..64], &4u64.to_be_bytes());
+        assert_eq!(&packet[64..72], &5u64.to_be_bytes());
+        assert_eq!(&packet[72..80], &3u64.to_be_bytes());
+        assert_eq!(&packet[80..84], &2i32.to_be_bytes());
+        assert_eq!(&packet[84..88], &[0u8; 4]);
+        assert_eq!(&packet[92..96], &(-1i32).to_be_bytes());
+        assert_eq!(&packet[96..], &6881u16.to_be_bytes());
+    }
+
+    #[test]
+    fn test_scrape_layout() {
+        let packet = Request::Scrape {
+            cid: 0x41727101980,
+            action: 2,
+            tid: 7,
+            hashes: vec![[1u8; 20], [2u8; 20]],
+        };
+        assert_eq!(packet.tid(), 7);
+
+        let packet = packet.to_request();
+        assert_eq!(packet.len(), 56);
+        assert_eq!(&packet[8..12], &2i32.to_be_bytes());
+        assert_eq!(&packet[16..36], &[1u8; 20]);
+        assert_eq!(&packet[36..], &[2u8; 20]);
+
+        let resp = [
+            [0, 0, 0, 2, 0, 0, 0, 7].as_slice(),
+            &5i32.to_be_bytes(),
+            &9i32.to_be_bytes(),
+            &2i32.to_be_bytes(),
+        ]
+        .concat();
+        let resp = Response::to_response(&resp, false).unwrap();
+        assert_eq!(resp.tid(), 7);
+        let Response::Scrape { hashes, .. } = resp else {
+            panic!("{resp:?}");
+        };
+        let status: Vec<data::Status> = hashes.into_iter().map(Into::into).collect();
+        assert_eq!(
+            status,
+            vec![data::Status {
+                seeders: 5,
+                finished: 9,
+                leechers: 2,
+                name: None,
+            }]
+        );
+    }
+}
@@ -0,0 +1,407 @@
+use std::{collections::VecDeque, net::Ipv4Addr, sync::Arc, time::Duration};
+
+use color_eyre::Report;
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
+use tracing::debug;
+
+use crate::{
+    data::GeneralError,
+    pwp::{self, Block, Message, ALLOWED_FAST, MAX_BLOCK},
+    torrent::Torrent,
+};
+
+// requests a single peer may have outstanding with us, the rest get dropped until it catches up
+const MAX_QUEUED: usize = 250;
+// how often the slots get handed out again, long enough for the rates to say something
+pub const RECHOKE: Duration = Duration::from_secs(10);
+
+// serves the requests of a single peer out of the pieces we already flushed. a peer gets unchoked
+// once it's interested and one of the session's upload slots is free, and keeps that slot until it
+// loses interest
+pub struct Uploader {
+    torrent: Arc<RwLock<Torrent>>,
+    slots: Arc<Semaphore>,
+    // how many there are, the semaphore only knows the free ones
+    count: usize,
+    slot: Option<OwnedSemaphorePermit>,
+    queue: VecDeque<Block>,
+    // BEP 6, requests we drop get rejected instead of silently forgotten, and the allowed fast
+    // pieces get served whether the peer is choked or not
+    fast: bool,
+    allowed: Vec<usize>,
+    rejected: Vec<Block>,
+}
+
+impl Uploader {
+    pub fn new(torrent: Arc<RwLock<Torrent>>, slots: Arc<Semaphore>, count: usize) -> Self {
+        Self {
+            torrent,
+            slots,
+            count,
+            slot: None,
+            queue: VecDeque::new(),
+            fast: false,
+            allowed: Vec::new(),
+            rejected: Vec::new(),
+        }
+    }
+
+    pub fn set_fast(&mut self, fast: bool) {
+        self.fast = fast;
+    }
+
+    // the allowed fast set of a peer, only the pieces we have get announced. IPv6 peers don't get
+    // one, BEP 6 only says how to pick them for IPv4
+    pub async fn allow_fast(&mut self, ip: Ipv4Addr) -> Vec<Message> {
+        let torrent = self.torrent.read().await;
+        let info = torrent.info();
+        let (Some(pieces), Some(bitfield)) = (info.info.as_ref(), torrent.bitfield()) else {
+            return Vec::new();
+        };
+        self.allowed = pwp::allowed_fast(ip, &info.hash, pieces.pieces.len(), ALLOWED_FAST);
+
+        self.allowed
+            .iter()
+            .filter(|&&i| bitfield[i / 8] & (0x80 >> (i % 8)) != 0)
+            .map(|&i| Message::AllowedFast(i))
+            .collect()
+    }
+
+    // requests we won't serve, to be answered with a RejectRequest each
+    pub fn rejects(&mut self) -> Vec<Message> {
+        self.rejected
+            .drain(..)
+            .map(|block| block.reject())
+            .collect()
+    }
+
+    fn reject(&mut self, block: Block) {
+        if self.fast {
+            self.rejected.push(block);
+        }
+    }
+
+    // a choked peer loses what it queued, but for the allowed fast pieces
+    fn clear_queue(&mut self) {
+        let queue = std::mem::take(&mut self.queue);
+        for block in queue {
+            match self.fast && self.allowed.contains(&block.index) {
+                true => self.queue.push_back(block),
+                false => self.reject(block),
+            }
+        }
+    }
+
+    // sent before anything else, so the peer knows what it can ask us for. Peers assume we have
+    // nothing when there's no BitField at all, unless we both speak the fast extension which
+    // has a message of its own for that, and for having everything
+    pub async fn bitfield(&self, fast: bool) -> Option<Message> {
+        let torrent = self.torrent.read().await;
+        let bytes = torrent.bitfield()?;
+        let pieces = torrent.info().info.as_ref()?.pieces.len();
+        let ones: usize = bytes.iter().map(|b| b.count_ones() as usize).sum();
+
+        match (ones, fast) {
+            (0, true) => Some(Message::HaveNone),
+            (0, false) => None,
+            (n, true) if n == pieces => Some(Message::HaveAll),
+            _ => Some(Message::BitField(bytes.into())),
+        }
+    }
+
+    pub fn is_choked(&self) -> bool {
+        self.slot.is_none()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    // true if the peer just got a slot and has to be sent an Unchoke
+    pub fn interested(&mut self) -> bool {
+        if self.slot.is_some() {
+            return false;
+        }
+        self.slot = self.slots.clone().try_acquire_owned().ok();
+
+        self.slot.is_some()
+    }
+
+    // true if the peer gave up its slot and has to be sent a Choke, whatever it still had queued
+    // is forgotten as the spec requires
+    pub fn uninterested(&mut self) -> bool {
+        self.clear_queue();
+        self.slot.take().is_some()
+    }
+
+    // every RECHOKE the slots go to the interested peers that are worth the most, `rank` is where
+    // this one stands among them. Some(true) if the peer has to be sent an Unchoke, Some(false)
+    // for a Choke
+    pub fn rechoke(&mut self, rank: Option<usize>) -> Option<bool> {
+        match rank {
+            Some(rank) if rank < self.count => {
+                (self.is_choked() && self.interested()).then_some(true)
+            }
+            // more peers are interested than there are slots, one of the better ones is waiting
+            // for this one
+            Some(_) if !self.is_choked() => {
+                self.slot = None;
+                self.clear_queue();
+                Some(false)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn request(&mut self, block: Block) -> Result<(), Report> {
+        let allowed = self.fast && self.allowed.contains(&block.index);
+        if self.is_choked() && !allowed {
+            debug!(
+                "dropping request for piece {} from a choked peer",
+                block.index
+            );
+            self.reject(block);
+            return Ok(());
+        }
+        if block.length == 0 || block.length > MAX_BLOCK {
+            return Err(GeneralError::InvalidRange.into());
+        }
+        if self.queue.contains(&block) {
+            return Ok(());
+        }
+        if self.queue.len() >= MAX_QUEUED {
+            self.reject(block);
+            return Ok(());
+        }
+        self.queue.push_back(block);
+
+        Ok(())
+    }
+
+    // with the fast extension every cancel gets an answer, the block itself or a reject
+    pub fn cancel(&mut self, block: Block) {
+        let queued = self.queue.len();
+        self.queue.retain(|b| *b != block);
+
+        if self.queue.len() < queued {
+            self.reject(block);
+        }
+    }
+
+    // reads the oldest request, it only leaves the queue once the block has been read so this can
+    // be raced against incoming messages without losing any
+    pub async fn next(&mut self) -> Option<Result<Message, Report>> {
+        let block = *self.queue.front()?;
+
+        let mut torrent = self.torrent.write().await;
+        let read = torrent.read_block(block.index, block.begin, block.length);
+        if read.is_ok() {
+            torrent.stats().uploaded(block.length as u64);
+        }
+        drop(torrent);
+        self.queue.pop_front();
+
+        Some(read.map(|block_data| Message::Piece {
+            index: block.index,
+            begin: block.begin,
+            block: block_data,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::{Info, Mode, TorrentInfo},
+        piece_manager::sha1,
+        testing::TempDir,
+        torrent::AddOptions,
+    };
+    use std::fs;
+
+    #[test]
+    fn test_uploader() -> Result<(), Report> {
+        let root = TempDir::new();
+        let data: Vec<u8> = (0..8).collect();
+        fs::write(root.join("data"), &data)?;
+
+        let info = Info {
+            mode: Mode::Single {
+                name: "data".to_owned(),
+                length: 8,
+                md5sum: None,
+            },
+            piece_length: 4,
+            pieces: vec![sha1(&data[..4]), sha1(&data[4..])].into_boxed_slice(),
+            ..Default::default()
+        };
+        let options = AddOptions {
+            seed_mode: true,
+            root: Some(root.to_path_buf()),
+            ..Default::default()
+        };
+        let torrent = Torrent::new(
+            TorrentInfo {
+                info: Some(info),
+                ..Default::default()
+            },
+            options,
+        )?;
+        let stats = torrent.stats();
+        let torrent = Arc::new(RwLock::new(torrent));
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        rt.block_on(async {
+            let slots = Arc::new(Semaphore::new(1));
+            let mut first = Uploader::new(torrent.clone(), slots.clone(), 1);
+            let mut second = Uploader::new(torrent, slots, 1);
+            let block = |index, begin, length| Block {
+                index,
+                begin,
+                length,
+            };
+
+            // a seed says so in a single message to peers that speak the fast extension
+            assert!(matches!(first.bitfield(true).await, Some(Message::HaveAll)));
+            assert!(matches!(
+                first.bitfield(false).await,
+                Some(Message::BitField(bytes)) if bytes[..] == [0xc0]
+            ));
+
+            // choked peers don't get anything
+            first.request(block(0, 0, 4))?;
+            assert!(first.is_empty());
+
+            assert!(first.interested());
+            assert!(!first.interested());
+            assert!(!second.interested());
+
+            first.request(block(1, 2, 2))?;
+            first.request(block(0, 0, 4))?;
+            first.request(block(0, 0, 4))?;
+            first.cancel(block(0, 0, 4));
+            assert!(first.request(block(0, 0, MAX_BLOCK + 1)).is_err());
+
+            match first.next().await {
+                Some(Ok(Message::Piece {
+                    index,
+                    begin,
+                    block,
+                })) => {
+                    assert_eq!((index, begin), (1, 2));
+                    assert_eq!(&block[..], &data[6..]);
+                }
+                _ => panic!("expected a piece"),
+            }
+            assert!(first.next().await.is_none());
+            assert_eq!(stats.up_down_left().0, 2);
+
+            // the slot goes to whoever asks next
+            first.request(block(0, 0, 4))?;
+            assert!(first.uninterested());
+            assert!(first.is_empty());
+            assert!(second.interested());
+
+            // the first one gives us more, the second one loses its slot to it
+            assert_eq!(first.rechoke(Some(0)), None);
+            assert_eq!(second.rechoke(Some(1)), Some(false));
+            assert!(second.is_choked());
+            assert_eq!(first.rechoke(Some(0)), Some(true));
+            assert_eq!(first.rechoke(Some(0)), None);
+            assert_eq!(second.rechoke(None), None);
+
+            Ok::<_, Report>(())
+        })?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fast() -> Result<(), Report> {
+        let root = TempDir::new();
+        let data: Vec<u8> = (0..8).collect();
+        fs::write(root.join("data"), &data)?;
+
+        let info = Info {
+            mode: Mode::Single {
+                name: "data".to_owned(),
+                length: 8,
+                md5sum: None,
+            },
+            piece_length: 4,
+            pieces: vec![sha1(&data[..4]), sha1(&data[4..])].into_boxed_slice(),
+            ..Default::default()
+        };
+        let options = AddOptions {
+            seed_mode: true,
+            root: Some(root.to_path_buf()),
+            ..Default::default()
+        };
+        let torrent = Torrent::new(
+            TorrentInfo {
+                info: Some(info),
+                ..Default::default()
+            },
+            options,
+        )?;
+        let torrent = Arc::new(RwLock::new(torrent));
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        rt.block_on(async {
+            let mut uploader = Uploader::new(torrent, Arc::new(Semaphore::new(1)), 1);
+            uploader.set_fast(true);
+            let block = |index, begin, length| Block {
+                index,
+                begin,
+                length,
+            };
+
+            // a torrent this small has every piece in the set
+            let allowed = uploader.allow_fast(Ipv4Addr::new(80, 4, 4, 200)).await;
+            assert_eq!(allowed.len(), 2);
+            uploader.allowed = vec![1];
+
+            // choked, only the allowed fast piece gets served
+            uploader.request(block(0, 0, 4))?;
+            uploader.request(block(1, 0, 4))?;
+            assert!(matches!(
+                &uploader.rejects()[..],
+                [Message::RejectRequest { index: 0, .. }]
+            ));
+            assert_eq!(uploader.queue.len(), 1);
+
+            // every cancel gets answered
+            assert!(uploader.interested());
+            uploader.request(block(0, 0, 4))?;
+            uploader.cancel(block(0, 0, 4));
+            assert_eq!(uploader.rejects().len(), 1);
+
+            // losing the slot rejects everything queued, but for the allowed fast pieces
+            uploader.request(block(0, 2, 2))?;
+            assert_eq!(uploader.rechoke(Some(1)), Some(false));
+            assert!(matches!(
+                &uploader.rejects()[..],
+                [Message::RejectRequest {
+                    index: 0,
+                    begin: 2,
+                    ..
+                }]
+            ));
+            assert!(matches!(
+                uploader.next().await,
+                Some(Ok(Message::Piece { index: 1, .. }))
+            ));
+
+            Ok::<_, Report>(())
+        })?;
+
+        Ok(())
+    }
+}
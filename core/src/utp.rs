@@ -0,0 +1,838 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use color_eyre::Report;
+use rand::Rng;
+use tokio::{
+    io::{duplex, split, AsyncReadExt, AsyncWriteExt, DuplexStream, WriteHalf},
+    net::UdpSocket,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        oneshot,
+    },
+    time::{sleep_until, timeout, Instant},
+};
+use tracing::debug;
+
+use crate::{
+    data::GeneralError,
+    demux::Datagram,
+    helpers,
+    transport::{Stream, Transport},
+};
+
+const VERSION: u8 = 1;
+const HEADER: usize = 20;
+// fits into any path, IPv6 guarantees 1280 bytes
+const MAX_PAYLOAD: usize = 1200;
+// what we advertise, and how much the application side buffers
+const RECV_WINDOW: usize = 1 << 20;
+// packets that arrive ahead of a gap get kept around until it's filled, up to this many
+const REORDER_LIMIT: u16 = 512;
+// packets a connection can have queued up before the socket starts dropping them
+const CAPACITY: usize = 256;
+
+// LEDBAT, BEP 29: the queueing delay we're fine with adding to the link, and how fast the window
+// may grow per round trip while we're below it
+const TARGET_DELAY: u32 = 100_000;
+const MAX_WINDOW_GAIN: f64 = 3000.0;
+const MIN_WINDOW: usize = MAX_PAYLOAD;
+// the lowest delay seen over this long is taken for the link without any queues
+const BASE_DELAY_HISTORY: Duration = Duration::from_secs(120);
+
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TRANSMISSIONS: u32 = 5;
+// NATs forget quiet UDP flows, libutp pokes them this often
+const KEEP_ALIVE: Duration = Duration::from_secs(29);
+// how long a connection we're done writing to waits for the peer to finish as well
+const LINGER: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Type {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl TryFrom<u8> for Type {
+    type Error = GeneralError;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(Type::Data),
+            1 => Ok(Type::Fin),
+            2 => Ok(Type::State),
+            3 => Ok(Type::Reset),
+            4 => Ok(Type::Syn),
+            _ => Err(GeneralError::MalformedPacket(format!(
+                "uTP packet type {v}"
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub kind: Type,
+    pub conn_id: u16,
+    // microseconds, only ever compared against each other
+    pub timestamp: u32,
+    pub timestamp_diff: u32,
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    pub payload: Bytes,
+}
+
+impl Packet {
+    fn new(kind: Type, conn_id: u16, seq_nr: u16, ack_nr: u16, payload: Bytes) -> Self {
+        Self {
+            kind,
+            conn_id,
+            timestamp: 0,
+            timestamp_diff: 0,
+            wnd_size: 0,
+            seq_nr,
+            ack_nr,
+            payload,
+        }
+    }
+
+    // extensions like selective acks get skipped, we get by without them
+    pub fn parse(v: &[u8]) -> Result<Self, Report> {
+        let malformed = |reason: &str| GeneralError::MalformedPacket(reason.to_owned());
+
+        if v.len() < HEADER {
+            return Err(malformed("shorter than a uTP header").into());
+        }
+        if v[0] & 0x0f != VERSION {
+            return Err(malformed("unknown uTP version").into());
+        }
+
+        let (mut extension, mut pos) = (v[1], HEADER);
+        while extension != 0 {
+            let Some(&[next, len]) = v.get(pos..pos + 2) else {
+                return Err(malformed("truncated uTP extension").into());
+            };
+            extension = next;
+            pos += 2 + len as usize;
+        }
+        let payload = v
+            .get(pos..)
+            .ok_or_else(|| malformed("truncated uTP extension"))?;
+
+        let u16_at = |i: usize| u16::from_be_bytes([v[i], v[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes([v[i], v[i + 1], v[i + 2], v[i + 3]]);
+
+        Ok(Self {
+            kind: Type::try_from(v[0] >> 4)?,
+            conn_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            payload: Bytes::copy_from_slice(payload),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(HEADER + self.payload.len());
+
+        buf.put_u8((self.kind as u8) << 4 | VERSION);
+        buf.put_u8(0);
+        buf.put_u16(self.conn_id);
+        buf.put_u32(self.timestamp);
+        buf.put_u32(self.timestamp_diff);
+        buf.put_u32(self.wnd_size);
+        buf.put_u16(self.seq_nr);
+        buf.put_u16(self.ack_nr);
+        buf.put_slice(&self.payload);
+
+        buf.freeze()
+    }
+}
+
+fn now_micros() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_micros() as u32
+}
+
+// sequence numbers wrap around, `a` comes before `b` if it's less than half the space behind it
+fn before(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+// the congestion window: grows while our packets don't queue up anywhere, shrinks once they do,
+// so uTP backs off before the TCP traffic sharing the link notices anything
+#[derive(Debug)]
+struct Window {
+    size: f64,
+    // the lowest one-way delay of every minute, the oldest goes after two minutes
+    history: VecDeque<(Instant, u32)>,
+}
+
+impl Default for Window {
+    fn default() -> Self {
+        Self {
+            size: (2 * MAX_PAYLOAD) as f64,
+            history: VecDeque::new(),
+        }
+    }
+}
+
+impl Window {
+    fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    // the delay sample is the peer's clock minus ours, the offset cancels out against the base
+    fn acked(&mut self, bytes: usize, delay: u32, now: Instant) {
+        match self.history.back_mut() {
+            Some((since, base)) if now.duration_since(*since) < BASE_DELAY_HISTORY / 2 => {
+                *base = (*base).min(delay);
+            }
+            _ => self.history.push_back((now, delay)),
+        }
+        while self.history.len() > 2 {
+            self.history.pop_front();
+        }
+
+        let base = self
+            .history
+            .iter()
+            .map(|(_, base)| *base)
+            .min()
+            .unwrap_or(delay);
+        let queued = delay.wrapping_sub(base).min(TARGET_DELAY * 2);
+
+        let off_target = (TARGET_DELAY as f64 - queued as f64) / TARGET_DELAY as f64;
+        let window_factor = bytes.min(self.size()) as f64 / self.size.max(bytes as f64);
+        self.size = (self.size + MAX_WINDOW_GAIN * off_target * window_factor)
+            .max(MIN_WINDOW as f64)
+            .min(RECV_WINDOW as f64);
+    }
+
+    // three duplicate acks, something in between got dropped
+    fn lost(&mut self) {
+        self.size = (self.size / 2.0).max(MIN_WINDOW as f64);
+    }
+
+    fn timed_out(&mut self) {
+        self.size = MIN_WINDOW as f64;
+    }
+}
+
+// RFC 6298 with the floor BEP 29 asks for
+#[derive(Debug, Default)]
+struct Rtt {
+    rtt: Option<Duration>,
+    var: Duration,
+    backoff: u32,
+}
+
+impl Rtt {
+    fn sample(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.var = sample / 2;
+            }
+            Some(rtt) => {
+                let delta = rtt.max(sample) - rtt.min(sample);
+                self.var = (self.var * 3 + delta) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+        }
+        self.backoff = 0;
+    }
+
+    fn timeout(&self) -> Duration {
+        let timeout = match self.rtt {
+            Some(rtt) => (rtt + self.var * 4).max(MIN_TIMEOUT),
+            None => INITIAL_TIMEOUT,
+        };
+
+        timeout * 2u32.pow(self.backoff.min(6))
+    }
+}
+
+#[derive(Debug)]
+struct Sent {
+    packet: Packet,
+    sent: Instant,
+    transmissions: u32,
+}
+
+// a single connection, everything the application writes goes out in packets and whatever
+// arrives in order comes back to it
+struct Conn {
+    socket: Arc<UtpSocket>,
+    peer: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    // the next one we send
+    seq_nr: u16,
+    // the last one received in order
+    ack_nr: u16,
+    connected: bool,
+    sent: VecDeque<Sent>,
+    in_flight: usize,
+    peer_window: usize,
+    window: Window,
+    rtt: Rtt,
+    dup_acks: u32,
+    reorder: HashMap<u16, Packet>,
+    // what the peer's packets took to get here, it learns its delay from us
+    reply_micros: u32,
+    last_sent: Instant,
+    last_heard: Instant,
+    fin_sent: bool,
+    eof: bool,
+}
+
+impl Conn {
+    fn new(socket: Arc<UtpSocket>, peer: SocketAddr, recv_id: u16, send_id: u16) -> Self {
+        let now = Instant::now();
+
+        Self {
+            socket,
+            peer,
+            recv_id,
+            send_id,
+            seq_nr: 1,
+            ack_nr: 0,
+            connected: false,
+            sent: VecDeque::new(),
+            in_flight: 0,
+            peer_window: RECV_WINDOW,
+            window: Window::default(),
+            rtt: Rtt::default(),
+            dup_acks: 0,
+            reorder: HashMap::new(),
+            reply_micros: 0,
+            last_sent: now,
+            last_heard: now,
+            fin_sent: false,
+            eof: false,
+        }
+    }
+
+    async fn transmit(&mut self, packet: &Packet) {
+        let mut packet = packet.clone();
+        packet.timestamp = now_micros();
+        packet.timestamp_diff = self.reply_micros;
+        packet.wnd_size = (RECV_WINDOW - self.reorder.len() * MAX_PAYLOAD) as u32;
+        self.last_sent = Instant::now();
+
+        let Ok(local) = self.socket.socket.local_addr() else {
+            return;
+        };
+        let dst = helpers::mapped(&local, self.peer);
+        // lost like any other datagram, the retransmission timer takes care of it
+        if let Err(e) = self.socket.socket.send_to(&packet.to_bytes(), dst).await {
+            debug!("[{}] failed to send uTP packet: {e}", self.peer);
+        }
+    }
+
+    // SYN, data and FIN take up a sequence number and stay around until they're acked
+    async fn send(&mut self, kind: Type, payload: Bytes) {
+        let id = match kind {
+            Type::Syn => self.recv_id,
+            _ => self.send_id,
+        };
+        let packet = Packet::new(kind, id, self.seq_nr, self.ack_nr, payload);
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+
+        self.transmit(&packet).await;
+        self.in_flight += packet.payload.len();
+        self.sent.push_back(Sent {
+            packet,
+            sent: Instant::now(),
+            transmissions: 1,
+        });
+    }
+
+    async fn ack(&mut self) {
+        let packet = Packet::new(
+            Type::State,
+            self.send_id,
+            self.seq_nr,
+            self.ack_nr,
+            Bytes::new(),
+        );
+        self.transmit(&packet).await;
+    }
+
+    fn may_send(&self) -> bool {
+        let limit = self.window.size().min(self.peer_window);
+        self.connected
+            && !self.fin_sent
+            && (self.in_flight == 0 || self.in_flight + MAX_PAYLOAD <= limit)
+    }
+
+    fn deadline(&self) -> Instant {
+        match self.sent.front() {
+            Some(oldest) => oldest.sent + self.rtt.timeout(),
+            None => self.last_sent + KEEP_ALIVE,
+        }
+    }
+
+    // everything up to and including `ack_nr` made it
+    fn acked(&mut self, ack_nr: u16, delay: u32, kind: Type) {
+        let now = Instant::now();
+        let mut bytes = 0;
+        let mut sample = None;
+
+        while let Some(oldest) = self.sent.front() {
+            if before(ack_nr, oldest.packet.seq_nr) {
+                break;
+            }
+            let oldest = self.sent.pop_front().unwrap();
+            bytes += oldest.packet.payload.len();
+            // Karn: retransmitted packets don't say anything about the round trip
+            if oldest.transmissions == 1 {
+                sample = Some(now.duration_since(oldest.sent));
+            }
+        }
+        self.in_flight -= bytes;
+
+        if let Some(sample) = sample {
+            self.rtt.sample(sample);
+        }
+        if bytes > 0 || sample.is_some() {
+            self.dup_acks = 0;
+            self.window.acked(bytes, delay, now);
+        } else if kind == Type::State && !self.sent.is_empty() {
+            self.dup_acks += 1;
+        }
+    }
+
+    // in order goes straight to the application, anything after a gap waits for it to be filled
+    async fn received(&mut self, packet: Packet, app: &mut Option<WriteHalf<DuplexStream>>) {
+        let seq_nr = packet.seq_nr;
+
+        if seq_nr == self.ack_nr.wrapping_add(1) {
+            self.deliver(packet, app).await;
+
+            loop {
+                let next = self.ack_nr.wrapping_add(1);
+                match self.reorder.remove(&next) {
+                    Some(packet) => self.deliver(packet, app).await,
+                    None => break,
+                }
+            }
+        } else if before(self.ack_nr, seq_nr) && seq_nr.wrapping_sub(self.ack_nr) < REORDER_LIMIT {
+            self.reorder.insert(seq_nr, packet);
+        }
+    }
+
+    async fn deliver(&mut self, packet: Packet, app: &mut Option<WriteHalf<DuplexStream>>) {
+        self.ack_nr = packet.seq_nr;
+
+        // the application went away, the peer still gets its acks
+        let Some(writer) = app else {
+            return;
+        };
+        let delivered = match packet.kind {
+            Type::Fin => {
+                self.eof = true;
+                writer.shutdown().await
+            }
+            _ => writer.write_all(&packet.payload).await,
+        };
+        if delivered.is_err() || self.eof {
+            *app = None;
+        }
+    }
+
+    async fn run(
+        mut self,
+        mut packets: Receiver<Packet>,
+        app: DuplexStream,
+        mut connected: Option<oneshot::Sender<()>>,
+    ) -> Result<(), Report> {
+        let (mut app_rx, app_tx) = split(app);
+        let mut app_tx = Some(app_tx);
+        let mut buf = vec![0u8; MAX_PAYLOAD];
+        let mut reading = true;
+
+        if connected.is_some() {
+            self.send(Type::Syn, Bytes::new()).await;
+        }
+
+        loop {
+            // done writing, the peer is done too or has gone quiet
+            if !reading && self.sent.is_empty() && (self.eof || self.last_heard.elapsed() > LINGER)
+            {
+                return Ok(());
+            }
+
+            tokio::select! {
+                packet = packets.recv() => {
+                    let Some(packet) = packet else {
+                        return Ok(());
+                    };
+                    self.last_heard = Instant::now();
+                    self.reply_micros = now_micros().wrapping_sub(packet.timestamp);
+                    self.peer_window = packet.wnd_size as usize;
+
+                    match packet.kind {
+                        Type::Reset => return Err(GeneralError::ConnectionReset(self.peer).into()),
+                        // our answer got lost
+                        Type::Syn => {
+                            self.ack().await;
+                            continue;
+                        }
+                        _ => {}
+                    }
+
+                    self.acked(packet.ack_nr, packet.timestamp_diff, packet.kind);
+                    if self.dup_acks == 3 {
+                        self.window.lost();
+                        self.retransmit().await;
+                    }
+
+                    match packet.kind {
+                        Type::State if !self.connected => {
+                            self.connected = true;
+                            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+                            if let Some(connected) = connected.take() {
+                                let _ = connected.send(());
+                            }
+                        }
+                        Type::Data | Type::Fin if self.connected => {
+                            self.received(packet, &mut app_tx).await;
+                            self.ack().await;
+                        }
+                        _ => {}
+                    }
+                }
+                n = app_rx.read(&mut buf), if reading && self.may_send() => match n {
+                    Ok(n) if n > 0 => self.send(Type::Data, Bytes::copy_from_slice(&buf[..n])).await,
+                    _ => {
+                        reading = false;
+                        self.fin_sent = true;
+                        self.send(Type::Fin, Bytes::new()).await;
+                    }
+                },
+                _ = sleep_until(self.deadline()) => {
+                    if self.sent.is_empty() {
+                        self.ack().await;
+                        continue;
+                    }
+                    if self.sent[0].transmissions >= MAX_TRANSMISSIONS {
+                        return Err(GeneralError::Timeout(Some(self.peer)).into());
+                    }
+                    self.window.timed_out();
+                    self.rtt.backoff += 1;
+                    self.retransmit().await;
+                }
+                // whoever dialed gave up waiting
+                _ = closed(&mut connected), if connected.is_some() => return Ok(()),
+            }
+        }
+    }
+
+    async fn retransmit(&mut self) {
+        let Some(oldest) = self.sent.front() else {
+            return;
+        };
+        let mut packet = oldest.packet.clone();
+        packet.ack_nr = self.ack_nr;
+
+        self.transmit(&packet).await;
+        let oldest = &mut self.sent[0];
+        oldest.sent = Instant::now();
+        oldest.transmissions += 1;
+        self.dup_acks = 0;
+    }
+}
+
+async fn closed(connected: &mut Option<oneshot::Sender<()>>) {
+    match connected {
+        Some(connected) => connected.closed().await,
+        None => std::future::pending().await,
+    }
+}
+
+// the uTP side of our UDP port, BEP 29: packets find their connection by the id in the header,
+// a SYN nobody's waiting for is a peer dialing us
+pub struct UtpSocket {
+    socket: Arc<UdpSocket>,
+    conns: Mutex<HashMap<(SocketAddr, u16), Sender<Packet>>>,
+}
+
+impl UtpSocket {
+    pub fn new(socket: Arc<UdpSocket>) -> Arc<Self> {
+        Arc::new(Self {
+            socket,
+            conns: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn spawn(
+        self: &Arc<Self>,
+        conn: Conn,
+        app: DuplexStream,
+        connected: Option<oneshot::Sender<()>>,
+    ) -> Sender<Packet> {
+        let (packet_tx, packet_rx) = mpsc::channel(CAPACITY);
+        let key = (conn.peer, conn.recv_id);
+        self.conns.lock().unwrap().insert(key, packet_tx.clone());
+
+        let socket = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = conn.run(packet_rx, app, connected).await {
+                debug!("[{}] uTP connection closed: {e}", key.0);
+            }
+            socket.conns.lock().unwrap().remove(&key);
+        });
+
+        packet_tx
+    }
+
+    pub async fn connect(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+        limit: Duration,
+    ) -> Result<Stream, Report> {
+        let recv_id = {
+            let conns = self.conns.lock().unwrap();
+            loop {
+                let id: u16 = rand::thread_rng().gen();
+                let taken = |id| conns.contains_key(&(addr, id));
+                if !taken(id) && !taken(id.wrapping_add(1)) {
+                    break id;
+                }
+            }
+        };
+
+        let (ours, theirs) = duplex(RECV_WINDOW);
+        let (connected_tx, connected_rx) = oneshot::channel();
+        let conn = Conn::new(self.clone(), addr, recv_id, recv_id.wrapping_add(1));
+        self.spawn(conn, ours, Some(connected_tx));
+
+        match timeout(limit, connected_rx).await {
+            Ok(Ok(())) => {
+                let (r, w) = split(theirs);
+                Ok(Stream::new(r, w, addr))
+            }
+            Ok(Err(_)) => Err(GeneralError::ConnectionReset(addr).into()),
+            Err(_) => Err(GeneralError::Timeout(Some(addr)).into()),
+        }
+    }
+
+    // the demultiplexer hands us whatever looks like uTP, new connections go to `accept_tx`
+    pub async fn run(
+        self: Arc<Self>,
+        mut datagrams: Receiver<Datagram>,
+        accept_tx: Sender<Stream>,
+    ) {
+        while let Some((datagram, addr)) = datagrams.recv().await {
+            let packet = match Packet::parse(&datagram) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    debug!("dropping datagram from [{addr}]: {e}");
+                    continue;
+                }
+            };
+
+            // the SYN carries the id the peer receives on, we receive on the one after it
+            let id = match packet.kind {
+                Type::Syn => packet.conn_id.wrapping_add(1),
+                _ => packet.conn_id,
+            };
+            let conn = self.conns.lock().unwrap().get(&(addr, id)).cloned();
+
+            match (conn, packet.kind) {
+                // a connection that can't keep up loses packets, they get sent again
+                (Some(conn), _) => {
+                    let _ = conn.try_send(packet);
+                }
+                (None, Type::Syn) if !accept_tx.is_closed() => {
+                    self.accept(addr, packet, &accept_tx)
+                }
+                (None, Type::Reset) => {}
+                (None, _) => {
+                    let reset =
+                        Packet::new(Type::Reset, packet.conn_id, 0, packet.seq_nr, Bytes::new());
+                    let dst = match self.socket.local_addr() {
+                        Ok(local) => helpers::mapped(&local, addr),
+                        Err(_) => continue,
+                    };
+                    let _ = self.socket.send_to(&reset.to_bytes(), dst).await;
+                }
+            }
+        }
+    }
+
+    fn accept(self: &Arc<Self>, addr: SocketAddr, syn: Packet, accept_tx: &Sender<Stream>) {
+        let (ours, theirs) = duplex(RECV_WINDOW);
+        let mut conn = Conn::new(self.clone(), addr, syn.conn_id.wrapping_add(1), syn.conn_id);
+        conn.seq_nr = rand::thread_rng().gen();
+        conn.ack_nr = syn.seq_nr;
+        conn.connected = true;
+
+        let (r, w) = split(theirs);
+        if accept_tx.try_send(Stream::new(r, w, addr)).is_err() {
+            debug!("[{addr}] dropping uTP connection, too many waiting to be accepted");
+            return;
+        }
+
+        // the SYN goes through the connection like every other packet, it answers with a State
+        let packet_tx = self.spawn(conn, ours, None);
+        let _ = packet_tx.try_send(syn);
+    }
+}
+
+impl Transport for Arc<UtpSocket> {
+    async fn connect(&self, addr: SocketAddr, limit: Duration) -> Result<Stream, Report> {
+        UtpSocket::connect(self, addr, limit).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demux::Demux;
+
+    #[test]
+    fn test_packet() -> Result<(), Report> {
+        let packet = Packet {
+            kind: Type::Data,
+            conn_id: 0x1234,
+            timestamp: 1,
+            timestamp_diff: 2,
+            wnd_size: 3,
+            seq_nr: 4,
+            ack_nr: 5,
+            payload: Bytes::from_static(b"hello"),
+        };
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes[0], 0x01);
+        assert_eq!(&bytes[2..4], &[0x12, 0x34]);
+        assert_eq!(Packet::parse(&bytes)?, packet);
+
+        // a selective ack in front of the payload gets skipped
+        let mut extended = bytes.to_vec();
+        extended[1] = 1;
+        extended.splice(HEADER..HEADER, [0, 4, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(Packet::parse(&extended)?, packet);
+
+        extended.truncate(HEADER + 1);
+        assert!(Packet::parse(&extended).is_err());
+        assert!(Packet::parse(&bytes[..HEADER - 1]).is_err());
+        assert!(Packet::parse(&[&[0x02][..], &bytes[1..]].concat()).is_err());
+        assert!(Packet::parse(&[&[0x51][..], &bytes[1..]].concat()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_before() {
+        assert!(before(1, 2));
+        assert!(!before(2, 2));
+        assert!(!before(3, 2));
+        assert!(before(u16::MAX, 0));
+        assert!(!before(0, u16::MAX));
+    }
+
+    #[test]
+    fn test_window() {
+        let now = Instant::now();
+        let mut window = Window::default();
+        let start = window.size();
+
+        // no queueing delay on top of the base, the window opens up
+        for _ in 0..10 {
+            window.acked(MAX_PAYLOAD, 50_000, now);
+        }
+        let grown = window.size();
+        assert!(grown > start);
+
+        // twice the target on top of it, the window closes again
+        for _ in 0..10 {
+            window.acked(MAX_PAYLOAD, 50_000 + 2 * TARGET_DELAY, now);
+        }
+        assert!(window.size() < grown);
+
+        window.lost();
+        assert!(window.size() >= MIN_WINDOW);
+        window.timed_out();
+        assert_eq!(window.size(), MIN_WINDOW);
+    }
+
+    #[test]
+    fn test_rtt() {
+        let mut rtt = Rtt::default();
+        assert_eq!(rtt.timeout(), INITIAL_TIMEOUT);
+
+        rtt.sample(Duration::from_millis(10));
+        assert_eq!(rtt.timeout(), MIN_TIMEOUT);
+        rtt.sample(Duration::from_secs(1));
+        assert!(rtt.timeout() > MIN_TIMEOUT);
+
+        let timeout = rtt.timeout();
+        rtt.backoff += 1;
+        assert_eq!(rtt.timeout(), timeout * 2);
+    }
+
+    #[tokio::test]
+    async fn test_utp() -> Result<(), Report> {
+        let mut sockets = Vec::new();
+        let mut accepted = Vec::new();
+        for _ in 0..2 {
+            let (mut demux, _, _) = Demux::new(std::net::UdpSocket::bind("127.0.0.1:0")?)?;
+            let (utp_tx, utp_rx) = mpsc::channel(CAPACITY);
+            demux.set_utp(utp_tx);
+            let utp = UtpSocket::new(demux.socket());
+            tokio::spawn(demux.run());
+
+            let (accept_tx, accept_rx) = mpsc::channel(4);
+            tokio::spawn(utp.clone().run(utp_rx, accept_tx));
+            sockets.push(utp);
+            accepted.push(accept_rx);
+        }
+        let addr = sockets[1].socket.local_addr()?;
+
+        let limit = Duration::from_secs(5);
+        let mut dialed = sockets[0].connect(addr, limit).await?;
+        let mut accepted = accepted[1].recv().await.unwrap();
+        assert_eq!(
+            accepted.reader.peer_addr()?,
+            sockets[0].socket.local_addr()?
+        );
+
+        // more than fits into a single packet, in both directions
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        dialed.writer.write_all(&data).await?;
+        dialed.writer.shutdown().await?;
+        let mut received = Vec::new();
+        accepted.reader.read_to_end(&mut received).await?;
+        assert_eq!(received, data);
+
+        accepted.writer.write_all(b"bye").await?;
+        accepted.writer.shutdown().await?;
+        let mut received = Vec::new();
+        dialed.reader.read_to_end(&mut received).await?;
+        assert_eq!(received, b"bye");
+
+        // nobody listens on the port, the SYN goes unanswered
+        let closed = std::net::UdpSocket::bind("127.0.0.1:0")?.local_addr()?;
+        let limit = Duration::from_millis(200);
+        assert!(sockets[0].connect(closed, limit).await.is_err());
+
+        Ok(())
+    }
+}
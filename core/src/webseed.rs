@@ -0,0 +1,313 @@
+use std::{iter::once, net::SocketAddr, ops::Range, sync::Arc, time::Duration};
+
+use bytes::BytesMut;
+use color_eyre::Report;
+use reqwest::header::RANGE;
+use tokio::{net::lookup_host, sync::RwLock, time::sleep};
+use tracing::debug;
+use url::Url;
+
+use crate::{
+    data::{GeneralError, Mode},
+    download::Downloader,
+    limit::RateLimit,
+    proxy::{self, Proxy},
+    pwp::Block,
+    torrent::{State, Torrent},
+};
+
+// a whole piece has to come in within this, a server that stalls doesn't get to sit on it
+const PIECE_TIMEOUT: Duration = Duration::from_secs(60);
+// before asking again after a failure, or when there was nothing left to fetch
+const RETRY: Duration = Duration::from_secs(10);
+// a server that failed this often in a row gets dropped
+const MAX_FAILURES: usize = 5;
+
+// an HTTP server with the torrent's files (BEP 19). Pieces are claimed from the picker like a peer
+// would and fetched with range requests, the pipeline verifies them along with everyone else's
+pub struct WebSeed {
+    url: String,
+    client: reqwest::Client,
+    torrent: Arc<RwLock<Torrent>>,
+    downloader: Downloader,
+    limit: RateLimit,
+}
+
+impl WebSeed {
+    pub fn new(
+        url: String,
+        torrent: Arc<RwLock<Torrent>>,
+        downloader: Downloader,
+        connect_timeout: Duration,
+        proxy: Option<&Proxy>,
+    ) -> Result<Self, Report> {
+        let client = proxy::http_client(proxy)?
+            .connect_timeout(connect_timeout)
+            .timeout(PIECE_TIMEOUT)
+            .build()?;
+
+        Ok(Self {
+            url,
+            client,
+            torrent,
+            downloader,
+            limit: RateLimit::default(),
+        })
+    }
+
+    pub fn limit(mut self, limit: RateLimit) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    // until the torrent is complete or the server keeps failing
+    pub async fn run(mut self) -> Result<(), Report> {
+        // the pipeline knows where blocks came from by address, the server's will do
+        let url = Url::parse(&self.url)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| GeneralError::ParseFailure(self.url.clone()))?;
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addr = lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(|| GeneralError::ParseFailure(self.url.clone()))?;
+
+        let mut failures = 0;
+        loop {
+            let state = self.torrent.read().await.state();
+            if state == State::Seeding {
+                return Ok(());
+            }
+
+            // the server has every piece
+            let blocks = match state.is_active() {
+                true => self.downloader.next_blocks(&|_| true).await,
+                false => Vec::new(),
+            };
+            if blocks.is_empty() {
+                sleep(RETRY).await;
+                continue;
+            }
+
+            match self.fetch(addr, &blocks).await {
+                Ok(()) => failures = 0,
+                Err(e) => {
+                    self.downloader.choked().await;
+                    failures += 1;
+                    if failures == MAX_FAILURES {
+                        return Err(e);
+                    }
+
+                    debug!("[{}] piece {}: {e}", self.url, blocks[0].index);
+                    sleep(RETRY).await;
+                }
+            }
+        }
+    }
+
+    async fn fetch(&mut self, addr: SocketAddr, blocks: &[Block]) -> Result<(), Report> {
+        let index = blocks[0].index;
+        let (mode, piece_length) = {
+            let torrent = self.torrent.read().await;
+            let info = torrent
+                .info()
+                .info
+                .as_ref()
+                .ok_or(GeneralError::MissingInfo)?;
+            (info.mode.clone(), info.piece_length)
+        };
+
+        // only the blocks that are still missing, usually all of them
+        let begin = blocks.iter().map(|b| b.begin).min().unwrap_or_default();
+        let end = blocks
+            .iter()
+            .map(|b| b.begin + b.length)
+            .max()
+            .unwrap_or_default();
+        let start = piece_length * index as u64 + begin as u64;
+        self.limit.acquire(end - begin).await;
+
+        let mut data = BytesMut::with_capacity(end - begin);
+        for (url, range) in segments(&self.url, &mode, start..start + (end - begin) as u64) {
+            let resp = self
+                .client
+                .get(&url)
+                .header(RANGE, format!("bytes={}-{}", range.start, range.end - 1))
+                .send()
+                .await?
+                .error_for_status()?;
+
+            // a server that ignores the range sends the whole file instead
+            let body = resp.bytes().await?;
+            if body.len() as u64 != range.end - range.start {
+                return Err(GeneralError::UnexpectedResponse(format!(
+                    "{} bytes for {range:?} of {url}",
+                    body.len()
+                ))
+                .into());
+            }
+            data.extend_from_slice(&body);
+        }
+
+        let data = data.freeze();
+        for block in blocks {
+            let offset = block.begin - begin;
+            let block_data = data.slice(offset..offset + block.length);
+            self.downloader
+                .received(addr, index, block.begin, block_data)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+// the files of the torrent overlapping a range of it: where to get each one and the part of the
+// file that's needed. URLs ending in a slash get the torrent's name appended, multi-file torrents
+// always get the name and the file's path
+fn segments(base: &str, mode: &Mode, range: Range<u64>) -> Vec<(String, Range<u64>)> {
+    let files = match mode {
+        Mode::Single { name, length, .. } => {
+            let url = match base.ends_with('/') {
+                true => format!("{base}{}", urlencoding::encode(name)),
+                false => base.to_owned(),
+            };
+            vec![(url, *length)]
+        }
+        Mode::Multi {
+            dir_name, files, ..
+        } => files
+            .iter()
+            .map(|file| {
+                let path = once(dir_name)
+                    .chain(&file.path)
+                    .map(|part| urlencoding::encode(part))
+                    .collect::<Vec<_>>()
+                    .join("/");
+                (
+                    format!("{}/{path}", base.trim_end_matches('/')),
+                    file.length,
+                )
+            })
+            .collect(),
+    };
+
+    let mut segments = Vec::new();
+    let mut offset = 0;
+    for (url, length) in files {
+        let overlap = range.start.max(offset)..range.end.min(offset + length);
+        if !overlap.is_empty() {
+            segments.push((url, overlap.start - offset..overlap.end - offset));
+        }
+        offset += length;
+    }
+
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tokio::sync::broadcast;
+
+    use super::*;
+    use crate::{
+        data::{Info, TorrentInfo},
+        download::Pipeline,
+        piece_manager::sha1,
+        stream::StreamServer,
+        testing::{multi, TempDir},
+        torrent::AddOptions,
+    };
+
+    #[test]
+    fn test_segments() {
+        let single = Mode::Single {
+            name: "a b.iso".to_owned(),
+            length: 10,
+            md5sum: None,
+        };
+        assert_eq!(
+            segments("http://mirror/", &single, 2..6),
+            vec![("http://mirror/a%20b.iso".to_owned(), 2..6)]
+        );
+        assert_eq!(
+            segments("http://mirror/x.iso", &single, 0..10),
+            vec![("http://mirror/x.iso".to_owned(), 0..10)]
+        );
+
+        let multi = multi("dir", &[("a", 4), ("empty", 0), ("sub/b", 8)]);
+        assert_eq!(
+            segments("http://mirror/pub", &multi, 2..7),
+            vec![
+                ("http://mirror/pub/dir/a".to_owned(), 2..4),
+                ("http://mirror/pub/dir/sub/b".to_owned(), 0..3),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_webseed() -> Result<(), Report> {
+        let dir = TempDir::new();
+        let (mirror, root) = (dir.join("mirror"), dir.join("root"));
+        fs::create_dir_all(&mirror)?;
+        let data: Vec<u8> = (0..12).collect();
+        fs::write(mirror.join("data"), &data)?;
+
+        let info = TorrentInfo {
+            info: Some(Info {
+                mode: Mode::Single {
+                    name: "data".to_owned(),
+                    length: 12,
+                    md5sum: None,
+                },
+                piece_length: 8,
+                pieces: vec![sha1(&data[..8]), sha1(&data[8..])].into_boxed_slice(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        // the streaming server of a seed makes a fine mirror
+        let options = AddOptions {
+            seed_mode: true,
+            root: Some(mirror),
+            ..Default::default()
+        };
+        let seed = Arc::new(RwLock::new(Torrent::new(info.clone(), options)?));
+        let server = StreamServer::bind("127.0.0.1:0".parse()?, seed).await?;
+        let url = format!("http://{}/0", server.local_addr()?);
+        tokio::spawn(server.run());
+
+        let options = AddOptions {
+            root: Some(root.clone()),
+            block_size: Some(4),
+            ..Default::default()
+        };
+        let mut torrent = Torrent::new(info, options)?;
+        torrent.files_checked()?;
+        let torrent = Arc::new(RwLock::new(torrent));
+
+        let (have_tx, mut have_rx) = broadcast::channel(4);
+        let cancel_tx = broadcast::channel(4).0;
+        let (pipeline, block_tx) = Pipeline::new(torrent.clone(), have_tx, cancel_tx, 4);
+        tokio::spawn(pipeline.run());
+
+        let downloader = Downloader::new(torrent.clone(), block_tx);
+        let timeout = Duration::from_secs(1);
+        let seed = WebSeed::new(url, torrent.clone(), downloader, timeout, None)?;
+        let seed = tokio::spawn(seed.run());
+
+        let mut pieces = [have_rx.recv().await?, have_rx.recv().await?];
+        pieces.sort();
+        assert_eq!(pieces, [0, 1]);
+        assert_eq!(torrent.read().await.state(), State::Seeding);
+        assert_eq!(fs::read(root.join("data"))?, data);
+
+        seed.abort();
+
+        Ok(())
+    }
+}
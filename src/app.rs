@@ -1,84 +1,87 @@
-use std::collections::VecDeque;
-use std::{
-    io::Write,
-    sync::Arc,
-    time::{Duration, Instant},
-};
+use std::time::{Duration, Instant};
 
 use color_eyre::Report;
-use crossterm::event::Event;
-use tokio::sync::mpsc::{Sender, UnboundedReceiver};
-use tokio::sync::{oneshot, RwLock};
+use crossterm::event::{Event, KeyCode};
+use tokio::sync::{mpsc::Sender, watch};
 use tui::{
     backend::Backend,
-    layout::{Alignment, Constraint, Direction, Layout},
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
-    text::{Span, Text},
-    widgets::{Block, Borders, List, ListItem, ListState, Row, Table, TableState},
+    text::{Span, Spans},
+    widgets::{Block, Borders, Cell, Gauge, Paragraph, Row, Table, TableState, Tabs},
     Frame, Terminal,
 };
 
-use crate::helpers::prettier;
+use everlasting_core::{
+    monitor::{PeerSnapshot, Snapshot, TorrentSnapshot},
+    torrent::State,
+};
 
-pub struct App {
-    actions: StatefulList<String>,
-    table: StatefulTable<String>,
-    stdout: StatefulList<String>,
-    buffer: Arc<RwLock<VecDeque<String>>>,
+// characters the progress bars in the torrent list are drawn with
+const BAR_WIDTH: usize = 20;
 
-    tick_rate: Duration,
+// the torrent list, and the details of one of them once it's opened
+pub struct App {
+    snapshots: watch::Receiver<Snapshot>,
+    snapshot: Snapshot,
     tx_actions: Sender<Action>,
-    // writer: Writer,
+    tick_rate: Duration,
+    torrents: TableState,
+    // by hash, the list gets sorted again on every sample
+    detail: Option<[u8; 20]>,
+    tab: Tab,
 }
 
-impl App {
-    pub fn new(tx_actions: Sender<Action>, tick_rate: Duration) -> Self {
-        let titles = vec!["connect".to_owned(), "announce".to_owned()];
+// what the dashboard asks the session to do
+#[derive(Debug)]
+pub enum Action {
+    Pause([u8; 20]),
+    Resume([u8; 20]),
+    // announce right away instead of waiting out the interval
+    Reannounce([u8; 20]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Tab {
+    Files,
+    Peers,
+    Trackers,
+}
 
+impl Tab {
+    const ALL: [Tab; 3] = [Tab::Files, Tab::Peers, Tab::Trackers];
+
+    fn next(self) -> Self {
+        Self::ALL[(self as usize + 1) % Self::ALL.len()]
+    }
+
+    fn prev(self) -> Self {
+        Self::ALL[(self as usize + Self::ALL.len() - 1) % Self::ALL.len()]
+    }
+}
+
+impl App {
+    pub fn new(
+        snapshots: watch::Receiver<Snapshot>,
+        tx_actions: Sender<Action>,
+        tick_rate: Duration,
+    ) -> Self {
         Self {
-            actions: StatefulList::new(titles.clone()),
-            table: StatefulTable::new(titles),
-            stdout: StatefulList::new(Vec::new()),
-            buffer: Default::default(),
-            tick_rate,
+            snapshots,
+            snapshot: Snapshot::default(),
             tx_actions,
+            tick_rate,
+            torrents: TableState::default(),
+            detail: None,
+            tab: Tab::Files,
         }
     }
 
-    pub async fn run<B: Backend>(
-        &mut self,
-        term: &mut Terminal<B>,
-        (mut rx_stdout, mut rx_err): (UnboundedReceiver<String>, oneshot::Receiver<Report>),
-    ) -> Result<(), Report> {
-        let buffer = self.buffer.clone();
-        tokio::spawn(async move {
-            while let r = rx_stdout.try_recv() {
-                if let Ok(s) = r {
-                    let mut buffer = buffer.write().await;
-                    if buffer.len() > 100 {
-                        buffer.pop_back();
-                    }
-                    buffer.push_front(s);
-                }
-            }
-        });
-
-        tokio::spawn(async move {
-            while let r = rx_err.try_recv() {
-                if let Ok(e) = r {
-                    panic!("{}", e)
-                }
-            }
-        });
-
+    pub async fn run<B: Backend>(&mut self, term: &mut Terminal<B>) -> Result<(), Report> {
         let mut last_tick = Instant::now();
-        loop {
-            let buffer = self.buffer.read().await;
-            let b = buffer.as_slices();
-
-            self.stdout.items = [b.0, b.1].concat().to_vec();
-            drop(buffer);
+        self.on_tick();
 
+        loop {
             term.draw(|f| self.ui(f))?;
 
             let timeout = self
@@ -88,85 +91,157 @@ impl App {
 
             if crossterm::event::poll(timeout)? {
                 if let Event::Key(key) = crossterm::event::read()? {
-                    use crossterm::event::KeyCode::*;
-
-                    match self.table.state.selected() {
-                        Some(0) => match key.code {
-                            Char('q') => return Ok(()),
-                            Left => self.actions.unselect(),
-                            Down => self.actions.next(),
-                            Up => self.actions.prev(),
-                            Tab => {
-                                self.table.next();
-                            }
-                            Enter => {
-                                let i = self.actions.state.selected().unwrap_or(0);
-
-                                let action = match i {
-                                    0 => Action::Connect,
-                                    _ => Action::Announce,
-                                };
-
-                                self.tx_actions.send(action).await?;
-                            }
-                            _ => {}
-                        },
-                        Some(_) => match key.code {
-                            Char('q') => return Ok(()),
-                            Down => self.stdout.next(),
-                            Up => self.stdout.prev(),
-                            Tab => {
-                                self.table.next();
-                            }
-                            _ => {}
-                        },
-                        None => match key.code {
-                            Char('q') => return Ok(()),
-                            Enter => self.table.state.select(Some(0)),
-                            Down => self.table.next(),
-                            Up => self.table.prev(),
-                            Tab => {
-                                self.table.next();
-                            }
-                            _ => {}
-                        },
+                    if key.code == KeyCode::Char('q') {
+                        return Ok(());
                     }
+                    self.on_key(key.code).await?;
                 }
             }
 
             if last_tick.elapsed() >= self.tick_rate {
-                self.on_tick()?;
+                self.on_tick();
                 last_tick = Instant::now();
             }
         }
     }
 
+    async fn on_key(&mut self, key: KeyCode) -> Result<(), Report> {
+        use KeyCode::*;
+
+        match (key, self.detail) {
+            (Char('r'), _) => {
+                if let Some(torrent) = self.selected() {
+                    let action = Action::Reannounce(torrent.hash);
+                    self.tx_actions.send(action).await?;
+                }
+            }
+            (Char('p'), _) => {
+                if let Some(torrent) = self.selected() {
+                    let action = match torrent.state {
+                        State::Paused => Action::Resume(torrent.hash),
+                        _ => Action::Pause(torrent.hash),
+                    };
+                    self.tx_actions.send(action).await?;
+                }
+            }
+            (Down | Char('j'), None) => self.select(1),
+            (Up | Char('k'), None) => self.select(-1),
+            (Enter | Right, None) => {
+                self.detail = self.selected().map(|torrent| torrent.hash);
+            }
+            (Esc | Backspace, Some(_)) => self.detail = None,
+            (Tab | Right, Some(_)) => self.tab = self.tab.next(),
+            (BackTab | Left, Some(_)) => self.tab = self.tab.prev(),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn on_tick(&mut self) {
+        self.snapshot = self.snapshots.borrow().clone();
+
+        // rows come and go with the torrents of the session
+        let count = self.snapshot.torrents.len();
+        let selected = match self.torrents.selected() {
+            _ if count == 0 => None,
+            Some(i) => Some(i.min(count - 1)),
+            None => Some(0),
+        };
+        self.torrents.select(selected);
+
+        if let Some(hash) = self.detail {
+            match self.snapshot.torrents.iter().position(|t| t.hash == hash) {
+                Some(i) => self.torrents.select(Some(i)),
+                None => self.detail = None,
+            }
+        }
+    }
+
+    fn selected(&self) -> Option<&TorrentSnapshot> {
+        self.torrents
+            .selected()
+            .and_then(|i| self.snapshot.torrents.get(i))
+    }
+
+    fn select(&mut self, by: isize) {
+        let count = self.snapshot.torrents.len() as isize;
+        if count == 0 {
+            return;
+        }
+
+        let i = self.torrents.selected().unwrap_or(0) as isize;
+        self.torrents
+            .select(Some((i + by).rem_euclid(count) as usize));
+    }
+
     fn ui<B: Backend>(&mut self, f: &mut Frame<B>) {
         let layout = Layout::default()
             .direction(Direction::Vertical)
-            .constraints([Constraint::Percentage(50), Constraint::Percentage(25)].as_ref())
+            .constraints(
+                [
+                    Constraint::Length(1),
+                    Constraint::Min(0),
+                    Constraint::Length(1),
+                ]
+                .as_ref(),
+            )
             .split(f.size());
 
-        let items: Vec<ListItem> = self
-            .actions
-            .items
-            .iter()
-            .map(|a| ListItem::new(Span::styled(a, Style::default())))
-            .collect();
-
-        let style = match self.table.state.selected() {
-            None => (Style::default(), Style::default()),
-            Some(0) => (Style::default().fg(Color::LightGreen), Style::default()),
-            Some(_) => (Style::default(), Style::default().fg(Color::LightGreen)),
+        let session = &self.snapshot.session;
+        let header = format!(
+            "down {}/s, up {}/s, {} torrents",
+            human(session.down),
+            human(session.up),
+            self.snapshot.torrents.len()
+        );
+        f.render_widget(Paragraph::new(header), layout[0]);
+
+        let help = match self.detail {
+            None => "q quit, enter details, p pause/resume, r reannounce",
+            Some(_) => "q quit, esc back, tab next tab, p pause/resume, r reannounce",
         };
+        let help = Paragraph::new(help).style(Style::default().fg(Color::DarkGray));
+        f.render_widget(help, layout[2]);
+
+        match self.selected().filter(|_| self.detail.is_some()).cloned() {
+            Some(torrent) => self.details(f, layout[1], &torrent),
+            None => self.list(f, layout[1]),
+        }
+    }
+
+    fn list<B: Backend>(&mut self, f: &mut Frame<B>, area: Rect) {
+        let rows = self.snapshot.torrents.iter().map(|torrent| {
+            Row::new(vec![
+                Cell::from(torrent.name.clone()),
+                Cell::from(torrent.status.clone()).style(state_style(&torrent.state)),
+                Cell::from(bar(torrent.progress())),
+                Cell::from(format!("{}/s", human(torrent.down))),
+                Cell::from(format!("{}/s", human(torrent.up))),
+                Cell::from(eta(torrent.eta())),
+                Cell::from(torrent.peers.len().to_string()),
+            ])
+        });
+        let header = Row::new(["name", "state", "progress", "down", "up", "eta", "peers"])
+            .style(Style::default().add_modifier(Modifier::BOLD));
 
-        let items = List::new(items)
+        let table = Table::new(rows)
+            .header(header)
             .block(
                 Block::default()
-                    .borders(Borders::NONE)
-                    .title(Span::styled("actions", style.0))
+                    .borders(Borders::ALL)
+                    .title("torrents")
                     .title_alignment(Alignment::Center),
             )
+            .widths(&[
+                Constraint::Min(20),
+                Constraint::Length(18),
+                Constraint::Length(BAR_WIDTH as u16 + 8),
+                Constraint::Length(12),
+                Constraint::Length(12),
+                Constraint::Length(9),
+                Constraint::Length(5),
+            ])
             .highlight_style(
                 Style::default()
                     .bg(Color::Black)
@@ -174,135 +249,231 @@ impl App {
             )
             .highlight_symbol("> ");
 
-        f.render_stateful_widget(items, layout[0], &mut self.actions.state);
+        f.render_stateful_widget(table, area, &mut self.torrents);
+    }
 
-        let stdout = self
-            .stdout
-            .items
-            .iter()
-            .map(|s| Row::new(Text::from(prettier(s.to_owned()))))
-            .collect::<Vec<_>>();
+    fn details<B: Backend>(&self, f: &mut Frame<B>, area: Rect, torrent: &TorrentSnapshot) {
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .title(torrent.name.as_str())
+            .title_alignment(Alignment::Center);
+        let inner = block.inner(area);
+        f.render_widget(block, area);
 
-        let stdout = Table::new(stdout.clone())
-            .block(
-                Block::default()
-                    .borders(Borders::ALL)
-                    .title(Span::styled("stdout", style.1))
-                    .title_alignment(Alignment::Center),
+        let layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(
+                [
+                    Constraint::Length(1),
+                    Constraint::Length(3),
+                    Constraint::Length(1),
+                    Constraint::Min(0),
+                ]
+                .as_ref(),
             )
-            .highlight_style(
-                Style::default()
-                    .bg(Color::Black)
-                    .add_modifier(Modifier::BOLD),
-            );
+            .split(inner);
+
+        let gauge = Gauge::default()
+            .gauge_style(state_style(&torrent.state))
+            .ratio(torrent.progress())
+            .label(format!("{:.1}%", torrent.progress() * 100.0));
+        f.render_widget(gauge, layout[0]);
+
+        let summary = vec![
+            Spans::from(format!(
+                "{}, {} of {}, {} left, eta {}",
+                torrent.status,
+                human(torrent.size.saturating_sub(torrent.left)),
+                human(torrent.size),
+                human(torrent.left),
+                eta(torrent.eta()),
+            )),
+            Spans::from(format!(
+                "down {}/s ({} total), up {}/s ({} total)",
+                human(torrent.down),
+                human(torrent.downloaded),
+                human(torrent.up),
+                human(torrent.uploaded),
+            )),
+            Spans::from(format!(
+                "info hash {}",
+                torrent
+                    .hash
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect::<String>()
+            )),
+        ];
+        f.render_widget(Paragraph::new(summary), layout[1]);
+
+        let titles = ["files", "peers", "trackers"]
+            .into_iter()
+            .map(Spans::from)
+            .collect();
+        let tabs = Tabs::new(titles).select(self.tab as usize).highlight_style(
+            Style::default()
+                .fg(Color::LightGreen)
+                .add_modifier(Modifier::BOLD),
+        );
+        f.render_widget(tabs, layout[2]);
+
+        let swarm = match &torrent.swarm {
+            Some(status) => format!(
+                "seeders {}, leechers {}, completed {}",
+                status.seeders, status.leechers, status.finished
+            ),
+            None => "not scraped yet".to_owned(),
+        };
 
-        f.render_stateful_widget(stdout, layout[1], &mut self.table.state);
+        match self.tab {
+            Tab::Files => {
+                let rows = torrent.files.iter().map(|file| {
+                    Row::new(vec![
+                        file.path.display().to_string(),
+                        human(file.size),
+                        match file.complete {
+                            true => "done".to_owned(),
+                            false => String::new(),
+                        },
+                    ])
+                });
+                let table = Table::new(rows)
+                    .header(
+                        Row::new(["path", "size", ""])
+                            .style(Style::default().add_modifier(Modifier::BOLD)),
+                    )
+                    .widths(&[
+                        Constraint::Min(20),
+                        Constraint::Length(12),
+                        Constraint::Length(4),
+                    ]);
+                f.render_widget(table, layout[3]);
+            }
+            Tab::Peers => {
+                let layout = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Length(1), Constraint::Min(0)].as_ref())
+                    .split(layout[3]);
+
+                let summary = format!("connected to {}, {swarm}", torrent.peers.len());
+                let summary = Paragraph::new(summary).style(Style::default().fg(Color::DarkGray));
+                f.render_widget(summary, layout[0]);
+
+                let rows = torrent.peers.iter().map(|peer| {
+                    Row::new(vec![
+                        peer.addr.to_string(),
+                        peer.status.client.clone().unwrap_or_default(),
+                        flags(peer),
+                        format!("{}/s", human(peer.rates.down)),
+                        format!("{}/s", human(peer.rates.up)),
+                        format!("{:.1}%", peer.completed * 100.0),
+                    ])
+                });
+                let table = Table::new(rows)
+                    .header(
+                        Row::new(["address", "client", "flags", "down", "up", "has"])
+                            .style(Style::default().add_modifier(Modifier::BOLD)),
+                    )
+                    .widths(&[
+                        Constraint::Length(24),
+                        Constraint::Min(16),
+                        Constraint::Length(8),
+                        Constraint::Length(12),
+                        Constraint::Length(12),
+                        Constraint::Length(6),
+                    ]);
+                f.render_widget(table, layout[1]);
+            }
+            Tab::Trackers => {
+                let mut lines: Vec<Spans> = torrent
+                    .trackers
+                    .iter()
+                    .map(|tracker| Spans::from(tracker.as_str()))
+                    .collect();
+                if lines.is_empty() {
+                    lines.push(Spans::from("no trackers, peers come from the DHT and PEX"));
+                }
+                lines.push(Spans::from(""));
+                lines.push(Spans::from(Span::styled(
+                    swarm,
+                    Style::default().fg(Color::DarkGray),
+                )));
+                f.render_widget(Paragraph::new(lines), layout[3]);
+            }
+        }
     }
+}
 
-    fn on_tick(&mut self) -> Result<(), Report> {
-        // let x = self.stdout.items.remove(0);
-        // self.stdout.items.push(x);
+fn state_style(state: &State) -> Style {
+    let color = match state {
+        State::Downloading | State::DownloadingMetadata => Color::LightBlue,
+        State::Seeding => Color::LightGreen,
+        State::CheckingFiles => Color::Yellow,
+        State::Paused => Color::DarkGray,
+        State::Error(_) => Color::LightRed,
+    };
 
-        Ok(())
-    }
-}
-#[derive(Debug)]
-pub enum Action {
-    Connect,
-    Announce,
+    Style::default().fg(color)
 }
 
-pub struct StatefulList<T> {
-    state: ListState,
-    items: Vec<T>,
+// the way most clients show them: D and d we want something and the peer unchoked or choked us, U
+// and u the other way around, K the peer unchoked us for nothing, ? we unchoked a peer that
+// doesn't want anything, S it snubs us
+fn flags(peer: &PeerSnapshot) -> String {
+    let status = &peer.status;
+    let flags = [
+        (status.interested && !status.choked, 'D'),
+        (status.interested && status.choked, 'd'),
+        (status.peer_interested && !status.peer_choked, 'U'),
+        (status.peer_interested && status.peer_choked, 'u'),
+        (!status.interested && !status.choked, 'K'),
+        (!status.peer_interested && !status.peer_choked, '?'),
+        (peer.rates.snubbed, 'S'),
+    ];
+
+    flags
+        .into_iter()
+        .filter_map(|(set, flag)| set.then_some(flag))
+        .collect()
 }
 
-pub struct StatefulTable<T> {
-    state: TableState,
-    items: Vec<T>,
+fn bar(progress: f64) -> String {
+    let filled = (progress * BAR_WIDTH as f64).round() as usize;
+    format!(
+        "{}{} {:5.1}%",
+        "#".repeat(filled),
+        "-".repeat(BAR_WIDTH - filled.min(BAR_WIDTH)),
+        progress * 100.0
+    )
 }
 
-impl<T> StatefulList<T> {
-    pub fn new(items: Vec<T>) -> Self {
-        Self {
-            state: ListState::default(),
-            items,
-        }
-    }
-
-    fn next(&mut self) {
-        let i = match self.state.selected() {
-            Some(i) => {
-                if i >= self.items.len() - 1 {
-                    0
-                } else {
-                    i + 1
-                }
-            }
-            None => 0,
-        };
-
-        self.state.select(Some(i));
-    }
-
-    fn prev(&mut self) {
-        let i = match self.state.selected() {
-            Some(i) => {
-                if i == 0 {
-                    self.items.len() - 1
-                } else {
-                    i - 1
-                }
-            }
-            None => 0,
-        };
+// bytes, the way people read them
+fn human(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
 
-        self.state.select(Some(i));
+    let mut size = n as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
     }
 
-    fn unselect(&mut self) {
-        self.state.select(None);
+    match unit {
+        0 => format!("{n} B"),
+        _ => format!("{size:.1} {}", UNITS[unit]),
     }
 }
-impl<T> StatefulTable<T> {
-    pub fn new(items: Vec<T>) -> Self {
-        Self {
-            state: TableState::default(),
-            items,
-        }
-    }
-
-    fn next(&mut self) {
-        let i = match self.state.selected() {
-            Some(i) => {
-                if i >= self.items.len() - 1 {
-                    0
-                } else {
-                    i + 1
-                }
-            }
-            None => 0,
-        };
 
-        self.state.select(Some(i));
+fn eta(eta: Option<Duration>) -> String {
+    let Some(eta) = eta else {
+        return "-".to_owned();
+    };
+
+    let secs = eta.as_secs();
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        3600..=86399 => format!("{}h {:02}m", secs / 3600, secs / 60 % 60),
+        _ => format!("{}d {:02}h", secs / 86400, secs / 3600 % 24),
     }
-
-    fn prev(&mut self) {
-        let i = match self.state.selected() {
-            Some(i) => {
-                if i == 0 {
-                    self.items.len() - 1
-                } else {
-                    i - 1
-                }
-            }
-            None => 0,
-        };
-
-        self.state.select(Some(i));
-    }
-
-    // fn unselect(&mut self) {
-    //     self.state.select(None);
-    // }
 }
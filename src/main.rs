@@ -1,87 +1,627 @@
-#![feature(vec_push_within_capacity)]
-#![feature(slice_take)]
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+};
 
-use ahash::HashSet;
 use bendy::decoding::FromBencode;
-use data::TorrentInfo;
+use clap::{Parser, Subcommand};
 
-use lazy_static::lazy_static;
-use rand::distributions::Alphanumeric;
-use rand::Rng;
-
-use tracker::HttpTracker;
-
-use crate::tracker::UdpTracker;
+use everlasting_core::config::{Config, Context};
+use everlasting_core::data::{self, TorrentInfo};
+use everlasting_core::demux::Demux;
+use everlasting_core::dht::{Dht, DhtState, Table};
+use everlasting_core::extensions::ExtensionRegistry;
+use everlasting_core::fetch::{self, Fetcher};
+#[cfg(feature = "fuse")]
+use everlasting_core::fuse;
+use everlasting_core::helpers::PortRange;
+use everlasting_core::lsd::Lsd;
+use everlasting_core::manager::TorrentManager;
+use everlasting_core::monitor::Monitor;
+use everlasting_core::mse::Encryption;
+use everlasting_core::peer::PeerListener;
+use everlasting_core::peer_id::{self, parse_version, PeerIdConfig};
+use everlasting_core::picker::{Priority, Strategy};
+use everlasting_core::piece_manager::SyncPolicy;
+use everlasting_core::port_mapping::PortMapper;
+use everlasting_core::proxy::Proxy;
+use everlasting_core::rpc::{self, Request, Response, RpcClient, RpcServer};
+use everlasting_core::settings::{self, Settings};
+use everlasting_core::sqlite::Database;
+use everlasting_core::stats::{Format, Summary};
+use everlasting_core::storage::Allocation;
+use everlasting_core::stream::StreamServer;
+use everlasting_core::torrent::AddOptions;
+use everlasting_core::trace::Inspector;
+use everlasting_core::tracker_session::AnnounceInterval;
+use everlasting_core::utp::UtpSocket;
 
 use color_eyre::Report;
 
+use tokio::sync::{mpsc, Mutex};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+use app::{Action, App};
+
 pub mod app;
-pub mod bencode;
-pub mod data;
-pub mod dht;
-pub mod extensions;
-pub mod framing;
-pub mod helpers;
-pub mod krpc;
-pub mod peer;
-pub mod piece_manager;
-pub mod pwp;
-pub mod sqlite;
-pub mod torrent;
-pub mod tracker;
-pub mod tracker_session;
-pub mod udp;
-
-lazy_static! {
-    static ref BLOCK_SIZE: usize = 2 ^ 14;
-    static ref BITTORRENT_PORT: u16 = 1317;
-    static ref PEER_ID_PREFIX: &'static str = "XV";
-    static ref SUFFIX: Vec<char> = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(16)
-        .map(Into::<char>::into)
-        .collect();
-    static ref PEER_ID: [u8; 20] = format!(
-        "-XV{}-{}",
-        SUFFIX[..4].iter().collect::<String>(),
-        SUFFIX[4..].iter().collect::<String>()
-    )
-    .as_bytes()
-    .try_into()
-    .unwrap();
-    static ref EXTENSION_MAP: HashSet<&'static str> =
-        HashSet::from_iter(["xv_metadata"].into_iter());
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, subcommand_negates_reqs = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Paths or URLs of .torrent files, or magnet links, all of them get downloaded at the same
+    /// time
+    #[arg(value_name = "TORRENT", required_unless_present = "rpc")]
+    torrents: Vec<String>,
+    /// Trust the files already on disk and start seeding without a hash check
+    #[arg(long)]
+    seed_mode: bool,
+    /// Hash pieces read back from disk before uploading them to peers
+    #[arg(long)]
+    verify_uploads: bool,
+    /// When to fsync pieces that were written to disk
+    #[arg(long, value_enum, default_value_t)]
+    sync: SyncPolicy,
+    /// How files get sized before the download starts: sparse only sets their length, full
+    /// writes them out so the space is reserved
+    #[arg(long, value_enum, default_value_t)]
+    allocation: Allocation,
+    /// Memory for caching pieces that get uploaded, in MiB
+    #[arg(long, value_name = "MIB", default_value_t = 16)]
+    read_cache: usize,
+    /// Memory for pieces waiting to be written to disk, in MiB, 0 writes them right away
+    #[arg(long, value_name = "MIB", default_value_t = 16)]
+    write_cache: usize,
+    /// Set the modification time of finished files to the torrent's creation date
+    #[arg(long)]
+    set_mtimes: bool,
+    /// Move the files here once the download is complete
+    #[arg(long, value_name = "DIR")]
+    completed_dir: Option<PathBuf>,
+    /// Read settings from this TOML file instead of ~/.config/everlasting/config.toml, its keys
+    /// are named like the flags here, EVERLASTING_* variables override them and the flags override
+    /// both
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Download into this directory instead of ./downloads
+    #[arg(long, value_name = "DIR")]
+    root: Option<PathBuf>,
+    /// Keep .torrent files that were fetched from a URL here instead of in ./torrents
+    #[arg(long, value_name = "DIR")]
+    torrents_dir: Option<PathBuf>,
+    /// Port to listen on, or LOW-HIGH to pick a random one from the range at startup [default:
+    /// 1317]
+    #[arg(long, value_name = "PORT")]
+    port: Option<PortRange>,
+    /// Size of the blocks we request pieces in, in bytes [default: 16384]
+    #[arg(long, value_name = "BYTES", value_parser = settings::parse_block_size)]
+    block_size: Option<usize>,
+    /// Order pieces get downloaded in: sequential, or rarest-first with an optional number of
+    /// random pieces to start with, like rarest-first:4
+    #[arg(long, value_name = "STRATEGY", default_value = "sequential")]
+    strategy: Strategy,
+    /// How our peer ID is built
+    #[arg(long, value_enum, default_value_t)]
+    peer_id_style: peer_id::Style,
+    /// Client code at the start of our peer ID
+    #[arg(long, value_name = "CODE", default_value = "XV")]
+    client_code: String,
+    /// Client version encoded in our peer ID
+    #[arg(long, value_name = "VERSION", default_value = env!("CARGO_PKG_VERSION"))]
+    client_version: String,
+    /// Use a different peer ID for every torrent instead of one per session
+    #[arg(long)]
+    per_torrent_peer_id: bool,
+    /// Peers we upload to at the same time, the others stay choked [default: 4]
+    #[arg(long, value_name = "N")]
+    upload_slots: Option<usize>,
+    /// Peers each torrent is connected to at most [default: 50]
+    #[arg(long, value_name = "N")]
+    max_peers: Option<usize>,
+    /// Upload no faster than this over all torrents, in KiB/s, 0 for no limit [default: 0]
+    #[arg(long, value_name = "KIB")]
+    upload_limit: Option<u64>,
+    /// Download no faster than this over all torrents, in KiB/s, 0 for no limit [default: 0]
+    #[arg(long, value_name = "KIB")]
+    download_limit: Option<u64>,
+    /// Upload no faster than this for each torrent on its own, in KiB/s, 0 for no limit
+    #[arg(long, value_name = "KIB", default_value_t = 0)]
+    torrent_upload_limit: u64,
+    /// Download no faster than this for each torrent on its own, in KiB/s, 0 for no limit
+    #[arg(long, value_name = "KIB", default_value_t = 0)]
+    torrent_download_limit: u64,
+    /// Tell peers about every piece we complete, even the ones they already have
+    #[arg(long)]
+    send_all_haves: bool,
+    /// Seconds to wait for a peer to accept our connection [default: 3]
+    #[arg(long, value_name = "SECS")]
+    connect_timeout: Option<u64>,
+    /// Only connect to peers over TCP, and don't accept uTP connections either
+    #[arg(long)]
+    no_utp: bool,
+    /// Reach trackers, web seeds and peers through a proxy, socks5://[USER:PASS@]HOST[:PORT] or
+    /// http://[USER:PASS@]HOST[:PORT]. UDP trackers and the DHT can't use it, --no-dht keeps
+    /// them from going around it
+    #[arg(long, value_name = "URL")]
+    proxy: Option<Proxy>,
+    /// Find peers through trackers and peer exchange only, without joining the DHT
+    #[arg(long)]
+    no_dht: bool,
+    /// Don't announce torrents to the local network or look for peers there
+    #[arg(long)]
+    no_lsd: bool,
+    /// Don't ask the router to forward the listen port over UPnP or NAT-PMP
+    #[arg(long)]
+    no_port_mapping: bool,
+    /// Obfuscate peer connections with Message Stream Encryption: preferred falls back to
+    /// plaintext for peers that don't support it, required refuses them [default: preferred]
+    #[arg(long, value_enum)]
+    encryption: Option<Encryption>,
+    /// Seconds to wait for a tracker to accept our connection [default: 5]
+    #[arg(long, value_name = "SECS")]
+    tracker_timeout: Option<u64>,
+    /// How often a tracker request gets sent before giving up [default: 4]
+    #[arg(long, value_name = "N")]
+    tracker_retries: Option<u8>,
+    /// Announce to HOST no more often than every MIN and at least every MAX seconds, either may be
+    /// left out, the tracker's own minimum interval still applies; SIGUSR1 forces an announce
+    #[arg(long, value_name = "HOST=MIN:MAX")]
+    announce_interval: Vec<AnnounceInterval>,
+    /// Seed the DHT routing table from this dht.dat and keep it up to date while running, the
+    /// format other clients use, so their tables can be imported
+    #[arg(long, value_name = "FILE")]
+    dht_state: Option<PathBuf>,
+    /// Write every peer wire message that is sent or received to this file, one line each
+    #[arg(long, value_name = "FILE")]
+    trace_messages: Option<PathBuf>,
+    /// Only trace the messages exchanged with this peer
+    #[arg(long, value_name = "IP", requires = "trace_messages")]
+    trace_peer: Option<IpAddr>,
+    /// Serve the torrent's files over HTTP on this address while downloading
+    #[arg(long, value_name = "ADDR")]
+    stream: Option<SocketAddr>,
+    /// Mount the torrent's files read-only on this directory
+    #[cfg(feature = "fuse")]
+    #[arg(long, value_name = "DIR")]
+    mount: Option<PathBuf>,
+    /// Accept commands from `everlasting remote` on this Unix socket
+    #[arg(long, value_name = "PATH")]
+    rpc: Option<PathBuf>,
+    /// Print the recorded history of the torrent and exit
+    #[arg(long)]
+    history: bool,
+    /// Show a dashboard of the torrents instead of logging to the terminal, q quits
+    #[arg(long)]
+    tui: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Report session totals, per-torrent ratios and daily transfer from the history
+    Stats {
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+        /// Same as --format csv
+        #[arg(long, conflicts_with_all = ["format", "json"])]
+        csv: bool,
+        /// Same as --format json
+        #[arg(long, conflicts_with = "format")]
+        json: bool,
+    },
+    /// Control a session that was started with --rpc
+    Remote {
+        /// The socket the session accepts commands on
+        #[arg(long, value_name = "PATH", default_value = rpc::DEFAULT_SOCKET)]
+        socket: PathBuf,
+        #[command(subcommand)]
+        command: RemoteCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum RemoteCommand {
+    /// Start downloading a .torrent file, the URL of one or a magnet link
+    Add {
+        torrent: String,
+        /// Download this torrent into DIR instead of the session's directory, relative paths
+        /// are taken from where the session was started
+        #[arg(long, value_name = "DIR")]
+        root: Option<PathBuf>,
+    },
+    /// Print the state and transfer totals of every torrent
+    List,
+    /// Stop exchanging pieces for a torrent, given its info hash
+    Pause {
+        #[arg(value_parser = rpc::parse_hash)]
+        hash: [u8; 20],
+    },
+    /// Pick a paused torrent back up
+    Resume {
+        #[arg(value_parser = rpc::parse_hash)]
+        hash: [u8; 20],
+    },
+    /// Hash the files of a torrent again, pieces that don't match get downloaded again
+    Recheck {
+        #[arg(value_parser = rpc::parse_hash)]
+        hash: [u8; 20],
+    },
+    /// Announce a torrent to its trackers now instead of waiting out the interval
+    Reannounce {
+        #[arg(value_parser = rpc::parse_hash)]
+        hash: [u8; 20],
+    },
+    /// Choose how much a file of a torrent is wanted: skip, low, normal or high
+    SetPriority {
+        #[arg(value_parser = rpc::parse_hash)]
+        hash: [u8; 20],
+        /// Counting from 0, in the order the torrent lists its files
+        file: usize,
+        priority: Priority,
+    },
+    /// Fetch a byte range of a file ahead of everything else, for players seeking into a file
+    /// that isn't complete yet
+    PrioritizeRange {
+        #[arg(value_parser = rpc::parse_hash)]
+        hash: [u8; 20],
+        /// Counting from 0, in the order the torrent lists its files
+        file: usize,
+        /// First byte of the range
+        start: u64,
+        /// Byte right after the range
+        end: u64,
+    },
+    /// Stop a torrent and forget about it, its files stay on disk
+    Remove {
+        #[arg(value_parser = rpc::parse_hash)]
+        hash: [u8; 20],
+    },
+    /// Print the transfer rates and totals of the session
+    Stats,
+}
+
+// anything that isn't a URL either is taken for the path of a .torrent file
+fn is_magnet(s: &str) -> bool {
+    s.starts_with("magnet:")
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Report> {
     color_eyre::install()?;
+    let args = Args::parse();
 
     tracing_subscriber::registry()
         .with(tracing_subscriber::EnvFilter::new(
             std::env::var("RUST_LOG").unwrap_or_else(|_| "everlasting=debug".into()),
             // .unwrap_or_else(|_| "everlasting=debug,tokio=trace,runtime=trace".into()),
         ))
-        .with(tracing_subscriber::fmt::layer())
+        // the dashboard has the terminal to itself
+        .with((!args.tui).then(tracing_subscriber::fmt::layer))
         .with(console_subscriber::spawn())
         .init();
     dbg!("tracing_subscriber and color_eyre done setting up");
 
-    let torrent = std::fs::read("/home/mikoto/everlasting/music.torrent")?;
-    let info = TorrentInfo::from_bencode(&torrent).unwrap();
+    if let Some(Command::Stats { format, csv, json }) = args.command {
+        let format = match (csv, json) {
+            (true, _) => Format::Csv,
+            (_, true) => Format::Json,
+            _ => format,
+        };
+
+        let db = Database::open("./db")?;
+        let summary = Summary::new(&db.transfers()?, |hash| db.name(hash).ok().flatten());
+        print!("{}", summary.export(format));
+
+        return Ok(());
+    }
+
+    if let Some(Command::Remote { socket, command }) = args.command {
+        let request = match command {
+            RemoteCommand::Add { torrent, root } if is_magnet(&torrent) => Request::AddMagnet {
+                link: torrent,
+                root,
+            },
+            RemoteCommand::Add { torrent, root } if fetch::is_url(&torrent) => {
+                Request::AddUrl { url: torrent, root }
+            }
+            RemoteCommand::Add { torrent, root } => Request::Add {
+                torrent: std::fs::read(torrent)?,
+                root,
+            },
+            RemoteCommand::List => Request::List,
+            RemoteCommand::Pause { hash } => Request::Pause(hash),
+            RemoteCommand::Resume { hash } => Request::Resume(hash),
+            RemoteCommand::Recheck { hash } => Request::Recheck(hash),
+            RemoteCommand::Reannounce { hash } => Request::Reannounce(hash),
+            RemoteCommand::SetPriority {
+                hash,
+                file,
+                priority,
+            } => Request::SetPriority {
+                hash,
+                file,
+                priority,
+            },
+            RemoteCommand::PrioritizeRange {
+                hash,
+                file,
+                start,
+                end,
+            } => Request::PrioritizeRange {
+                hash,
+                file,
+                range: start..end,
+            },
+            RemoteCommand::Remove { hash } => Request::Remove(hash),
+            RemoteCommand::Stats => Request::Stats,
+        };
+
+        let mut client = RpcClient::connect(&socket).await?;
+        match client.call(&request).await? {
+            Response::Done => {}
+            response => println!("{response}"),
+        }
+
+        return Ok(());
+    }
+
+    if args.torrents.is_empty() && args.rpc.is_none() {
+        return Err(data::GeneralError::Usage.into());
+    }
+
+    // flags win over the environment, which wins over the settings file
+    let flags = Settings {
+        port: args.port,
+        download_dir: args.root,
+        torrents_dir: args.torrents_dir,
+        max_peers: args.max_peers,
+        block_size: args.block_size,
+        upload_slots: args.upload_slots,
+        upload_limit: args.upload_limit,
+        download_limit: args.download_limit,
+        dht: args.no_dht.then_some(false),
+        lsd: args.no_lsd.then_some(false),
+        port_mapping: args.no_port_mapping.then_some(false),
+        utp: args.no_utp.then_some(false),
+        encryption: args.encryption,
+        proxy: args.proxy,
+        connect_timeout: args.connect_timeout,
+        tracker_timeout: args.tracker_timeout,
+        tracker_retries: args.tracker_retries,
+    };
+    let settings = flags.or(Settings::load(args.config.as_deref())?);
+    let config = settings.apply(Config {
+        peer_id: PeerIdConfig {
+            style: args.peer_id_style,
+            client: args.client_code,
+            version: parse_version(&args.client_version),
+            per_torrent: args.per_torrent_peer_id,
+        },
+        suppress_have: !args.send_all_haves,
+        announce_intervals: args.announce_interval,
+        ..Default::default()
+    });
+
+    // URLs go through the proxy as well, so they wait for the settings
+    let fetcher = Fetcher::new(&config)?;
+    let mut infos = Vec::with_capacity(args.torrents.len());
+    for torrent in &args.torrents {
+        let info = match torrent {
+            _ if is_magnet(torrent) => TorrentInfo::from_magnet(torrent)?,
+            _ if fetch::is_url(torrent) => fetcher.fetch(torrent).await?.0,
+            _ => TorrentInfo::from_bencode(&std::fs::read(torrent)?).unwrap(),
+        };
+        infos.push(info);
+    }
+
+    let db = Database::open("./db")?;
+    if args.history {
+        for info in &infos {
+            for entry in db.history(&info.hash)? {
+                println!("{entry}");
+            }
+        }
+        return Ok(());
+    }
+
+    let options = AddOptions {
+        seed_mode: args.seed_mode,
+        verify_uploads: args.verify_uploads,
+        sync: args.sync,
+        allocation: args.allocation,
+        read_cache: args.read_cache << 20,
+        write_cache: args.write_cache << 20,
+        set_mtimes: args.set_mtimes,
+        completed_dir: args.completed_dir,
+        block_size: Some(config.block_size),
+        strategy: args.strategy,
+        upload_limit: args.torrent_upload_limit << 10,
+        download_limit: args.torrent_download_limit << 10,
+        ..Default::default()
+    };
+
+    // trackers, peers and the DHT all get told about the port we actually got
+    let socket = config.port.bind()?;
+    let local = socket.local_addr()?;
+    let port = local.port();
+    tracing::debug!("listening on port {port}");
+
+    let tunables = config.tunables.clone();
+    let (dht_enabled, lsd_enabled, utp_enabled, encryption) =
+        (config.dht, config.lsd, config.utp, config.encryption);
+    let port_mapping = config.port_mapping;
+    let mut ctx = Context::new(config, port, ExtensionRegistry::default());
+    if let Some(path) = &args.trace_messages {
+        ctx.set_inspector(Inspector::to_file(path, args.trace_peer)?);
+    }
+
+    // trackers, the DHT and uTP share the socket, whatever comes in gets sorted out here
+    let (mut demux, tracker_rx, dht_rx) = Demux::new(socket)?;
+    let socket = demux.socket();
+    let utp = utp_enabled.then(|| {
+        let (utp_tx, utp_rx) = mpsc::channel(tunables.channel_capacity);
+        demux.set_utp(utp_tx);
+        (UtpSocket::new(socket.clone()), utp_rx)
+    });
+    tokio::spawn(demux.run());
+
+    let announce_port = ctx.announce_port(utp.is_some());
+    let mut manager = TorrentManager::new(ctx, db.clone(), socket.clone(), tracker_rx);
+
+    // without the DHT its datagrams go nowhere
+    let dht_handle = match dht_enabled {
+        true => {
+            // a table saved by us or another client spares most of the bootstrap, without a
+            // dht.dat it's the one we left in the database
+            let table = match &args.dht_state {
+                Some(path) if path.exists() => Table::from_state(&DhtState::load(path)?)?,
+                _ => Table::load(&db)?,
+            };
+            let (mut dht, dht_handle) = Dht::new(table, socket.clone(), dht_rx, announce_port)?;
+            // trackerless torrents name a few DHT nodes to start from
+            for info in &infos {
+                dht.add_routers(&info.nodes);
+            }
+            if let Some(path) = args.dht_state.clone() {
+                dht.set_state(path);
+            }
+            dht.set_database(db);
+            dht.set_query_timeout(tunables.dht_timeout, tunables.dht_retries);
+            dht.set_shutdown(manager.signal());
+            tokio::spawn(dht.run());
+            Some(dht_handle)
+        }
+        false => None,
+    };
+
+    if let Some(dht_handle) = dht_handle {
+        manager.set_dht(dht_handle);
+    }
+
+    // somebody else may have the multicast group to themselves, the session goes on without it
+    if lsd_enabled {
+        match Lsd::new(port) {
+            Ok((lsd, lsd_handle)) => {
+                tokio::spawn(lsd.run());
+                manager.set_lsd(lsd_handle);
+            }
+            Err(e) => tracing::debug!("local service discovery is off: {e}"),
+        }
+    }
+
+    // behind a home router nobody reaches us otherwise, the forwards go away with the session
+    if port_mapping {
+        let mut mapper = PortMapper::new(port);
+        mapper.set_shutdown(manager.signal());
+        tokio::spawn(mapper.run());
+    }
+
+    // peers reach us over TCP on the same port the trackers and the DHT know about, and over the
+    // same families
+    let addr = SocketAddr::new(local.ip(), port);
+    let mut listener = PeerListener::bind(addr, manager.incoming(), tunables.clone()).await?;
+    listener.set_encryption(encryption);
+    if let Some((utp, utp_rx)) = utp {
+        let (accept_tx, accept_rx) = mpsc::channel(tunables.channel_capacity);
+        tokio::spawn(utp.clone().run(utp_rx, accept_tx));
+        listener.set_utp(accept_rx);
+        manager.set_utp(utp);
+    }
+    tokio::spawn(listener.run());
+
+    let mut torrents = Vec::with_capacity(infos.len());
+    for info in infos {
+        torrents.push(manager.add(info, options.clone()).await?);
+    }
+
+    // only the first torrent gets streamed, the others can still be mounted
+    if let (Some(addr), Some(torrent)) = (args.stream, torrents.first()) {
+        let server = StreamServer::bind(addr, torrent.clone()).await?;
+        tokio::spawn(server.run());
+    }
+
+    #[cfg(feature = "fuse")]
+    if let Some(mountpoint) = args.mount {
+        let torrents = torrents.clone();
+        tokio::task::spawn_blocking(move || fuse::mount(torrents, &mountpoint));
+    }
+
+    let manager = Arc::new(Mutex::new(manager));
+    if let Some(path) = &args.rpc {
+        let mut server = RpcServer::bind(path, manager.clone(), options)?;
+        server.set_fetcher(fetcher);
+        tokio::spawn(server.run());
+    }
+
+    // everything runs in the background from here on, until we're told to stop
+    if args.tui {
+        dashboard(manager.clone()).await?;
+    }
+    #[cfg(unix)]
+    if !args.tui {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut usr1 = signal(SignalKind::user_defined1())?;
+        let mut term = signal(SignalKind::terminate())?;
+        loop {
+            tokio::select! {
+                Some(()) = usr1.recv() => manager.lock().await.reannounce_all(),
+                _ = tokio::signal::ctrl_c() => break,
+                _ = term.recv() => break,
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    if !args.tui {
+        tokio::signal::ctrl_c().await?;
+    }
+
+    // a second Ctrl-C doesn't wait for the trackers
+    tracing::debug!("shutting down");
+    tokio::select! {
+        stopped = async { manager.lock().await.shutdown().await } => stopped,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    }
+}
+
+// until the dashboard gets closed, the terminal is given back the way it was found
+async fn dashboard(manager: Arc<Mutex<TorrentManager>>) -> Result<(), Report> {
+    use crossterm::{
+        execute,
+        terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    };
+    use tui::{backend::CrosstermBackend, Terminal};
+
+    let (monitor, snapshots) = Monitor::new(manager.clone());
+    tokio::spawn(monitor.run());
+
+    let (tx_actions, mut rx_actions) = mpsc::channel(16);
+    tokio::spawn(async move {
+        while let Some(action) = rx_actions.recv().await {
+            let manager = manager.lock().await;
+            let done = match action {
+                Action::Pause(hash) => manager.pause(&hash).await,
+                Action::Resume(hash) => manager.resume(&hash).await,
+                Action::Reannounce(hash) => manager.reannounce(&hash),
+            };
+            if let Err(e) = done {
+                tracing::debug!("{e}");
+            }
+        }
+    });
 
-    // if announce is empty we want to rely on the DHT to get a complete TorrentInfo
-    let (http, peer_rx) = HttpTracker::new(&info)?;
-    let (udp, peer_rx) = UdpTracker::new(&info)?;
-    tokio::spawn(http.run());
-    tokio::spawn(udp.run());
+    enable_raw_mode()?;
+    let mut stdout = std::io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let mut term = Terminal::new(CrosstermBackend::new(stdout))?;
 
-    // let router = Router::new(, peer_rx);
-    // router.run().await;
+    let mut app = App::new(snapshots, tx_actions, std::time::Duration::from_millis(250));
+    let ran = app.run(&mut term).await;
 
-    loop {}
+    disable_raw_mode()?;
+    execute!(term.backend_mut(), LeaveAlternateScreen)?;
+    term.show_cursor()?;
 
-    Ok(())
+    ran
 }